@@ -0,0 +1,805 @@
+//! A textual assembly language for chunks, for exercising the VM without the compiler.
+//!
+//! ```text
+//! ; Comments run to the end of the line
+//! .const 1.5          ; Append a value to the constant pool
+//! .const "name"       ; Strings are double-quoted, with \n, \t, \" and \\ escapes
+//! .line 3:7           ; Source position of the following instructions (column optional)
+//! .function add 2     ; Append a function taking 2 arguments to the constant pool
+//! .upvalue local 1    ; Capture slot 1 of the enclosing frame (or `upvalue N` to re-capture)
+//!     GetLocal 1      ; The function's code, with its own constants, lines and labels
+//!     GetLocal 2
+//!     Add
+//!     Return
+//! .end
+//! start:              ; Label the offset of the next instruction
+//!     Constant #0     ; Load pool entry 0
+//!     Constant 2      ; Append 2 to the pool and load it
+//!     GetGlobal "x"   ; Name operands are string constants, given the same way
+//!     Invoke #1 2     ; Method name and argument count
+//!     LessLocals 1 2  ; Two stack slots
+//!     IncrLocal 1 #0  ; A stack slot and a constant
+//!     Closure #2      ; Create a closure over a function constant
+//!     JumpIfFalse end ; Jump operands are labels, or raw byte distances
+//!     Loop start
+//! end:
+//!     Return
+//! .byte 0xff          ; Emit a raw byte, used to represent invalid code
+//! ```
+//!
+//! Mnemonics are the `Debug` names of [`OpCode`]. [`to_assembly`] prints any chunk in this
+//! syntax such that [`assemble`] reproduces its code, constants and positions exactly. Local
+//! variable names, which only the debugger uses, are not part of the syntax.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::rc::Rc;
+
+use thiserror::Error;
+
+use crate::chunk::{
+    Chunk, ChunkError, ColumnNum, JUMP_OPERAND_SIZE, LineNum, MAX_CONSTANTS, OPCODE_SIZE, OpCode,
+    Operand, Position,
+};
+use crate::value::{Function, Unpacked, UpvalueRef, Value};
+
+#[derive(Debug, Error)]
+pub enum AsmError {
+    #[error("[Line {line}] Unknown mnemonic '{name}'")]
+    UnknownMnemonic { line: usize, name: String },
+    #[error("[Line {line}] Unknown directive '.{name}'")]
+    UnknownDirective { line: usize, name: String },
+    #[error("[Line {line}] Expected an operand")]
+    MissingOperand { line: usize },
+    #[error("[Line {line}] Invalid operand '{operand}'")]
+    BadOperand { line: usize, operand: String },
+    #[error("[Line {line}] Unexpected operand '{operand}'")]
+    UnexpectedOperand { line: usize, operand: String },
+    #[error("[Line {line}] Constant index {index} does not fit in a {opcode:?} operand")]
+    OperandTooWide {
+        line: usize,
+        index: usize,
+        opcode: OpCode,
+    },
+    #[error("[Line {line}] Duplicate label '{name}'")]
+    DuplicateLabel { line: usize, name: String },
+    #[error("[Line {line}] Unknown label '{name}'")]
+    UnknownLabel { line: usize, name: String },
+    #[error("[Line {line}] Jump to '{name}' is too far or in the wrong direction")]
+    BadJump { line: usize, name: String },
+    #[error("[Line {line}] '.{directive}' outside of a function")]
+    NotInFunction {
+        line: usize,
+        directive: &'static str,
+    },
+    #[error("Function '{name}' is missing its '.end'")]
+    UnterminatedFunction { name: String },
+    #[error("[Line {line}] {source}")]
+    Chunk { line: usize, source: ChunkError },
+}
+
+pub type AsmResult<T> = Result<T, AsmError>;
+
+/// A jump whose label had not been defined yet when it was assembled.
+struct Fixup<'a> {
+    opcode: OpCode,
+    /// Offset of the jump's opcode
+    offset: usize,
+    label: &'a str,
+    line: usize,
+}
+
+/// The function being assembled, with the top-level code at the bottom of the stack.
+struct Builder<'a> {
+    function: Function,
+    position: Option<Position>,
+    // Number of code bytes the chunk's position table accounts for
+    covered: usize,
+    labels: HashMap<&'a str, usize>,
+    fixups: Vec<Fixup<'a>>,
+}
+
+impl Builder<'_> {
+    fn new(function: Function) -> Self {
+        Self {
+            function,
+            position: None,
+            covered: 0,
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    /// Resolve the forward jumps of a finished function.
+    fn finish(mut self) -> AsmResult<Function> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let Some(&target) = self.labels.get(fixup.label) else {
+                return Err(AsmError::UnknownLabel {
+                    line: fixup.line,
+                    name: fixup.label.to_string(),
+                });
+            };
+
+            let distance =
+                jump_distance(fixup.opcode, fixup.offset, target).ok_or(AsmError::BadJump {
+                    line: fixup.line,
+                    name: fixup.label.to_string(),
+                })?;
+
+            let operand = fixup.offset + OPCODE_SIZE;
+            self.function.chunk.code[operand..operand + JUMP_OPERAND_SIZE]
+                .copy_from_slice(&distance.to_le_bytes());
+        }
+
+        Ok(self.function)
+    }
+}
+
+struct Assembler<'a> {
+    builders: Vec<Builder<'a>>,
+    line: usize,
+}
+
+pub fn assemble(source: &str) -> AsmResult<Chunk> {
+    let mut assembler = Assembler {
+        builders: vec![Builder::new(Function::default())],
+        line: 0,
+    };
+
+    for (i, text) in source.lines().enumerate() {
+        assembler.line = i + 1;
+
+        let text = strip_comment(text).trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        assembler.statement(text)?;
+    }
+
+    let builder = assembler
+        .builders
+        .pop()
+        .expect("The top level is never popped");
+    if !assembler.builders.is_empty() {
+        return Err(AsmError::UnterminatedFunction {
+            name: builder.function.name.unwrap_or_default(),
+        });
+    }
+
+    Ok(builder.finish()?.chunk)
+}
+
+/// Remove a `;` comment, ignoring semicolons inside string literals.
+fn strip_comment(text: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &text[..i],
+            _ => {}
+        }
+    }
+
+    text
+}
+
+impl<'a> Assembler<'a> {
+    fn builder(&mut self) -> &mut Builder<'a> {
+        self.builders
+            .last_mut()
+            .expect("The top level is never popped")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.builder().function.chunk
+    }
+
+    fn statement(&mut self, text: &'a str) -> AsmResult<()> {
+        if let Some(label) = text.strip_suffix(':') {
+            return self.label(label.trim());
+        }
+
+        let (head, operand) = match text.split_once(char::is_whitespace) {
+            Some((head, rest)) => (head, Some(rest.trim())),
+            None => (text, None),
+        };
+
+        if let Some(directive) = head.strip_prefix('.') {
+            self.directive(directive, operand)
+        } else {
+            self.instruction(head, operand)
+        }
+    }
+
+    fn label(&mut self, name: &'a str) -> AsmResult<()> {
+        let offset = self.chunk().code.len();
+        if self.builder().labels.insert(name, offset).is_some() {
+            return Err(AsmError::DuplicateLabel {
+                line: self.line,
+                name: name.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn directive(&mut self, name: &str, operand: Option<&'a str>) -> AsmResult<()> {
+        if name == "end" {
+            if let Some(operand) = operand {
+                return Err(AsmError::UnexpectedOperand {
+                    line: self.line,
+                    operand: operand.to_string(),
+                });
+            }
+
+            return self.end_function();
+        }
+
+        let operand = operand.ok_or(AsmError::MissingOperand { line: self.line })?;
+
+        match name {
+            "const" => {
+                let value = self.literal(operand)?;
+                self.add_constant(value)?;
+            }
+
+            "line" => {
+                let (line, column) = operand.split_once(':').unwrap_or((operand, "0"));
+                let line: LineNum = line.trim().parse().map_err(|_| self.bad(operand))?;
+                let column: ColumnNum = column.trim().parse().map_err(|_| self.bad(operand))?;
+                self.builder().position = Some(Position::new(line, column));
+            }
+
+            "byte" => {
+                let digits = operand
+                    .strip_prefix("0x")
+                    .ok_or_else(|| self.bad(operand))?;
+                let byte = u8::from_str_radix(digits, 16).map_err(|_| self.bad(operand))?;
+                self.emit(&[byte]);
+            }
+
+            "function" => {
+                let (name, arity) = operand
+                    .rsplit_once(char::is_whitespace)
+                    .ok_or_else(|| self.bad(operand))?;
+                let arity = arity.parse().map_err(|_| self.bad(operand))?;
+
+                self.builders.push(Builder::new(Function {
+                    name: Some(name.trim().to_string()),
+                    arity,
+                    ..Function::default()
+                }));
+            }
+
+            "upvalue" => {
+                if self.builders.len() < 2 {
+                    return Err(AsmError::NotInFunction {
+                        line: self.line,
+                        directive: "upvalue",
+                    });
+                }
+
+                let (kind, index) = operand
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| self.bad(operand))?;
+                let is_local = match kind {
+                    "local" => true,
+                    "upvalue" => false,
+                    _ => return Err(self.bad(operand)),
+                };
+                let index = index.trim().parse().map_err(|_| self.bad(operand))?;
+
+                self.builder()
+                    .function
+                    .upvalues
+                    .push(UpvalueRef { is_local, index });
+            }
+
+            _ => {
+                return Err(AsmError::UnknownDirective {
+                    line: self.line,
+                    name: name.to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finish the innermost function and append it to the constant pool of the one enclosing it.
+    fn end_function(&mut self) -> AsmResult<()> {
+        if self.builders.len() < 2 {
+            return Err(AsmError::NotInFunction {
+                line: self.line,
+                directive: "end",
+            });
+        }
+
+        let builder = self.builders.pop().expect("Checked above");
+        let function = builder.finish()?;
+        self.add_constant(Value::from(Rc::new(function)))?;
+
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operand: Option<&'a str>) -> AsmResult<()> {
+        let opcode: OpCode = mnemonic.parse().map_err(|_| AsmError::UnknownMnemonic {
+            line: self.line,
+            name: mnemonic.to_string(),
+        })?;
+
+        let kind = opcode.operand();
+        let operand = match (kind, operand) {
+            (Operand::None, None) => None,
+            (Operand::None, Some(operand)) => {
+                return Err(AsmError::UnexpectedOperand {
+                    line: self.line,
+                    operand: operand.to_string(),
+                });
+            }
+            (_, None) => return Err(AsmError::MissingOperand { line: self.line }),
+            (_, Some(operand)) => Some(operand),
+        };
+
+        let operand_bytes = match (kind, operand) {
+            (_, None) => Vec::new(),
+
+            (
+                Operand::Constant(width) | Operand::Name(width) | Operand::Closure(width),
+                Some(operand),
+            ) => {
+                let index = self.constant_operand(operand)?;
+                self.constant_bytes(opcode, index, width)?
+            }
+
+            (Operand::Byte, Some(operand)) => vec![self.byte(operand)?],
+
+            (Operand::Locals, Some(operand)) => {
+                let (a, b) = operand
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| self.bad(operand))?;
+                vec![self.byte(a)?, self.byte(b)?]
+            }
+
+            (Operand::LocalConstant, Some(operand)) => {
+                let (slot, constant) = operand
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| self.bad(operand))?;
+                let slot = self.byte(slot)?;
+                let index = self.constant_operand(constant.trim())?;
+                let mut bytes = vec![slot];
+                bytes.extend(self.constant_bytes(opcode, index, 1)?);
+                bytes
+            }
+
+            (Operand::Invoke(width), Some(operand)) => {
+                let (name, argc) = operand
+                    .rsplit_once(char::is_whitespace)
+                    .ok_or_else(|| self.bad(operand))?;
+                let index = self.constant_operand(name.trim())?;
+                let mut bytes = self.constant_bytes(opcode, index, width)?;
+                bytes.push(self.byte(argc)?);
+                bytes
+            }
+
+            (Operand::Jump | Operand::Loop, Some(operand)) => {
+                let offset = self.chunk().code.len();
+                let distance = match operand.parse::<u16>() {
+                    Ok(distance) => distance,
+                    Err(_) => self.label_distance(opcode, offset, operand)?,
+                };
+                distance.to_le_bytes().to_vec()
+            }
+
+            (Operand::None, Some(_)) => unreachable!("Rejected above"),
+        };
+
+        self.emit(&[opcode as u8]);
+        self.emit(&operand_bytes);
+
+        Ok(())
+    }
+
+    /// The distance for a jump at `offset` to `label`, or a placeholder to be filled in when the
+    /// function ends if the label comes later.
+    fn label_distance(&mut self, opcode: OpCode, offset: usize, label: &'a str) -> AsmResult<u16> {
+        let line = self.line;
+        let builder = self.builder();
+
+        if let Some(&target) = builder.labels.get(label) {
+            return jump_distance(opcode, offset, target).ok_or_else(|| AsmError::BadJump {
+                line,
+                name: label.to_string(),
+            });
+        }
+
+        builder.fixups.push(Fixup {
+            opcode,
+            offset,
+            label,
+            line,
+        });
+        Ok(0)
+    }
+
+    /// Resolve a constant operand: `#N` names pool entry N, a literal is appended to the pool.
+    fn constant_operand(&mut self, operand: &str) -> AsmResult<usize> {
+        if let Some(index) = operand.strip_prefix('#') {
+            return index.parse().map_err(|_| self.bad(operand));
+        }
+
+        let value = self.literal(operand)?;
+        self.add_constant(value)
+    }
+
+    fn constant_bytes(&self, opcode: OpCode, index: usize, width: usize) -> AsmResult<Vec<u8>> {
+        if index >= 1 << (8 * width) {
+            return Err(AsmError::OperandTooWide {
+                line: self.line,
+                index,
+                opcode,
+            });
+        }
+
+        Ok(index.to_le_bytes()[..width].to_vec())
+    }
+
+    fn add_constant(&mut self, value: Value) -> AsmResult<usize> {
+        if self.chunk().constants.len() >= MAX_CONSTANTS {
+            return Err(AsmError::Chunk {
+                line: self.line,
+                source: ChunkError::TooManyConstants,
+            });
+        }
+
+        Ok(self.chunk().push_constant(value))
+    }
+
+    /// Parse a number or a double-quoted string.
+    fn literal(&self, operand: &str) -> AsmResult<Value> {
+        if let Some(quoted) = operand.strip_prefix('"') {
+            let text = quoted.strip_suffix('"').ok_or_else(|| self.bad(operand))?;
+            return unescape(text)
+                .map(Value::string)
+                .ok_or_else(|| self.bad(operand));
+        }
+
+        operand
+            .parse::<f64>()
+            .map(Value::from)
+            .map_err(|_| self.bad(operand))
+    }
+
+    fn byte(&self, operand: &str) -> AsmResult<u8> {
+        operand.trim().parse().map_err(|_| self.bad(operand))
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        let builder = self.builder();
+        let chunk = &mut builder.function.chunk;
+
+        if let Some(position) = builder.position {
+            // The position table covers a prefix of the code, so bytes emitted before the first
+            // `.line` directive get a placeholder position
+            let uncovered = chunk.code.len() - builder.covered;
+            if uncovered > 0 {
+                chunk.push_position(Position::default(), uncovered);
+            }
+
+            chunk.push_position(position, bytes.len());
+            builder.covered = chunk.code.len() + bytes.len();
+        }
+
+        chunk.code.extend_from_slice(bytes);
+    }
+
+    fn bad(&self, operand: &str) -> AsmError {
+        AsmError::BadOperand {
+            line: self.line,
+            operand: operand.to_string(),
+        }
+    }
+}
+
+/// The operand of an `opcode` jump at `offset` that makes it land on `target`, if that is the
+/// direction the jump goes in.
+fn jump_distance(opcode: OpCode, offset: usize, target: usize) -> Option<u16> {
+    let next = offset + OPCODE_SIZE + JUMP_OPERAND_SIZE;
+
+    let distance = match opcode.operand() {
+        Operand::Jump => target.checked_sub(next)?,
+        Operand::Loop => next.checked_sub(target)?,
+        _ => return None,
+    };
+
+    u16::try_from(distance).ok()
+}
+
+fn unescape(text: &str) -> Option<String> {
+    let mut res = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+
+        res.push(match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            c @ ('"' | '\\') => c,
+            _ => return None,
+        });
+    }
+
+    Some(res)
+}
+
+fn escape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            '"' | '\\' => {
+                res.push('\\');
+                res.push(c);
+            }
+            _ => res.push(c),
+        }
+    }
+
+    res
+}
+
+/// Print `chunk` as assembly that [`assemble`] turns back into an identical chunk.
+pub fn to_assembly(chunk: &Chunk) -> String {
+    let mut res = String::new();
+    write_chunk(&mut res, chunk);
+    res
+}
+
+fn write_chunk(res: &mut String, chunk: &Chunk) {
+    // `write!`ing into a String is infallible
+    for constant in &chunk.constants {
+        match constant.unpack() {
+            Unpacked::String(s) => writeln!(res, ".const \"{}\"", escape(&s)).unwrap(),
+
+            Unpacked::Function(function) => {
+                let name = function.name.as_deref().unwrap_or("script");
+                writeln!(res, ".function {name} {}", function.arity).unwrap();
+                for upvalue in &function.upvalues {
+                    let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                    writeln!(res, ".upvalue {kind} {}", upvalue.index).unwrap();
+                }
+
+                write_chunk(res, &function.chunk);
+                writeln!(res, ".end").unwrap();
+            }
+
+            _ => writeln!(res, ".const {constant}").unwrap(),
+        }
+    }
+
+    let boundaries = instruction_offsets(chunk);
+    let labels: BTreeSet<usize> = boundaries
+        .iter()
+        .filter_map(|&i| chunk.jump_target(i))
+        .filter(|target| boundaries.contains(target) || *target == chunk.code.len())
+        .collect();
+
+    let mut position = None;
+    let mut i = 0;
+    while i < chunk.code.len() {
+        if labels.contains(&i) {
+            writeln!(res, "L{i:04}:").unwrap();
+        }
+
+        let here = chunk.get_position(i);
+        if here != position
+            && let Some(p) = here
+        {
+            writeln!(res, ".line {p}").unwrap();
+            position = here;
+        }
+
+        let next = chunk.next_instruction(i);
+        let opcode = OpCode::try_from(chunk.code[i]).ok();
+
+        match opcode {
+            Some(opcode) if next <= chunk.code.len() => {
+                write!(res, "    {opcode:?}").unwrap();
+                let operands = i + OPCODE_SIZE;
+
+                match opcode.operand() {
+                    Operand::None => {}
+
+                    Operand::Constant(width) | Operand::Name(width) | Operand::Closure(width) => {
+                        let index = chunk
+                            .constant_index(operands, width)
+                            .expect("Operand bounds were checked above");
+                        write!(res, " #{index}").unwrap();
+                    }
+
+                    Operand::Byte => write!(res, " {}", chunk.code[operands]).unwrap(),
+
+                    Operand::Invoke(width) => {
+                        let name = chunk
+                            .constant_index(operands, width)
+                            .expect("Operand bounds were checked above");
+                        let argc = chunk.code[operands + width];
+                        write!(res, " #{name} {argc}").unwrap();
+                    }
+
+                    Operand::Locals => {
+                        let (a, b) = (chunk.code[operands], chunk.code[operands + 1]);
+                        write!(res, " {a} {b}").unwrap();
+                    }
+
+                    Operand::LocalConstant => {
+                        let (slot, index) = (chunk.code[operands], chunk.code[operands + 1]);
+                        write!(res, " {slot} #{index}").unwrap();
+                    }
+
+                    Operand::Jump | Operand::Loop => match chunk.jump_target(i) {
+                        Some(target) if labels.contains(&target) => {
+                            write!(res, " L{target:04}").unwrap();
+                        }
+                        _ => {
+                            let distance = chunk
+                                .jump_distance(operands)
+                                .expect("Operand bounds were checked above");
+                            write!(res, " {distance}").unwrap();
+                        }
+                    },
+                }
+
+                writeln!(res).unwrap();
+                i = next;
+            }
+
+            // Invalid opcodes and instructions truncated by the end of the chunk
+            _ => {
+                writeln!(res, ".byte {:#04x}", chunk.code[i]).unwrap();
+                i += 1;
+            }
+        }
+    }
+
+    if labels.contains(&chunk.code.len()) {
+        writeln!(res, "L{:04}:", chunk.code.len()).unwrap();
+    }
+}
+
+/// Offsets at which `to_assembly` starts printing an instruction.
+fn instruction_offsets(chunk: &Chunk) -> BTreeSet<usize> {
+    let mut offsets = BTreeSet::new();
+    let mut i = 0;
+
+    while i < chunk.code.len() {
+        offsets.insert(i);
+        let next = chunk.next_instruction(i);
+        i = if next <= chunk.code.len() {
+            next
+        } else {
+            i + 1
+        };
+    }
+
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read_to_string;
+    use std::path::Path;
+
+    use super::*;
+    use crate::compiler::compile;
+    use crate::difftest::scripts;
+    use crate::optimizer::optimize;
+
+    fn assert_round_trips(chunk: &Chunk) {
+        let text = to_assembly(chunk);
+        let reassembled = assemble(&text).unwrap_or_else(|e| panic!("{e}\n{text}"));
+
+        assert_eq!(reassembled.code, chunk.code);
+        assert_eq!(to_assembly(&reassembled), text);
+    }
+
+    #[test]
+    fn round_trips_every_test_program() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+        for path in scripts(&[dir]).unwrap() {
+            let Ok(chunk) = compile(&read_to_string(&path).unwrap()) else {
+                continue;
+            };
+
+            assert_round_trips(&chunk);
+            assert_round_trips(&optimize(&chunk));
+        }
+    }
+
+    #[test]
+    fn round_trips_invalid_and_truncated_code() {
+        let mut chunk = assemble(".line 1\nNil\n.byte 0xff").unwrap();
+        chunk.push_opcode(OpCode::Constant, Position::new(2, 1));
+        assert_round_trips(&chunk);
+    }
+
+    #[test]
+    fn resolves_labels_in_both_directions() {
+        let chunk = assemble(
+            "
+            .line 1
+            start:
+                True
+                JumpIfFalse end
+                Pop
+                Loop start
+            end:
+                Return",
+        )
+        .unwrap();
+
+        assert_eq!(chunk.jump_target(1), Some(8));
+        assert_eq!(chunk.jump_target(5), Some(0));
+    }
+
+    #[test]
+    fn assembles_nested_functions() {
+        let chunk = assemble(
+            "
+            .line 1
+            .function add 2
+            .upvalue local 1
+            .line 2
+                GetLocal 1
+                GetLocal 2
+                Add
+                Return
+            .end
+                Closure #0
+                Return",
+        )
+        .unwrap();
+
+        let function = chunk.functions().next().unwrap();
+        assert_eq!(function.to_string(), "<fn add>");
+        assert_eq!(function.arity, 2);
+        assert_eq!(function.upvalues.len(), 1);
+        assert_eq!(function.chunk.get_line(0), Some(2));
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert!(matches!(
+            assemble("Nil\nFrobnicate"),
+            Err(AsmError::UnknownMnemonic { line: 2, .. })
+        ));
+        assert!(matches!(
+            assemble("Jump nowhere"),
+            Err(AsmError::UnknownLabel { line: 1, .. })
+        ));
+        assert!(matches!(
+            assemble("a:\nNil\na:"),
+            Err(AsmError::DuplicateLabel { line: 3, .. })
+        ));
+        assert!(matches!(
+            assemble("GetLocal"),
+            Err(AsmError::MissingOperand { line: 1 })
+        ));
+        assert!(matches!(
+            assemble(".function f 0\nReturn"),
+            Err(AsmError::UnterminatedFunction { .. })
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+//! Benchmarking: run scripts repeatedly, report how long they take and how many instructions
+//! they execute, and compare the results with a saved baseline to catch regressions.
+//!
+//! A baseline is the JSON written by `--save`: the configuration the scripts ran with and, for
+//! each script by file stem, its mean, median and standard deviation in seconds and its
+//! instruction count.
+
+use std::fmt::Write as _;
+use std::fs::{read_to_string, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde_json::{Value as Json, json};
+
+use rlox::{InterpretResult, RegisterVM, VM};
+
+use crate::{Backend, CliError, CliResult};
+
+#[derive(Debug)]
+pub struct Options {
+    pub runs: u32,
+    pub backend: Backend,
+    pub optimize: bool,
+    pub baseline: Option<PathBuf>,
+    pub save: Option<PathBuf>,
+    /// Percentage by which a median time or instruction count may exceed the baseline's
+    pub threshold: f64,
+}
+
+/// Timings of one script over all its runs.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub name: String,
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64,
+    pub instructions: u64,
+}
+
+impl Summary {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(name: String, mut times: Vec<f64>, instructions: u64) -> Self {
+        times.sort_by(f64::total_cmp);
+
+        let n = times.len() as f64;
+        let mean = times.iter().sum::<f64>() / n;
+        let mid = times.len() / 2;
+        let median = if times.len().is_multiple_of(2) {
+            f64::midpoint(times[mid - 1], times[mid])
+        } else {
+            times[mid]
+        };
+
+        // The sample standard deviation, which is zero for a single run
+        let variance = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0);
+
+        Self {
+            name,
+            mean,
+            median,
+            stddev: variance.sqrt(),
+            instructions,
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        json!({
+            "mean": self.mean,
+            "median": self.median,
+            "stddev": self.stddev,
+            "instructions": self.instructions,
+        })
+    }
+}
+
+/// Compile `source` and run it once with its output discarded, returning the seconds the run
+/// took, not counting compilation, and the number of instructions executed.
+fn run_once(source: &str, backend: Backend, optimize: bool) -> InterpretResult<(f64, u64)> {
+    match backend {
+        Backend::Stack => {
+            let mut vm = VM::new();
+            vm.set_output(Box::new(io::sink()));
+
+            let mut chunk = rlox::compile(source)?;
+            if optimize {
+                chunk = rlox::optimize(&chunk);
+            }
+
+            let start = Instant::now();
+            vm.interpret_chunk(chunk)?;
+            Ok((start.elapsed().as_secs_f64(), vm.instructions_executed()))
+        }
+
+        Backend::Register => {
+            let mut vm = RegisterVM::new();
+            vm.set_output(Box::new(io::sink()));
+            let chunk = rlox::compile_registers(source)?;
+
+            let start = Instant::now();
+            vm.interpret_chunk(chunk)?;
+            Ok((start.elapsed().as_secs_f64(), vm.instructions_executed()))
+        }
+    }
+}
+
+/// Run the script at `path` `options.runs` times.
+fn measure(path: &Path, options: &Options) -> CliResult<Summary> {
+    let source = read_to_string(path)?;
+    let name = path.file_stem().map_or_else(
+        || path.display().to_string(),
+        |s| s.to_string_lossy().into_owned(),
+    );
+
+    let mut times = Vec::new();
+    let mut instructions = 0;
+    for _ in 0..options.runs {
+        let (time, executed) = run_once(&source, options.backend, options.optimize)?;
+        times.push(time);
+        instructions = executed;
+    }
+
+    Ok(Summary::new(name, times, instructions))
+}
+
+fn configuration(options: &Options) -> Json {
+    let backend = match options.backend {
+        Backend::Stack => "stack",
+        Backend::Register => "register",
+    };
+
+    json!({ "backend": backend, "optimize": options.optimize })
+}
+
+fn to_json(summaries: &[Summary], options: &Options) -> Json {
+    let benchmarks: serde_json::Map<_, _> = summaries
+        .iter()
+        .map(|s| (s.name.clone(), s.to_json()))
+        .collect();
+
+    json!({
+        "configuration": configuration(options),
+        "runs": options.runs,
+        "benchmarks": benchmarks,
+    })
+}
+
+/// Percentage change from `baseline` to `current`.
+fn change(baseline: f64, current: f64) -> f64 {
+    if baseline == 0.0 {
+        0.0
+    } else {
+        (current - baseline) / baseline * 100.0
+    }
+}
+
+/// Describe how `summaries` compare with `baseline`, returning the description and the number of
+/// benchmarks whose median time or instruction count grew by more than the threshold.
+#[allow(clippy::cast_precision_loss)]
+fn compare(summaries: &[Summary], baseline: &Json, options: &Options) -> (String, usize) {
+    let mut res = String::new();
+    let mut regressed = 0;
+
+    // `write!`ing into a String is infallible
+    if baseline["configuration"] != configuration(options) {
+        writeln!(
+            res,
+            "Warning: the baseline was recorded with a different configuration ({})",
+            baseline["configuration"]
+        )
+        .unwrap();
+    }
+
+    for summary in summaries {
+        let entry = &baseline["benchmarks"][&summary.name];
+        let (Some(median), Some(instructions)) =
+            (entry["median"].as_f64(), entry["instructions"].as_u64())
+        else {
+            writeln!(res, "{:<20} not in baseline", summary.name).unwrap();
+            continue;
+        };
+
+        let time_change = change(median, summary.median);
+        let instruction_change = change(instructions as f64, summary.instructions as f64);
+        let verdict = if time_change > options.threshold || instruction_change > options.threshold {
+            regressed += 1;
+            "  REGRESSED"
+        } else {
+            ""
+        };
+
+        writeln!(
+            res,
+            "{:<20} median {time_change:>+7.1}%   instructions {instruction_change:>+7.1}%{verdict}",
+            summary.name
+        )
+        .unwrap();
+    }
+
+    (res, regressed)
+}
+
+/// Benchmark every script in `inputs`, printing a line per script, and compare the results with
+/// a baseline or save them as one if asked to.
+pub fn run(inputs: &[PathBuf], options: &Options) -> CliResult<()> {
+    let scripts = rlox::scripts(inputs)?;
+
+    println!(
+        "{:<20} {:>10} {:>10} {:>10} {:>14}",
+        "Benchmark", "Mean", "Median", "Std dev", "Instructions"
+    );
+
+    let mut summaries = Vec::new();
+    for path in &scripts {
+        let s = measure(path, options)?;
+        println!(
+            "{:<20} {:>9.3}s {:>9.3}s {:>9.3}s {:>14}",
+            s.name, s.mean, s.median, s.stddev, s.instructions
+        );
+        summaries.push(s);
+    }
+
+    if let Some(path) = &options.save {
+        let json =
+            serde_json::to_string_pretty(&to_json(&summaries, options)).map_err(io::Error::from)?;
+        write(path, json + "\n")?;
+    }
+
+    let Some(path) = &options.baseline else {
+        return Ok(());
+    };
+
+    let baseline: Json = serde_json::from_str(&read_to_string(path)?).map_err(io::Error::from)?;
+    let (comparison, regressed) = compare(&summaries, &baseline, options);
+    println!(
+        "\nCompared with {} (threshold {}%):",
+        path.display(),
+        options.threshold
+    );
+    print!("{comparison}");
+
+    if regressed > 0 {
+        return Err(CliError::Regressed { count: regressed });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+// The timings below are exact in binary, so their summaries are too
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+
+    fn options(threshold: f64) -> Options {
+        Options {
+            runs: 3,
+            backend: Backend::Stack,
+            optimize: false,
+            baseline: None,
+            save: None,
+            threshold,
+        }
+    }
+
+    fn summary(name: &str, median: f64, instructions: u64) -> Summary {
+        Summary::new(name.to_string(), vec![median], instructions)
+    }
+
+    #[test]
+    fn summarizes_an_odd_number_of_runs() {
+        let s = Summary::new("odd".to_string(), vec![3.0, 1.0, 2.0], 7);
+        assert_eq!(s.mean, 2.0);
+        assert_eq!(s.median, 2.0);
+        assert_eq!(s.stddev, 1.0);
+        assert_eq!(s.instructions, 7);
+    }
+
+    #[test]
+    fn summarizes_an_even_number_of_runs() {
+        let s = Summary::new("even".to_string(), vec![4.0, 1.0, 3.0, 2.0], 0);
+        assert_eq!(s.mean, 2.5);
+        assert_eq!(s.median, 2.5);
+        // The sample variance divides by n - 1: 5 / 3
+        assert!((s.stddev - (5.0f64 / 3.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn has_no_deviation_over_a_single_run() {
+        let s = summary("single", 1.5, 0);
+        assert_eq!((s.mean, s.median, s.stddev), (1.5, 1.5, 0.0));
+    }
+
+    #[test]
+    fn measures_no_change_from_a_zero_baseline() {
+        assert_eq!(change(2.0, 3.0), 50.0);
+        assert_eq!(change(2.0, 1.0), -50.0);
+        assert_eq!(change(0.0, 3.0), 0.0);
+    }
+
+    #[test]
+    fn counts_benchmarks_over_the_threshold() {
+        let options = options(10.0);
+        let baseline = json!({
+            "configuration": configuration(&options),
+            "benchmarks": {
+                "same": { "median": 1.0, "instructions": 100 },
+                "slower": { "median": 1.0, "instructions": 100 },
+                "longer": { "median": 1.0, "instructions": 100 },
+                "within": { "median": 1.0, "instructions": 100 },
+            },
+        });
+        let summaries = [
+            summary("same", 1.0, 100),
+            summary("slower", 1.5, 100),
+            summary("longer", 1.0, 120),
+            summary("within", 1.05, 110),
+            summary("new", 1.0, 100),
+        ];
+
+        let (description, regressed) = compare(&summaries, &baseline, &options);
+        assert_eq!(regressed, 2);
+
+        let lines: Vec<_> = description.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(!lines[0].contains("REGRESSED"));
+        assert!(lines[1].ends_with("REGRESSED"));
+        assert!(lines[2].ends_with("REGRESSED"));
+        assert!(!lines[3].contains("REGRESSED"));
+        assert_eq!(lines[4], "new                  not in baseline");
+    }
+
+    #[test]
+    fn warns_when_the_configuration_differs() {
+        let options = options(10.0);
+        let mut baseline = json!({
+            "configuration": configuration(&options),
+            "benchmarks": {},
+        });
+        let (description, _) = compare(&[], &baseline, &options);
+        assert_eq!(description, "");
+
+        baseline["configuration"]["optimize"] = json!(true);
+        let (description, regressed) = compare(&[], &baseline, &options);
+        assert!(description.starts_with("Warning: the baseline was recorded with a different"));
+        assert_eq!(regressed, 0);
+    }
+}
@@ -0,0 +1,413 @@
+//! The `.loxc` precompiled bytecode format.
+//!
+//! All integers are little-endian regardless of the host, so files can be moved between
+//! machines. A file is laid out as:
+//!
+//! ```text
+//! magic      b"LOXC"
+//! version    u16
+//! chunk      code length (u32), code bytes
+//!            constant count (u32), constants (tag u8 + payload)
+//!            position run count (u32), runs (line u32, column u32, byte count u32)
+//!            local variable count (u32), locals (name as a string, slot u8, start offset u32,
+//!            end offset u32 or 0xffffffff if the variable lasts until the function returns)
+//! ```
+//!
+//! Constants are tagged so that new value kinds can be added without disturbing existing ones:
+//!
+//! ```text
+//! 0 number   IEEE 754 bits (u64)
+//! 1 string   length (u32), UTF-8 bytes
+//! 2 function name flag (u8), name (as a string, if the flag is 1), arity (u8),
+//!            upvalue count (u32), upvalues (is_local u8, index u8), chunk
+//! ```
+
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use thiserror::Error;
+
+use crate::chunk::{Chunk, ColumnNum, LineNum, Position};
+use crate::value::{Function, Unpacked, UpvalueRef, Value};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const FORMAT_VERSION: u16 = 5;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+/// The end offset of a local variable whose scope lasts until its function returns.
+const OPEN_SCOPE: u32 = u32::MAX;
+
+/// How deeply function constants may nest, so corrupt input cannot exhaust the native stack.
+pub const MAX_NESTING: usize = 256;
+
+#[derive(Debug, Error)]
+pub enum BytecodeError {
+    #[error("Not a compiled Lox file (bad magic header)")]
+    BadMagic,
+    #[error("Unsupported bytecode format version {0} (expected {FORMAT_VERSION})")]
+    UnsupportedVersion(u16),
+    #[error("Unknown constant tag {0}")]
+    UnknownConstantTag(u8),
+    #[error("A {0} cannot be stored as a constant")]
+    UnsupportedConstant(&'static str),
+    #[error("Constant string is not valid UTF-8")]
+    InvalidString,
+    #[error("Functions are nested more than {MAX_NESTING} deep")]
+    TooDeep,
+    #[error("{what} does not fit in the bytecode format")]
+    TooLarge { what: &'static str },
+    #[error("Malformed bytecode file: {0}")]
+    Io(#[from] io::Error),
+}
+
+pub type BytecodeResult<T> = Result<T, BytecodeError>;
+
+/// Whether `bytes` begins with the `.loxc` magic header.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn write_chunk(chunk: &Chunk, out: &mut impl Write) -> BytecodeResult<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+    write_chunk_body(chunk, out)
+}
+
+pub fn read_chunk(input: &mut impl Read) -> BytecodeResult<Chunk> {
+    let mut magic = [0; MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(BytecodeError::BadMagic);
+    }
+
+    let version = u16::from_le_bytes(read_array(input)?);
+    if version != FORMAT_VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    read_chunk_body(input, 0)
+}
+
+fn write_chunk_body(chunk: &Chunk, out: &mut impl Write) -> BytecodeResult<()> {
+    write_len(out, chunk.code.len(), "Code")?;
+    out.write_all(&chunk.code)?;
+
+    write_len(out, chunk.constants.len(), "Constant pool")?;
+    for constant in &chunk.constants {
+        write_constant(out, constant)?;
+    }
+
+    let runs = chunk.position_runs();
+    write_len(out, runs.len(), "Position table")?;
+    for (position, count) in runs {
+        out.write_all(&position.line.to_le_bytes())?;
+        out.write_all(&position.column.to_le_bytes())?;
+        write_len(out, count, "Position run")?;
+    }
+
+    write_len(out, chunk.locals().len(), "Local variable table")?;
+    for local in chunk.locals() {
+        write_string(out, &local.name)?;
+        out.write_all(&[local.slot])?;
+        write_len(out, local.start, "Local variable offset")?;
+        match local.end {
+            Some(end) => write_len(out, end, "Local variable offset")?,
+            None => out.write_all(&OPEN_SCOPE.to_le_bytes())?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a chunk belonging to a function nested `depth` levels inside the script.
+fn read_chunk_body(input: &mut impl Read, depth: usize) -> BytecodeResult<Chunk> {
+    if depth > MAX_NESTING {
+        return Err(BytecodeError::TooDeep);
+    }
+
+    let mut chunk = Chunk::new();
+
+    let code_len = read_len(input)?;
+    chunk.code = read_bytes(input, code_len)?;
+
+    let constant_count = read_len(input)?;
+    for _ in 0..constant_count {
+        let constant = read_constant(input, depth)?;
+        chunk.push_constant(constant);
+    }
+
+    let run_count = read_len(input)?;
+    for _ in 0..run_count {
+        let line = LineNum::from_le_bytes(read_array(input)?);
+        let column = ColumnNum::from_le_bytes(read_array(input)?);
+        let count = read_len(input)?;
+        chunk.push_position(Position::new(line, column), count);
+    }
+
+    let local_count = read_len(input)?;
+    for _ in 0..local_count {
+        let name = read_string(input)?;
+        let [slot] = read_array(input)?;
+        let start = read_len(input)?;
+        chunk.begin_local(&name, slot, start);
+
+        let end = u32::from_le_bytes(read_array(input)?);
+        if end != OPEN_SCOPE {
+            chunk.end_local(slot, end as usize);
+        }
+    }
+
+    Ok(chunk)
+}
+
+fn write_constant(out: &mut impl Write, constant: &Value) -> BytecodeResult<()> {
+    match constant.unpack() {
+        Unpacked::Number(n) => {
+            out.write_all(&[TAG_NUMBER])?;
+            out.write_all(&n.to_bits().to_le_bytes())?;
+        }
+
+        Unpacked::String(s) => {
+            out.write_all(&[TAG_STRING])?;
+            write_string(out, &s)?;
+        }
+
+        Unpacked::Function(function) => {
+            out.write_all(&[TAG_FUNCTION])?;
+            match &function.name {
+                Some(name) => {
+                    out.write_all(&[1])?;
+                    write_string(out, name)?;
+                }
+                None => out.write_all(&[0])?,
+            }
+
+            out.write_all(&[function.arity])?;
+            write_len(out, function.upvalues.len(), "Upvalue list")?;
+            for upvalue in &function.upvalues {
+                out.write_all(&[u8::from(upvalue.is_local), upvalue.index])?;
+            }
+
+            write_chunk_body(&function.chunk, out)?;
+        }
+
+        _ => return Err(BytecodeError::UnsupportedConstant(constant.type_name())),
+    }
+
+    Ok(())
+}
+
+fn read_constant(input: &mut impl Read, depth: usize) -> BytecodeResult<Value> {
+    let [tag] = read_array(input)?;
+    match tag {
+        TAG_NUMBER => Ok(Value::from(f64::from_bits(u64::from_le_bytes(read_array(
+            input,
+        )?)))),
+
+        TAG_STRING => Ok(Value::string(read_string(input)?)),
+
+        TAG_FUNCTION => {
+            let [has_name] = read_array(input)?;
+            let name = match has_name {
+                0 => None,
+                _ => Some(read_string(input)?),
+            };
+
+            let [arity] = read_array(input)?;
+            // Counts are untrusted, so grow the list as entries arrive rather than reserving it
+            let upvalue_count = read_len(input)?;
+            let mut upvalues = Vec::new();
+            for _ in 0..upvalue_count {
+                let [is_local, index] = read_array(input)?;
+                upvalues.push(UpvalueRef {
+                    is_local: is_local != 0,
+                    index,
+                });
+            }
+
+            let chunk = read_chunk_body(input, depth + 1)?;
+            Ok(Value::from(Rc::new(Function {
+                name,
+                arity,
+                upvalues,
+                chunk,
+                ..Function::default()
+            })))
+        }
+
+        _ => Err(BytecodeError::UnknownConstantTag(tag)),
+    }
+}
+
+fn write_string(out: &mut impl Write, s: &str) -> BytecodeResult<()> {
+    write_len(out, s.len(), "String")?;
+    out.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn read_string(input: &mut impl Read) -> BytecodeResult<String> {
+    let len = read_len(input)?;
+    let bytes = read_bytes(input, len)?;
+    String::from_utf8(bytes).map_err(|_| BytecodeError::InvalidString)
+}
+
+fn write_len(out: &mut impl Write, len: usize, what: &'static str) -> BytecodeResult<()> {
+    let len = u32::try_from(len).map_err(|_| BytecodeError::TooLarge { what })?;
+    out.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_len(input: &mut impl Read) -> BytecodeResult<usize> {
+    let len = u32::from_le_bytes(read_array(input)?);
+    usize::try_from(len).map_err(|_| BytecodeError::TooLarge { what: "Length" })
+}
+
+/// Read exactly `len` bytes, allocating only as much as the input actually holds.
+fn read_bytes(input: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(len as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(bytes)
+}
+
+fn read_array<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::to_assembly;
+    use crate::compiler::compile;
+
+    const PROGRAM: &str = r#"
+        fun counter(start) {
+          var count = start;
+          fun next() {
+            count = count + 1;
+            return count;
+          }
+          return next;
+        }
+
+        class Greeter {
+          greet(name) { print "Hello, " + name + "!"; }
+        }
+
+        var c = counter(0.5);
+        print c();
+        Greeter().greet("world");
+    "#;
+
+    fn serialize(chunk: &Chunk) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_chunk(chunk, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn header() -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn round_trips_compiled_programs() {
+        let chunk = compile(PROGRAM).unwrap();
+        let read = read_chunk(&mut serialize(&chunk).as_slice()).unwrap();
+
+        assert_eq!(read.code, chunk.code);
+        assert_eq!(to_assembly(&read), to_assembly(&chunk));
+
+        for (read, original) in read.functions().zip(chunk.functions()) {
+            assert!(!original.chunk.locals().is_empty());
+            assert_eq!(read.chunk.locals(), original.chunk.locals());
+        }
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let bytes = serialize(&compile(PROGRAM).unwrap());
+
+        for len in 0..bytes.len() {
+            assert!(
+                read_chunk(&mut &bytes[..len]).is_err(),
+                "Truncated to {len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(
+            read_chunk(&mut b"LOXB\x03\x00".as_slice()),
+            Err(BytecodeError::BadMagic)
+        ));
+        assert!(matches!(
+            read_chunk(&mut b"LOXC\x63\x00".as_slice()),
+            Err(BytecodeError::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn rejects_lengths_beyond_the_input_without_allocating_them() {
+        let mut bytes = header();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(b"short");
+
+        assert!(matches!(
+            read_chunk(&mut bytes.as_slice()),
+            Err(BytecodeError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupt_constants() {
+        let mut unknown = header();
+        unknown.extend_from_slice(&0_u32.to_le_bytes());
+        unknown.extend_from_slice(&1_u32.to_le_bytes());
+        unknown.push(9);
+        assert!(matches!(
+            read_chunk(&mut unknown.as_slice()),
+            Err(BytecodeError::UnknownConstantTag(9))
+        ));
+
+        let mut invalid_utf8 = header();
+        invalid_utf8.extend_from_slice(&0_u32.to_le_bytes());
+        invalid_utf8.extend_from_slice(&1_u32.to_le_bytes());
+        invalid_utf8.push(TAG_STRING);
+        invalid_utf8.extend_from_slice(&2_u32.to_le_bytes());
+        invalid_utf8.extend_from_slice(&[0xc3, 0x28]);
+        assert!(matches!(
+            read_chunk(&mut invalid_utf8.as_slice()),
+            Err(BytecodeError::InvalidString)
+        ));
+    }
+
+    #[test]
+    fn rejects_functions_nested_too_deeply() {
+        // Each level is an empty chunk whose only constant is the next level's function
+        let mut bytes = header();
+        for _ in 0..=MAX_NESTING {
+            bytes.extend_from_slice(&0_u32.to_le_bytes());
+            bytes.extend_from_slice(&1_u32.to_le_bytes());
+            bytes.extend_from_slice(&[TAG_FUNCTION, 0, 0]);
+            bytes.extend_from_slice(&0_u32.to_le_bytes());
+        }
+
+        assert!(matches!(
+            read_chunk(&mut bytes.as_slice()),
+            Err(BytecodeError::TooDeep)
+        ));
+    }
+}
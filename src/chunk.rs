@@ -1,47 +1,398 @@
-use std::fmt::Write;
+use std::fmt::Write as _;
 
-use crate::USIZE_SIZE;
+use thiserror::Error;
 
-pub type Value = f64;
+use crate::value::{Function, Value};
 
 pub const OPCODE_SIZE: usize = 1;
 
-#[derive(Debug)]
+/// Width of the constant index of `OpCode::ConstantLong` and the other long forms, a
+/// little-endian constant index.
+pub const LONG_OPERAND_SIZE: usize = 3;
+
+/// Width of the operand of the jump instructions, a little-endian distance in bytes.
+pub const JUMP_OPERAND_SIZE: usize = 2;
+
+/// Constant pool indices must fit in the operand of a long form.
+pub const MAX_CONSTANTS: usize = 1 << (8 * LONG_OPERAND_SIZE);
+
+#[derive(Debug, Error)]
+pub enum ChunkError {
+    #[error("Too many constants in one chunk (the limit is {MAX_CONSTANTS})")]
+    TooManyConstants,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u8)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
     Add,
     Sub,
     Mul,
     Div,
     Negate,
     Return,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    Equal,
+    Greater,
+    Less,
+    Not,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Invoke,
+    SuperInvoke,
+    Closure,
+    CloseUpvalue,
+    Class,
+    Inherit,
+    Method,
+    JumpIfTrue,
+    // Superinstructions, emitted by the optimizer for common sequences
+    GetLocal0,
+    GetLocal1,
+    GetLocal2,
+    GetLocal3,
+    AddConst,
+    LessLocals,
+    IncrLocal,
+    // Long forms, for constant indexes that do not fit in a byte
+    GetGlobalLong,
+    DefineGlobalLong,
+    SetGlobalLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    GetSuperLong,
+    ClassLong,
+    MethodLong,
+    InvokeLong,
+    SuperInvokeLong,
+    ClosureLong,
 }
 
+/// How the operand bytes following an opcode are interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    None,
+    /// A constant pool index of the given width in bytes
+    Constant(usize),
+    /// A constant index of the given width in bytes, of a string naming a variable, property,
+    /// method or class
+    Name(usize),
+    /// A one-byte stack slot, upvalue index or argument count
+    Byte,
+    /// A two-byte distance to jump forwards
+    Jump,
+    /// A two-byte distance to jump backwards
+    Loop,
+    /// A method name constant index of the given width followed by a one-byte argument count
+    Invoke(usize),
+    /// A function constant index of the given width
+    Closure(usize),
+    /// Two one-byte stack slots
+    Locals,
+    /// A one-byte stack slot followed by a one-byte constant index
+    LocalConstant,
+}
+
+impl Operand {
+    pub fn size(self) -> usize {
+        match self {
+            Operand::None => 0,
+            Operand::Constant(width) | Operand::Name(width) | Operand::Closure(width) => width,
+            Operand::Invoke(width) => width + 1,
+            Operand::Byte => 1,
+            Operand::Jump | Operand::Loop => JUMP_OPERAND_SIZE,
+            Operand::Locals | Operand::LocalConstant => 2,
+        }
+    }
+
+    /// Width of the constant index the operand starts with, if it starts with one.
+    pub fn index_width(self) -> Option<usize> {
+        match self {
+            Operand::Constant(width)
+            | Operand::Name(width)
+            | Operand::Invoke(width)
+            | Operand::Closure(width) => Some(width),
+            _ => None,
+        }
+    }
+}
+
+impl OpCode {
+    /// Every opcode, indexed by its byte value.
+    pub const ALL: [OpCode; 57] = [
+        OpCode::Constant,
+        OpCode::ConstantLong,
+        OpCode::Add,
+        OpCode::Sub,
+        OpCode::Mul,
+        OpCode::Div,
+        OpCode::Negate,
+        OpCode::Return,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::Less,
+        OpCode::Not,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Invoke,
+        OpCode::SuperInvoke,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+        OpCode::JumpIfTrue,
+        OpCode::GetLocal0,
+        OpCode::GetLocal1,
+        OpCode::GetLocal2,
+        OpCode::GetLocal3,
+        OpCode::AddConst,
+        OpCode::LessLocals,
+        OpCode::IncrLocal,
+        OpCode::GetGlobalLong,
+        OpCode::DefineGlobalLong,
+        OpCode::SetGlobalLong,
+        OpCode::GetPropertyLong,
+        OpCode::SetPropertyLong,
+        OpCode::GetSuperLong,
+        OpCode::ClassLong,
+        OpCode::MethodLong,
+        OpCode::InvokeLong,
+        OpCode::SuperInvokeLong,
+        OpCode::ClosureLong,
+    ];
+
+    pub(crate) fn operand(self) -> Operand {
+        match self {
+            OpCode::Constant | OpCode::AddConst => Operand::Constant(1),
+            OpCode::ConstantLong => Operand::Constant(LONG_OPERAND_SIZE),
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method => Operand::Name(1),
+            OpCode::GetGlobalLong
+            | OpCode::DefineGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::GetPropertyLong
+            | OpCode::SetPropertyLong
+            | OpCode::GetSuperLong
+            | OpCode::ClassLong
+            | OpCode::MethodLong => Operand::Name(LONG_OPERAND_SIZE),
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => Operand::Byte,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue => Operand::Jump,
+            OpCode::Loop => Operand::Loop,
+            OpCode::Invoke | OpCode::SuperInvoke => Operand::Invoke(1),
+            OpCode::InvokeLong | OpCode::SuperInvokeLong => Operand::Invoke(LONG_OPERAND_SIZE),
+            OpCode::Closure => Operand::Closure(1),
+            OpCode::ClosureLong => Operand::Closure(LONG_OPERAND_SIZE),
+            OpCode::LessLocals => Operand::Locals,
+            OpCode::IncrLocal => Operand::LocalConstant,
+            OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Div
+            | OpCode::Negate
+            | OpCode::Return
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Pop
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Not
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Inherit
+            | OpCode::GetLocal0
+            | OpCode::GetLocal1
+            | OpCode::GetLocal2
+            | OpCode::GetLocal3 => Operand::None,
+        }
+    }
+
+    /// The form of an instruction with a one-byte constant index whose index is
+    /// `LONG_OPERAND_SIZE` bytes wide instead.
+    pub(crate) fn long_form(self) -> Option<OpCode> {
+        let long = match self {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::Method => OpCode::MethodLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            _ => return None,
+        };
+
+        Some(long)
+    }
+
+    /// Number of operand bytes that follow this opcode in the instruction stream.
+    pub(crate) fn operand_size(self) -> usize {
+        self.operand().size()
+    }
+
+    /// Decode an opcode byte without checking that it names an opcode.
+    ///
+    /// # Safety
+    ///
+    /// `byte` must be less than `OpCode::ALL.len()`, as every opcode byte of a verified chunk is.
+    pub(crate) unsafe fn from_byte_unchecked(byte: u8) -> OpCode {
+        debug_assert!(usize::from(byte) < OpCode::ALL.len());
+
+        // SAFETY: The discriminants of this `repr(u8)` enum are `0..ALL.len()`, as asserted below
+        unsafe { std::mem::transmute::<u8, OpCode>(byte) }
+    }
+}
+
+// Decoding an opcode relies on `ALL` listing every opcode in discriminant order
+const _: () = {
+    let mut i = 0;
+    while i < OpCode::ALL.len() {
+        assert!(OpCode::ALL[i] as usize == i);
+        i += 1;
+    }
+};
+
 impl TryFrom<u8> for OpCode {
     type Error = &'static str;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            x if x == OpCode::Constant as u8 => Ok(OpCode::Constant),
-            x if x == OpCode::Add as u8 => Ok(OpCode::Add),
-            x if x == OpCode::Sub as u8 => Ok(OpCode::Sub),
-            x if x == OpCode::Mul as u8 => Ok(OpCode::Mul),
-            x if x == OpCode::Div as u8 => Ok(OpCode::Div),
-            x if x == OpCode::Negate as u8 => Ok(OpCode::Negate),
-            x if x == OpCode::Return as u8 => Ok(OpCode::Return),
-            _ => Err("Invalid opcode"),
-        }
+        OpCode::ALL
+            .get(usize::from(value))
+            .copied()
+            .ok_or("Invalid opcode")
+    }
+}
+
+impl std::str::FromStr for OpCode {
+    type Err = &'static str;
+
+    /// Parse an opcode from its mnemonic, which is the same as its `Debug` name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OpCode::ALL
+            .into_iter()
+            .find(|op| format!("{op:?}") == s)
+            .ok_or("Unknown mnemonic")
     }
 }
 
 pub type LineNum = u32;
+pub type ColumnNum = u32;
+
+/// A location in the source text. Columns are 1-based and count characters, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Position {
+    pub line: LineNum,
+    pub column: ColumnNum,
+}
+
+impl Position {
+    pub fn new(line: LineNum, column: ColumnNum) -> Self {
+        Self { line, column }
+    }
+
+    /// The position just past `text`, if `text` starts at this position.
+    #[must_use]
+    pub fn after(self, text: &str) -> Self {
+        text.chars().fold(self, |position, ch| match ch {
+            '\n' => Self::new(position.line + 1, 1),
+            _ => Self::new(position.line, position.column + 1),
+        })
+    }
+}
 
-#[derive(Debug)]
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A run of consecutive code bytes that all came from the same source position.
+#[derive(Debug, Clone, Copy)]
+struct PositionRun {
+    start: usize,
+    position: Position,
+}
+
+/// The name of a local variable and the code over which it occupies its stack slot, kept so
+/// the debugger can show and evaluate locals by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalVariable {
+    pub name: String,
+    pub slot: u8,
+    /// Offset of the first instruction that runs with the variable initialized
+    pub start: usize,
+    /// Offset at which its scope ends, or `None` if it lasts until the function returns
+    pub end: Option<usize>,
+}
+
+impl LocalVariable {
+    /// Whether the variable is initialized while the instruction at `offset` executes.
+    pub fn is_live_at(&self, offset: usize) -> bool {
+        self.start <= offset && self.end.is_none_or(|end| offset < end)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Chunk {
-    pub code: Vec<u8>,
-    pub constants: Vec<Value>,
-    lines: Vec<(LineNum, usize)>, // RLE: (line number, byte count)
+    pub(crate) code: Vec<u8>,
+    pub(crate) constants: Vec<Value>,
+    // RLE, sorted by start offset so lookups can binary search
+    positions: Vec<PositionRun>,
+    // Number of code bytes covered by `positions`
+    positions_end: usize,
+    // In order of declaration
+    locals: Vec<LocalVariable>,
 }
 
 impl Chunk {
@@ -49,100 +400,556 @@ impl Chunk {
         Self {
             code: Vec::new(),
             constants: Vec::new(),
-            lines: Vec::new(),
+            positions: Vec::new(),
+            positions_end: 0,
+            locals: Vec::new(),
         }
     }
 
-    pub fn push_opcode(&mut self, opcode: OpCode, line: LineNum) {
+    pub(crate) fn push_opcode(&mut self, opcode: OpCode, position: Position) {
         self.code.push(opcode as u8);
-        self.push_line(line, OPCODE_SIZE);
+        self.push_position(position, OPCODE_SIZE);
     }
 
-    pub fn push_constant(&mut self, value: Value) -> usize {
+    pub(crate) fn push_byte(&mut self, byte: u8, position: Position) {
+        self.code.push(byte);
+        self.push_position(position, 1);
+    }
+
+    pub(crate) fn push_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
         self.constants.len() - 1
     }
 
-    /// Called when `self.ip` is pointing to the offset stored immediately after an
-    /// `OpCode::Constant`. Resolves it into an offset, fetches the corresponding constant, and
-    /// returns (offset, constant).
-    pub fn get_constant(&self, lower: usize) -> Option<(usize, Value)> {
-        let upper = lower.checked_add(USIZE_SIZE)?;
+    /// Called when `self.ip` is pointing to the operand of an `OpCode::Constant` (`width` 1) or
+    /// `OpCode::ConstantLong` (`width` 3). Decodes the little-endian index, fetches the
+    /// corresponding constant, and returns (index, constant).
+    pub(crate) fn get_constant(&self, lower: usize, width: usize) -> Option<(usize, Value)> {
+        let const_i = self.constant_index(lower, width)?;
+        let constant = self.constants.get(const_i).cloned()?;
+
+        Some((const_i, constant))
+    }
 
+    /// Decode the little-endian constant index of `width` bytes starting at `lower`, without
+    /// checking it against the constant pool.
+    pub(crate) fn constant_index(&self, lower: usize, width: usize) -> Option<usize> {
+        let upper = lower.checked_add(width)?;
         let bytes = self.code.get(lower..upper)?;
-        // This should be safe since get() already did the bounds checking for us
-        let bytes: [u8; USIZE_SIZE] = bytes.try_into().expect("Slice length OOB");
 
-        let const_i = usize::from_ne_bytes(bytes);
-        let constant = self.constants.get(const_i).copied()?;
+        let const_i = bytes
+            .iter()
+            .rev()
+            .fold(0, |acc, &byte| (acc << 8) | usize::from(byte));
 
-        Some((const_i, constant))
+        Some(const_i)
     }
 
-    pub fn push_const_opcode(&mut self, value: Value, line: LineNum) {
-        let i = self.push_constant(value);
-        self.code.push(OpCode::Constant as u8);
-        self.push_line(line, OPCODE_SIZE + USIZE_SIZE);
-        self.code.extend(i.to_ne_bytes());
+    /// Emit a load of `value`, using the one-byte `Constant` form when the index allows it and
+    /// falling back to `ConstantLong` otherwise.
+    pub(crate) fn push_const_opcode(
+        &mut self,
+        value: Value,
+        position: Position,
+    ) -> Result<(), ChunkError> {
+        let i = self.constants.len();
+        if i >= MAX_CONSTANTS {
+            return Err(ChunkError::TooManyConstants);
+        }
+
+        self.push_constant(value);
+        self.push_indexed(OpCode::Constant, i, position);
+
+        Ok(())
     }
 
-    pub fn push_line(&mut self, line: LineNum, byte_count: usize) {
-        if let Some(last) = self.lines.last_mut()
-            && last.0 == line
-        {
-            last.1 += byte_count;
+    /// Emit `opcode` with the constant index `index`, switching to its long form when the index
+    /// does not fit in a byte. Operands that follow the index, such as the argument count of
+    /// `Invoke`, are pushed by the caller.
+    pub(crate) fn push_indexed(&mut self, opcode: OpCode, index: usize, position: Position) {
+        debug_assert!(index < MAX_CONSTANTS);
+
+        if let Ok(short) = u8::try_from(index) {
+            self.code.push(opcode as u8);
+            self.code.push(short);
+            self.push_position(position, OPCODE_SIZE + 1);
         } else {
-            self.lines.push((line, byte_count));
+            let long = opcode
+                .long_form()
+                .expect("Only instructions with a long form take a constant index");
+            self.code.push(long as u8);
+            self.code
+                .extend_from_slice(&index.to_le_bytes()[..LONG_OPERAND_SIZE]);
+            self.push_position(position, OPCODE_SIZE + LONG_OPERAND_SIZE);
         }
     }
 
-    pub fn get_line(&self, mut i: usize) -> Option<LineNum> {
-        for (line, count) in self.lines.iter().copied() {
-            if i < count {
-                return Some(line);
-            }
+    /// The position table as run-length encoded (position, byte count) pairs.
+    pub(crate) fn position_runs(&self) -> impl ExactSizeIterator<Item = (Position, usize)> + '_ {
+        self.positions.iter().enumerate().map(|(i, run)| {
+            let end = self
+                .positions
+                .get(i + 1)
+                .map_or(self.positions_end, |next| next.start);
+
+            (run.position, end - run.start)
+        })
+    }
+
+    /// Record that the next `byte_count` bytes of code came from `position`.
+    pub(crate) fn push_position(&mut self, position: Position, byte_count: usize) {
+        let extends_last = self
+            .positions
+            .last()
+            .is_some_and(|last| last.position == position);
+
+        if !extends_last {
+            self.positions.push(PositionRun {
+                start: self.positions_end,
+                position,
+            });
+        }
+
+        self.positions_end += byte_count;
+    }
 
-            i -= count;
+    pub(crate) fn get_position(&self, i: usize) -> Option<Position> {
+        if i >= self.positions_end {
+            return None;
         }
 
-        None
+        // Index of the first run starting after `i`; the run containing `i` precedes it
+        let after = self.positions.partition_point(|run| run.start <= i);
+        let run = self.positions.get(after.checked_sub(1)?)?;
+
+        Some(run.position)
     }
 
-    pub fn disassemble_instruction(&self, i: usize) -> Option<String> {
-        let line = self.get_line(i)?;
+    pub(crate) fn get_line(&self, i: usize) -> Option<LineNum> {
+        self.get_position(i).map(|p| p.line)
+    }
 
-        let mut res = String::new();
+    pub(crate) fn disassemble_instruction(&self, i: usize) -> Option<String> {
+        let position = self.get_position(i)?;
+        let description = self.describe_instruction(i)?;
 
-        // `write!`ing into a String is infallible
-        write!(res, "{i:04} {line:4} ").unwrap();
+        Some(format!(
+            "{i:04} {:>8} {description}\n",
+            position.to_string()
+        ))
+    }
+
+    /// The mnemonic and decoded operands of the instruction at `i`, without its offset or
+    /// position.
+    pub(crate) fn describe_instruction(&self, i: usize) -> Option<String> {
         let instruction = *self.code.get(i)?;
         let instruction: OpCode = instruction.try_into().ok()?;
-        match instruction {
-            OpCode::Constant => {
-                let (const_i, constant) = self.get_constant(i + 1)?;
-                writeln!(res, "Constant {const_i}: {constant}").unwrap();
+        let operands = i + OPCODE_SIZE;
+
+        let res = match instruction.operand() {
+            Operand::None => format!("{instruction:?}"),
+
+            Operand::Constant(width) | Operand::Name(width) => {
+                let (const_i, constant) = self.get_constant(operands, width)?;
+                format!("{instruction:?} {const_i} {}", describe_constant(&constant))
             }
 
-            OpCode::Negate => writeln!(res, "Negate").unwrap(),
-            OpCode::Add => writeln!(res, "Add").unwrap(),
-            OpCode::Sub => writeln!(res, "Sub").unwrap(),
-            OpCode::Mul => writeln!(res, "Mul").unwrap(),
-            OpCode::Div => writeln!(res, "Div").unwrap(),
+            Operand::Byte => format!("{instruction:?} {}", self.code.get(operands)?),
 
-            OpCode::Return => writeln!(res, "Return").unwrap(),
-        }
+            Operand::Jump | Operand::Loop => {
+                let distance = self.jump_distance(operands)?;
+                let target = self.jump_target(i)?;
+                format!("{instruction:?} {distance} -> {target:04}")
+            }
+
+            Operand::Locals => {
+                let a = self.code.get(operands)?;
+                let b = self.code.get(operands + 1)?;
+                format!("{instruction:?} {a} {b}")
+            }
+
+            Operand::LocalConstant => {
+                let slot = self.code.get(operands)?;
+                let (const_i, constant) = self.get_constant(operands + 1, 1)?;
+                let value = describe_constant(&constant);
+                format!("{instruction:?} {slot} {const_i} {value}")
+            }
+
+            Operand::Invoke(width) => {
+                let (const_i, constant) = self.get_constant(operands, width)?;
+                let argc = self.code.get(operands + width)?;
+                let name = describe_constant(&constant);
+                format!("{instruction:?} {const_i} {name} {argc}")
+            }
+
+            Operand::Closure(width) => {
+                let (const_i, constant) = self.get_constant(operands, width)?;
+                let mut res = format!("{instruction:?} {const_i} {}", describe_constant(&constant));
+                if let Some(function) = constant.as_function() {
+                    for upvalue in &function.upvalues {
+                        let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                        write!(res, " [{kind} {}]", upvalue.index).unwrap();
+                    }
+                }
+                res
+            }
+        };
 
         Some(res)
     }
+
+    /// Decode the little-endian distance operand of a jump starting at `lower`.
+    pub(crate) fn jump_distance(&self, lower: usize) -> Option<usize> {
+        let bytes = self.code.get(lower..lower + JUMP_OPERAND_SIZE)?;
+        Some(usize::from(u16::from_le_bytes([bytes[0], bytes[1]])))
+    }
+
+    /// Offset a jump or loop instruction at `i` transfers control to, if it is one. Distances are
+    /// measured from the end of the instruction.
+    pub(crate) fn jump_target(&self, i: usize) -> Option<usize> {
+        let opcode = OpCode::try_from(*self.code.get(i)?).ok()?;
+        let next = i + OPCODE_SIZE + JUMP_OPERAND_SIZE;
+        let distance = self.jump_distance(i + OPCODE_SIZE)?;
+
+        match opcode.operand() {
+            Operand::Jump => Some(next + distance),
+            Operand::Loop => next.checked_sub(distance),
+            _ => None,
+        }
+    }
+
+    /// Offset of the instruction following the one at `i`. Bytes that are not valid opcodes are
+    /// treated as one-byte instructions so that a corrupt chunk can still be walked.
+    pub(crate) fn next_instruction(&self, i: usize) -> usize {
+        let opcode = self.code.get(i).and_then(|&b| OpCode::try_from(b).ok());
+        i + opcode.map_or(1, |op| OPCODE_SIZE + op.operand_size())
+    }
+
+    /// Disassemble the whole chunk under a `== name ==` header.
+    /// Nested functions follow under headers of their own.
+    pub fn disassemble(&self, name: &str) -> String {
+        let mut res = format!("== {name} ==\n{self}");
+        for function in self.functions() {
+            res.push_str(&function.chunk.disassemble(&function.to_string()));
+        }
+
+        res
+    }
+
+    /// Functions in the constant pool, which hold the code of nested function declarations.
+    pub(crate) fn functions(&self) -> impl Iterator<Item = &Function> + '_ {
+        self.constants.iter().filter_map(Value::as_function)
+    }
+
+    /// Whether any instruction in this chunk or a nested function comes from `line`.
+    pub(crate) fn has_line(&self, line: LineNum) -> bool {
+        self.positions.iter().any(|run| run.position.line == line)
+            || self.functions().any(|f| f.chunk.has_line(line))
+    }
+
+    /// Record that the local variable `name` occupies `slot` from offset `start` until a matching
+    /// [`Chunk::end_local`].
+    pub(crate) fn begin_local(&mut self, name: &str, slot: u8, start: usize) {
+        self.locals.push(LocalVariable {
+            name: name.to_string(),
+            slot,
+            start,
+            end: None,
+        });
+    }
+
+    /// End the scope of the most recently begun variable in `slot` at offset `end`.
+    pub(crate) fn end_local(&mut self, slot: u8, end: usize) {
+        if let Some(local) = self
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|l| l.slot == slot && l.end.is_none())
+        {
+            local.end = Some(end);
+        }
+    }
+
+    /// Every local variable declared in the chunk, in order of declaration.
+    pub(crate) fn locals(&self) -> &[LocalVariable] {
+        &self.locals
+    }
+
+    /// The local variables initialized while the instruction at `offset` executes, by slot.
+    pub(crate) fn locals_at(&self, offset: usize) -> impl Iterator<Item = &LocalVariable> + '_ {
+        self.locals.iter().filter(move |l| l.is_live_at(offset))
+    }
+}
+
+/// A constant as shown in disassembly, such as `(number 1.5)`.
+fn describe_constant(constant: &Value) -> String {
+    format!("({} {constant})", constant.type_name())
 }
 
 impl std::fmt::Display for Chunk {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for i in 0..self.code.len() {
-            let formatted_instruction = self.disassemble_instruction(i).unwrap();
-            write!(f, "{formatted_instruction}")?;
+        let mut i = 0;
+        while i < self.code.len() {
+            if let Some(formatted_instruction) = self.disassemble_instruction(i) {
+                write!(f, "{formatted_instruction}")?;
+            } else {
+                let position = self
+                    .get_position(i)
+                    .map_or("?".to_string(), |p| p.to_string());
+                writeln!(f, "{i:04} {position:>8} <invalid {:#04x}>", self.code[i])?;
+            }
+
+            i = self.next_instruction(i);
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use super::*;
+    use crate::difftest::run_vm;
+
+    fn chunk_with_constants(count: u32) -> Chunk {
+        let mut chunk = Chunk::new();
+        for i in 0..count {
+            chunk
+                .push_const_opcode(Value::from(f64::from(i)), Position::default())
+                .unwrap();
+        }
+        chunk
+    }
+
+    #[test]
+    fn small_indices_use_a_one_byte_operand() {
+        let chunk = chunk_with_constants(256);
+
+        let last = chunk.code.len() - OPCODE_SIZE - 1;
+        assert_eq!(chunk.code.len(), 256 * 2);
+        assert_eq!(chunk.code[last], OpCode::Constant as u8);
+        assert_eq!(chunk.constant_index(last + OPCODE_SIZE, 1), Some(255));
+    }
+
+    #[test]
+    fn large_indices_use_a_three_byte_little_endian_operand() {
+        let chunk = chunk_with_constants(258);
+
+        let offset = 256 * 2;
+        assert_eq!(chunk.code[offset], OpCode::ConstantLong as u8);
+        assert_eq!(chunk.code[offset + OPCODE_SIZE..offset + 4], [0, 1, 0]);
+        assert_eq!(chunk.code[offset + 4 + OPCODE_SIZE..], [1, 1, 0]);
+
+        let (index, value) = chunk
+            .get_constant(offset + 4 + OPCODE_SIZE, LONG_OPERAND_SIZE)
+            .unwrap();
+        assert_eq!(index, 257);
+        assert_eq!(value.to_string(), "257");
+    }
+
+    #[test]
+    fn disassembles_both_forms() {
+        let chunk = chunk_with_constants(257);
+
+        assert_eq!(
+            chunk.describe_instruction(0).unwrap(),
+            "Constant 0 (number 0)"
+        );
+        assert_eq!(
+            chunk.describe_instruction(512).unwrap(),
+            "ConstantLong 256 (number 256)"
+        );
+    }
+
+    fn at(line: LineNum, column: ColumnNum) -> Position {
+        Position { line, column }
+    }
+
+    /// Positions recorded for 7 bytes: three runs, two of them on the same line.
+    fn chunk_with_positions() -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.push_position(at(1, 1), 2);
+        chunk.push_position(at(1, 1), 1);
+        chunk.push_position(at(1, 5), 3);
+        chunk.push_position(at(3, 1), 1);
+        chunk
+    }
+
+    #[test]
+    fn merges_consecutive_bytes_from_one_position_into_a_run() {
+        let chunk = chunk_with_positions();
+        let runs: Vec<_> = chunk.position_runs().collect();
+        assert_eq!(runs, [(at(1, 1), 3), (at(1, 5), 3), (at(3, 1), 1)]);
+    }
+
+    #[test]
+    fn looks_up_positions_by_offset() {
+        let chunk = chunk_with_positions();
+
+        let positions: Vec<_> = (0..7).map(|i| chunk.get_position(i).unwrap()).collect();
+        assert_eq!(
+            positions,
+            [
+                at(1, 1),
+                at(1, 1),
+                at(1, 1),
+                at(1, 5),
+                at(1, 5),
+                at(1, 5),
+                at(3, 1),
+            ]
+        );
+
+        // The same line, but a different column
+        assert_eq!(chunk.get_line(2), chunk.get_line(3));
+        assert_ne!(chunk.get_position(2), chunk.get_position(3));
+    }
+
+    #[test]
+    fn finds_every_run_boundary() {
+        let mut chunk = Chunk::new();
+        let mut expected = Vec::new();
+        for (line, len) in (1..=100).zip([1, 3, 2, 4].into_iter().cycle()) {
+            chunk.push_position(at(line, 1), len);
+            expected.extend(std::iter::repeat_n(at(line, 1), len));
+        }
+
+        for (i, &position) in expected.iter().enumerate() {
+            assert_eq!(chunk.get_position(i), Some(position), "offset {i}");
+        }
+        assert_eq!(chunk.get_position(expected.len()), None);
+    }
+
+    #[test]
+    fn has_no_position_past_the_end() {
+        let chunk = chunk_with_positions();
+        assert_eq!(chunk.get_position(7), None);
+        assert_eq!(chunk.get_position(usize::MAX), None);
+        assert_eq!(Chunk::new().get_position(0), None);
+    }
+
+    #[test]
+    fn disassembles_every_operand_kind() {
+        let mut source = String::from(
+            "\
+.function add 2
+.upvalue local 1
+.upvalue upvalue 0
+.line 2
+    GetLocal 1
+    Return
+.end
+.line 3:5
+start:
+    Closure #0
+    GetGlobal \"x\"
+    Constant 1.5
+    GetLocal 1
+    LessLocals 1 2
+    IncrLocal 1 #2
+    Invoke \"f\" 2
+.line 4
+    JumpIfFalse end
+    Loop start
+end:
+",
+        );
+        for i in 4..300 {
+            writeln!(source, ".const {i}").unwrap();
+        }
+        source.push_str(
+            "    ConstantLong #299
+    GetGlobalLong \"y\"
+    InvokeLong \"y\" 1
+    ClosureLong #0
+    Return
+.byte 0xff
+",
+        );
+        let chunk = crate::asm::assemble(&source).unwrap();
+
+        assert_eq!(
+            chunk.disassemble("script"),
+            "\
+== script ==
+0000      3:5 Closure 0 (function <fn add>) [local 1] [upvalue 0]
+0002      3:5 GetGlobal 1 (string x)
+0004      3:5 Constant 2 (number 1.5)
+0006      3:5 GetLocal 1
+0008      3:5 LessLocals 1 2
+0011      3:5 IncrLocal 1 2 (number 1.5)
+0014      3:5 Invoke 3 (string f) 2
+0017      4:0 JumpIfFalse 3 -> 0023
+0020      4:0 Loop 23 -> 0000
+0023      4:0 ConstantLong 299 (number 299)
+0027      4:0 GetGlobalLong 300 (string y)
+0031      4:0 InvokeLong 301 (string y) 1
+0036      4:0 ClosureLong 0 (function <fn add>) [local 1] [upvalue 0]
+0040      4:0 Return
+0041      4:0 <invalid 0xff>
+== <fn add> ==
+0000      2:0 GetLocal 1
+0002      2:0 Return
+"
+        );
+    }
+
+    #[test]
+    fn disassembles_compiled_functions_under_their_own_headers() {
+        let source = "\
+fun outer() {
+  var a = 1;
+  fun inner() { return a; }
+  return inner;
+}
+while (x) outer();";
+        let chunk = crate::compiler::compile(source).unwrap();
+
+        assert_eq!(
+            chunk.disassemble("script"),
+            "\
+== script ==
+0000      1:5 Closure 1 (function <fn outer>)
+0002      1:5 DefineGlobal 0 (string outer)
+0004      6:8 GetGlobal 2 (string x)
+0006      6:8 JumpIfFalse 9 -> 0018
+0009      6:8 Pop
+0010     6:11 GetGlobal 0 (string outer)
+0012     6:16 Call 0
+0014     6:16 Pop
+0015      6:8 Loop 14 -> 0004
+0018      6:8 Pop
+0019      6:8 Nil
+0020      6:8 Return
+== <fn outer> ==
+0000     2:11 Constant 0 (number 1)
+0002      3:7 Closure 1 (function <fn inner>) [local 1]
+0004     4:10 GetLocal 2
+0006      4:3 Return
+0007      4:3 Nil
+0008      4:3 Return
+== <fn inner> ==
+0000     3:24 GetUpvalue 0
+0002     3:17 Return
+0003     3:17 Nil
+0004     3:17 Return
+"
+        );
+    }
+
+    #[test]
+    fn runs_programs_with_more_than_256_constants() {
+        let mut source = String::new();
+        let mut expected = String::new();
+        for i in 0..300 {
+            writeln!(source, "print {i};").unwrap();
+            writeln!(expected, "{i}").unwrap();
+        }
+
+        let outcome = run_vm(&source, false);
+        assert_eq!(outcome.error, None);
+        assert_eq!(outcome.output, expected);
+    }
+}
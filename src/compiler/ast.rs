@@ -0,0 +1,188 @@
+//! Abstract syntax tree produced by the parser. `for` loops are desugared into `while` loops, so
+//! later passes only deal with the core statements.
+
+use std::fmt::Display;
+use std::rc::Rc;
+
+use crate::chunk::Position;
+
+/// Identifies an expression within one parse, so passes can attach facts to it.
+pub type ExprId = usize;
+
+/// An identifier and where it appears.
+#[derive(Debug, Clone)]
+pub struct Name {
+    pub text: String,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Var {
+        name: Name,
+        initializer: Option<Expr>,
+    },
+    Function(Rc<Function>),
+    Class {
+        name: Name,
+        superclass: Option<Expr>,
+        methods: Vec<Rc<Function>>,
+    },
+    Expression(Expr),
+    Print(Expr),
+    Return {
+        position: Position,
+        value: Option<Expr>,
+    },
+    If {
+        condition: Expr,
+        then: Box<Stmt>,
+        otherwise: Option<Box<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    Block(Vec<Stmt>),
+}
+
+impl Stmt {
+    /// Position of the start of the statement.
+    pub fn position(&self) -> Position {
+        match self {
+            Stmt::Var { name, .. } | Stmt::Class { name, .. } => name.position,
+            Stmt::Function(function) => function.name.position,
+            Stmt::Expression(expr) | Stmt::Print(expr) => expr.position,
+            Stmt::Return { position, .. } => *position,
+            Stmt::If { condition, .. } | Stmt::While { condition, .. } => condition.position,
+            Stmt::Block(stmts) => stmts.first().map_or_else(Position::default, Stmt::position),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionKind {
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub kind: FunctionKind,
+    pub name: Name,
+    pub params: Vec<Name>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub id: ExprId,
+    pub position: Position,
+    pub kind: ExprKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Literal(Literal),
+    Variable(Name),
+    Assign {
+        name: Name,
+        value: Box<Expr>,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// `and` and `or`, which only evaluate their right operand when needed
+    Logical {
+        op: LogicalOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Grouping(Box<Expr>),
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Name,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Name,
+        value: Box<Expr>,
+    },
+    This,
+    Super {
+        method: Name,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl BinaryOp {
+    pub fn is_comparison(self) -> bool {
+        !matches!(
+            self,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+impl Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+        };
+
+        write!(f, "{symbol}")
+    }
+}
@@ -0,0 +1,703 @@
+//! Bytecode generation from the syntax tree. Each function declaration becomes a [`Function`]
+//! constant in the chunk of the function enclosing it; the program itself is the top-level
+//! chunk.
+
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, JUMP_OPERAND_SIZE, LocalVariable, MAX_CONSTANTS, OpCode, Position};
+use crate::value::{Function, UpvalueRef, Value};
+
+use super::CompileError;
+use super::ast::Function as FunctionDecl;
+use super::ast::{BinaryOp, Expr, ExprKind, FunctionKind, Literal, LogicalOp, Name, Stmt, UnaryOp};
+
+/// Locals and upvalues are addressed by one-byte operands.
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+
+/// Generate the top-level chunk for a program that has already passed scope resolution. The chunk
+/// returns the value of the program's final statement if that is an expression statement, and
+/// nil otherwise.
+pub fn generate(program: &[Stmt]) -> Result<Chunk, Vec<CompileError>> {
+    let mut generator = Generator::new();
+
+    // A final expression statement leaves its value as the result of the program
+    if let Some((Stmt::Expression(expr), rest)) = program.split_last() {
+        generator.statements(rest);
+        generator.expression(expr);
+        generator.emit(OpCode::Return, expr.position);
+        return generator.into_chunk();
+    }
+
+    generator.statements(program);
+    generator.finish(
+        program
+            .last()
+            .map_or_else(Position::default, Stmt::position),
+    )
+}
+
+/// Generate a function that evaluates `expr` and returns its value, as if `expr` appeared in a
+/// function whose initialized locals are `scope`. The locals `expr` uses become upvalues of the
+/// generated function, captured from their slots.
+pub fn generate_expression(
+    expr: &Expr,
+    scope: &[LocalVariable],
+) -> Result<Function, Vec<CompileError>> {
+    let mut generator = Generator::new();
+
+    let enclosing = &mut generator.current().locals;
+    for local in scope {
+        let slot = usize::from(local.slot);
+        if enclosing.len() <= slot {
+            enclosing.resize_with(slot + 1, || Local {
+                name: String::new(),
+                depth: Some(0),
+                captured: false,
+            });
+        }
+        enclosing[slot].name.clone_from(&local.name);
+    }
+
+    generator
+        .functions
+        .push(FunctionState::new(Some(FunctionKind::Function), None, 0));
+    generator.expression(expr);
+    generator.emit(OpCode::Return, expr.position);
+
+    let state = generator.functions.pop().expect("Pushed above");
+    if !generator.errors.is_empty() {
+        return Err(generator.errors);
+    }
+
+    Ok(state.function)
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    /// Scope depth, or `None` while the variable's initializer is being compiled
+    depth: Option<usize>,
+    captured: bool,
+}
+
+/// Compilation state of one function, innermost last.
+struct FunctionState {
+    function: Function,
+    kind: Option<FunctionKind>,
+    locals: Vec<Local>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(kind: Option<FunctionKind>, name: Option<String>, arity: u8) -> Self {
+        // Slot 0 holds the function being called, or the receiver in methods
+        let slot_zero = match kind {
+            Some(FunctionKind::Method | FunctionKind::Initializer) => "this",
+            _ => "",
+        };
+
+        let mut chunk = Chunk::new();
+        if !slot_zero.is_empty() {
+            chunk.begin_local(slot_zero, 0, 0);
+        }
+
+        Self {
+            function: Function {
+                name,
+                arity,
+                chunk,
+                ..Function::default()
+            },
+            kind,
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: Some(0),
+                captured: false,
+            }],
+            scope_depth: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<u8> {
+        let slot = self.locals.iter().rposition(|l| l.name == name)?;
+        Some(u8::try_from(slot).expect("Locals are limited to one-byte slots"))
+    }
+}
+
+enum Variable {
+    Local(u8),
+    Upvalue(u8),
+    Global(usize),
+}
+
+struct Generator {
+    functions: Vec<FunctionState>,
+    errors: Vec<CompileError>,
+}
+
+impl Generator {
+    fn new() -> Self {
+        Self {
+            functions: vec![FunctionState::new(None, None, 0)],
+            errors: Vec::new(),
+        }
+    }
+
+    fn finish(mut self, position: Position) -> Result<Chunk, Vec<CompileError>> {
+        self.emit_return(position);
+        self.into_chunk()
+    }
+
+    fn into_chunk(mut self) -> Result<Chunk, Vec<CompileError>> {
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+
+        let script = self
+            .functions
+            .pop()
+            .expect("The script is always being compiled");
+        Ok(script.function.chunk)
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("The script is always being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().function.chunk
+    }
+
+    /// Report an error at `position`, naming the token `name` found there if there is one.
+    fn error(&mut self, position: Position, name: Option<&str>, message: &str) {
+        self.errors.push(CompileError {
+            position,
+            location: name.map(|name| format!(" at '{name}'")).unwrap_or_default(),
+            message: message.to_string(),
+            end: name.map(|name| position.after(name)),
+        });
+    }
+
+    fn emit(&mut self, opcode: OpCode, position: Position) {
+        self.chunk().push_opcode(opcode, position);
+    }
+
+    fn emit_with(&mut self, opcode: OpCode, operand: u8, position: Position) {
+        self.emit(opcode, position);
+        self.chunk().push_byte(operand, position);
+    }
+
+    fn emit_return(&mut self, position: Position) {
+        if self.current().kind == Some(FunctionKind::Initializer) {
+            self.emit_with(OpCode::GetLocal, 0, position);
+        } else {
+            self.emit(OpCode::Nil, position);
+        }
+
+        self.emit(OpCode::Return, position);
+    }
+
+    fn emit_constant(&mut self, value: Value, position: Position) {
+        if self.chunk().push_const_opcode(value, position).is_err() {
+            self.error(position, None, "Too many constants in one chunk.");
+        }
+    }
+
+    /// Emit `opcode` with the constant index `index`, in its long form if the index needs it.
+    fn emit_indexed(&mut self, opcode: OpCode, index: usize, position: Position) {
+        self.chunk().push_indexed(opcode, index, position);
+    }
+
+    /// Add `value` to the constant pool for an instruction with a constant index operand.
+    fn make_constant(&mut self, value: Value, position: Position) -> usize {
+        if self.chunk().constants.len() >= MAX_CONSTANTS {
+            self.error(position, None, "Too many constants in one chunk.");
+            return 0;
+        }
+
+        self.chunk().push_constant(value)
+    }
+
+    /// The constant holding the string `name`, reusing an existing one where possible.
+    fn name_constant(&mut self, name: &str, position: Position) -> usize {
+        let existing = self
+            .chunk()
+            .constants
+            .iter()
+            .position(|c| c.as_str() == Some(name));
+        match existing {
+            Some(index) => index,
+            None => self.make_constant(Value::string(name), position),
+        }
+    }
+
+    /// Emit a jump with a placeholder distance, returning the offset of its operand.
+    fn emit_jump(&mut self, opcode: OpCode, position: Position) -> usize {
+        self.emit(opcode, position);
+        for _ in 0..JUMP_OPERAND_SIZE {
+            self.chunk().push_byte(0xff, position);
+        }
+
+        self.chunk().code.len() - JUMP_OPERAND_SIZE
+    }
+
+    /// Point the jump whose operand is at `operand` to the next instruction.
+    fn patch_jump(&mut self, operand: usize, position: Position) {
+        let distance = self.chunk().code.len() - operand - JUMP_OPERAND_SIZE;
+        let Ok(distance) = u16::try_from(distance) else {
+            self.error(position, None, "Too much code to jump over.");
+            return;
+        };
+
+        self.chunk().code[operand..operand + JUMP_OPERAND_SIZE]
+            .copy_from_slice(&distance.to_le_bytes());
+    }
+
+    fn emit_loop(&mut self, start: usize, position: Position) {
+        self.emit(OpCode::Loop, position);
+
+        let distance = self.chunk().code.len() + JUMP_OPERAND_SIZE - start;
+        let bytes = u16::try_from(distance).unwrap_or_else(|_| {
+            self.error(position, None, "Loop body too large.");
+            0
+        });
+
+        for byte in bytes.to_le_bytes() {
+            self.chunk().push_byte(byte, position);
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self, position: Position) {
+        let state = self.current();
+        state.scope_depth -= 1;
+
+        let depth = state.scope_depth;
+        while let Some(local) = self.current().locals.pop_if(|l| l.depth > Some(depth)) {
+            let slot = u8::try_from(self.current().locals.len())
+                .expect("Locals are limited to one-byte slots");
+            let end = self.chunk().code.len();
+            self.chunk().end_local(slot, end);
+
+            let opcode = if local.captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            self.emit(opcode, position);
+        }
+    }
+
+    fn add_local(&mut self, name: &str, position: Position) {
+        if self.current().locals.len() >= MAX_LOCALS {
+            self.error(
+                position,
+                Some(name),
+                "Too many local variables in function.",
+            );
+            return;
+        }
+
+        self.current().locals.push(Local {
+            name: name.to_string(),
+            depth: None,
+            captured: false,
+        });
+    }
+
+    /// Declare `name` in the current scope. Returns the name constant for globals, which are
+    /// defined by an instruction rather than by occupying a stack slot.
+    fn declare(&mut self, name: &Name) -> Option<usize> {
+        if self.current().scope_depth == 0 {
+            return Some(self.name_constant(&name.text, name.position));
+        }
+
+        self.add_local(&name.text, name.position);
+        None
+    }
+
+    /// Make the variable declared by `declare` available, leaving it initialized with the value
+    /// on top of the stack.
+    fn define(&mut self, global: Option<usize>, position: Position) {
+        if let Some(constant) = global {
+            self.emit_indexed(OpCode::DefineGlobal, constant, position);
+            return;
+        }
+
+        let state = self.current();
+        let Some(slot) = state.locals.len().checked_sub(1) else {
+            return;
+        };
+
+        let local = &mut state.locals[slot];
+        local.depth = Some(state.scope_depth);
+
+        // Recorded for the debugger, which shows locals from where they are initialized
+        let slot = u8::try_from(slot).expect("Locals are limited to one-byte slots");
+        let start = state.function.chunk.code.len();
+        state.function.chunk.begin_local(&local.name, slot, start);
+    }
+
+    fn resolve(&mut self, name: &str, position: Position) -> Variable {
+        let level = self.functions.len() - 1;
+        if let Some(slot) = self.functions[level].resolve_local(name) {
+            return Variable::Local(slot);
+        }
+
+        if let Some(index) = self.resolve_upvalue(level, name, position) {
+            return Variable::Upvalue(index);
+        }
+
+        Variable::Global(self.name_constant(name, position))
+    }
+
+    /// Find `name` in the functions enclosing the one at `level`, capturing it through every
+    /// function in between.
+    fn resolve_upvalue(&mut self, level: usize, name: &str, position: Position) -> Option<u8> {
+        let enclosing = level.checked_sub(1)?;
+
+        if let Some(slot) = self.functions[enclosing].resolve_local(name) {
+            self.functions[enclosing].locals[usize::from(slot)].captured = true;
+            return Some(self.add_upvalue(level, slot, true, position));
+        }
+
+        let index = self.resolve_upvalue(enclosing, name, position)?;
+        Some(self.add_upvalue(level, index, false, position))
+    }
+
+    fn add_upvalue(&mut self, level: usize, index: u8, is_local: bool, position: Position) -> u8 {
+        let upvalue = UpvalueRef { is_local, index };
+        let upvalues = &mut self.functions[level].function.upvalues;
+
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return u8::try_from(existing).expect("Upvalue count is checked when adding");
+        }
+
+        if upvalues.len() >= MAX_UPVALUES {
+            self.error(position, None, "Too many closure variables in function.");
+            return 0;
+        }
+
+        upvalues.push(upvalue);
+        u8::try_from(upvalues.len() - 1).expect("Upvalue count was checked above")
+    }
+
+    fn get_variable(&mut self, name: &str, position: Position) {
+        match self.resolve(name, position) {
+            Variable::Local(slot) => self.emit_with(OpCode::GetLocal, slot, position),
+            Variable::Upvalue(index) => self.emit_with(OpCode::GetUpvalue, index, position),
+            Variable::Global(constant) => self.emit_indexed(OpCode::GetGlobal, constant, position),
+        }
+    }
+
+    fn set_variable(&mut self, name: &str, position: Position) {
+        match self.resolve(name, position) {
+            Variable::Local(slot) => self.emit_with(OpCode::SetLocal, slot, position),
+            Variable::Upvalue(index) => self.emit_with(OpCode::SetUpvalue, index, position),
+            Variable::Global(constant) => self.emit_indexed(OpCode::SetGlobal, constant, position),
+        }
+    }
+
+    fn statements(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Var { name, initializer } => {
+                let global = self.declare(name);
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit(OpCode::Nil, name.position),
+                }
+                self.define(global, name.position);
+            }
+
+            Stmt::Function(function) => {
+                let global = self.declare(&function.name);
+                // A function may refer to itself, so it is usable before its body is compiled
+                if global.is_none() {
+                    self.define(None, function.name.position);
+                }
+                self.function(function);
+                self.define(global, function.name.position);
+            }
+
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+
+            Stmt::Expression(expr) => {
+                self.expression(expr);
+                self.emit(OpCode::Pop, expr.position);
+            }
+
+            Stmt::Print(expr) => {
+                self.expression(expr);
+                self.emit(OpCode::Print, expr.position);
+            }
+
+            Stmt::Return { position, value } => match value {
+                Some(value) => {
+                    self.expression(value);
+                    self.emit(OpCode::Return, *position);
+                }
+                None => self.emit_return(*position),
+            },
+
+            Stmt::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let position = condition.position;
+                self.expression(condition);
+
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse, position);
+                self.emit(OpCode::Pop, position);
+                self.statement(then);
+
+                let else_jump = self.emit_jump(OpCode::Jump, position);
+                self.patch_jump(then_jump, position);
+                self.emit(OpCode::Pop, position);
+
+                if let Some(otherwise) = otherwise {
+                    self.statement(otherwise);
+                }
+                self.patch_jump(else_jump, position);
+            }
+
+            Stmt::While { condition, body } => {
+                let position = condition.position;
+                let start = self.chunk().code.len();
+                self.expression(condition);
+
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse, position);
+                self.emit(OpCode::Pop, position);
+                self.statement(body);
+                self.emit_loop(start, position);
+
+                self.patch_jump(exit_jump, position);
+                self.emit(OpCode::Pop, position);
+            }
+
+            Stmt::Block(stmts) => {
+                self.begin_scope();
+                self.statements(stmts);
+                let end = stmts.last().map_or_else(Position::default, Stmt::position);
+                self.end_scope(end);
+            }
+        }
+    }
+
+    /// Compile `decl` into a function constant and emit the closure that captures its upvalues.
+    fn function(&mut self, decl: &FunctionDecl) {
+        let position = decl.name.position;
+        let arity = u8::try_from(decl.params.len()).expect("The parser limits parameter counts");
+        self.functions.push(FunctionState::new(
+            Some(decl.kind),
+            Some(decl.name.text.clone()),
+            arity,
+        ));
+
+        self.begin_scope();
+        for param in &decl.params {
+            self.add_local(&param.text, param.position);
+            self.define(None, param.position);
+        }
+
+        self.statements(&decl.body);
+        let end = decl.body.last().map_or(position, Stmt::position);
+        self.emit_return(end);
+
+        // The function's locals are discarded with its frame, so the scope is not closed
+        let state = self.functions.pop().expect("Pushed above");
+        let constant = self.make_constant(Value::from(Rc::new(state.function)), position);
+        self.emit_indexed(OpCode::Closure, constant, position);
+    }
+
+    fn class(&mut self, name: &Name, superclass: Option<&Expr>, methods: &[Rc<FunctionDecl>]) {
+        let position = name.position;
+        let name_constant = self.name_constant(&name.text, position);
+        let global = self.declare(name);
+
+        self.emit_indexed(OpCode::Class, name_constant, position);
+        self.define(global, position);
+
+        if let Some(superclass) = superclass {
+            self.expression(superclass);
+
+            self.begin_scope();
+            self.add_local("super", superclass.position);
+            self.define(None, superclass.position);
+
+            self.get_variable(&name.text, position);
+            self.emit(OpCode::Inherit, superclass.position);
+        }
+
+        self.get_variable(&name.text, position);
+        for method in methods {
+            let method_constant = self.name_constant(&method.name.text, method.name.position);
+            self.function(method);
+            self.emit_indexed(OpCode::Method, method_constant, method.name.position);
+        }
+        self.emit(OpCode::Pop, position);
+
+        if superclass.is_some() {
+            self.end_scope(position);
+        }
+    }
+
+    fn literal(&mut self, literal: &Literal, position: Position) {
+        match literal {
+            Literal::Nil => self.emit(OpCode::Nil, position),
+            Literal::Bool(true) => self.emit(OpCode::True, position),
+            Literal::Bool(false) => self.emit(OpCode::False, position),
+            Literal::Number(n) => self.emit_constant(Value::from(*n), position),
+            Literal::String(s) => self.emit_constant(Value::string(s.as_str()), position),
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        let position = expr.position;
+
+        match &expr.kind {
+            ExprKind::Literal(literal) => self.literal(literal, position),
+
+            ExprKind::Variable(name) => self.get_variable(&name.text, name.position),
+
+            ExprKind::Assign { name, value } => {
+                self.expression(value);
+                self.set_variable(&name.text, position);
+            }
+
+            ExprKind::Unary { op, operand } => {
+                self.expression(operand);
+                let opcode = match op {
+                    UnaryOp::Negate => OpCode::Negate,
+                    UnaryOp::Not => OpCode::Not,
+                };
+                self.emit(opcode, position);
+            }
+
+            ExprKind::Binary { op, left, right } => {
+                self.expression(left);
+                self.expression(right);
+
+                let (opcode, negate) = match op {
+                    BinaryOp::Add => (OpCode::Add, false),
+                    BinaryOp::Sub => (OpCode::Sub, false),
+                    BinaryOp::Mul => (OpCode::Mul, false),
+                    BinaryOp::Div => (OpCode::Div, false),
+                    BinaryOp::Equal => (OpCode::Equal, false),
+                    BinaryOp::NotEqual => (OpCode::Equal, true),
+                    BinaryOp::Less => (OpCode::Less, false),
+                    BinaryOp::LessEqual => (OpCode::Greater, true),
+                    BinaryOp::Greater => (OpCode::Greater, false),
+                    BinaryOp::GreaterEqual => (OpCode::Less, true),
+                };
+
+                self.emit(opcode, position);
+                if negate {
+                    self.emit(OpCode::Not, position);
+                }
+            }
+
+            ExprKind::Logical { op, left, right } => {
+                self.expression(left);
+                match op {
+                    LogicalOp::And => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse, position);
+                        self.emit(OpCode::Pop, position);
+                        self.expression(right);
+                        self.patch_jump(end_jump, position);
+                    }
+                    LogicalOp::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse, position);
+                        let end_jump = self.emit_jump(OpCode::Jump, position);
+                        self.patch_jump(else_jump, position);
+                        self.emit(OpCode::Pop, position);
+                        self.expression(right);
+                        self.patch_jump(end_jump, position);
+                    }
+                }
+            }
+
+            ExprKind::Grouping(inner) => self.expression(inner),
+
+            ExprKind::Call { callee, args } => self.call(callee, args, position),
+
+            ExprKind::Get { object, name } => {
+                self.expression(object);
+                let constant = self.name_constant(&name.text, name.position);
+                self.emit_indexed(OpCode::GetProperty, constant, position);
+            }
+
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object);
+                self.expression(value);
+                let constant = self.name_constant(&name.text, name.position);
+                self.emit_indexed(OpCode::SetProperty, constant, position);
+            }
+
+            ExprKind::This => self.get_variable("this", position),
+
+            ExprKind::Super { method } => {
+                let constant = self.name_constant(&method.text, method.position);
+                self.get_variable("this", position);
+                self.get_variable("super", position);
+                self.emit_indexed(OpCode::GetSuper, constant, position);
+            }
+        }
+    }
+
+    /// Compile a call, fusing method lookups with the call into a single `Invoke`.
+    fn call(&mut self, callee: &Expr, args: &[Expr], position: Position) {
+        let arg_count = u8::try_from(args.len()).expect("The parser limits argument counts");
+
+        match &callee.kind {
+            ExprKind::Get { object, name } => {
+                self.expression(object);
+                let constant = self.name_constant(&name.text, name.position);
+                self.arguments(args);
+                self.emit_indexed(OpCode::Invoke, constant, position);
+                self.chunk().push_byte(arg_count, position);
+            }
+
+            ExprKind::Super { method } => {
+                let constant = self.name_constant(&method.text, method.position);
+                self.get_variable("this", callee.position);
+                self.arguments(args);
+                self.get_variable("super", callee.position);
+                self.emit_indexed(OpCode::SuperInvoke, constant, position);
+                self.chunk().push_byte(arg_count, position);
+            }
+
+            _ => {
+                self.expression(callee);
+                self.arguments(args);
+                self.emit_with(OpCode::Call, arg_count, position);
+            }
+        }
+    }
+
+    fn arguments(&mut self, args: &[Expr]) {
+        for arg in args {
+            self.expression(arg);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Constant folding: evaluating expressions whose operands are all literals at compile time.
+//! The syntax tree is folded once, after it is resolved, so code generators only see literals.
+//!
+//! Folding must not change what a program does, so an operation that would fail at runtime,
+//! such as adding a number to a string, is left for the VM to report. Comparisons fold to the
+//! same result as the instructions they would compile to, which implement `a <= b` as
+//! `!(a > b)`.
+
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use super::ast::{BinaryOp, Expr, ExprKind, Literal, LogicalOp, Stmt, UnaryOp};
+
+/// Fold the constant expressions throughout `program`.
+pub fn fold_program(program: &mut [Stmt]) {
+    for stmt in program {
+        fold_statement(stmt);
+    }
+}
+
+fn fold_statement(stmt: &mut Stmt) {
+    match stmt {
+        Stmt::Var { initializer, .. } => {
+            if let Some(initializer) = initializer {
+                fold(initializer);
+            }
+        }
+
+        Stmt::Function(function) => fold_program(&mut Rc::make_mut(function).body),
+
+        Stmt::Class {
+            superclass,
+            methods,
+            ..
+        } => {
+            if let Some(superclass) = superclass {
+                fold(superclass);
+            }
+
+            for method in methods {
+                fold_program(&mut Rc::make_mut(method).body);
+            }
+        }
+
+        Stmt::Expression(expr) | Stmt::Print(expr) => fold(expr),
+
+        Stmt::Return { value, .. } => {
+            if let Some(value) = value {
+                fold(value);
+            }
+        }
+
+        Stmt::If {
+            condition,
+            then,
+            otherwise,
+        } => {
+            fold(condition);
+            fold_statement(then);
+            if let Some(otherwise) = otherwise {
+                fold_statement(otherwise);
+            }
+        }
+
+        Stmt::While { condition, body } => {
+            fold(condition);
+            fold_statement(body);
+        }
+
+        Stmt::Block(stmts) => fold_program(stmts),
+    }
+}
+
+/// Replace every constant subexpression of `expr`, and `expr` itself if it is constant, with a
+/// literal. Operands are folded before the operations on them, so each node is visited once.
+pub fn fold(expr: &mut Expr) {
+    match &mut expr.kind {
+        ExprKind::Literal(_) | ExprKind::Variable(_) | ExprKind::This | ExprKind::Super { .. } => {}
+
+        ExprKind::Grouping(inner)
+        | ExprKind::Unary { operand: inner, .. }
+        | ExprKind::Assign { value: inner, .. }
+        | ExprKind::Get { object: inner, .. } => fold(inner),
+
+        ExprKind::Binary { left, right, .. }
+        | ExprKind::Logical { left, right, .. }
+        | ExprKind::Set {
+            object: left,
+            value: right,
+            ..
+        } => {
+            fold(left);
+            fold(right);
+        }
+
+        ExprKind::Call { callee, args } => {
+            fold(callee);
+            args.iter_mut().for_each(fold);
+        }
+    }
+
+    // A constant left operand either decides a logical expression or is discarded, leaving its
+    // right operand
+    if let ExprKind::Logical { op, left, right } = &mut expr.kind
+        && let ExprKind::Literal(value) = &left.kind
+    {
+        let operand = if short_circuits(*op, value) {
+            left
+        } else {
+            right
+        };
+
+        let placeholder = Expr {
+            id: operand.id,
+            position: operand.position,
+            kind: ExprKind::Literal(Literal::Nil),
+        };
+        *expr = std::mem::replace(operand, placeholder);
+        return;
+    }
+
+    if let Some(literal) = value(expr) {
+        expr.kind = ExprKind::Literal(literal);
+    }
+}
+
+/// The value of an operation whose operands have been folded, if they were all constant.
+fn value(expr: &Expr) -> Option<Literal> {
+    match &expr.kind {
+        ExprKind::Grouping(inner) => literal(inner).cloned(),
+        ExprKind::Unary { op, operand } => unary(*op, literal(operand)?),
+        ExprKind::Binary { op, left, right } => binary(*op, literal(left)?, literal(right)?),
+        _ => None,
+    }
+}
+
+fn literal(expr: &Expr) -> Option<&Literal> {
+    match &expr.kind {
+        ExprKind::Literal(literal) => Some(literal),
+        _ => None,
+    }
+}
+
+/// Whether `left` alone decides the value of a logical expression, so that its right operand is
+/// never evaluated.
+fn short_circuits(op: LogicalOp, left: &Literal) -> bool {
+    match op {
+        LogicalOp::And => is_falsey(left),
+        LogicalOp::Or => !is_falsey(left),
+    }
+}
+
+fn is_falsey(literal: &Literal) -> bool {
+    matches!(literal, Literal::Nil | Literal::Bool(false))
+}
+
+fn unary(op: UnaryOp, operand: &Literal) -> Option<Literal> {
+    match (op, operand) {
+        (UnaryOp::Negate, Literal::Number(n)) => Some(Literal::Number(-n)),
+        (UnaryOp::Negate, _) => None,
+        (UnaryOp::Not, operand) => Some(Literal::Bool(is_falsey(operand))),
+    }
+}
+
+fn binary(op: BinaryOp, left: &Literal, right: &Literal) -> Option<Literal> {
+    let res = match (op, left, right) {
+        (BinaryOp::Equal, left, right) => Literal::Bool(left == right),
+        (BinaryOp::NotEqual, left, right) => Literal::Bool(left != right),
+        (BinaryOp::Add, Literal::String(a), Literal::String(b)) => Literal::String(a.clone() + b),
+        (op, &Literal::Number(a), &Literal::Number(b)) => match op {
+            BinaryOp::Add => Literal::Number(a + b),
+            BinaryOp::Sub => Literal::Number(a - b),
+            BinaryOp::Mul => Literal::Number(a * b),
+            BinaryOp::Div => Literal::Number(a / b),
+            BinaryOp::Less => Literal::Bool(a < b),
+            BinaryOp::LessEqual => Literal::Bool(a.partial_cmp(&b) != Some(Ordering::Greater)),
+            BinaryOp::Greater => Literal::Bool(a > b),
+            BinaryOp::GreaterEqual => Literal::Bool(a.partial_cmp(&b) != Some(Ordering::Less)),
+            BinaryOp::Equal | BinaryOp::NotEqual => unreachable!("Matched above"),
+        },
+        _ => return None,
+    };
+
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::parser::Parser;
+    use crate::compiler::scanner::Scanner;
+
+    fn folded(source: &str) -> ExprKind {
+        let mut expr = Parser::new(Scanner::new(source))
+            .parse_expression()
+            .unwrap();
+        fold(&mut expr);
+        expr.kind
+    }
+
+    fn constant(source: &str) -> Literal {
+        match folded(source) {
+            ExprKind::Literal(literal) => literal,
+            kind => panic!("{source} did not fold: {kind:?}"),
+        }
+    }
+
+    #[test]
+    fn folds_arithmetic() {
+        assert_eq!(constant("60 * 60 * 24"), Literal::Number(86400.0));
+        assert_eq!(constant("-(1 + 2) * (4 - 1) / 2"), Literal::Number(-4.5));
+    }
+
+    #[test]
+    fn folds_division_by_zero_like_the_vm() {
+        assert_eq!(constant("1 / 0"), Literal::Number(f64::INFINITY));
+        assert_eq!(constant("-1 / 0"), Literal::Number(f64::NEG_INFINITY));
+        assert!(matches!(constant("0 / 0"), Literal::Number(n) if n.is_nan()));
+    }
+
+    #[test]
+    fn folds_nan_comparisons_like_the_vm() {
+        assert_eq!(constant("0 / 0 == 0 / 0"), Literal::Bool(false));
+        assert_eq!(constant("0 / 0 != 0 / 0"), Literal::Bool(true));
+        assert_eq!(constant("0 / 0 < 1"), Literal::Bool(false));
+        assert_eq!(constant("0 / 0 > 1"), Literal::Bool(false));
+        assert_eq!(constant("0 / 0 <= 1"), Literal::Bool(true));
+        assert_eq!(constant("1 >= 0 / 0"), Literal::Bool(true));
+    }
+
+    #[test]
+    fn folds_string_concatenation() {
+        assert_eq!(
+            constant("\"a\" + (\"b\" + \"c\") + \"d\""),
+            Literal::String("abcd".to_string())
+        );
+        assert_eq!(constant("\"a\" + \"b\" == \"ab\""), Literal::Bool(true));
+    }
+
+    #[test]
+    fn folds_logical_operators() {
+        assert_eq!(
+            constant("nil or \"default\""),
+            Literal::String("default".to_string())
+        );
+        assert_eq!(constant("false and 1"), Literal::Bool(false));
+        assert_eq!(constant("!(1 < 2) or 0"), Literal::Number(0.0));
+
+        // A constant left operand is dropped even when the right one is not constant
+        assert!(matches!(folded("true and x"), ExprKind::Variable(_)));
+        assert!(matches!(
+            folded("(1 or x) + 2"),
+            ExprKind::Literal(Literal::Number(3.0))
+        ));
+        assert!(matches!(folded("x or 1"), ExprKind::Logical { .. }));
+    }
+
+    #[test]
+    fn leaves_runtime_errors_to_the_vm() {
+        for source in ["1 + \"a\"", "-\"a\"", "1 < nil", "\"a\" * 2"] {
+            assert!(
+                matches!(
+                    folded(source),
+                    ExprKind::Binary { .. } | ExprKind::Unary { .. }
+                ),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn folds_constant_operands_of_other_expressions() {
+        let ExprKind::Binary { left, right, .. } = folded("x + 2 * 3") else {
+            panic!("expected a binary expression");
+        };
+        assert!(matches!(left.kind, ExprKind::Variable(_)));
+        assert!(matches!(
+            right.kind,
+            ExprKind::Literal(Literal::Number(6.0))
+        ));
+
+        let ExprKind::Call { args, .. } = folded("f(1 + 1, \"a\" + \"b\")") else {
+            panic!("expected a call");
+        };
+        assert!(args.iter().all(|arg| literal(arg).is_some()));
+    }
+
+    #[test]
+    fn folds_long_chains() {
+        let source = vec!["1"; 2000].join(" + ");
+        assert_eq!(constant(&source), Literal::Number(2000.0));
+    }
+}
@@ -1,35 +1,64 @@
+pub mod ast;
+mod codegen;
+pub mod fold;
 mod parser;
-mod scanner;
-mod token;
+pub mod resolver;
+pub mod scanner;
+pub mod token;
 
-use crate::chunk::Chunk;
+use thiserror::Error;
+
+use crate::chunk::{Chunk, LocalVariable, Position};
+use crate::value::Function;
 use crate::{InterpretError, InterpretResult};
 
+pub use parser::MAX_ARGS;
 use parser::Parser;
 use scanner::Scanner;
 
+#[derive(Debug, Clone, Error)]
+#[error("[{position}] Error{location}: {message}")]
+pub struct CompileError {
+    pub position: Position,
+    /// Where in the line the error was found, such as " at 'x'" or " at end"
+    pub location: String,
+    pub message: String,
+    /// Just past the end of the token the error names, if it names one
+    pub end: Option<Position>,
+}
+
+/// Parse `source` into a syntax tree, reporting every syntax error found.
+pub fn parse(source: &str) -> Result<Vec<ast::Stmt>, Vec<CompileError>> {
+    Parser::new(Scanner::new(source)).parse()
+}
+
+/// Compile `source` to the chunk of its top-level code. The code of each function is a
+/// function constant in the chunk enclosing it.
 pub fn compile(source: &str) -> InterpretResult<Chunk> {
-    let c = Chunk::new();
-
-    let scanner = Scanner::new(source);
-    let mut parser = Parser::new(scanner);
-
-    // for token_res in scanner {
-    //     let token = match token_res {
-    //         Ok(t) => t,
-    //         Err(e) => {
-    //             parser.erred = true;
-    //             eprintln!("[Line {}] Syntax error: {}\n", e.line(), e);
-    //             parser.panicking = true;
-    //             continue;
-    //         }
-    //     };
-    //     parser.panicking = false;
-    // }
-    //
-    if parser.erred {
-        Err(InterpretError::Compiler)
-    } else {
-        Ok(c)
+    let program = analyze(source)?;
+    codegen::generate(&program).map_err(InterpretError::Compiler)
+}
+
+/// Parse `source`, check its scoping and fold its constant expressions, producing a syntax tree
+/// ready for a code generator.
+pub fn analyze(source: &str) -> InterpretResult<Vec<ast::Stmt>> {
+    let mut program = parse(source).map_err(InterpretError::Compiler)?;
+    let resolution = resolver::resolve(&program);
+    if !resolution.errors.is_empty() {
+        return Err(InterpretError::Compiler(resolution.errors));
     }
+
+    fold::fold_program(&mut program);
+    Ok(program)
+}
+
+/// Compile a single expression to a function that returns its value. The expression may use the
+/// locals in `scope`, which the function captures as upvalues from the frame it is evaluated in.
+pub fn compile_expression(source: &str, scope: &[LocalVariable]) -> InterpretResult<Function> {
+    let mut expr = Parser::new(Scanner::new(source))
+        .parse_expression()
+        .map_err(InterpretError::Compiler)?;
+    fold::fold(&mut expr);
+
+    codegen::generate_expression(&expr, scope).map_err(InterpretError::Compiler)
 }
@@ -1,46 +1,625 @@
+use std::mem::{discriminant, replace};
+use std::rc::Rc;
+
+use crate::chunk::Position;
+
+use super::CompileError;
+use super::ast::{
+    BinaryOp, Expr, ExprId, ExprKind, Function, FunctionKind, Literal, LogicalOp, Name, Stmt,
+    UnaryOp,
+};
 use super::scanner::{Scanner, ScannerError};
 use super::token::{Token, TokenKind};
 
+/// Calls and functions are limited by the width of the `Call` operand.
+pub const MAX_ARGS: usize = 255;
+
 #[derive(Debug)]
 pub struct Parser<'a> {
     pub scanner: Scanner<'a>,
     pub current: Token<'a>,
     pub previous: Token<'a>,
-    pub erred: bool,
+    pub errors: Vec<CompileError>,
     pub panicking: bool,
+    next_id: ExprId,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(scanner: Scanner<'a>) -> Self {
-        Self {
+        let mut parser = Self {
             scanner,
             current: Token::new_undefined(),
             previous: Token::new_undefined(),
-            erred: false,
+            errors: Vec::new(),
             panicking: false,
+            next_id: 0,
+        };
+
+        parser.advance();
+        parser
+    }
+
+    /// Parse the whole program, recovering after errors so that as many as possible are
+    /// reported at once.
+    pub fn parse(mut self) -> Result<Vec<Stmt>, Vec<CompileError>> {
+        let mut program = Vec::new();
+        while !self.check(&TokenKind::Eof) {
+            if let Some(stmt) = self.declaration() {
+                program.push(stmt);
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(self.errors)
         }
     }
 
-    fn advance(&mut self) -> Option<Token<'a>> {
-        while let Some(token_res) = self.scanner.next() {
-            match token_res {
-                Ok(t) => return Some(t),
-                Err(e) => self.report_err(&e, "Syntax error"),
+    /// Parse a single expression spanning the whole input.
+    pub fn parse_expression(mut self) -> Result<Expr, Vec<CompileError>> {
+        let expr = self.expression();
+        if !self.check(&TokenKind::Eof) {
+            self.error_at_current("Expect end of expression.");
+        }
+
+        if self.errors.is_empty() {
+            Ok(expr)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn advance(&mut self) {
+        self.previous = replace(&mut self.current, Token::new_undefined());
+
+        loop {
+            match self.scanner.next() {
+                Some(Ok(t)) => {
+                    self.current = t;
+                    return;
+                }
+                Some(Err(e)) => self.report_err(&e, "Syntax error"),
+                None => {
+                    let end = self.scanner.position();
+                    self.current = Token {
+                        kind: TokenKind::Eof,
+                        lexeme: "",
+                        line: end.line,
+                        column: end.column,
+                    };
+                    return;
+                }
             }
         }
+    }
 
-        None
+    fn check(&self, kind: &TokenKind) -> bool {
+        discriminant(&self.current.kind) == discriminant(kind)
+    }
+
+    fn matches(&mut self, kind: &TokenKind) -> bool {
+        if !self.check(kind) {
+            return false;
+        }
+
+        self.advance();
+        true
     }
 
     fn consume(&mut self, kind: &TokenKind, err_message: &str) {
-        if matches!(&self.current.kind, kind) {
+        if self.check(kind) {
             self.advance();
+        } else {
+            self.error_at_current(err_message);
         }
     }
 
+    fn consume_name(&mut self, err_message: &str) -> Name {
+        self.consume(&TokenKind::Identifier(""), err_message);
+        name(&self.previous)
+    }
+
     fn report_err(&mut self, error: &ScannerError, message: &str) {
-        self.erred = true;
+        if self.panicking {
+            return;
+        }
+
+        self.panicking = true;
+        self.errors.push(CompileError {
+            position: self.scanner.position(),
+            location: String::new(),
+            message: format!("{message}: {error}"),
+            end: None,
+        });
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
+        if self.panicking {
+            return;
+        }
+
         self.panicking = true;
-        eprintln!("[Line {}] {message}: {}\n", error.line(), error);
+        let location = match token.kind {
+            TokenKind::Eof => " at end".to_string(),
+            _ => format!(" at '{}'", token.lexeme),
+        };
+
+        self.errors.push(CompileError {
+            position: token.position(),
+            location,
+            message: message.to_string(),
+            end: Some(token.position().after(token.lexeme)),
+        });
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        let token = self.current.clone();
+        self.error_at(&token, message);
+    }
+
+    /// Skip tokens until a likely statement boundary after an error.
+    fn synchronize(&mut self) {
+        self.panicking = false;
+
+        while !self.check(&TokenKind::Eof) {
+            if matches!(self.previous.kind, TokenKind::Semicolon) {
+                return;
+            }
+
+            match self.current.kind {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Print
+                | TokenKind::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn make_expr(&mut self, position: Position, kind: ExprKind) -> Expr {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        Expr { id, position, kind }
+    }
+
+    fn declaration(&mut self) -> Option<Stmt> {
+        let stmt = if self.matches(&TokenKind::Class) {
+            self.class_declaration()
+        } else if self.matches(&TokenKind::Fun) {
+            Stmt::Function(self.function(FunctionKind::Function))
+        } else if self.matches(&TokenKind::Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+
+        if self.panicking {
+            self.synchronize();
+            return None;
+        }
+
+        Some(stmt)
+    }
+
+    fn class_declaration(&mut self) -> Stmt {
+        let name = self.consume_name("Expect class name.");
+
+        let superclass = if self.matches(&TokenKind::Lt) {
+            let superclass = self.consume_name("Expect superclass name.");
+            Some(self.make_expr(superclass.position, ExprKind::Variable(superclass)))
+        } else {
+            None
+        };
+
+        self.consume(&TokenKind::LCurly, "Expect '{' before class body.");
+        let mut methods = Vec::new();
+        while !self.check(&TokenKind::RCurly) && !self.check(&TokenKind::Eof) {
+            let kind = if self.current.lexeme == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+
+            methods.push(self.function(kind));
+        }
+        self.consume(&TokenKind::RCurly, "Expect '}' after class body.");
+
+        Stmt::Class {
+            name,
+            superclass,
+            methods,
+        }
+    }
+
+    fn function(&mut self, kind: FunctionKind) -> Rc<Function> {
+        let name = self.consume_name("Expect function name.");
+
+        self.consume(&TokenKind::LParen, "Expect '(' after function name.");
+        let mut params = Vec::new();
+        if !self.check(&TokenKind::RParen) {
+            loop {
+                if params.len() == MAX_ARGS {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                params.push(self.consume_name("Expect parameter name."));
+                if !self.matches(&TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(&TokenKind::RParen, "Expect ')' after parameters.");
+
+        self.consume(&TokenKind::LCurly, "Expect '{' before function body.");
+        let body = self.block();
+
+        Rc::new(Function {
+            kind,
+            name,
+            params,
+            body,
+        })
+    }
+
+    fn var_declaration(&mut self) -> Stmt {
+        let name = self.consume_name("Expect variable name.");
+        let initializer = self.matches(&TokenKind::Eq).then(|| self.expression());
+        self.consume(
+            &TokenKind::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        Stmt::Var { name, initializer }
+    }
+
+    fn statement(&mut self) -> Stmt {
+        if self.matches(&TokenKind::Print) {
+            let value = self.expression();
+            self.consume(&TokenKind::Semicolon, "Expect ';' after value.");
+            Stmt::Print(value)
+        } else if self.matches(&TokenKind::Return) {
+            let position = self.previous.position();
+            let value = (!self.check(&TokenKind::Semicolon)).then(|| self.expression());
+            self.consume(&TokenKind::Semicolon, "Expect ';' after return value.");
+            Stmt::Return { position, value }
+        } else if self.matches(&TokenKind::If) {
+            self.if_statement()
+        } else if self.matches(&TokenKind::While) {
+            self.consume(&TokenKind::LParen, "Expect '(' after 'while'.");
+            let condition = self.expression();
+            self.consume(&TokenKind::RParen, "Expect ')' after condition.");
+            let body = Box::new(self.statement());
+            Stmt::While { condition, body }
+        } else if self.matches(&TokenKind::For) {
+            self.for_statement()
+        } else if self.matches(&TokenKind::LCurly) {
+            Stmt::Block(self.block())
+        } else {
+            let expr = self.expression();
+            self.consume(&TokenKind::Semicolon, "Expect ';' after expression.");
+            Stmt::Expression(expr)
+        }
+    }
+
+    fn if_statement(&mut self) -> Stmt {
+        self.consume(&TokenKind::LParen, "Expect '(' after 'if'.");
+        let condition = self.expression();
+        self.consume(&TokenKind::RParen, "Expect ')' after condition.");
+
+        let then = Box::new(self.statement());
+        let otherwise = self
+            .matches(&TokenKind::Else)
+            .then(|| Box::new(self.statement()));
+
+        Stmt::If {
+            condition,
+            then,
+            otherwise,
+        }
+    }
+
+    /// Desugar `for (init; condition; increment) body` into an equivalent `while` loop.
+    fn for_statement(&mut self) -> Stmt {
+        let position = self.previous.position();
+        self.consume(&TokenKind::LParen, "Expect '(' after 'for'.");
+
+        let initializer = if self.matches(&TokenKind::Semicolon) {
+            None
+        } else if self.matches(&TokenKind::Var) {
+            Some(self.var_declaration())
+        } else {
+            let expr = self.expression();
+            self.consume(&TokenKind::Semicolon, "Expect ';' after loop initializer.");
+            Some(Stmt::Expression(expr))
+        };
+
+        let condition = if self.check(&TokenKind::Semicolon) {
+            self.make_expr(position, ExprKind::Literal(Literal::Bool(true)))
+        } else {
+            self.expression()
+        };
+        self.consume(&TokenKind::Semicolon, "Expect ';' after loop condition.");
+
+        let increment = (!self.check(&TokenKind::RParen)).then(|| self.expression());
+        self.consume(&TokenKind::RParen, "Expect ')' after for clauses.");
+
+        let mut body = self.statement();
+        if let Some(increment) = increment {
+            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
+        }
+
+        let mut stmt = Stmt::While {
+            condition,
+            body: Box::new(body),
+        };
+        if let Some(initializer) = initializer {
+            stmt = Stmt::Block(vec![initializer, stmt]);
+        }
+
+        stmt
+    }
+
+    fn block(&mut self) -> Vec<Stmt> {
+        let mut stmts = Vec::new();
+        while !self.check(&TokenKind::RCurly) && !self.check(&TokenKind::Eof) {
+            if let Some(stmt) = self.declaration() {
+                stmts.push(stmt);
+            }
+        }
+
+        self.consume(&TokenKind::RCurly, "Expect '}' after block.");
+        stmts
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.assignment()
+    }
+
+    fn assignment(&mut self) -> Expr {
+        let target = self.or();
+
+        if !self.matches(&TokenKind::Eq) {
+            return target;
+        }
+
+        let equals = self.previous.clone();
+        let value = Box::new(self.assignment());
+        match target.kind {
+            ExprKind::Variable(name) => {
+                self.make_expr(target.position, ExprKind::Assign { name, value })
+            }
+            ExprKind::Get { object, name } => self.make_expr(
+                target.position,
+                ExprKind::Set {
+                    object,
+                    name,
+                    value,
+                },
+            ),
+            kind => {
+                self.error_at(&equals, "Invalid assignment target.");
+                Expr { kind, ..target }
+            }
+        }
+    }
+
+    fn or(&mut self) -> Expr {
+        let mut left = self.and();
+        while self.matches(&TokenKind::Or) {
+            let position = self.previous.position();
+            let right = Box::new(self.and());
+            left = self.make_expr(
+                position,
+                ExprKind::Logical {
+                    op: LogicalOp::Or,
+                    left: Box::new(left),
+                    right,
+                },
+            );
+        }
+
+        left
+    }
+
+    fn and(&mut self) -> Expr {
+        let mut left = self.equality();
+        while self.matches(&TokenKind::And) {
+            let position = self.previous.position();
+            let right = Box::new(self.equality());
+            left = self.make_expr(
+                position,
+                ExprKind::Logical {
+                    op: LogicalOp::And,
+                    left: Box::new(left),
+                    right,
+                },
+            );
+        }
+
+        left
+    }
+
+    /// Parse a left-associative chain of the binary operators chosen by `operator`, with
+    /// operands parsed by `operand`.
+    fn binary(
+        &mut self,
+        operator: fn(&TokenKind) -> Option<BinaryOp>,
+        operand: fn(&mut Self) -> Expr,
+    ) -> Expr {
+        let mut left = operand(self);
+        while let Some(op) = operator(&self.current.kind) {
+            self.advance();
+            let position = self.previous.position();
+            let right = Box::new(operand(self));
+            left = self.make_expr(
+                position,
+                ExprKind::Binary {
+                    op,
+                    left: Box::new(left),
+                    right,
+                },
+            );
+        }
+
+        left
+    }
+
+    fn equality(&mut self) -> Expr {
+        self.binary(
+            |kind| match kind {
+                TokenKind::EqEq => Some(BinaryOp::Equal),
+                TokenKind::BangEq => Some(BinaryOp::NotEqual),
+                _ => None,
+            },
+            Self::comparison,
+        )
+    }
+
+    fn comparison(&mut self) -> Expr {
+        self.binary(
+            |kind| match kind {
+                TokenKind::Lt => Some(BinaryOp::Less),
+                TokenKind::LtEq => Some(BinaryOp::LessEqual),
+                TokenKind::Gt => Some(BinaryOp::Greater),
+                TokenKind::GtEq => Some(BinaryOp::GreaterEqual),
+                _ => None,
+            },
+            Self::term,
+        )
+    }
+
+    fn term(&mut self) -> Expr {
+        self.binary(
+            |kind| match kind {
+                TokenKind::Plus => Some(BinaryOp::Add),
+                TokenKind::Minus => Some(BinaryOp::Sub),
+                _ => None,
+            },
+            Self::factor,
+        )
+    }
+
+    fn factor(&mut self) -> Expr {
+        self.binary(
+            |kind| match kind {
+                TokenKind::Star => Some(BinaryOp::Mul),
+                TokenKind::Slash => Some(BinaryOp::Div),
+                _ => None,
+            },
+            Self::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Expr {
+        let op = match self.current.kind {
+            TokenKind::Minus => UnaryOp::Negate,
+            TokenKind::Bang => UnaryOp::Not,
+            _ => return self.call(),
+        };
+
+        self.advance();
+        let position = self.previous.position();
+        let operand = Box::new(self.unary());
+        self.make_expr(position, ExprKind::Unary { op, operand })
+    }
+
+    fn call(&mut self) -> Expr {
+        let mut expr = self.primary();
+
+        loop {
+            if self.matches(&TokenKind::LParen) {
+                let position = self.previous.position();
+                let args = self.arguments();
+                expr = self.make_expr(
+                    position,
+                    ExprKind::Call {
+                        callee: Box::new(expr),
+                        args,
+                    },
+                );
+            } else if self.matches(&TokenKind::Dot) {
+                let name = self.consume_name("Expect property name after '.'.");
+                expr = self.make_expr(
+                    name.position,
+                    ExprKind::Get {
+                        object: Box::new(expr),
+                        name,
+                    },
+                );
+            } else {
+                return expr;
+            }
+        }
+    }
+
+    fn arguments(&mut self) -> Vec<Expr> {
+        let mut args = Vec::new();
+        if !self.check(&TokenKind::RParen) {
+            loop {
+                if args.len() == MAX_ARGS {
+                    self.error_at_current("Can't have more than 255 arguments.");
+                }
+
+                args.push(self.expression());
+                if !self.matches(&TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(&TokenKind::RParen, "Expect ')' after arguments.");
+        args
+    }
+
+    fn primary(&mut self) -> Expr {
+        let position = self.current.position();
+        let kind = match &self.current.kind {
+            TokenKind::False => ExprKind::Literal(Literal::Bool(false)),
+            TokenKind::True => ExprKind::Literal(Literal::Bool(true)),
+            TokenKind::Nil => ExprKind::Literal(Literal::Nil),
+            TokenKind::Number(n) => ExprKind::Literal(Literal::Number(*n)),
+            TokenKind::String(s) => {
+                // The lexeme includes the surrounding quotes
+                let contents = s.get(1..s.len() - 1).unwrap_or_default();
+                ExprKind::Literal(Literal::String(contents.to_string()))
+            }
+            TokenKind::Identifier(_) => ExprKind::Variable(name(&self.current)),
+            TokenKind::This => ExprKind::This,
+
+            TokenKind::Super => {
+                self.advance();
+                self.consume(&TokenKind::Dot, "Expect '.' after 'super'.");
+                let method = self.consume_name("Expect superclass method name.");
+                return self.make_expr(position, ExprKind::Super { method });
+            }
+
+            TokenKind::LParen => {
+                self.advance();
+                let expr = Box::new(self.expression());
+                self.consume(&TokenKind::RParen, "Expect ')' after expression.");
+                return self.make_expr(position, ExprKind::Grouping(expr));
+            }
+
+            _ => {
+                self.error_at_current("Expect expression.");
+                ExprKind::Literal(Literal::Nil)
+            }
+        };
+
+        self.advance();
+        self.make_expr(position, kind)
+    }
+}
+
+fn name(token: &Token) -> Name {
+    Name {
+        text: token.lexeme.to_string(),
+        position: token.position(),
     }
 }
@@ -0,0 +1,415 @@
+//! Static scope resolution over the syntax tree. Works out which declaration every variable
+//! reference binds to, reports the scoping errors the language forbids, and records facts
+//! about declarations that tools such as the linter turn into warnings.
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::chunk::Position;
+
+use super::CompileError;
+use super::ast::{Expr, ExprId, ExprKind, Function, FunctionKind, Name, Stmt};
+
+#[derive(Debug, Clone)]
+pub enum ScopeWarning {
+    /// A local variable or parameter that is never read
+    Unused {
+        name: String,
+        position: Position,
+        parameter: bool,
+    },
+    /// A local declaration hiding one of the same name in an enclosing scope
+    Shadowed {
+        name: String,
+        position: Position,
+        shadowed: Position,
+    },
+    /// An assignment to a global that no top-level declaration introduces
+    UndeclaredGlobal { name: String, position: Position },
+}
+
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// For each expression referring to a local variable, how many scopes out from the
+    /// innermost one the variable was declared. References to globals have no entry.
+    pub locals: HashMap<ExprId, usize>,
+    pub errors: Vec<CompileError>,
+    pub warnings: Vec<ScopeWarning>,
+}
+
+pub fn resolve(program: &[Stmt]) -> Resolution {
+    let globals = program
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Var { name, .. } | Stmt::Class { name, .. } => Some(name.text.clone()),
+            Stmt::Function(function) => Some(function.name.text.clone()),
+            _ => None,
+        })
+        .collect();
+
+    let mut resolver = Resolver {
+        scopes: Vec::new(),
+        function: None,
+        class: ClassKind::None,
+        globals,
+        resolution: Resolution::default(),
+    };
+
+    resolver.statements(program);
+    resolver.resolution
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LocalKind {
+    Variable,
+    Parameter,
+    /// `this` and `super`, which are bound without being written in the source
+    Implicit,
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    position: Position,
+    kind: LocalKind,
+    defined: bool,
+    used: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClassKind {
+    None,
+    Class,
+    Subclass,
+}
+
+struct Resolver {
+    scopes: Vec<Vec<Local>>,
+    function: Option<FunctionKind>,
+    class: ClassKind,
+    globals: HashSet<String>,
+    resolution: Resolution,
+}
+
+impl Resolver {
+    fn error(&mut self, name: &str, position: Position, message: &str) {
+        self.resolution.errors.push(CompileError {
+            position,
+            location: format!(" at '{name}'"),
+            message: message.to_string(),
+            end: Some(position.after(name)),
+        });
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn end_scope(&mut self) {
+        let Some(scope) = self.scopes.pop() else {
+            return;
+        };
+
+        let unused = scope
+            .into_iter()
+            .filter(|l| !l.used && l.kind != LocalKind::Implicit && !l.name.starts_with('_'))
+            .map(|l| ScopeWarning::Unused {
+                parameter: l.kind == LocalKind::Parameter,
+                name: l.name,
+                position: l.position,
+            });
+
+        self.resolution.warnings.extend(unused);
+    }
+
+    /// Add `name` to the innermost scope without making it readable yet. Globals are late
+    /// bound, so nothing is recorded for them.
+    fn declare(&mut self, name: &Name, kind: LocalKind) {
+        let Some((scope, enclosing)) = self.scopes.split_last_mut() else {
+            return;
+        };
+
+        if scope.iter().any(|l| l.name == name.text) {
+            self.error(
+                &name.text,
+                name.position,
+                "Already a variable with this name in this scope.",
+            );
+            return;
+        }
+
+        let shadowed = enclosing
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|l| l.name == name.text && l.kind != LocalKind::Implicit);
+
+        if let Some(shadowed) = shadowed {
+            self.resolution.warnings.push(ScopeWarning::Shadowed {
+                name: name.text.clone(),
+                position: name.position,
+                shadowed: shadowed.position,
+            });
+        }
+
+        scope.push(Local {
+            name: name.text.clone(),
+            position: name.position,
+            kind,
+            defined: false,
+            used: false,
+        });
+    }
+
+    /// Make the most recently declared local readable.
+    fn define(&mut self) {
+        if let Some(local) = self.scopes.last_mut().and_then(|scope| scope.last_mut()) {
+            local.defined = true;
+        }
+    }
+
+    fn define_implicit(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push(Local {
+                name: name.to_string(),
+                position: Position::default(),
+                kind: LocalKind::Implicit,
+                defined: true,
+                used: false,
+            });
+        }
+    }
+
+    /// Record the scope distance of a reference to `name`, returning whether it is a local.
+    fn resolve_local(&mut self, id: ExprId, name: &str, position: Position, read: bool) -> bool {
+        let found = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| {
+                let local = scope.iter_mut().rev().find(|l| l.name == name)?;
+                Some((depth, local))
+            });
+
+        let Some((depth, local)) = found else {
+            return false;
+        };
+
+        let uninitialized = read && !local.defined;
+        local.used |= read;
+        self.resolution.locals.insert(id, depth);
+
+        if uninitialized {
+            self.error(
+                name,
+                position,
+                "Can't read local variable in its own initializer.",
+            );
+        }
+
+        true
+    }
+
+    fn statements(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Var { name, initializer } => {
+                self.declare(name, LocalKind::Variable);
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.define();
+            }
+
+            Stmt::Function(function) => {
+                self.declare(&function.name, LocalKind::Variable);
+                self.define();
+                self.function(function);
+            }
+
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expression(expr),
+
+            Stmt::Return { position, value } => {
+                match self.function {
+                    None => self.error("return", *position, "Can't return from top-level code."),
+                    Some(FunctionKind::Initializer) if value.is_some() => self.error(
+                        "return",
+                        *position,
+                        "Can't return a value from an initializer.",
+                    ),
+                    Some(_) => {}
+                }
+
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+
+            Stmt::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.expression(condition);
+                self.statement(then);
+                if let Some(otherwise) = otherwise {
+                    self.statement(otherwise);
+                }
+            }
+
+            Stmt::While { condition, body } => {
+                self.expression(condition);
+                self.statement(body);
+            }
+
+            Stmt::Block(stmts) => {
+                self.begin_scope();
+                self.statements(stmts);
+                self.end_scope();
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        let enclosing = self.function.replace(function.kind);
+        self.begin_scope();
+
+        for param in &function.params {
+            self.declare(param, LocalKind::Parameter);
+            self.define();
+        }
+        self.statements(&function.body);
+
+        self.end_scope();
+        self.function = enclosing;
+    }
+
+    fn class(&mut self, name: &Name, superclass: Option<&Expr>, methods: &[Rc<Function>]) {
+        self.declare(name, LocalKind::Variable);
+        self.define();
+
+        let enclosing = self.class;
+        self.class = ClassKind::Class;
+
+        if let Some(superclass) = superclass {
+            if let ExprKind::Variable(super_name) = &superclass.kind
+                && super_name.text == name.text
+            {
+                self.error(
+                    &super_name.text,
+                    super_name.position,
+                    "A class can't inherit from itself.",
+                );
+            }
+
+            self.class = ClassKind::Subclass;
+            self.expression(superclass);
+
+            self.begin_scope();
+            self.define_implicit("super");
+        }
+
+        self.begin_scope();
+        self.define_implicit("this");
+        for method in methods {
+            self.function(method);
+        }
+        self.end_scope();
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+
+        self.class = enclosing;
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(_) => {}
+
+            ExprKind::Variable(name) => {
+                self.resolve_local(expr.id, &name.text, name.position, true);
+            }
+
+            ExprKind::Assign { name, value } => {
+                self.expression(value);
+
+                let local = self.resolve_local(expr.id, &name.text, name.position, false);
+                if !local && !self.globals.contains(&name.text) {
+                    self.resolution
+                        .warnings
+                        .push(ScopeWarning::UndeclaredGlobal {
+                            name: name.text.clone(),
+                            position: name.position,
+                        });
+                }
+            }
+
+            ExprKind::Unary { operand, .. } | ExprKind::Grouping(operand) => {
+                self.expression(operand);
+            }
+
+            ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+
+            ExprKind::Call { callee, args } => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+
+            ExprKind::Get { object, .. } => self.expression(object),
+
+            ExprKind::Set { object, value, .. } => {
+                self.expression(value);
+                self.expression(object);
+            }
+
+            ExprKind::This => {
+                if self.class == ClassKind::None {
+                    self.error(
+                        "this",
+                        expr.position,
+                        "Can't use 'this' outside of a class.",
+                    );
+                    return;
+                }
+
+                self.resolve_local(expr.id, "this", expr.position, true);
+            }
+
+            ExprKind::Super { .. } => match self.class {
+                ClassKind::None => {
+                    self.error(
+                        "super",
+                        expr.position,
+                        "Can't use 'super' outside of a class.",
+                    );
+                }
+                ClassKind::Class => self.error(
+                    "super",
+                    expr.position,
+                    "Can't use 'super' in a class with no superclass.",
+                ),
+                ClassKind::Subclass => {
+                    self.resolve_local(expr.id, "super", expr.position, true);
+                }
+            },
+        }
+    }
+}
@@ -4,11 +4,11 @@ use std::str::CharIndices;
 
 use thiserror::Error;
 
-use crate::chunk::LineNum;
+use crate::chunk::{ColumnNum, LineNum, Position};
 
-use super::token::{Token, TokenKind};
+use super::token::{LosslessToken, Token, TokenKind, Trivia, TriviaKind};
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum ScannerError {
     #[error("Invalid character '{c}'")]
     BadChar { line: LineNum, c: char },
@@ -49,6 +49,11 @@ static KEYWORDS: phf::Map<&'static str, TokenKind> = phf::phf_map! {
     "var" => TokenKind::Var,
 };
 
+/// Every reserved word of the language.
+pub fn keywords() -> impl Iterator<Item = &'static str> {
+    KEYWORDS.keys().copied()
+}
+
 #[derive(Debug)]
 pub struct Scanner<'a> {
     source: &'a str,
@@ -56,6 +61,11 @@ pub struct Scanner<'a> {
     start: usize,
     current: usize,
     line: LineNum,
+    // Columns of `start` and `current`, kept up to date as characters are consumed
+    start_column: ColumnNum,
+    current_column: ColumnNum,
+    // Whitespace and comments skipped before the current token, if they are being kept
+    trivia: Option<Vec<Trivia<'a>>>,
 }
 
 impl<'a> Scanner<'a> {
@@ -66,6 +76,21 @@ impl<'a> Scanner<'a> {
             start: 0,
             current: 0,
             line: 1,
+            start_column: 1,
+            current_column: 1,
+            trivia: None,
+        }
+    }
+
+    /// Scan in lossless mode, keeping whitespace and comments as trivia on the following token
+    /// and ending with an `Eof` token that carries any trailing trivia.
+    pub fn lossless(source: &'a str) -> Lossless<'a> {
+        let mut scanner = Self::new(source);
+        scanner.trivia = Some(Vec::new());
+
+        Lossless {
+            scanner,
+            done: false,
         }
     }
 
@@ -73,9 +98,21 @@ impl<'a> Scanner<'a> {
     fn advance(&mut self) -> Option<(usize, char)> {
         let (i, ch) = self.source_iter.next()?;
         self.current = i + ch.len_utf8();
+        self.current_column = if ch == '\n' {
+            1
+        } else {
+            self.current_column.saturating_add(1)
+        };
+
         Some((i, ch))
     }
 
+    /// Start the next lexeme where the previous one ended.
+    fn begin_lexeme(&mut self) {
+        self.start = self.current;
+        self.start_column = self.current_column;
+    }
+
     /// Consume characters in the source iterator until the predicate is false or the iterator is
     /// exhausted.
     fn consume_while(&mut self, predicate: impl Fn(char) -> bool) {
@@ -90,24 +127,54 @@ impl<'a> Scanner<'a> {
         self.source.get(self.start..self.current)
     }
 
+    /// 1-based column of the start of the current lexeme, counted in characters.
+    fn column(&self) -> ColumnNum {
+        self.start_column
+    }
+
+    /// Position of the start of the most recently scanned lexeme, or of the end of the source
+    /// once it is exhausted.
+    pub fn position(&self) -> Position {
+        Position::new(self.line, self.column())
+    }
+
     fn make_token(&self, kind: TokenKind<'a>) -> Option<Token<'a>> {
         Some(Token {
             kind,
             line: self.line,
+            column: self.column(),
             lexeme: self.make_lexeme()?,
         })
     }
 
     fn skip_whitespace(&mut self) {
-        while let Some((_, ch)) = self.source_iter.peek()
+        while let Some(&(_, ch)) = self.source_iter.peek()
             && ch.is_whitespace()
         {
-            // This additional logic prevents the usage of consume_while
-            if *ch == '\n' {
+            self.begin_lexeme();
+
+            // Newlines are consumed one at a time so lines can be counted
+            if ch == '\n' {
+                self.advance();
+                self.push_trivia(TriviaKind::Newline);
                 self.line += 1;
+            } else {
+                self.consume_while(|c| c.is_whitespace() && c != '\n');
+                self.push_trivia(TriviaKind::Whitespace);
             }
+        }
+    }
 
-            self.advance();
+    /// Record the text between `start` and `current` as trivia, if trivia is being kept.
+    fn push_trivia(&mut self, kind: TriviaKind) {
+        if let Some(trivia) = &mut self.trivia
+            && let Some(text) = self.source.get(self.start..self.current)
+        {
+            trivia.push(Trivia {
+                kind,
+                text,
+                line: self.line,
+            });
         }
     }
 
@@ -117,8 +184,7 @@ impl<'a> Scanner<'a> {
         yes: TokenKind<'a>,
         no: TokenKind<'a>,
     ) -> Option<Token<'a>> {
-        let (_, ch) = self.source_iter.peek()?;
-        if *ch == guess {
+        if self.source_iter.peek().is_some_and(|&(_, ch)| ch == guess) {
             self.advance();
             Some(self.make_token(yes)?)
         } else {
@@ -141,6 +207,11 @@ impl<'a> Scanner<'a> {
         let lexeme = self.make_lexeme()?;
         let s = Cow::Borrowed(lexeme);
         let token = self.make_token(TokenKind::String(s))?;
+
+        // Strings may span lines; the token itself is positioned at its opening quote
+        let newlines = lexeme.matches('\n').count();
+        self.line += LineNum::try_from(newlines).unwrap_or(LineNum::MAX);
+
         Some(Ok(token))
     }
 
@@ -185,7 +256,7 @@ impl<'a> Iterator for Scanner<'a> {
             use TokenKind as TK;
 
             self.skip_whitespace();
-            self.start = self.current;
+            self.begin_lexeme();
 
             let (_, ch) = self.advance()?;
 
@@ -202,11 +273,12 @@ impl<'a> Iterator for Scanner<'a> {
                 '*' => Ok(self.make_token(TK::Star)?),
 
                 '/' => {
-                    if self.source_iter.peek()?.1 == '/' {
+                    if self.source_iter.peek().is_some_and(|&(_, c)| c == '/') {
                         // '//' is a comment, so skip the rest of the line
                         // The newline itself will be handled on the next loop by skip_whitespace. It
                         // will also handle the increment of self.line
                         self.consume_while(|c| c != '\n');
+                        self.push_trivia(TriviaKind::Comment);
                         continue;
                     }
 
@@ -232,3 +304,127 @@ impl<'a> Iterator for Scanner<'a> {
         }
     }
 }
+
+/// Iterator returned by [`Scanner::lossless`].
+#[derive(Debug)]
+pub struct Lossless<'a> {
+    scanner: Scanner<'a>,
+    done: bool,
+}
+
+impl<'a> Iterator for Lossless<'a> {
+    type Item = LosslessToken<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = self.scanner.next();
+        let leading = self
+            .scanner
+            .trivia
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default();
+
+        let Some(token) = result else {
+            self.done = true;
+            self.scanner.begin_lexeme();
+
+            return Some(LosslessToken {
+                leading,
+                text: "",
+                token: Ok(self.scanner.make_token(TokenKind::Eof)?),
+            });
+        };
+
+        Some(LosslessToken {
+            leading,
+            text: self.scanner.make_lexeme()?,
+            token,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::difftest::scripts;
+
+    fn round_trip(source: &str) -> String {
+        let mut text = String::new();
+        for token in Scanner::lossless(source) {
+            for trivia in &token.leading {
+                text.push_str(trivia.text);
+            }
+            text.push_str(token.text);
+        }
+
+        text
+    }
+
+    fn positions(source: &str) -> Vec<(LineNum, ColumnNum)> {
+        Scanner::new(source)
+            .map(|token| {
+                let token = token.unwrap();
+                (token.line, token.column)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lossless_scanning_reproduces_the_test_programs() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+        for path in scripts(&[dir]).unwrap() {
+            let source = fs::read_to_string(&path).unwrap();
+            assert_eq!(round_trip(&source), source, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn lossless_scanning_reproduces_malformed_source() {
+        for source in [
+            "print \"unterminated",
+            "var s = \"spans\nlines",
+            "print 1 +",
+            "a = b ==",
+            "x /",
+            "print 1; // trailing comment",
+            "  \t\n\n",
+            "@ # $",
+            "print 1.2.3;",
+            "",
+        ] {
+            assert_eq!(round_trip(source), source, "{source:?}");
+        }
+    }
+
+    #[test]
+    fn counts_columns_in_characters() {
+        assert_eq!(
+            positions("var é = \"ü\";\n  print é;"),
+            [
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 9),
+                (1, 12),
+                (2, 3),
+                (2, 9),
+                (2, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_columns_after_strings_spanning_lines() {
+        assert_eq!(
+            positions("print \"a\nbc\" + 1;\nx"),
+            [(1, 1), (1, 7), (2, 5), (2, 7), (2, 8), (3, 1)]
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use std::{borrow::Cow, fmt::Display};
 
-use crate::chunk::LineNum;
+use crate::chunk::{ColumnNum, LineNum, Position};
+
+use super::scanner::ScannerResult;
 
 #[rustfmt::skip]
 #[derive(Debug, Clone)]
@@ -28,6 +30,9 @@ pub enum TokenKind<'a> {
     Class, Super, This, Fun, Return,
     Nil, Print, Var,
 
+    // End of input, only produced by the lossless scanner to carry trailing trivia
+    Eof,
+
     // Sentinel for uninitialized tokens
     Undefined,
 }
@@ -77,6 +82,8 @@ impl Display for TokenKind<'_> {
             TokenKind::Print => write!(f, "Print"),
             TokenKind::Var => write!(f, "Let"),
 
+            TokenKind::Eof => write!(f, "Eof"),
+
             TokenKind::Undefined => write!(f, "Undefined"),
         }
     }
@@ -87,6 +94,7 @@ pub struct Token<'a> {
     pub kind: TokenKind<'a>,
     pub lexeme: &'a str,
     pub line: LineNum,
+    pub column: ColumnNum,
 }
 
 impl Token<'_> {
@@ -95,12 +103,48 @@ impl Token<'_> {
             kind: TokenKind::Undefined,
             lexeme: "",
             line: 0,
+            column: 0,
         }
     }
+
+    pub fn position(&self) -> Position {
+        Position::new(self.line, self.column)
+    }
 }
 
 impl Display for Token<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {} '{}'", self.line, self.kind, self.lexeme)
+        write!(
+            f,
+            "{}:{} {} '{}'",
+            self.line, self.column, self.kind, self.lexeme
+        )
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    /// A run of whitespace other than newlines
+    Whitespace,
+    Newline,
+    /// A `//` comment, excluding the newline that ends it
+    Comment,
+}
+
+/// Source text with no meaning to the compiler, kept by the lossless scanner for tooling.
+#[derive(Debug, Clone)]
+pub struct Trivia<'a> {
+    pub kind: TriviaKind,
+    pub text: &'a str,
+    pub line: LineNum,
+}
+
+/// A token together with the trivia preceding it. `text` is the exact source slice of the token,
+/// which is also available when scanning it failed, so concatenating the trivia and text of
+/// every lossless token reproduces the source.
+#[derive(Debug, Clone)]
+pub struct LosslessToken<'a> {
+    pub leading: Vec<Trivia<'a>>,
+    pub text: &'a str,
+    pub token: ScannerResult<Token<'a>>,
+}
@@ -0,0 +1,291 @@
+//! An interactive, line-oriented debugger for the terminal.
+
+use std::io::{self, BufRead, Write};
+
+use super::{Resume, Session, Stop};
+use crate::InterpretResult;
+use crate::chunk::{Chunk, LineNum};
+
+const HELP: &str = "\
+Commands:
+  break [FILE:]LINE  (b)   Set a breakpoint on LINE
+  delete [LINE]      (d)   Delete the breakpoint on LINE, or all breakpoints
+  breakpoints              List breakpoints
+  continue           (c)   Run until a breakpoint or the end of the program
+  step               (s)   Run until the next source line, stepping into calls
+  next               (n)   Run until the next source line, stepping over calls
+  finish             (out) Run until the current frame returns
+  stepi              (si)  Execute a single instruction
+  stack                    Show the value stack
+  backtrace          (bt)  Show the call frames
+  locals                   Show the local variables of the current frame
+  globals                  Show the global variables
+  print EXPR         (p)   Evaluate EXPR in the current frame and print the result
+  list               (l)   Show source around the current line
+  restart                  Reload the program and pause at entry
+  quit               (q)   Exit the debugger";
+
+pub struct Debugger {
+    session: Session,
+    name: String,
+    source: Option<String>,
+}
+
+impl Debugger {
+    /// Create a debugger for `program`, paused before its first instruction. `source` is the
+    /// text the program was compiled from, if available, and is used for listings.
+    pub fn new(program: Chunk, name: String, source: Option<String>) -> InterpretResult<Self> {
+        Ok(Self {
+            session: Session::new(program)?,
+            name,
+            source,
+        })
+    }
+
+    /// Read commands from `input` until it is exhausted or the user quits.
+    pub fn run(&mut self, input: &mut impl BufRead) -> io::Result<()> {
+        println!(
+            "Debugging {}. Type 'help' for a list of commands.",
+            self.name
+        );
+        self.show_location();
+
+        let mut command = String::new();
+        loop {
+            print!("(rlox-dbg) ");
+            io::stdout().flush()?;
+
+            command.clear();
+            if input.read_line(&mut command)? == 0 {
+                return Ok(());
+            }
+
+            let command = command.trim();
+            let (name, arg) = command
+                .split_once(char::is_whitespace)
+                .map_or((command, ""), |(name, arg)| (name, arg.trim()));
+
+            match name {
+                "" => {}
+                "help" | "h" => println!("{HELP}"),
+                "quit" | "q" => return Ok(()),
+
+                "break" | "b" => self.set_breakpoint(arg),
+                "delete" | "d" => self.delete_breakpoint(arg),
+                "breakpoints" => self.list_breakpoints(),
+
+                "continue" | "c" => self.resume(Resume::Continue),
+                "step" | "s" => self.resume(Resume::Line),
+                "next" | "n" => self.resume(Resume::Over),
+                "stepi" | "si" => self.resume(Resume::Instruction),
+                "finish" | "out" => self.resume(Resume::Finish),
+
+                "stack" => {
+                    let values: Vec<_> = self
+                        .session
+                        .vm()
+                        .stack()
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect();
+                    println!("[{}]", values.join(", "));
+                }
+                "backtrace" | "bt" => self.backtrace(),
+                "locals" => self.locals(),
+                "globals" => self.globals(),
+                "print" | "p" => self.print(arg),
+                "list" | "l" => self.list(),
+
+                "restart" => {
+                    if let Err(e) = self.session.restart() {
+                        println!("{e}");
+                    } else {
+                        self.show_location();
+                    }
+                }
+
+                _ => println!("Unknown command '{name}'. Type 'help' for a list of commands."),
+            }
+        }
+    }
+
+    fn resume(&mut self, mode: Resume) {
+        match self.session.resume(mode) {
+            Stop::Step => {}
+            Stop::Breakpoint(line) => println!("Breakpoint at line {line}"),
+            Stop::Returned(value) => {
+                let value = value.map_or("nil".to_string(), |v| v.to_string());
+                println!("Program returned {value}");
+                return;
+            }
+            Stop::Error(e) => {
+                println!("{e}");
+                return;
+            }
+            Stop::NotRunning => {
+                println!("The program is not running. Use 'restart' to run it again.");
+                return;
+            }
+        }
+
+        self.show_location();
+    }
+
+    fn show_location(&self) {
+        let vm = self.session.vm();
+        let ip = vm.ip();
+        match vm.chunk().get_position(ip) {
+            Some(position) => print!("{}:{position} ", self.name),
+            None => print!("{}:? ", self.name),
+        }
+
+        match vm.chunk().describe_instruction(ip) {
+            Some(instruction) => println!("{ip:04} {instruction}"),
+            None => println!("{ip:04} <invalid>"),
+        }
+
+        if let Some(text) = self
+            .session
+            .current_line()
+            .and_then(|line| self.source_line(line))
+        {
+            println!("    {text}");
+        }
+    }
+
+    fn source_line(&self, line: LineNum) -> Option<&str> {
+        let index = usize::try_from(line).ok()?.checked_sub(1)?;
+        self.source.as_deref()?.lines().nth(index)
+    }
+
+    /// Parse a `[FILE:]LINE` location, checking that FILE names the program being debugged.
+    fn parse_location(&self, arg: &str) -> Option<LineNum> {
+        let line = match arg.rsplit_once(':') {
+            Some((file, line)) => {
+                if !self.name.ends_with(file) {
+                    println!("No such file '{file}' (debugging {})", self.name);
+                    return None;
+                }
+
+                line
+            }
+
+            None => arg,
+        };
+
+        let parsed = line.parse().ok();
+        if parsed.is_none() {
+            println!("Invalid line number '{line}'");
+        }
+
+        parsed
+    }
+
+    fn set_breakpoint(&mut self, arg: &str) {
+        let Some(line) = self.parse_location(arg) else {
+            return;
+        };
+
+        if !self.session.has_code(line) {
+            println!("Line {line} has no code; the breakpoint will never be hit");
+        }
+
+        self.session.breakpoints_mut().insert(line);
+        println!("Breakpoint set at {}:{line}", self.name);
+    }
+
+    fn delete_breakpoint(&mut self, arg: &str) {
+        if arg.is_empty() {
+            self.session.breakpoints_mut().clear();
+            println!("Deleted all breakpoints");
+            return;
+        }
+
+        if let Some(line) = self.parse_location(arg) {
+            if self.session.breakpoints_mut().remove(&line) {
+                println!("Deleted breakpoint at line {line}");
+            } else {
+                println!("No breakpoint at line {line}");
+            }
+        }
+    }
+
+    fn list_breakpoints(&self) {
+        let breakpoints = self.session.breakpoints();
+        if breakpoints.is_empty() {
+            println!("No breakpoints");
+        }
+
+        for line in breakpoints {
+            println!("{}:{line}", self.name);
+        }
+    }
+
+    fn backtrace(&self) {
+        let frames = self.session.frames();
+        if frames.is_empty() {
+            println!("The program is not running");
+        }
+
+        for (i, frame) in frames.iter().enumerate() {
+            let line = frame
+                .position()
+                .map_or("?".to_string(), |p| p.line.to_string());
+            println!("#{i} {} at {}:{line}", frame.function, self.name);
+        }
+    }
+
+    fn locals(&self) {
+        let frames = self.session.frames();
+        let Some(frame) = frames.first() else {
+            println!("The program is not running");
+            return;
+        };
+
+        if frame.locals.is_empty() {
+            println!("No locals");
+        }
+
+        for (name, value) in &frame.locals {
+            println!("{name} = {value}");
+        }
+    }
+
+    fn globals(&self) {
+        for (name, value) in self.session.vm().globals() {
+            println!("{name} = {value}");
+        }
+    }
+
+    fn print(&mut self, expression: &str) {
+        if expression.is_empty() {
+            println!("Usage: print EXPR");
+            return;
+        }
+
+        match self.session.evaluate(expression, 0) {
+            Ok(value) => println!("{value}"),
+            Err(e) => println!("{e}"),
+        }
+    }
+
+    fn list(&self) {
+        let Some(source) = &self.source else {
+            println!("No source available for {}", self.name);
+            return;
+        };
+
+        let current = self
+            .session
+            .current_line()
+            .and_then(|l| usize::try_from(l).ok())
+            .unwrap_or(1);
+        let first = current.saturating_sub(5).max(1);
+
+        for (i, text) in source.lines().enumerate().skip(first - 1).take(11) {
+            let number = i + 1;
+            let marker = if number == current { "->" } else { "  " };
+            println!("{marker} {number:4} {text}");
+        }
+    }
+}
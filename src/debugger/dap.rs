@@ -0,0 +1,397 @@
+//! A Debug Adapter Protocol server speaking over stdin and stdout.
+//!
+//! The program runs on a single thread. Each call frame has a scope of its local variables, and
+//! a second scope holds the globals.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{Value as Json, json};
+
+use super::{Resume, Session, Stop};
+use crate::chunk::LineNum;
+use crate::protocol::{read_message, write_message};
+use crate::value::Value;
+use crate::vm::Capture;
+
+const THREAD_ID: i64 = 1;
+/// Variables reference of the globals. Frame `i`, counted from the innermost, has its locals at
+/// `LOCALS_REFERENCE + i`.
+const GLOBALS_REFERENCE: i64 = 1;
+const LOCALS_REFERENCE: i64 = 2;
+
+struct Server<W: Write> {
+    out: W,
+    seq: i64,
+    session: Option<Session>,
+    /// The program's `print` output, forwarded as output events since stdout carries the protocol
+    output: Capture,
+    program: Option<PathBuf>,
+    /// Lines with breakpoints, which may be set before the program is launched
+    breakpoints: BTreeSet<LineNum>,
+    stop_on_entry: bool,
+}
+
+/// Serve DAP requests from `input`, writing responses and events to `out`, until the client
+/// disconnects or the input ends.
+pub fn serve(input: &mut impl BufRead, out: impl Write) -> io::Result<()> {
+    let mut server = Server {
+        out,
+        seq: 0,
+        session: None,
+        output: Capture::default(),
+        program: None,
+        breakpoints: BTreeSet::new(),
+        stop_on_entry: false,
+    };
+
+    while let Some(message) = read_message(input)? {
+        if message["type"] == "request" && !server.handle(&message)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+impl<W: Write> Server<W> {
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+
+        write_message(&mut self.out, &message)
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+        });
+        response["body"] = body;
+
+        self.send(response)
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        let mut message = json!({ "type": "event", "event": event });
+        message["body"] = body;
+
+        self.send(message)
+    }
+
+    /// Handle one request, returning whether to keep serving.
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let arguments = &request["arguments"];
+
+        match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                self.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsEvaluateForHovers": true,
+                        "supportsRestartRequest": true,
+                    }),
+                )?;
+                self.event("initialized", json!({}))?;
+            }
+
+            "launch" => self.launch(request)?,
+
+            "setBreakpoints" => self.set_breakpoints(request)?,
+
+            "configurationDone" => {
+                self.respond(request, json!({}))?;
+                if self.stop_on_entry {
+                    self.stopped("entry")?;
+                } else {
+                    self.resume(Resume::Continue)?;
+                }
+            }
+
+            "threads" => self.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            )?,
+
+            "stackTrace" => self.stack_trace(request)?,
+
+            "scopes" => {
+                let frame = arguments["frameId"].as_i64().unwrap_or_default();
+                self.respond(
+                    request,
+                    json!({
+                        "scopes": [
+                            {
+                                "name": "Locals",
+                                "variablesReference": LOCALS_REFERENCE + frame,
+                                "expensive": false,
+                            },
+                            {
+                                "name": "Globals",
+                                "variablesReference": GLOBALS_REFERENCE,
+                                "expensive": false,
+                            },
+                        ],
+                    }),
+                )?;
+            }
+
+            "variables" => self.variables(request)?,
+
+            "continue" => {
+                self.respond(request, json!({ "allThreadsContinued": true }))?;
+                self.resume(Resume::Continue)?;
+            }
+
+            "next" => {
+                self.respond(request, json!({}))?;
+                self.resume(Resume::Over)?;
+            }
+
+            "stepIn" => {
+                self.respond(request, json!({}))?;
+                self.resume(Resume::Line)?;
+            }
+
+            "stepOut" => {
+                self.respond(request, json!({}))?;
+                self.resume(Resume::Finish)?;
+            }
+
+            "restart" => match self.session.as_mut().map(Session::restart) {
+                Some(Ok(())) => {
+                    self.respond(request, json!({}))?;
+                    self.stopped("entry")?;
+                }
+                Some(Err(e)) => self.fail(request, &e.to_string())?,
+                None => self.fail(request, "No program has been launched")?,
+            },
+
+            "evaluate" => {
+                let expression = arguments["expression"].as_str().unwrap_or_default();
+                let frame = arguments["frameId"]
+                    .as_u64()
+                    .and_then(|f| usize::try_from(f).ok())
+                    .unwrap_or_default();
+                match self.session.as_mut().map(|s| s.evaluate(expression, frame)) {
+                    Some(Ok(value)) => self.respond(
+                        request,
+                        json!({ "result": value.to_string(), "variablesReference": 0 }),
+                    )?,
+                    Some(Err(e)) => self.fail(request, &e.to_string())?,
+                    None => self.fail(request, "No program has been launched")?,
+                }
+            }
+
+            "disconnect" | "terminate" => {
+                self.respond(request, json!({}))?;
+                return Ok(false);
+            }
+
+            command => self.fail(request, &format!("Unsupported request '{command}'"))?,
+        }
+
+        Ok(true)
+    }
+
+    fn launch(&mut self, request: &Json) -> io::Result<()> {
+        let arguments = &request["arguments"];
+        let Some(program) = arguments["program"].as_str() else {
+            return self.fail(request, "Missing 'program' launch argument");
+        };
+
+        let program = PathBuf::from(program);
+        let session = crate::load_chunk(&program).and_then(Session::new);
+
+        match session {
+            Ok(mut session) => {
+                session.set_output(Box::new(self.output.clone()));
+                session.breakpoints_mut().clone_from(&self.breakpoints);
+                self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                self.respond(request, json!({}))?;
+
+                // Breakpoints set before launching can only be checked now
+                let breakpoints: Vec<_> = self
+                    .breakpoints
+                    .iter()
+                    .map(|&line| breakpoint(&session, line))
+                    .collect();
+                self.session = Some(session);
+                self.program = Some(program);
+
+                for breakpoint in breakpoints {
+                    self.event(
+                        "breakpoint",
+                        json!({ "reason": "changed", "breakpoint": breakpoint }),
+                    )?;
+                }
+
+                Ok(())
+            }
+            Err(e) => self.fail(request, &e.to_string()),
+        }
+    }
+
+    fn set_breakpoints(&mut self, request: &Json) -> io::Result<()> {
+        self.breakpoints = request["arguments"]["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|b| b["line"].as_u64())
+            .filter_map(|line| line.try_into().ok())
+            .collect();
+
+        let results: Vec<_> = match &mut self.session {
+            Some(session) => {
+                session.breakpoints_mut().clone_from(&self.breakpoints);
+                self.breakpoints
+                    .iter()
+                    .map(|&line| breakpoint(session, line))
+                    .collect()
+            }
+            None => self
+                .breakpoints
+                .iter()
+                .map(|&line| json!({ "id": line, "verified": false, "line": line }))
+                .collect(),
+        };
+
+        self.respond(request, json!({ "breakpoints": results }))
+    }
+
+    fn stack_trace(&mut self, request: &Json) -> io::Result<()> {
+        let Some(session) = &self.session else {
+            return self.fail(request, "No program has been launched");
+        };
+
+        let source = self.program.as_deref().map(source);
+        let frames: Vec<_> = session
+            .frames()
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                let position = frame.position().unwrap_or_default();
+                json!({
+                    "id": id,
+                    "name": frame.function.to_string(),
+                    "line": position.line,
+                    "column": position.column,
+                    "source": source,
+                })
+            })
+            .collect();
+
+        let total = frames.len();
+        self.respond(
+            request,
+            json!({ "stackFrames": frames, "totalFrames": total }),
+        )
+    }
+
+    fn variables(&mut self, request: &Json) -> io::Result<()> {
+        let reference = request["arguments"]["variablesReference"].as_i64();
+        let variables: Vec<_> = match (&self.session, reference) {
+            (Some(session), Some(GLOBALS_REFERENCE)) => session
+                .vm()
+                .globals()
+                .iter()
+                .map(|(name, value)| variable(name, value))
+                .collect(),
+
+            (Some(session), Some(reference)) => {
+                let frame = usize::try_from(reference - LOCALS_REFERENCE).ok();
+                session
+                    .frames()
+                    .get(frame.unwrap_or(usize::MAX))
+                    .map(|frame| {
+                        frame
+                            .locals
+                            .iter()
+                            .map(|(name, value)| variable(name, value))
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            }
+
+            _ => Vec::new(),
+        };
+
+        self.respond(request, json!({ "variables": variables }))
+    }
+
+    fn resume(&mut self, mode: Resume) -> io::Result<()> {
+        let Some(session) = &mut self.session else {
+            return Ok(());
+        };
+
+        let stop = session.resume(mode);
+        let printed = self.output.take();
+        if !printed.is_empty() {
+            self.output("stdout", &printed)?;
+        }
+
+        match stop {
+            Stop::Step => self.stopped("step"),
+            Stop::Breakpoint(_) => self.stopped("breakpoint"),
+            Stop::Returned(value) => {
+                let value = value.map_or("nil".to_string(), |v| v.to_string());
+                self.output("console", &format!("Program returned {value}\n"))?;
+                self.finished(0)
+            }
+            Stop::Error(e) => {
+                self.output("stderr", &format!("{e}\n"))?;
+                self.finished(70)
+            }
+            Stop::NotRunning => Ok(()),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        )
+    }
+
+    fn output(&mut self, category: &str, text: &str) -> io::Result<()> {
+        self.event("output", json!({ "category": category, "output": text }))
+    }
+
+    fn finished(&mut self, exit_code: i32) -> io::Result<()> {
+        self.event("exited", json!({ "exitCode": exit_code }))?;
+        self.event("terminated", json!({}))
+    }
+}
+
+fn breakpoint(session: &Session, line: LineNum) -> Json {
+    json!({ "id": line, "verified": session.has_code(line), "line": line })
+}
+
+fn variable(name: &str, value: &Value) -> Json {
+    json!({ "name": name, "value": value.to_string(), "variablesReference": 0 })
+}
+
+fn source(path: &Path) -> Json {
+    json!({
+        "name": path.file_name().map(|n| n.to_string_lossy()),
+        "path": path.display().to_string(),
+    })
+}
@@ -0,0 +1,311 @@
+//! Debugging support: a stepping session over the VM, driven either interactively from the
+//! terminal or by an editor over the Debug Adapter Protocol.
+
+mod cli;
+mod dap;
+
+use std::collections::BTreeSet;
+use std::io::Write;
+
+pub use cli::Debugger;
+pub use dap::serve as serve_dap;
+
+use crate::chunk::{Chunk, LineNum};
+use crate::value::Value;
+use crate::vm::{FrameInfo, Step, VM};
+use crate::{InterpretError, InterpretResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Run until a breakpoint or the end of the program
+    Continue,
+    /// Run until execution reaches a new source line, stepping into calls
+    Line,
+    /// Run until execution reaches a new source line in the current frame or returns from it,
+    /// stepping over calls
+    Over,
+    /// Execute a single instruction
+    Instruction,
+    /// Run until the current frame returns, ignoring breakpoints
+    Finish,
+}
+
+/// Why a resumed session stopped.
+#[derive(Debug)]
+pub enum Stop {
+    Step,
+    Breakpoint(LineNum),
+    Returned(Option<Value>),
+    Error(InterpretError),
+    /// The program already finished, so there was nothing to resume
+    NotRunning,
+}
+
+/// A program loaded into a VM that can be paused, stepped and inspected.
+pub struct Session {
+    vm: VM,
+    program: Chunk,
+    breakpoints: BTreeSet<LineNum>,
+    // Line each active frame is executing, outermost first, used to detect when execution
+    // reaches a new line
+    lines: Vec<Option<LineNum>>,
+    finished: bool,
+}
+
+impl Session {
+    /// Load `program`, paused before its first instruction.
+    pub fn new(program: Chunk) -> InterpretResult<Self> {
+        let mut session = Self {
+            vm: VM::new(),
+            program,
+            breakpoints: BTreeSet::new(),
+            lines: Vec::new(),
+            finished: false,
+        };
+
+        session.restart()?;
+        Ok(session)
+    }
+
+    /// Reload the program and pause before its first instruction.
+    pub fn restart(&mut self) -> InterpretResult<()> {
+        self.vm.load(self.program.clone())?;
+        self.lines = vec![self.current_line()];
+        self.finished = false;
+
+        Ok(())
+    }
+
+    /// Send the program's `print` output to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.vm.set_output(output);
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn current_line(&self) -> Option<LineNum> {
+        self.vm.chunk().get_line(self.vm.ip())
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<LineNum> {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut BTreeSet<LineNum> {
+        &mut self.breakpoints
+    }
+
+    /// Whether any instruction in the program or the functions it declares comes from `line`.
+    pub fn has_code(&self, line: LineNum) -> bool {
+        self.program.has_line(line)
+    }
+
+    /// The active calls, innermost first, or none once the program has finished.
+    pub fn frames(&self) -> Vec<FrameInfo<'_>> {
+        let mut frames = self.vm.frames();
+        frames.reverse();
+        frames
+    }
+
+    /// Evaluate `expression` where frame `frame`, counted from the innermost, is paused, with its
+    /// locals and the globals in scope. Once the program has finished only globals are visible.
+    pub fn evaluate(&mut self, expression: &str, frame: usize) -> InterpretResult<Value> {
+        let frame = self.vm.depth().checked_sub(frame + 1);
+        self.vm.evaluate(expression, frame)
+    }
+
+    pub fn resume(&mut self, mode: Resume) -> Stop {
+        if self.finished {
+            return Stop::NotRunning;
+        }
+
+        // `Over` and `Finish` are relative to the frame executing now
+        let depth = self.vm.depth();
+
+        loop {
+            let step = match self.vm.step() {
+                Ok(step) => step,
+                Err(e) => {
+                    self.finished = true;
+                    return Stop::Error(self.vm.locate(e));
+                }
+            };
+
+            if let Step::Returned(value) = step {
+                self.finished = true;
+                return Stop::Returned(value);
+            }
+
+            // Whether execution reached a line anew, rather than returning to one mid-way
+            let line = self.current_line();
+            let now = self.vm.depth();
+            let returned = now < self.lines.len();
+            self.lines.truncate(now);
+            let arrived = if self.lines.len() == now
+                && let Some(last) = self.lines.last_mut()
+            {
+                std::mem::replace(last, line) != line
+            } else {
+                self.lines.push(line);
+                true
+            };
+
+            let pause = match mode {
+                Resume::Continue => false,
+                Resume::Line => arrived || returned,
+                Resume::Over => now < depth || (now == depth && arrived),
+                Resume::Instruction => true,
+                Resume::Finish => now < depth,
+            };
+            if pause {
+                return Stop::Step;
+            }
+
+            if arrived
+                && mode != Resume::Finish
+                && let Some(line) = line
+                && self.breakpoints.contains(&line)
+            {
+                return Stop::Breakpoint(line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::vm::Capture;
+
+    const PROGRAM: &str = "\
+var total = 0;
+fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+{
+  var x = 1;
+  total = add(x, 2);
+}
+print total;
+";
+
+    fn session() -> (Session, Capture) {
+        let output = Capture::default();
+        let mut session = Session::new(compile(PROGRAM).unwrap()).unwrap();
+        session.set_output(Box::new(output.clone()));
+        (session, output)
+    }
+
+    fn run_to(session: &mut Session, line: LineNum) {
+        session.breakpoints_mut().insert(line);
+        assert!(matches!(session.resume(Resume::Continue), Stop::Breakpoint(l) if l == line));
+        session.breakpoints_mut().remove(&line);
+    }
+
+    fn backtrace(session: &Session) -> Vec<(String, LineNum)> {
+        session
+            .frames()
+            .iter()
+            .map(|f| (f.function.to_string(), f.position().unwrap().line))
+            .collect()
+    }
+
+    fn locals(session: &Session) -> Vec<String> {
+        session.frames()[0]
+            .locals
+            .iter()
+            .map(|(name, value)| format!("{name} = {value}"))
+            .collect()
+    }
+
+    #[test]
+    fn walks_the_call_frames() {
+        let (mut session, _) = session();
+        run_to(&mut session, 3);
+
+        assert_eq!(
+            backtrace(&session),
+            [("<fn add>".to_string(), 3), ("<script>".to_string(), 8)]
+        );
+        assert_eq!(locals(&session), ["a = 1", "b = 2"]);
+    }
+
+    #[test]
+    fn evaluates_in_the_paused_frame() {
+        let (mut session, _) = session();
+        run_to(&mut session, 3);
+
+        assert_eq!(session.evaluate("a + b", 0).unwrap().to_string(), "3");
+        assert_eq!(session.evaluate("total", 0).unwrap().to_string(), "0");
+        assert_eq!(session.evaluate("x", 1).unwrap().to_string(), "1");
+        // `sum` is not initialized yet, and `a` is not in scope in the caller
+        assert!(session.evaluate("sum", 0).is_err());
+        assert!(session.evaluate("a", 1).is_err());
+    }
+
+    #[test]
+    fn assignments_change_the_paused_program() {
+        let (mut session, output) = session();
+        run_to(&mut session, 3);
+
+        session.evaluate("a = 10", 0).unwrap();
+        assert_eq!(locals(&session), ["a = 10", "b = 2"]);
+
+        // Evaluating captures `x`, which its block then pops without closing
+        assert_eq!(session.evaluate("x", 1).unwrap().to_string(), "1");
+
+        run_to(&mut session, 10);
+        session.evaluate("total = total * 2", 0).unwrap();
+        assert!(matches!(
+            session.resume(Resume::Continue),
+            Stop::Returned(_)
+        ));
+        assert_eq!(output.contents(), "24\n");
+    }
+
+    #[test]
+    fn leaves_the_program_intact_after_an_evaluation_error() {
+        let (mut session, output) = session();
+        run_to(&mut session, 3);
+
+        assert!(session.evaluate("a + nil", 0).is_err());
+        assert_eq!(backtrace(&session).len(), 2);
+        assert!(matches!(
+            session.resume(Resume::Continue),
+            Stop::Returned(_)
+        ));
+        assert_eq!(output.contents(), "3\n");
+    }
+
+    #[test]
+    fn steps_into_calls() {
+        let (mut session, _) = session();
+        run_to(&mut session, 8);
+
+        session.resume(Resume::Line);
+        assert_eq!(backtrace(&session)[0], ("<fn add>".to_string(), 3));
+    }
+
+    #[test]
+    fn steps_over_calls() {
+        let (mut session, _) = session();
+        run_to(&mut session, 8);
+
+        session.resume(Resume::Over);
+        assert_eq!(backtrace(&session), [("<script>".to_string(), 10)]);
+    }
+
+    #[test]
+    fn steps_out_of_calls() {
+        let (mut session, _) = session();
+        run_to(&mut session, 3);
+
+        session.resume(Resume::Finish);
+        assert_eq!(backtrace(&session), [("<script>".to_string(), 8)]);
+        assert_eq!(locals(&session), ["x = 1"]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Differential testing: run a script through both the tree-walking interpreter and the bytecode
+//! VMs and report any difference in what they print or how they fail. The stack VM runs each
+//! script twice, with and without the peephole optimizer, and the register VM runs it once.
+
+use std::fmt::Write as _;
+use std::fs::read_dir;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::compiler::compile;
+use crate::optimizer;
+use crate::register::RegisterVM;
+use crate::tree_walker::Interpreter;
+use crate::vm::{Capture, VM};
+use crate::{InterpretError, InterpretResult};
+
+/// What running a script produced.
+#[derive(Debug)]
+pub struct Outcome {
+    pub output: String,
+    pub error: Option<String>,
+}
+
+/// Run `source` in the VM, optionally passing the compiled chunk through the optimizer first.
+pub fn run_vm(source: &str, optimize: bool) -> Outcome {
+    let capture = Capture::default();
+    let mut vm = VM::new();
+    vm.set_output(Box::new(capture.clone()));
+
+    let result = compile(source).and_then(|chunk| {
+        if optimize {
+            vm.interpret_chunk(optimizer::optimize(&chunk))
+        } else {
+            vm.interpret_chunk(chunk)
+        }
+    });
+    outcome(&capture, result)
+}
+
+pub fn run_register_vm(source: &str) -> Outcome {
+    let capture = Capture::default();
+    let mut vm = RegisterVM::new();
+    vm.set_output(Box::new(capture.clone()));
+
+    let result = vm.interpret(source);
+    outcome(&capture, result)
+}
+
+pub fn run_tree_walker(source: &str) -> Outcome {
+    let capture = Capture::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_output(Box::new(capture.clone()));
+
+    let result = interpreter.interpret(source);
+    outcome(&capture, result)
+}
+
+fn outcome<T>(capture: &Capture, result: InterpretResult<T>) -> Outcome {
+    Outcome {
+        output: capture.contents(),
+        error: result.err().as_ref().map(describe_error),
+    }
+}
+
+/// An error as compared between interpreters. Runtime errors are compared by line rather than
+/// exact position, since the two attribute some failures to different tokens of an expression.
+fn describe_error(error: &InterpretError) -> String {
+    match error {
+        InterpretError::Runtime { error, position } => {
+            let line = position.map_or("?".to_string(), |p| p.line.to_string());
+            format!("[line {line}] Runtime error: {error}")
+        }
+        _ => error.to_string(),
+    }
+}
+
+/// Run `source` through the tree-walker, through the stack VM with and without optimization and
+/// through the register VM, returning a description of how the VM runs differ from the
+/// tree-walker, if they do.
+pub fn compare(source: &str) -> Option<String> {
+    let expected = run_tree_walker(source);
+    let mut res = String::new();
+
+    let runs = [
+        ("vm", run_vm(source, false)),
+        ("vm -O", run_vm(source, true)),
+        ("register", run_register_vm(source)),
+    ];
+
+    for (name, actual) in runs {
+        let label = format!("{name}:");
+
+        // `write!`ing into a String is infallible
+        if expected.output != actual.output {
+            let expected_lines: Vec<_> = expected.output.lines().collect();
+            let actual_lines: Vec<_> = actual.output.lines().collect();
+            let first = (0..expected_lines.len().max(actual_lines.len()))
+                .find(|&i| expected_lines.get(i) != actual_lines.get(i))
+                .expect("The outputs differ");
+
+            writeln!(res, "  {name} output differs at line {}:", first + 1).unwrap();
+            writeln!(res, "    tree-walker: {}", show(expected_lines.get(first))).unwrap();
+            writeln!(res, "    {label:<12} {}", show(actual_lines.get(first))).unwrap();
+        }
+
+        if expected.error != actual.error {
+            writeln!(res, "  {name} error differs:").unwrap();
+            writeln!(res, "    tree-walker: {}", show(expected.error.as_ref())).unwrap();
+            writeln!(res, "    {label:<12} {}", show(actual.error.as_ref())).unwrap();
+        }
+    }
+
+    (!res.is_empty()).then_some(res)
+}
+
+fn show(line: Option<&impl AsRef<str>>) -> String {
+    line.map_or("<nothing>".to_string(), |l| l.as_ref().to_string())
+}
+
+/// The scripts named by `inputs`, with directories searched recursively for `.lox` files.
+pub fn scripts(inputs: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut res = Vec::new();
+    for input in inputs {
+        collect(input, &mut res)?;
+    }
+
+    Ok(res)
+}
+
+fn collect(path: &Path, res: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        res.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries: Vec<_> = read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|e| e == "lox") {
+            collect(&entry, res)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,874 @@
+//! Concrete syntax tree for the formatter. Unlike a compiler AST it keeps every token, and every
+//! token keeps the comments that preceded it, so nothing in the source is lost when printing.
+//!
+//! The grammar here duplicates the compiler's parser, and the tests below check that the two
+//! accept and reject the same programs. Syntax errors are reported by the compiler's parser,
+//! which runs first, so `rlox fmt` and `rlox run` describe a broken file the same way. The plan is to share one parser instead: the compiler's
+//! parser would read [`Scanner::lossless`] tokens and report each production it recognises to a
+//! builder, so the compiler builds its AST and the formatter this tree with the same grammar and
+//! error recovery. Only the tree types and a builder would then remain in this module.
+
+use thiserror::Error;
+
+use crate::chunk::Position;
+use crate::compiler::scanner::{Scanner, ScannerError};
+use crate::compiler::token::{LosslessToken, TokenKind, TriviaKind};
+use crate::compiler::{CompileError, MAX_ARGS};
+
+/// Why this parser rejected a program. It only parses programs the compiler's parser accepted,
+/// so these are never reported unless the two grammars disagree.
+#[derive(Debug, Error)]
+pub(crate) enum SyntaxError {
+    #[error("{0}")]
+    Scanner(#[from] ScannerError),
+    #[error("Expect {expected}.")]
+    Expected {
+        position: Position,
+        expected: &'static str,
+        found: String,
+    },
+    #[error("Can't have more than {MAX_ARGS} {what}.")]
+    TooMany {
+        position: Position,
+        what: &'static str,
+        found: String,
+    },
+}
+
+impl From<SyntaxError> for CompileError {
+    fn from(error: SyntaxError) -> Self {
+        let (position, found) = match &error {
+            SyntaxError::Scanner(error) => (Position::new(error.line(), 1), None),
+            SyntaxError::Expected {
+                position, found, ..
+            }
+            | SyntaxError::TooMany {
+                position, found, ..
+            } => (*position, Some(found.as_str())),
+        };
+
+        // Only the end of the input has no text
+        let location = match found {
+            Some("") => " at end".to_string(),
+            Some(found) => format!(" at '{found}'"),
+            None => String::new(),
+        };
+
+        CompileError {
+            position,
+            location,
+            message: error.to_string(),
+            end: found.map(|f| position.after(f)),
+        }
+    }
+}
+
+/// A comment on a line of its own.
+#[derive(Debug, Clone)]
+pub struct Comment<'a> {
+    pub text: &'a str,
+    /// Whether a blank line separates this comment from what precedes it
+    pub blank_before: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tok<'a> {
+    pub kind: TokenKind<'a>,
+    pub text: &'a str,
+    pub position: Position,
+    /// A comment at the end of the line holding the preceding token
+    pub trailing_comment: Option<&'a str>,
+    /// Comments on their own lines between the preceding token and this one
+    pub comments: Vec<Comment<'a>>,
+    /// Whether a blank line separates this token from the preceding token or comment
+    pub blank_before: bool,
+}
+
+impl<'a> Tok<'a> {
+    fn new(token: LosslessToken<'a>, first: bool) -> Result<Self, SyntaxError> {
+        let scanned = token.token?;
+
+        let mut trailing_comment = None;
+        let mut comments = Vec::new();
+        let mut newlines = 0;
+        let mut seen_comment = false;
+
+        for trivia in &token.leading {
+            match trivia.kind {
+                TriviaKind::Whitespace => {}
+                TriviaKind::Newline => newlines += 1,
+                TriviaKind::Comment => {
+                    let text = trivia.text.trim_end();
+                    if newlines == 0 && !seen_comment && !first {
+                        trailing_comment = Some(text);
+                    } else {
+                        comments.push(Comment {
+                            text,
+                            blank_before: newlines >= 2,
+                        });
+                    }
+
+                    seen_comment = true;
+                    newlines = 0;
+                }
+            }
+        }
+
+        Ok(Self {
+            position: scanned.position(),
+            kind: scanned.kind,
+            text: token.text,
+            trailing_comment,
+            comments,
+            blank_before: newlines >= 2,
+        })
+    }
+
+    /// Whether the source had a blank line before this token or its first own-line comment.
+    pub fn starts_paragraph(&self) -> bool {
+        self.comments
+            .first()
+            .map_or(self.blank_before, |c| c.blank_before)
+    }
+
+    pub fn has_comments(&self) -> bool {
+        self.trailing_comment.is_some() || !self.comments.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct Program<'a> {
+    pub decls: Vec<Decl<'a>>,
+    /// Carries the comments at the end of the file
+    pub eof: Tok<'a>,
+}
+
+// The tree only lives while one file is printed, so boxing variants would buy nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Decl<'a> {
+    Class {
+        class: Tok<'a>,
+        name: Tok<'a>,
+        superclass: Option<(Tok<'a>, Tok<'a>)>,
+        lbrace: Tok<'a>,
+        methods: Vec<Function<'a>>,
+        rbrace: Tok<'a>,
+    },
+    Fun {
+        fun: Tok<'a>,
+        function: Function<'a>,
+    },
+    Var {
+        var: Tok<'a>,
+        name: Tok<'a>,
+        initializer: Option<(Tok<'a>, Expr<'a>)>,
+        semicolon: Tok<'a>,
+    },
+    Stmt(Stmt<'a>),
+}
+
+#[derive(Debug)]
+pub struct Function<'a> {
+    pub name: Tok<'a>,
+    pub lparen: Tok<'a>,
+    /// Each parameter with the comma following it, if any
+    pub params: Vec<(Tok<'a>, Option<Tok<'a>>)>,
+    pub rparen: Tok<'a>,
+    pub body: Block<'a>,
+}
+
+#[derive(Debug)]
+pub struct Block<'a> {
+    pub lbrace: Tok<'a>,
+    pub decls: Vec<Decl<'a>>,
+    pub rbrace: Tok<'a>,
+}
+
+#[derive(Debug)]
+pub enum ForInit<'a> {
+    Empty(Tok<'a>),
+    Decl(Box<Decl<'a>>),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Stmt<'a> {
+    Expr {
+        expr: Expr<'a>,
+        semicolon: Tok<'a>,
+    },
+    Print {
+        print: Tok<'a>,
+        expr: Expr<'a>,
+        semicolon: Tok<'a>,
+    },
+    Return {
+        ret: Tok<'a>,
+        value: Option<Expr<'a>>,
+        semicolon: Tok<'a>,
+    },
+    If {
+        if_: Tok<'a>,
+        lparen: Tok<'a>,
+        condition: Expr<'a>,
+        rparen: Tok<'a>,
+        then: Box<Stmt<'a>>,
+        else_: Option<(Tok<'a>, Box<Stmt<'a>>)>,
+    },
+    While {
+        while_: Tok<'a>,
+        lparen: Tok<'a>,
+        condition: Expr<'a>,
+        rparen: Tok<'a>,
+        body: Box<Stmt<'a>>,
+    },
+    For {
+        for_: Tok<'a>,
+        lparen: Tok<'a>,
+        init: ForInit<'a>,
+        condition: Option<Expr<'a>>,
+        semicolon: Tok<'a>,
+        increment: Option<Expr<'a>>,
+        rparen: Tok<'a>,
+        body: Box<Stmt<'a>>,
+    },
+    Block(Block<'a>),
+}
+
+#[derive(Debug)]
+pub enum Expr<'a> {
+    /// A literal, identifier or `this`
+    Atom(Tok<'a>),
+    Super {
+        super_: Tok<'a>,
+        dot: Tok<'a>,
+        name: Tok<'a>,
+    },
+    Grouping {
+        lparen: Tok<'a>,
+        expr: Box<Expr<'a>>,
+        rparen: Tok<'a>,
+    },
+    Unary {
+        op: Tok<'a>,
+        operand: Box<Expr<'a>>,
+    },
+    /// Arithmetic, comparison and logical operators
+    Binary {
+        left: Box<Expr<'a>>,
+        op: Tok<'a>,
+        right: Box<Expr<'a>>,
+    },
+    Assign {
+        target: Box<Expr<'a>>,
+        eq: Tok<'a>,
+        value: Box<Expr<'a>>,
+    },
+    Call {
+        callee: Box<Expr<'a>>,
+        lparen: Tok<'a>,
+        /// Each argument with the comma following it, if any
+        args: Vec<(Expr<'a>, Option<Tok<'a>>)>,
+        rparen: Tok<'a>,
+    },
+    Get {
+        object: Box<Expr<'a>>,
+        dot: Tok<'a>,
+        name: Tok<'a>,
+    },
+}
+
+impl<'a> Decl<'a> {
+    pub fn first_token_mut(&mut self) -> &mut Tok<'a> {
+        match self {
+            Decl::Class { class, .. } => class,
+            Decl::Fun { fun, .. } => fun,
+            Decl::Var { var, .. } => var,
+            Decl::Stmt(stmt) => stmt.first_token_mut(),
+        }
+    }
+}
+
+impl<'a> Stmt<'a> {
+    pub fn first_token_mut(&mut self) -> &mut Tok<'a> {
+        match self {
+            Stmt::Expr { expr, .. } => expr.first_token_mut(),
+            Stmt::Print { print, .. } => print,
+            Stmt::Return { ret, .. } => ret,
+            Stmt::If { if_, .. } => if_,
+            Stmt::While { while_, .. } => while_,
+            Stmt::For { for_, .. } => for_,
+            Stmt::Block(block) => &mut block.lbrace,
+        }
+    }
+}
+
+impl<'a> Expr<'a> {
+    pub fn first_token_mut(&mut self) -> &mut Tok<'a> {
+        match self {
+            Expr::Atom(tok) => tok,
+            Expr::Super { super_, .. } => super_,
+            Expr::Grouping { lparen, .. } => lparen,
+            Expr::Unary { op, .. } => op,
+            Expr::Binary { left, .. } => left.first_token_mut(),
+            Expr::Assign { target, .. } => target.first_token_mut(),
+            Expr::Call { callee, .. } => callee.first_token_mut(),
+            Expr::Get { object, .. } => object.first_token_mut(),
+        }
+    }
+}
+
+type ParseResult<T> = Result<T, SyntaxError>;
+
+/// Parse a whole program. Any scanner or syntax error fails the parse, since a file that does
+/// not parse cannot be formatted safely.
+pub fn parse(source: &str) -> ParseResult<Program<'_>> {
+    let tokens = Scanner::lossless(source)
+        .enumerate()
+        .map(|(i, token)| Tok::new(token, i == 0))
+        .collect::<ParseResult<Vec<_>>>()?;
+
+    let mut parser = Parser {
+        tokens: tokens.into_iter().peekable(),
+    };
+
+    let mut decls = Vec::new();
+    while !parser.check(|k| matches!(k, TokenKind::Eof)) {
+        decls.push(parser.declaration()?);
+    }
+
+    let eof = parser.advance()?;
+    Ok(Program { decls, eof })
+}
+
+struct Parser<'a> {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Tok<'a>>>,
+}
+
+impl<'a> Parser<'a> {
+    fn check(&mut self, predicate: impl Fn(&TokenKind) -> bool) -> bool {
+        self.tokens.peek().is_some_and(|t| predicate(&t.kind))
+    }
+
+    fn advance(&mut self) -> ParseResult<Tok<'a>> {
+        // The lossless scanner always ends with `Eof`, and nothing consumes past it
+        self.tokens.next().ok_or(SyntaxError::Expected {
+            position: Position::default(),
+            expected: "more input",
+            found: String::new(),
+        })
+    }
+
+    fn consume(
+        &mut self,
+        predicate: impl Fn(&TokenKind) -> bool,
+        expected: &'static str,
+    ) -> ParseResult<Tok<'a>> {
+        if self.check(predicate) {
+            return self.advance();
+        }
+
+        let token = self.advance()?;
+        Err(SyntaxError::Expected {
+            position: token.position,
+            expected,
+            found: token.text.to_string(),
+        })
+    }
+
+    fn take(&mut self, predicate: impl Fn(&TokenKind) -> bool) -> ParseResult<Option<Tok<'a>>> {
+        if self.check(predicate) {
+            Ok(Some(self.advance()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn identifier(&mut self, expected: &'static str) -> ParseResult<Tok<'a>> {
+        self.consume(|k| matches!(k, TokenKind::Identifier(_)), expected)
+    }
+
+    fn declaration(&mut self) -> ParseResult<Decl<'a>> {
+        if let Some(class) = self.take(|k| matches!(k, TokenKind::Class))? {
+            let name = self.identifier("class name")?;
+            let superclass = match self.take(|k| matches!(k, TokenKind::Lt))? {
+                Some(lt) => Some((lt, self.identifier("superclass name")?)),
+                None => None,
+            };
+
+            let lbrace = self.consume(|k| matches!(k, TokenKind::LCurly), "'{'")?;
+            let mut methods = Vec::new();
+            while !self.check(|k| matches!(k, TokenKind::RCurly | TokenKind::Eof)) {
+                methods.push(self.function()?);
+            }
+
+            let rbrace = self.consume(|k| matches!(k, TokenKind::RCurly), "'}'")?;
+            return Ok(Decl::Class {
+                class,
+                name,
+                superclass,
+                lbrace,
+                methods,
+                rbrace,
+            });
+        }
+
+        if let Some(fun) = self.take(|k| matches!(k, TokenKind::Fun))? {
+            let function = self.function()?;
+            return Ok(Decl::Fun { fun, function });
+        }
+
+        if let Some(var) = self.take(|k| matches!(k, TokenKind::Var))? {
+            return self.var_declaration(var);
+        }
+
+        Ok(Decl::Stmt(self.statement()?))
+    }
+
+    fn var_declaration(&mut self, var: Tok<'a>) -> ParseResult<Decl<'a>> {
+        let name = self.identifier("variable name")?;
+        let initializer = match self.take(|k| matches!(k, TokenKind::Eq))? {
+            Some(eq) => Some((eq, self.expression()?)),
+            None => None,
+        };
+
+        let semicolon = self.semicolon()?;
+        Ok(Decl::Var {
+            var,
+            name,
+            initializer,
+            semicolon,
+        })
+    }
+
+    fn function(&mut self) -> ParseResult<Function<'a>> {
+        let name = self.identifier("function name")?;
+        let lparen = self.consume(|k| matches!(k, TokenKind::LParen), "'('")?;
+
+        let mut params = Vec::new();
+        if !self.check(|k| matches!(k, TokenKind::RParen)) {
+            loop {
+                let param = self.identifier("parameter name")?;
+                if params.len() == MAX_ARGS {
+                    return Err(SyntaxError::TooMany {
+                        position: param.position,
+                        what: "parameters",
+                        found: param.text.to_string(),
+                    });
+                }
+
+                let comma = self.take(|k| matches!(k, TokenKind::Comma))?;
+                let done = comma.is_none();
+                params.push((param, comma));
+                if done {
+                    break;
+                }
+            }
+        }
+
+        let rparen = self.consume(|k| matches!(k, TokenKind::RParen), "')'")?;
+        let lbrace = self.consume(|k| matches!(k, TokenKind::LCurly), "'{'")?;
+        let body = self.block(lbrace)?;
+
+        Ok(Function {
+            name,
+            lparen,
+            params,
+            rparen,
+            body,
+        })
+    }
+
+    fn block(&mut self, lbrace: Tok<'a>) -> ParseResult<Block<'a>> {
+        let mut decls = Vec::new();
+        while !self.check(|k| matches!(k, TokenKind::RCurly | TokenKind::Eof)) {
+            decls.push(self.declaration()?);
+        }
+
+        let rbrace = self.consume(|k| matches!(k, TokenKind::RCurly), "'}'")?;
+        Ok(Block {
+            lbrace,
+            decls,
+            rbrace,
+        })
+    }
+
+    fn semicolon(&mut self) -> ParseResult<Tok<'a>> {
+        self.consume(|k| matches!(k, TokenKind::Semicolon), "';'")
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt<'a>> {
+        if let Some(print) = self.take(|k| matches!(k, TokenKind::Print))? {
+            let expr = self.expression()?;
+            let semicolon = self.semicolon()?;
+            return Ok(Stmt::Print {
+                print,
+                expr,
+                semicolon,
+            });
+        }
+
+        if let Some(ret) = self.take(|k| matches!(k, TokenKind::Return))? {
+            let value = if self.check(|k| matches!(k, TokenKind::Semicolon)) {
+                None
+            } else {
+                Some(self.expression()?)
+            };
+
+            let semicolon = self.semicolon()?;
+            return Ok(Stmt::Return {
+                ret,
+                value,
+                semicolon,
+            });
+        }
+
+        if let Some(if_) = self.take(|k| matches!(k, TokenKind::If))? {
+            let (lparen, condition, rparen) = self.condition()?;
+            let then = Box::new(self.statement()?);
+            let else_ = match self.take(|k| matches!(k, TokenKind::Else))? {
+                Some(else_) => Some((else_, Box::new(self.statement()?))),
+                None => None,
+            };
+
+            return Ok(Stmt::If {
+                if_,
+                lparen,
+                condition,
+                rparen,
+                then,
+                else_,
+            });
+        }
+
+        if let Some(while_) = self.take(|k| matches!(k, TokenKind::While))? {
+            let (lparen, condition, rparen) = self.condition()?;
+            let body = Box::new(self.statement()?);
+            return Ok(Stmt::While {
+                while_,
+                lparen,
+                condition,
+                rparen,
+                body,
+            });
+        }
+
+        if let Some(for_) = self.take(|k| matches!(k, TokenKind::For))? {
+            return self.for_statement(for_);
+        }
+
+        if let Some(lbrace) = self.take(|k| matches!(k, TokenKind::LCurly))? {
+            return Ok(Stmt::Block(self.block(lbrace)?));
+        }
+
+        let expr = self.expression()?;
+        let semicolon = self.semicolon()?;
+        Ok(Stmt::Expr { expr, semicolon })
+    }
+
+    fn condition(&mut self) -> ParseResult<(Tok<'a>, Expr<'a>, Tok<'a>)> {
+        let lparen = self.consume(|k| matches!(k, TokenKind::LParen), "'('")?;
+        let condition = self.expression()?;
+        let rparen = self.consume(|k| matches!(k, TokenKind::RParen), "')'")?;
+
+        Ok((lparen, condition, rparen))
+    }
+
+    fn for_statement(&mut self, for_: Tok<'a>) -> ParseResult<Stmt<'a>> {
+        let lparen = self.consume(|k| matches!(k, TokenKind::LParen), "'('")?;
+
+        let init = if let Some(semicolon) = self.take(|k| matches!(k, TokenKind::Semicolon))? {
+            ForInit::Empty(semicolon)
+        } else if let Some(var) = self.take(|k| matches!(k, TokenKind::Var))? {
+            ForInit::Decl(Box::new(self.var_declaration(var)?))
+        } else {
+            let expr = self.expression()?;
+            let semicolon = self.semicolon()?;
+            ForInit::Decl(Box::new(Decl::Stmt(Stmt::Expr { expr, semicolon })))
+        };
+
+        let condition = if self.check(|k| matches!(k, TokenKind::Semicolon)) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        let semicolon = self.semicolon()?;
+
+        let increment = if self.check(|k| matches!(k, TokenKind::RParen)) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        let rparen = self.consume(|k| matches!(k, TokenKind::RParen), "')'")?;
+
+        let body = Box::new(self.statement()?);
+        Ok(Stmt::For {
+            for_,
+            lparen,
+            init,
+            condition,
+            semicolon,
+            increment,
+            rparen,
+            body,
+        })
+    }
+
+    fn expression(&mut self) -> ParseResult<Expr<'a>> {
+        self.assignment()
+    }
+
+    fn assignment(&mut self) -> ParseResult<Expr<'a>> {
+        let target = self.binary(0)?;
+
+        if let Some(eq) = self.take(|k| matches!(k, TokenKind::Eq))? {
+            if !matches!(
+                target,
+                Expr::Atom(Tok {
+                    kind: TokenKind::Identifier(_),
+                    ..
+                }) | Expr::Get { .. }
+            ) {
+                return Err(SyntaxError::Expected {
+                    position: eq.position,
+                    expected: "an assignment target before '='",
+                    found: eq.text.to_string(),
+                });
+            }
+
+            let value = self.assignment()?;
+            return Ok(Expr::Assign {
+                target: Box::new(target),
+                eq,
+                value: Box::new(value),
+            });
+        }
+
+        Ok(target)
+    }
+
+    /// Parse a left-associative chain of binary operators binding at least as tightly as
+    /// `level`, from `or` (0) up to multiplication (5).
+    fn binary(&mut self, level: usize) -> ParseResult<Expr<'a>> {
+        if level > 5 {
+            return self.unary();
+        }
+
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.take(|k| binary_level(k) == Some(level))? {
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr<'a>> {
+        if let Some(op) = self.take(|k| matches!(k, TokenKind::Bang | TokenKind::Minus))? {
+            let operand = self.unary()?;
+            return Ok(Expr::Unary {
+                op,
+                operand: Box::new(operand),
+            });
+        }
+
+        self.call()
+    }
+
+    fn call(&mut self) -> ParseResult<Expr<'a>> {
+        let mut expr = self.primary()?;
+
+        loop {
+            if let Some(lparen) = self.take(|k| matches!(k, TokenKind::LParen))? {
+                let mut args = Vec::new();
+                if !self.check(|k| matches!(k, TokenKind::RParen)) {
+                    loop {
+                        let arg = self.expression()?;
+                        if args.len() == MAX_ARGS {
+                            return Err(SyntaxError::TooMany {
+                                position: lparen.position,
+                                what: "arguments",
+                                found: lparen.text.to_string(),
+                            });
+                        }
+
+                        let comma = self.take(|k| matches!(k, TokenKind::Comma))?;
+                        let done = comma.is_none();
+                        args.push((arg, comma));
+                        if done {
+                            break;
+                        }
+                    }
+                }
+
+                let rparen = self.consume(|k| matches!(k, TokenKind::RParen), "')'")?;
+                expr = Expr::Call {
+                    callee: Box::new(expr),
+                    lparen,
+                    args,
+                    rparen,
+                };
+            } else if let Some(dot) = self.take(|k| matches!(k, TokenKind::Dot))? {
+                let name = self.identifier("property name after '.'")?;
+                expr = Expr::Get {
+                    object: Box::new(expr),
+                    dot,
+                    name,
+                };
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr<'a>> {
+        if let Some(lparen) = self.take(|k| matches!(k, TokenKind::LParen))? {
+            let expr = self.expression()?;
+            let rparen = self.consume(|k| matches!(k, TokenKind::RParen), "')'")?;
+            return Ok(Expr::Grouping {
+                lparen,
+                expr: Box::new(expr),
+                rparen,
+            });
+        }
+
+        if let Some(super_) = self.take(|k| matches!(k, TokenKind::Super))? {
+            let dot = self.consume(|k| matches!(k, TokenKind::Dot), "'.' after 'super'")?;
+            let name = self.identifier("superclass method name")?;
+            return Ok(Expr::Super { super_, dot, name });
+        }
+
+        let atom = self.consume(
+            |k| {
+                matches!(
+                    k,
+                    TokenKind::Identifier(_)
+                        | TokenKind::String(_)
+                        | TokenKind::Number(_)
+                        | TokenKind::True
+                        | TokenKind::False
+                        | TokenKind::Nil
+                        | TokenKind::This
+                )
+            },
+            "an expression",
+        )?;
+
+        Ok(Expr::Atom(atom))
+    }
+}
+
+fn binary_level(kind: &TokenKind) -> Option<usize> {
+    match kind {
+        TokenKind::Or => Some(0),
+        TokenKind::And => Some(1),
+        TokenKind::EqEq | TokenKind::BangEq => Some(2),
+        TokenKind::Lt | TokenKind::LtEq | TokenKind::Gt | TokenKind::GtEq => Some(3),
+        TokenKind::Plus | TokenKind::Minus => Some(4),
+        TokenKind::Star | TokenKind::Slash => Some(5),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::compiler;
+    use crate::difftest::scripts;
+
+    const SNIPPETS: &[&str] = &[
+        "",
+        "// only a comment",
+        "print 1;",
+        "print 1",
+        "var = ;",
+        "var a",
+        "var a = 1;",
+        "a = b = c;",
+        "1 = 2;",
+        "a + b = c;",
+        "a.b = c;",
+        "a.b.c = d;",
+        "f(a)(b).c = d;",
+        "f() = 1;",
+        "this = 1;",
+        "print this.x;",
+        "print super.x;",
+        "print super;",
+        "print (1;",
+        "print 1 +;",
+        "print -!-1;",
+        "print a or b and c;",
+        "print 1 < 2 == 3 >= 4;",
+        "{ var a = 1; ",
+        "}",
+        "if (a) print 1; else print 2;",
+        "if a print 1;",
+        "while (true) {}",
+        "for (;;) {}",
+        "for (var i = 0; i < 1; i = i + 1) print i;",
+        "for (i = 0; i; ) print i;",
+        "for (var i = 0) {}",
+        "fun f() {}",
+        "fun f(a, b,) {}",
+        "fun f(a b) {}",
+        "fun () {}",
+        "fun f(a, b) { return a + b; }",
+        "return;",
+        "return 1",
+        "class A {}",
+        "class A < B { init() { this.x = 1; } m() { return super.m(); } }",
+        "class A { var x; }",
+        "class A < {}",
+        "print \"unterminated;",
+        "print 1.;",
+        "print @;",
+        "print f(1, 2)(3);",
+        "print f(1, 2;",
+        "print a.;",
+        "print ;",
+        ";",
+    ];
+
+    fn same_verdict(source: &str) {
+        let cst = parse(source).map(|_| ());
+        let ast = compiler::parse(source).map(|_| ());
+        assert_eq!(
+            cst.is_ok(),
+            ast.is_ok(),
+            "{source:?}: formatter {cst:?}, compiler {ast:?}"
+        );
+    }
+
+    #[test]
+    fn accepts_the_same_snippets_as_the_compiler() {
+        for source in SNIPPETS {
+            same_verdict(source);
+        }
+    }
+
+    #[test]
+    fn accepts_the_same_programs_as_the_compiler() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+        for path in scripts(&[dir]).unwrap() {
+            same_verdict(&fs::read_to_string(path).unwrap());
+        }
+    }
+
+    #[test]
+    fn accepts_the_same_argument_counts_as_the_compiler() {
+        for count in [255, 256] {
+            let names = vec!["a"; count].join(", ");
+            same_verdict(&format!("fun f({names}) {{}}"));
+            same_verdict(&format!("f({names});"));
+        }
+    }
+}
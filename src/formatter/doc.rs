@@ -0,0 +1,183 @@
+//! A small Wadler-style pretty-printing algebra: documents describe where lines may break, and
+//! the renderer breaks a group only when its contents do not fit in the remaining width.
+
+#[derive(Debug, Clone)]
+pub enum Doc {
+    Text(String),
+    /// A space, or a newline if the enclosing group is broken
+    Line,
+    /// Nothing, or a newline if the enclosing group is broken
+    SoftLine,
+    /// Always a newline, and forces every enclosing group to break
+    HardLine,
+    /// A newline unless output is already at the start of a line; forces groups to break
+    LineStart,
+    /// A `//` comment ending the current line. If a break was just emitted, the comment is moved
+    /// back before it so it stays with the token it followed.
+    TrailingComment(String),
+    Indent(Box<Doc>),
+    Group(Box<Doc>),
+    Concat(Vec<Doc>),
+}
+
+impl Doc {
+    pub fn text(s: impl Into<String>) -> Self {
+        Doc::Text(s.into())
+    }
+
+    pub fn indent(doc: Doc) -> Self {
+        Doc::Indent(Box::new(doc))
+    }
+
+    pub fn group(doc: Doc) -> Self {
+        Doc::Group(Box::new(doc))
+    }
+
+    fn has_hard_line(&self) -> bool {
+        match self {
+            Doc::HardLine | Doc::LineStart | Doc::TrailingComment(_) => true,
+            Doc::Text(_) | Doc::Line | Doc::SoftLine => false,
+            Doc::Indent(doc) | Doc::Group(doc) => doc.has_hard_line(),
+            Doc::Concat(docs) => docs.iter().any(Doc::has_hard_line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+/// Render `doc`, breaking groups that would exceed `width` columns, indenting by `indent`
+/// spaces per level.
+pub fn render(doc: &Doc, width: usize, indent: usize) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    // Indentation is written lazily so blank lines carry no trailing whitespace
+    let mut pending_indent = None;
+
+    let mut stack = vec![(0, Mode::Break, doc)];
+    while let Some((level, mode, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(s) => {
+                if let Some(spaces) = pending_indent.take() {
+                    out.push_str(&" ".repeat(spaces));
+                    column = spaces;
+                }
+
+                out.push_str(s);
+                column += s.chars().count();
+            }
+
+            Doc::Line | Doc::SoftLine if mode == Mode::Flat => {
+                if let Doc::Line = doc {
+                    out.push(' ');
+                    column += 1;
+                }
+            }
+
+            Doc::TrailingComment(comment) => {
+                if pending_indent.is_some() && out.ends_with('\n') {
+                    out.pop();
+                    out.push(' ');
+                    out.push_str(comment);
+                    out.push('\n');
+                } else {
+                    out.push(' ');
+                    out.push_str(comment);
+                    column += 1 + comment.chars().count();
+                }
+            }
+
+            Doc::LineStart if pending_indent.is_some() || out.is_empty() => {
+                pending_indent = Some(level * indent);
+            }
+
+            Doc::Line | Doc::SoftLine | Doc::HardLine | Doc::LineStart => {
+                out.push('\n');
+                column = 0;
+                pending_indent = Some(level * indent);
+            }
+
+            Doc::Indent(inner) => stack.push((level + 1, mode, inner)),
+
+            Doc::Group(inner) => {
+                let flat = !inner.has_hard_line()
+                    && fits(
+                        width.saturating_sub(column),
+                        (level, Mode::Flat, inner),
+                        &stack,
+                    );
+                let mode = if flat { Mode::Flat } else { Mode::Break };
+                stack.push((level, mode, inner));
+            }
+
+            Doc::Concat(docs) => {
+                for doc in docs.iter().rev() {
+                    stack.push((level, mode, doc));
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// Whether `next`, followed by the rest of the stack, fits in `remaining` columns up to the
+/// next line break.
+fn fits(remaining: usize, next: (usize, Mode, &Doc), rest: &[(usize, Mode, &Doc)]) -> bool {
+    let mut remaining = remaining;
+    let mut stack = vec![next];
+    let mut rest = rest.iter().rev();
+
+    loop {
+        let Some((level, mode, doc)) = stack.pop().or_else(|| rest.next().copied()) else {
+            return true;
+        };
+
+        match doc {
+            Doc::Text(s) => {
+                let len = s.chars().count();
+                if len > remaining {
+                    return false;
+                }
+
+                remaining -= len;
+            }
+
+            Doc::Line if mode == Mode::Flat => {
+                if remaining == 0 {
+                    return false;
+                }
+
+                remaining -= 1;
+            }
+
+            Doc::SoftLine if mode == Mode::Flat => {}
+
+            Doc::Line
+            | Doc::SoftLine
+            | Doc::HardLine
+            | Doc::LineStart
+            | Doc::TrailingComment(_) => return true,
+
+            Doc::Indent(inner) => stack.push((level + 1, mode, inner)),
+
+            Doc::Group(inner) => {
+                let mode = if inner.has_hard_line() {
+                    Mode::Break
+                } else {
+                    mode
+                };
+                stack.push((level, mode, inner));
+            }
+
+            Doc::Concat(docs) => {
+                for doc in docs.iter().rev() {
+                    stack.push((level, mode, doc));
+                }
+            }
+        }
+    }
+}
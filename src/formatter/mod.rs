@@ -0,0 +1,538 @@
+//! Canonical source formatter used by `rlox fmt`.
+//!
+//! The source is parsed into a concrete syntax tree that keeps comments, which is then printed
+//! with fixed rules: four-space indentation, opening braces on the same line, one space around
+//! binary operators, and at most one blank line between statements. Groups such as argument
+//! lists are only broken over several lines when they do not fit in the line width. Formatting
+//! the output again leaves it unchanged.
+
+mod cst;
+mod doc;
+
+use std::mem::take;
+
+use cst::{Block, Decl, Expr, ForInit, Function, Stmt, Tok};
+use doc::{Doc, render};
+
+use crate::compiler::{self, CompileError};
+
+pub const DEFAULT_WIDTH: usize = 80;
+
+const INDENT: usize = 4;
+
+/// Format `source` to fit in `width` columns where possible. A program with syntax errors is
+/// left alone, and its errors are the compiler's.
+pub fn format(source: &str, width: usize) -> Result<String, Vec<CompileError>> {
+    compiler::parse(source)?;
+    let program = cst::parse(source).map_err(|error| vec![error.into()])?;
+
+    let mut list = List::top_level();
+    for decl in program.decls {
+        list.push(decl, Decl::first_token_mut, declaration);
+    }
+
+    let mut eof = program.eof;
+    let doc = list.finish(&mut eof);
+    let mut out = render(&doc, width, INDENT);
+    if !out.is_empty() {
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+/// A sequence of declarations, one per line, keeping comments between them and single blank
+/// lines where the source had any.
+struct List {
+    parts: Vec<Doc>,
+    top_level: bool,
+    printed: bool,
+}
+
+impl List {
+    fn top_level() -> Self {
+        Self {
+            parts: Vec::new(),
+            top_level: true,
+            printed: false,
+        }
+    }
+
+    fn nested() -> Self {
+        Self {
+            top_level: false,
+            ..Self::top_level()
+        }
+    }
+
+    fn new_line(&mut self, blank: bool) {
+        if self.printed || !self.top_level {
+            self.parts.push(Doc::HardLine);
+        }
+
+        if blank && self.printed {
+            self.parts.push(Doc::HardLine);
+        }
+
+        self.printed = true;
+    }
+
+    /// Move the comments before `tok` into the list, each on its own line.
+    fn comments(&mut self, tok: &mut Tok) {
+        if let Some(comment) = tok.trailing_comment.take() {
+            self.parts.push(Doc::TrailingComment(comment.to_string()));
+        }
+
+        for comment in take(&mut tok.comments) {
+            self.new_line(comment.blank_before);
+            self.parts.push(Doc::text(comment.text));
+        }
+    }
+
+    fn push<'a, T>(
+        &mut self,
+        mut item: T,
+        first: impl Fn(&mut T) -> &mut Tok<'a>,
+        print: fn(T) -> Doc,
+    ) {
+        let tok = first(&mut item);
+        let blank = tok.starts_paragraph();
+        let had_comments = !tok.comments.is_empty();
+        let tok_blank = tok.blank_before;
+
+        self.comments(tok);
+        self.new_line(if had_comments { tok_blank } else { blank });
+        self.parts.push(print(item));
+    }
+
+    /// Finish with the token that closes the list, placing the comments before it.
+    fn finish(mut self, closing: &mut Tok) -> Doc {
+        self.comments(closing);
+        Doc::Concat(self.parts)
+    }
+}
+
+fn tok(tok: Tok) -> Doc {
+    if !tok.has_comments() {
+        return Doc::text(tok.text);
+    }
+
+    let mut parts = Vec::new();
+    if let Some(comment) = tok.trailing_comment {
+        parts.extend([Doc::TrailingComment(comment.to_string()), Doc::LineStart]);
+    }
+
+    for comment in tok.comments {
+        parts.extend([Doc::LineStart, Doc::text(comment.text), Doc::HardLine]);
+    }
+
+    parts.push(Doc::text(tok.text));
+    Doc::Concat(parts)
+}
+
+fn space() -> Doc {
+    Doc::text(" ")
+}
+
+/// Print `items` between brackets, separated by the comma tokens that follow them, on one line
+/// if they fit and otherwise one per line.
+fn delimited<T>(
+    open: Tok,
+    items: Vec<(T, Option<Tok>)>,
+    close: Tok,
+    print: impl Fn(T) -> Doc,
+) -> Doc {
+    if items.is_empty() {
+        return Doc::Concat(vec![tok(open), tok(close)]);
+    }
+
+    let mut inner = vec![Doc::SoftLine];
+    for (item, comma) in items {
+        inner.push(print(item));
+        if let Some(comma) = comma {
+            inner.extend([tok(comma), Doc::Line]);
+        }
+    }
+
+    Doc::Concat(vec![
+        tok(open),
+        Doc::group(Doc::Concat(vec![
+            Doc::indent(Doc::Concat(inner)),
+            Doc::SoftLine,
+        ])),
+        tok(close),
+    ])
+}
+
+fn block(block: Block) -> Doc {
+    braced(
+        block.lbrace,
+        block.decls,
+        block.rbrace,
+        Decl::first_token_mut,
+        declaration,
+    )
+}
+
+fn braced<'a, T>(
+    lbrace: Tok<'a>,
+    items: Vec<T>,
+    mut rbrace: Tok<'a>,
+    first: impl Fn(&mut T) -> &mut Tok<'a>,
+    print: fn(T) -> Doc,
+) -> Doc {
+    if items.is_empty() && !rbrace.has_comments() {
+        return Doc::Concat(vec![tok(lbrace), tok(rbrace)]);
+    }
+
+    let mut list = List::nested();
+    for item in items {
+        list.push(item, &first, print);
+    }
+
+    Doc::Concat(vec![
+        tok(lbrace),
+        Doc::indent(list.finish(&mut rbrace)),
+        Doc::HardLine,
+        tok(rbrace),
+    ])
+}
+
+fn declaration(decl: Decl) -> Doc {
+    match decl {
+        Decl::Class {
+            class,
+            name,
+            superclass,
+            lbrace,
+            methods,
+            rbrace,
+        } => {
+            let mut parts = vec![tok(class), space(), tok(name)];
+            if let Some((lt, superclass)) = superclass {
+                parts.extend([space(), tok(lt), space(), tok(superclass)]);
+            }
+
+            parts.push(space());
+            parts.push(braced(
+                lbrace,
+                methods,
+                rbrace,
+                |method| &mut method.name,
+                function,
+            ));
+            Doc::Concat(parts)
+        }
+
+        Decl::Fun { fun, function: f } => Doc::Concat(vec![tok(fun), space(), function(f)]),
+
+        Decl::Var {
+            var,
+            name,
+            initializer,
+            semicolon,
+        } => {
+            let mut parts = vec![tok(var), space(), tok(name)];
+            if let Some((eq, value)) = initializer {
+                parts.extend([space(), tok(eq), space(), expression(value)]);
+            }
+
+            parts.push(tok(semicolon));
+            Doc::Concat(parts)
+        }
+
+        Decl::Stmt(stmt) => statement(stmt),
+    }
+}
+
+fn function(function: Function) -> Doc {
+    Doc::Concat(vec![
+        tok(function.name),
+        delimited(function.lparen, function.params, function.rparen, tok),
+        space(),
+        block(function.body),
+    ])
+}
+
+fn statement(stmt: Stmt) -> Doc {
+    match stmt {
+        Stmt::Expr { expr, semicolon } => Doc::Concat(vec![expression(expr), tok(semicolon)]),
+
+        Stmt::Print {
+            print,
+            expr,
+            semicolon,
+        } => Doc::Concat(vec![tok(print), space(), expression(expr), tok(semicolon)]),
+
+        Stmt::Return {
+            ret,
+            value,
+            semicolon,
+        } => {
+            let mut parts = vec![tok(ret)];
+            if let Some(value) = value {
+                parts.extend([space(), expression(value)]);
+            }
+
+            parts.push(tok(semicolon));
+            Doc::Concat(parts)
+        }
+
+        Stmt::If {
+            if_,
+            lparen,
+            condition,
+            rparen,
+            then,
+            else_,
+        } => {
+            let then_is_block = matches!(*then, Stmt::Block(_));
+            let mut parts = vec![
+                tok(if_),
+                space(),
+                tok(lparen),
+                expression(condition),
+                tok(rparen),
+                body(*then),
+            ];
+
+            if let Some((else_, otherwise)) = else_ {
+                parts.push(if then_is_block {
+                    space()
+                } else {
+                    Doc::HardLine
+                });
+                parts.push(tok(else_));
+                parts.push(match *otherwise {
+                    // Keep `else if` chains flat rather than nesting them
+                    chained @ Stmt::If { .. } => Doc::Concat(vec![space(), statement(chained)]),
+                    otherwise => body(otherwise),
+                });
+            }
+
+            Doc::Concat(parts)
+        }
+
+        Stmt::While {
+            while_,
+            lparen,
+            condition,
+            rparen,
+            body: loop_body,
+        } => Doc::Concat(vec![
+            tok(while_),
+            space(),
+            tok(lparen),
+            expression(condition),
+            tok(rparen),
+            body(*loop_body),
+        ]),
+
+        Stmt::For {
+            for_,
+            lparen,
+            init,
+            condition,
+            semicolon,
+            increment,
+            rparen,
+            body: loop_body,
+        } => {
+            let mut parts = vec![tok(for_), space(), tok(lparen)];
+            parts.push(match init {
+                ForInit::Empty(semicolon) => tok(semicolon),
+                ForInit::Decl(decl) => declaration(*decl),
+            });
+
+            if let Some(condition) = condition {
+                parts.extend([space(), expression(condition)]);
+            }
+            parts.push(tok(semicolon));
+
+            if let Some(increment) = increment {
+                parts.extend([space(), expression(increment)]);
+            }
+            parts.extend([tok(rparen), body(*loop_body)]);
+
+            Doc::Concat(parts)
+        }
+
+        Stmt::Block(b) => block(b),
+    }
+}
+
+/// The body of a control flow statement: a block opens on the same line, and any other
+/// statement follows on the same line if it fits or indented on the next otherwise.
+fn body(stmt: Stmt) -> Doc {
+    if let Stmt::Block(b) = stmt {
+        return Doc::Concat(vec![space(), block(b)]);
+    }
+
+    Doc::group(Doc::indent(Doc::Concat(vec![Doc::Line, statement(stmt)])))
+}
+
+fn expression(expr: Expr) -> Doc {
+    match expr {
+        Expr::Atom(atom) => tok(atom),
+
+        Expr::Super { super_, dot, name } => Doc::Concat(vec![tok(super_), tok(dot), tok(name)]),
+
+        Expr::Grouping {
+            lparen,
+            expr,
+            rparen,
+        } => Doc::Concat(vec![tok(lparen), expression(*expr), tok(rparen)]),
+
+        Expr::Unary { op, operand } => Doc::Concat(vec![tok(op), expression(*operand)]),
+
+        // Long operands break after the operator, with the right operand indented
+        Expr::Binary { left, op, right } => Doc::group(Doc::Concat(vec![
+            expression(*left),
+            space(),
+            tok(op),
+            Doc::indent(Doc::Concat(vec![Doc::Line, expression(*right)])),
+        ])),
+
+        Expr::Assign { target, eq, value } => Doc::Concat(vec![
+            expression(*target),
+            space(),
+            tok(eq),
+            space(),
+            expression(*value),
+        ]),
+
+        Expr::Call {
+            callee,
+            lparen,
+            args,
+            rparen,
+        } => Doc::Concat(vec![
+            expression(*callee),
+            delimited(lparen, args, rparen, expression),
+        ]),
+
+        Expr::Get { object, dot, name } => {
+            Doc::Concat(vec![expression(*object), tok(dot), tok(name)])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::compiler::parse;
+    use crate::difftest::scripts;
+
+    const COMMENTED: &str = "\
+// Leading comment
+
+var a = 1; // after a statement
+fun f(x) {
+    // inside a body
+    return x;
+
+
+    // after blank lines
+}
+
+class C < B {
+    // in a class
+    m() {}
+}
+// trailing comment
+";
+
+    /// The test programs that parse; some deliberately have syntax errors.
+    fn corpus() -> Vec<String> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+        scripts(&[dir])
+            .unwrap()
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap())
+            .filter(|source| parse(source).is_ok())
+            .collect()
+    }
+
+    fn comments(source: &str) -> Vec<&str> {
+        source
+            .lines()
+            .filter_map(|line| line.find("//").map(|i| line[i..].trim_end()))
+            .collect()
+    }
+
+    #[test]
+    fn reports_the_compilers_syntax_errors() {
+        let messages =
+            |errors: Vec<CompileError>| errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        for source in [
+            "print 1 +;\nvar = 2;",
+            "print \"unterminated;",
+            "fun f(a b) {}",
+            "print 1",
+        ] {
+            let formatted = format(source, DEFAULT_WIDTH).unwrap_err();
+            assert_eq!(messages(formatted), messages(parse(source).unwrap_err()));
+        }
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let mut sources = corpus();
+        sources.push(COMMENTED.to_string());
+
+        for source in &sources {
+            for width in [DEFAULT_WIDTH, 20] {
+                let once = format(source, width).unwrap();
+                let twice = format(&once, width).unwrap();
+                assert_eq!(twice, once, "width {width}:\n{source}");
+            }
+        }
+    }
+
+    #[test]
+    fn formatting_keeps_comments() {
+        let mut sources = corpus();
+        sources.push(COMMENTED.to_string());
+
+        for source in &sources {
+            let formatted = format(source, DEFAULT_WIDTH).unwrap();
+            assert_eq!(comments(&formatted), comments(source), "{source}");
+        }
+    }
+
+    #[test]
+    fn formats_to_the_canonical_layout() {
+        assert_eq!(
+            format(COMMENTED, DEFAULT_WIDTH).unwrap(),
+            "\
+// Leading comment
+
+var a = 1; // after a statement
+fun f(x) {
+    // inside a body
+    return x;
+
+    // after blank lines
+}
+
+class C < B {
+    // in a class
+    m() {}
+}
+// trailing comment
+"
+        );
+    }
+
+    #[test]
+    fn breaks_groups_that_do_not_fit() {
+        assert_eq!(
+            format("print f(first, second, third);", 20).unwrap(),
+            "print f(\n    first,\n    second,\n    third\n);\n"
+        );
+    }
+}
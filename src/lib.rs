@@ -0,0 +1,105 @@
+//! A Lox interpreter, run from the command line by the `rlox` binary or embedded in Rust programs.
+//!
+//! To embed it, create a [`VM`] and pass source to [`VM::interpret`], which returns the value of
+//! the program's final statement when that is an expression statement. Globals outlive each
+//! program, so Rust code shares state with scripts through them: [`VM::define_global`],
+//! [`VM::global`] and [`VM::set_global`] read and write them, and [`VM::define_native`] makes a
+//! Rust function callable from Lox.
+//!
+//! Lox functions, classes and bound methods can be called from Rust with [`VM::call`]. Natives
+//! are given the VM calling them as a [`Context`], so they can call back into Lox the same way,
+//! to any depth.
+//!
+//! The rest of the API is the tooling behind `rlox`. [`compile`] turns source into a [`Chunk`],
+//! which [`optimize`] rewrites, [`write_chunk`] and [`read_chunk`] save and load as `.loxc`
+//! bytecode, and [`assemble`] and [`to_assembly`] convert from and to assembly source.
+//! [`RegisterVM`] runs the code of [`compile_registers`] instead. [`format_source`] and [`lint`]
+//! work on source, and [`Debugger`], [`serve_dap`] and [`serve_lsp`] drive the debugger and
+//! editors.
+
+#![warn(clippy::all, clippy::pedantic, unnameable_types)]
+#![allow(
+    clippy::must_use_candidate,
+    clippy::missing_errors_doc,
+    clippy::missing_panics_doc
+)]
+
+pub(crate) mod asm;
+pub(crate) mod bytecode;
+pub(crate) mod chunk;
+pub(crate) mod compiler;
+pub(crate) mod debugger;
+pub(crate) mod difftest;
+pub(crate) mod formatter;
+pub(crate) mod lint;
+pub(crate) mod lsp;
+pub(crate) mod natives;
+pub(crate) mod optimizer;
+pub(crate) mod profile;
+pub(crate) mod protocol;
+pub(crate) mod register;
+pub(crate) mod trace;
+pub(crate) mod tree_walker;
+pub(crate) mod value;
+pub(crate) mod verifier;
+pub(crate) mod vm;
+
+use std::fs::read;
+use std::io;
+use std::path::Path;
+
+use thiserror::Error;
+
+pub use asm::{AsmError, assemble, to_assembly};
+pub use bytecode::{BytecodeError, is_bytecode, read_chunk, write_chunk};
+pub use chunk::{Chunk, ChunkError, OpCode, Position};
+pub use compiler::{CompileError, compile};
+pub use debugger::{Debugger, serve_dap};
+pub use difftest::{compare, scripts};
+pub use formatter::{DEFAULT_WIDTH as DEFAULT_FORMAT_WIDTH, format as format_source};
+pub use lint::{Config as LintConfig, Diagnostic, Lint, Severity, lint};
+pub use lsp::serve as serve_lsp;
+pub use natives::{Context, NativeError};
+pub use optimizer::optimize;
+pub use profile::Profiler;
+pub use register::{
+    RegisterVM, compile as compile_registers, disassemble as disassemble_registers,
+};
+pub use trace::{LineRange, TraceFormat, Tracer};
+pub use value::{NativeFn, Value};
+pub use verifier::VerifyError;
+pub use vm::{RuntimeError, VM};
+
+#[derive(Debug, Error)]
+pub enum InterpretError {
+    #[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))]
+    Compiler(Vec<CompileError>),
+    #[error("[{}] Runtime error: {error}", position.map_or("?".to_string(), |p| p.to_string()))]
+    Runtime {
+        error: RuntimeError,
+        position: Option<Position>,
+    },
+    #[error("Invalid bytecode: {0}")]
+    Verify(#[from] VerifyError),
+    #[error("{0}")]
+    Bytecode(#[from] BytecodeError),
+    #[error("Assembler error: {0}")]
+    Asm(#[from] AsmError),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+pub type InterpretResult<T> = Result<T, InterpretError>;
+
+/// Load the chunk for `p`, compiling it if it is source rather than a `.loxc` file.
+pub fn load_chunk(p: &Path) -> InterpretResult<Chunk> {
+    let bytes = read(p)?;
+    if bytecode::is_bytecode(&bytes) {
+        return Ok(bytecode::read_chunk(&mut bytes.as_slice())?);
+    }
+
+    let source =
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    compile(&source)
+}
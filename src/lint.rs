@@ -0,0 +1,611 @@
+//! Static checks for `rlox lint`, built on the compiler's parser and scope resolution.
+//!
+//! A warning can be silenced with a `// lint: allow(name, ...)` comment, or `// lint: allow` for
+//! every lint, at the end of the offending line or on the line above it.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::chunk::{LineNum, Position};
+use crate::compiler::ast::{Expr, ExprKind, Stmt};
+use crate::compiler::resolver::{ScopeWarning, resolve};
+use crate::compiler::scanner::Scanner;
+use crate::compiler::token::TriviaKind;
+use crate::compiler::{CompileError, parse};
+
+const SUPPRESSION_PREFIX: &str = "lint: allow";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnusedVariable,
+    UnusedParameter,
+    ShadowedVariable,
+    UnreachableCode,
+    UndeclaredGlobal,
+    SelfComparison,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 6] = [
+        Lint::UnusedVariable,
+        Lint::UnusedParameter,
+        Lint::ShadowedVariable,
+        Lint::UnreachableCode,
+        Lint::UndeclaredGlobal,
+        Lint::SelfComparison,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnusedVariable => "unused-variable",
+            Lint::UnusedParameter => "unused-parameter",
+            Lint::ShadowedVariable => "shadowed-variable",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::UndeclaredGlobal => "undeclared-global",
+            Lint::SelfComparison => "self-comparison",
+        }
+    }
+}
+
+impl std::str::FromStr for Lint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Lint::ALL
+            .into_iter()
+            .find(|lint| lint.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Lint::ALL.iter().map(|lint| lint.name()).collect();
+                format!("Unknown lint '{s}' (expected one of {})", names.join(", "))
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub position: Position,
+    pub severity: Severity,
+    /// `None` for compile errors, which cannot be allowed or suppressed
+    pub lint: Option<Lint>,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+
+        write!(f, "{}: {severity}", self.position)?;
+        if let Some(lint) = self.lint {
+            write!(f, "[{}]", lint.name())?;
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Which lints run, and which of them are reported as errors.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub allow: HashSet<Lint>,
+    pub deny: HashSet<Lint>,
+    /// Report every warning as an error
+    pub deny_warnings: bool,
+}
+
+impl Config {
+    fn severity(&self, lint: Lint) -> Option<Severity> {
+        if self.allow.contains(&lint) {
+            None
+        } else if self.deny_warnings || self.deny.contains(&lint) {
+            Some(Severity::Error)
+        } else {
+            Some(Severity::Warning)
+        }
+    }
+}
+
+/// Check `source`, returning diagnostics in source order.
+pub fn lint(source: &str, config: &Config) -> Vec<Diagnostic> {
+    let program = match parse(source) {
+        Ok(program) => program,
+        Err(errors) => return errors.into_iter().map(compile_error).collect(),
+    };
+
+    let resolution = resolve(&program);
+    let mut found: Vec<(Lint, Position, String)> = resolution
+        .warnings
+        .into_iter()
+        .map(|warning| match warning {
+            ScopeWarning::Unused {
+                name,
+                position,
+                parameter: false,
+            } => (
+                Lint::UnusedVariable,
+                position,
+                format!("Local variable '{name}' is never read"),
+            ),
+            ScopeWarning::Unused {
+                name,
+                position,
+                parameter: true,
+            } => (
+                Lint::UnusedParameter,
+                position,
+                format!("Parameter '{name}' is never read"),
+            ),
+            ScopeWarning::Shadowed {
+                name,
+                position,
+                shadowed,
+            } => (
+                Lint::ShadowedVariable,
+                position,
+                format!("'{name}' shadows the variable declared at {shadowed}"),
+            ),
+            ScopeWarning::UndeclaredGlobal { name, position } => (
+                Lint::UndeclaredGlobal,
+                position,
+                format!("Assignment to undeclared global '{name}'"),
+            ),
+        })
+        .collect();
+
+    let mut checker = Checker { found: &mut found };
+    checker.statements(&program);
+
+    let suppressed = suppressions(source);
+    let mut diagnostics: Vec<_> = resolution
+        .errors
+        .into_iter()
+        .map(compile_error)
+        .chain(found.into_iter().filter_map(|(lint, position, message)| {
+            let allowed = suppressed
+                .get(&position.line)
+                .is_some_and(|allowed| allowed.contains(lint));
+            if allowed {
+                return None;
+            }
+
+            Some(Diagnostic {
+                position,
+                severity: config.severity(lint)?,
+                lint: Some(lint),
+                message,
+            })
+        }))
+        .collect();
+
+    diagnostics.sort_by_key(|d| d.position);
+    diagnostics
+}
+
+fn compile_error(error: CompileError) -> Diagnostic {
+    Diagnostic {
+        position: error.position,
+        severity: Severity::Error,
+        lint: None,
+        message: if error.location.is_empty() {
+            error.message
+        } else {
+            format!("{} ({})", error.message, error.location.trim())
+        },
+    }
+}
+
+/// Lints silenced on one line by a suppression comment.
+#[derive(Debug)]
+enum Allowed {
+    All,
+    Only(HashSet<Lint>),
+}
+
+impl Allowed {
+    fn contains(&self, lint: Lint) -> bool {
+        match self {
+            Allowed::All => true,
+            Allowed::Only(lints) => lints.contains(&lint),
+        }
+    }
+}
+
+/// Lines with suppression comments, mapped to the lints allowed there.
+fn suppressions(source: &str) -> HashMap<LineNum, Allowed> {
+    let mut suppressed = HashMap::new();
+    let mut last_token_line = None;
+
+    for token in Scanner::lossless(source) {
+        for trivia in &token.leading {
+            if trivia.kind != TriviaKind::Comment {
+                continue;
+            }
+
+            let Some(allowed) = parse_suppression(trivia.text) else {
+                continue;
+            };
+
+            // A comment after code applies to that line, one on its own to the next line
+            let line = if last_token_line == Some(trivia.line) {
+                trivia.line
+            } else {
+                trivia.line + 1
+            };
+
+            suppressed.insert(line, allowed);
+        }
+
+        if let Ok(token) = &token.token {
+            last_token_line = Some(token.line);
+        }
+    }
+
+    suppressed
+}
+
+/// Parse `// lint: allow` or `// lint: allow(a, b)`. Unknown lint names are ignored.
+fn parse_suppression(comment: &str) -> Option<Allowed> {
+    let rest = comment
+        .trim_start_matches('/')
+        .trim()
+        .strip_prefix(SUPPRESSION_PREFIX)?
+        .trim();
+
+    if rest.is_empty() {
+        return Some(Allowed::All);
+    }
+
+    let names = rest.strip_prefix('(')?.strip_suffix(')')?;
+    let lints = names
+        .split(',')
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+
+    Some(Allowed::Only(lints))
+}
+
+/// Structural checks that need no scope information.
+struct Checker<'a> {
+    found: &'a mut Vec<(Lint, Position, String)>,
+}
+
+impl Checker<'_> {
+    fn statements(&mut self, stmts: &[Stmt]) {
+        let mut diverged = false;
+        for stmt in stmts {
+            if diverged {
+                self.found.push((
+                    Lint::UnreachableCode,
+                    stmt.position(),
+                    "Unreachable code after 'return'".to_string(),
+                ));
+                break;
+            }
+
+            self.statement(stmt);
+            diverged = diverges(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Var { initializer, .. } => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+            }
+
+            Stmt::Function(function) => self.statements(&function.body),
+
+            Stmt::Class {
+                superclass,
+                methods,
+                ..
+            } => {
+                if let Some(superclass) = superclass {
+                    self.expression(superclass);
+                }
+
+                for method in methods {
+                    self.statements(&method.body);
+                }
+            }
+
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expression(expr),
+
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+
+            Stmt::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.expression(condition);
+                self.statement(then);
+                if let Some(otherwise) = otherwise {
+                    self.statement(otherwise);
+                }
+            }
+
+            Stmt::While { condition, body } => {
+                self.expression(condition);
+                self.statement(body);
+            }
+
+            Stmt::Block(stmts) => self.statements(stmts),
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(_)
+            | ExprKind::Variable(_)
+            | ExprKind::This
+            | ExprKind::Super { .. } => {}
+
+            ExprKind::Binary { op, left, right } => {
+                if op.is_comparison() && same_value(left, right) {
+                    self.found.push((
+                        Lint::SelfComparison,
+                        expr.position,
+                        format!("Both sides of '{op}' are the same expression"),
+                    ));
+                }
+
+                self.expression(left);
+                self.expression(right);
+            }
+
+            ExprKind::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+
+            ExprKind::Assign { value, .. } => self.expression(value),
+
+            ExprKind::Unary { operand, .. } | ExprKind::Grouping(operand) => {
+                self.expression(operand);
+            }
+
+            ExprKind::Call { callee, args } => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+
+            ExprKind::Get { object, .. } => self.expression(object),
+
+            ExprKind::Set { object, value, .. } => {
+                self.expression(object);
+                self.expression(value);
+            }
+        }
+    }
+}
+
+/// Whether control never continues past `stmt`.
+fn diverges(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return { .. } => true,
+        Stmt::Block(stmts) => stmts.iter().any(diverges),
+        Stmt::If {
+            then,
+            otherwise: Some(otherwise),
+            ..
+        } => diverges(then) && diverges(otherwise),
+        _ => false,
+    }
+}
+
+/// Whether `a` and `b` are the same side-effect free expression, and so always evaluate to the
+/// same value.
+fn same_value(a: &Expr, b: &Expr) -> bool {
+    match (&a.kind, &b.kind) {
+        (ExprKind::Grouping(a), _) => same_value(a, b),
+        (_, ExprKind::Grouping(b)) => same_value(a, b),
+        (ExprKind::Literal(a), ExprKind::Literal(b)) => a == b,
+        (ExprKind::Variable(a), ExprKind::Variable(b)) => a.text == b.text,
+        (ExprKind::This, ExprKind::This) => true,
+        (
+            ExprKind::Get {
+                object: a,
+                name: a_name,
+            },
+            ExprKind::Get {
+                object: b,
+                name: b_name,
+            },
+        ) => a_name.text == b_name.text && same_value(a, b),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(source: &str, config: &Config) -> Vec<(LineNum, Option<&'static str>, Severity)> {
+        lint(source, config)
+            .iter()
+            .map(|d| (d.position.line, d.lint.map(Lint::name), d.severity))
+            .collect()
+    }
+
+    fn warnings(source: &str) -> Vec<(LineNum, &'static str)> {
+        check(source, &Config::default())
+            .into_iter()
+            .map(|(line, lint, severity)| {
+                assert_eq!(severity, Severity::Warning, "{source}");
+                (line, lint.expect("a lint, not a compile error"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn warns_about_unused_locals_and_parameters() {
+        let source = "\
+fun f(a, b) {
+  var c = 1;
+  var d = 2;
+  return a + d;
+}
+var global = 1;
+";
+        assert_eq!(
+            warnings(source),
+            [(1, "unused-parameter"), (2, "unused-variable")]
+        );
+    }
+
+    #[test]
+    fn warns_about_shadowed_variables() {
+        let source = "\
+{
+  var a = 1;
+  {
+    var a = 2;
+    print a;
+  }
+  print a;
+}
+";
+        assert_eq!(warnings(source), [(4, "shadowed-variable")]);
+    }
+
+    #[test]
+    fn warns_about_unreachable_code() {
+        let source = "\
+fun f(a) {
+  if (a) return 1; else { return 2; }
+  print a;
+}
+fun g(a) {
+  if (a) return 1;
+  return 2;
+}
+print f(1) + g(1);
+";
+        assert_eq!(warnings(source), [(3, "unreachable-code")]);
+    }
+
+    #[test]
+    fn warns_about_assignments_to_undeclared_globals() {
+        let source = "\
+var declared;
+declared = 1;
+undeclared = 2;
+fun f() { later = 3; }
+var later;
+";
+        assert_eq!(warnings(source), [(3, "undeclared-global")]);
+    }
+
+    #[test]
+    fn warns_about_self_comparisons() {
+        let source = "\
+var x = 1;
+print x == x;
+print (x) < x;
+print x == 1;
+print x.y >= x.y;
+print f() == f();
+";
+        assert_eq!(
+            warnings(source),
+            [
+                (2, "self-comparison"),
+                (3, "self-comparison"),
+                (5, "self-comparison")
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_compile_errors() {
+        assert_eq!(
+            check("print this;", &Config::default()),
+            [(1, None, Severity::Error)]
+        );
+        assert_eq!(
+            check("var = ;", &Config::default()),
+            [(1, None, Severity::Error)]
+        );
+    }
+
+    #[test]
+    fn suppression_comments_silence_warnings() {
+        let source = "\
+var x = 1;
+print x == x; // lint: allow(self-comparison)
+// lint: allow
+print x == x;
+print x == x; // lint: allow(unused-variable, no-such-lint)
+print x == x; // not a suppression
+";
+        assert_eq!(
+            warnings(source),
+            [(5, "self-comparison"), (6, "self-comparison")]
+        );
+    }
+
+    #[test]
+    fn configuration_allows_and_denies_lints() {
+        let source = "\
+fun f(a) {
+  var b;
+  return a == a;
+}
+";
+        let allow = Config {
+            allow: HashSet::from([Lint::UnusedVariable]),
+            ..Config::default()
+        };
+        assert_eq!(
+            check(source, &allow),
+            [(3, Some("self-comparison"), Severity::Warning)]
+        );
+
+        let deny = Config {
+            deny: HashSet::from([Lint::SelfComparison]),
+            ..Config::default()
+        };
+        assert_eq!(
+            check(source, &deny),
+            [
+                (2, Some("unused-variable"), Severity::Warning),
+                (3, Some("self-comparison"), Severity::Error)
+            ]
+        );
+
+        let deny_warnings = Config {
+            deny_warnings: true,
+            ..Config::default()
+        };
+        assert!(
+            check(source, &deny_warnings)
+                .iter()
+                .all(|&(_, _, severity)| severity == Severity::Error)
+        );
+    }
+
+    #[test]
+    fn parses_lint_names() {
+        for lint in Lint::ALL {
+            assert_eq!(lint.name().parse::<Lint>(), Ok(lint));
+        }
+        assert!("unused".parse::<Lint>().is_err());
+    }
+}
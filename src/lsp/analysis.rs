@@ -0,0 +1,485 @@
+//! Analysis of a Lox document: the compiler's syntax and scoping errors, and at the token level,
+//! declarations, the scopes they live in, and which declaration each identifier refers to.
+
+use std::collections::HashMap;
+
+use crate::InterpretError;
+use crate::chunk::{ColumnNum, LineNum, Position};
+use crate::compiler::scanner::Scanner;
+use crate::compiler::token::{Token, TokenKind};
+use crate::compiler::{CompileError, analyze};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Variable,
+    Function,
+    Parameter,
+    Class,
+    Method,
+}
+
+impl SymbolKind {
+    pub fn describe(self) -> &'static str {
+        match self {
+            SymbolKind::Variable => "variable",
+            SymbolKind::Function => "function",
+            SymbolKind::Parameter => "parameter",
+            SymbolKind::Class => "class",
+            SymbolKind::Method => "method",
+        }
+    }
+}
+
+/// A span of a single line of source, in 1-based lines and character columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: LineNum,
+    pub column: ColumnNum,
+    pub len: usize,
+}
+
+impl Span {
+    fn of(token: &Token) -> Self {
+        Self {
+            line: token.line,
+            column: token.column,
+            len: token.lexeme.chars().count(),
+        }
+    }
+
+    fn contains(&self, line: LineNum, column: ColumnNum) -> bool {
+        let end = usize::try_from(self.column).unwrap_or(usize::MAX) + self.len;
+        self.line == line
+            && self.column <= column
+            && usize::try_from(column).is_ok_and(|column| column <= end)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Declaration {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub span: Span,
+    /// Index of the enclosing class or function declaration, if any
+    pub container: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub name: String,
+    pub span: Span,
+    /// Index into `declarations`, if the name could be resolved
+    pub declaration: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub start: Position,
+    /// Just past the offending token, which may span lines; `None` when the error applies to
+    /// the whole line
+    pub end: Option<Position>,
+    pub message: String,
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(error: &CompileError) -> Self {
+        Self {
+            start: error.position,
+            end: error.end,
+            message: error.message.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub declarations: Vec<Declaration>,
+    pub references: Vec<Reference>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// What the token stream expects next, used to recognise declarations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    Nothing,
+    VarName,
+    FunName,
+    ClassName,
+    /// Inside a parameter list; the payload is the function's declaration index
+    Params(usize),
+}
+
+struct Scope {
+    names: HashMap<String, usize>,
+    /// Index of the function or class this block is the body of
+    owner: Option<usize>,
+    is_class_body: bool,
+    /// Set for the scope of a `for` loop's initializer, which lasts until the loop's body ends
+    for_loop: Option<ForLoop>,
+}
+
+impl Scope {
+    fn new(owner: Option<usize>, is_class_body: bool) -> Self {
+        Self {
+            names: HashMap::new(),
+            owner,
+            is_class_body,
+            for_loop: None,
+        }
+    }
+
+    /// Whether this is the scope of a `for` loop whose body is being read.
+    fn is_for_body(&self) -> bool {
+        self.for_loop.is_some_and(|l| !l.in_clauses)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ForLoop {
+    /// How many parentheses were open before the loop's own
+    parens: usize,
+    /// Whether the clauses between the parentheses are being read, rather than the body
+    in_clauses: bool,
+}
+
+impl Analysis {
+    pub fn new(source: &str) -> Self {
+        let mut analysis = Self::default();
+
+        // Errors come from the compiler's own parser and resolver, so the editor reports exactly
+        // what running the program would
+        if let Err(InterpretError::Compiler(errors)) = analyze(source) {
+            analysis.diagnostics = errors.iter().map(Diagnostic::from).collect();
+        }
+
+        // Navigation works on the tokens, so it keeps working while the document doesn't parse
+        let tokens: Vec<_> = Scanner::new(source).filter_map(Result::ok).collect();
+        analysis.resolve(&tokens);
+        analysis
+    }
+
+    fn resolve(&mut self, tokens: &[Token]) {
+        let mut scopes = vec![Scope::new(None, false)];
+
+        // Parameters are declared before the body's brace opens its scope
+        let mut pending_params: Vec<usize> = Vec::new();
+        // Function or class whose body the next `{` opens
+        let mut pending_owner: Option<(usize, bool)> = None;
+        let mut expect = Expect::Nothing;
+        let mut parens = 0;
+
+        for (i, token) in tokens.iter().enumerate() {
+            let previous = i.checked_sub(1).map(|p| &tokens[p].kind);
+            let next = tokens.get(i + 1).map(|t| &t.kind);
+
+            track_for_loops(&mut scopes, &mut parens, &token.kind, previous);
+
+            match (&token.kind, expect) {
+                (TokenKind::Var, _) => expect = Expect::VarName,
+                (TokenKind::Fun, _) => expect = Expect::FunName,
+                (TokenKind::Class, _) => expect = Expect::ClassName,
+
+                (TokenKind::Identifier(name), Expect::VarName) => {
+                    self.declare(&mut scopes, name, SymbolKind::Variable, token);
+                    expect = Expect::Nothing;
+                }
+
+                (TokenKind::Identifier(name), Expect::FunName) => {
+                    let decl = self.declare(&mut scopes, name, SymbolKind::Function, token);
+                    expect = Expect::Params(decl);
+                }
+
+                (TokenKind::Identifier(name), Expect::ClassName) => {
+                    let decl = self.declare(&mut scopes, name, SymbolKind::Class, token);
+                    pending_owner = Some((decl, true));
+                    expect = Expect::Nothing;
+                }
+
+                (TokenKind::Identifier(name), Expect::Params(function)) => {
+                    let decl = self.push_declaration(name, SymbolKind::Parameter, token, &scopes);
+                    self.declarations[decl].container = Some(function);
+                    pending_params.push(decl);
+                }
+
+                (TokenKind::RParen, Expect::Params(function)) => {
+                    pending_owner = Some((function, false));
+                    expect = Expect::Nothing;
+                }
+
+                (TokenKind::LParen | TokenKind::Comma, Expect::Params(_)) => {}
+
+                // A method: `name (` directly inside a class body
+                (TokenKind::Identifier(name), _)
+                    if scopes.last().is_some_and(|s| s.is_class_body)
+                        && matches!(next, Some(TokenKind::LParen)) =>
+                {
+                    let decl = self.push_declaration(name, SymbolKind::Method, token, &scopes);
+                    expect = Expect::Params(decl);
+                }
+
+                (TokenKind::Identifier(name), _) => {
+                    // Property accesses are resolved at runtime, not lexically
+                    if !matches!(previous, Some(TokenKind::Dot)) {
+                        let declaration = lookup(&scopes, name);
+                        self.references.push(Reference {
+                            name: (*name).to_string(),
+                            span: Span::of(token),
+                            declaration,
+                        });
+                    }
+                }
+
+                (TokenKind::LCurly, _) => {
+                    let (owner, is_class_body) = pending_owner
+                        .take()
+                        .map_or((None, false), |(o, c)| (Some(o), c));
+
+                    let mut scope = Scope::new(owner, is_class_body);
+
+                    for param in pending_params.drain(..) {
+                        let name = self.declarations[param].name.clone();
+                        scope.names.insert(name, param);
+                    }
+
+                    scopes.push(scope);
+                    expect = Expect::Nothing;
+                }
+
+                (TokenKind::RCurly, _) => {
+                    // Close any loop left open inside the block, the block, and then any loops
+                    // the block was the body of
+                    while scopes.last().is_some_and(|s| s.for_loop.is_some()) {
+                        scopes.pop();
+                    }
+                    if scopes.len() > 1 {
+                        scopes.pop();
+                    }
+                    while scopes.last().is_some_and(Scope::is_for_body) {
+                        scopes.pop();
+                    }
+                }
+
+                _ => expect = Expect::Nothing,
+            }
+        }
+
+        // Globals are late bound, so uses that precede a global declaration still refer to it
+        let globals = &scopes[0].names;
+        for reference in &mut self.references {
+            if reference.declaration.is_none() {
+                reference.declaration = globals.get(&reference.name).copied();
+            }
+        }
+    }
+
+    fn push_declaration(
+        &mut self,
+        name: &str,
+        kind: SymbolKind,
+        token: &Token,
+        scopes: &[Scope],
+    ) -> usize {
+        let container = scopes.iter().rev().find_map(|s| s.owner);
+        self.declarations.push(Declaration {
+            name: name.to_string(),
+            kind,
+            span: Span::of(token),
+            container,
+        });
+
+        self.declarations.len() - 1
+    }
+
+    fn declare(
+        &mut self,
+        scopes: &mut [Scope],
+        name: &str,
+        kind: SymbolKind,
+        token: &Token,
+    ) -> usize {
+        let decl = self.push_declaration(name, kind, token, scopes);
+
+        let scope = scopes.last_mut().expect("The global scope is never popped");
+        scope.names.insert(name.to_string(), decl);
+
+        decl
+    }
+
+    /// The declaration or reference at a 1-based line and character column, as the index of
+    /// the declaration it names.
+    pub fn declaration_at(&self, line: LineNum, column: ColumnNum) -> Option<usize> {
+        if let Some(i) = self
+            .declarations
+            .iter()
+            .position(|d| d.span.contains(line, column))
+        {
+            return Some(i);
+        }
+
+        self.references
+            .iter()
+            .find(|r| r.span.contains(line, column))
+            .and_then(|r| r.declaration)
+    }
+
+    /// Every span naming the declaration `decl`, starting with the declaration itself.
+    pub fn occurrences(&self, decl: usize) -> impl Iterator<Item = Span> + '_ {
+        std::iter::once(self.declarations[decl].span).chain(
+            self.references
+                .iter()
+                .filter(move |r| r.declaration == Some(decl))
+                .map(|r| r.span),
+        )
+    }
+}
+
+/// Open and close the scopes of `for` loops, whose clauses and body are a scope of their own
+/// even without braces. `parens` counts the parentheses open before `token`.
+fn track_for_loops(
+    scopes: &mut Vec<Scope>,
+    parens: &mut usize,
+    token: &TokenKind,
+    previous: Option<&TokenKind>,
+) {
+    match token {
+        TokenKind::LParen => {
+            if matches!(previous, Some(TokenKind::For)) {
+                let mut scope = Scope::new(None, false);
+                scope.for_loop = Some(ForLoop {
+                    parens: *parens,
+                    in_clauses: true,
+                });
+                scopes.push(scope);
+            }
+            *parens += 1;
+        }
+
+        TokenKind::RParen => {
+            *parens = parens.saturating_sub(1);
+            if let Some(for_loop) = scopes.last_mut().and_then(|s| s.for_loop.as_mut())
+                && for_loop.in_clauses
+                && for_loop.parens == *parens
+            {
+                for_loop.in_clauses = false;
+            }
+        }
+
+        // The end of a body that is a single statement
+        TokenKind::Semicolon => {
+            while scopes.last().is_some_and(|s| {
+                s.for_loop
+                    .is_some_and(|l| !l.in_clauses && l.parens == *parens)
+            }) {
+                scopes.pop();
+            }
+        }
+
+        _ => {}
+    }
+}
+
+fn lookup(scopes: &[Scope], name: &str) -> Option<usize> {
+    // The global scope is handled after the whole document has been seen
+    scopes[1..]
+        .iter()
+        .rev()
+        .find_map(|scope| scope.names.get(name).copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostics(source: &str) -> Vec<(Position, Option<Position>, String)> {
+        Analysis::new(source)
+            .diagnostics
+            .into_iter()
+            .map(|d| (d.start, d.end, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        assert_eq!(
+            diagnostics("var = ;"),
+            [(
+                Position::new(1, 5),
+                Some(Position::new(1, 6)),
+                "Expect variable name.".to_string()
+            )]
+        );
+        assert_eq!(
+            diagnostics("print 1"),
+            [(
+                Position::new(1, 8),
+                Some(Position::new(1, 8)),
+                "Expect ';' after value.".to_string()
+            )]
+        );
+        assert!(diagnostics("print 1;").is_empty());
+    }
+
+    #[test]
+    fn spans_tokens_over_several_lines() {
+        let errors = diagnostics("var \"ab\ncdé\" = 1;");
+        assert_eq!(
+            (errors[0].0, errors[0].1),
+            (Position::new(1, 5), Some(Position::new(2, 5)))
+        );
+    }
+
+    #[test]
+    fn reports_scoping_errors() {
+        let errors = diagnostics("{\n  var a = 1;\n  var a = 2;\n}\nreturn 3;");
+        let spans: Vec<_> = errors
+            .iter()
+            .map(|(start, end, _)| (*start, *end))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (Position::new(3, 7), Some(Position::new(3, 8))),
+                (Position::new(5, 1), Some(Position::new(5, 7))),
+            ]
+        );
+    }
+
+    fn declaration_names(analysis: &Analysis, line: LineNum, column: ColumnNum) -> Option<&str> {
+        let decl = analysis.declaration_at(line, column)?;
+        Some(&analysis.declarations[decl].name)
+    }
+
+    #[test]
+    fn scopes_for_loop_variables_to_the_loop() {
+        let analysis = Analysis::new(
+            "\
+var i = \"outer\";
+for (var i = 0; i < 2; i = i + 1) print i;
+print i;
+for (var i = 0; i < 2; i = i + 1) { print i; }
+print i;
+for (var i = 0; i < 2; i = i + 1) for (var j = 0; j < i; j = j + 1) print i + j;
+print i;",
+        );
+
+        let outer = analysis.declaration_at(1, 5);
+        for line in [3, 5, 7] {
+            assert_eq!(analysis.declaration_at(line, 7), outer, "line {line}");
+        }
+
+        let first = analysis.declaration_at(2, 10);
+        assert_ne!(first, outer);
+        assert_eq!(analysis.declaration_at(2, 17), first);
+        assert_eq!(analysis.declaration_at(2, 41), first);
+        assert_ne!(analysis.declaration_at(4, 43), outer);
+        assert_eq!(declaration_names(&analysis, 6, 79), Some("j"));
+        assert_ne!(analysis.declaration_at(6, 75), outer);
+    }
+
+    #[test]
+    fn resolves_names_in_documents_that_do_not_parse() {
+        let analysis = Analysis::new("fun f(a) {\n  print a +\n}");
+        assert!(!analysis.diagnostics.is_empty());
+        assert_eq!(analysis.declaration_at(2, 9), Some(1));
+    }
+}
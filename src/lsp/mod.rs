@@ -0,0 +1,612 @@
+//! A Language Server Protocol server for Lox, speaking JSON-RPC over stdin and stdout.
+//!
+//! Documents are synchronised in full on every change and re-analysed from scratch: the compiler
+//! parses and resolves them for diagnostics, and navigation works on their tokens.
+
+mod analysis;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{Value as Json, json};
+
+use analysis::{Analysis, Span, SymbolKind};
+
+use crate::chunk::{ColumnNum, LineNum, Position};
+use crate::compiler::scanner::keywords;
+use crate::protocol::{read_message, write_message};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+struct Server<W: Write> {
+    out: W,
+    documents: HashMap<String, Document>,
+}
+
+/// Serve LSP requests from `input`, writing responses and notifications to `out`, until the
+/// client sends `exit` or the input ends.
+pub fn serve(input: &mut impl BufRead, out: impl Write) -> io::Result<()> {
+    let mut server = Server {
+        out,
+        documents: HashMap::new(),
+    };
+
+    while let Some(message) = read_message(input)? {
+        let Some(method) = message["method"].as_str() else {
+            // Responses to requests we never send
+            continue;
+        };
+
+        if method == "exit" {
+            break;
+        }
+
+        let params = &message["params"];
+        match message.get("id") {
+            Some(id) => server.request(id, method, params)?,
+            None => server.notification(method, params)?,
+        }
+    }
+
+    Ok(())
+}
+
+impl<W: Write> Server<W> {
+    fn respond(&mut self, id: &Json, result: Json) -> io::Result<()> {
+        let mut response = json!({ "jsonrpc": "2.0", "id": id });
+        response["result"] = result;
+
+        write_message(&mut self.out, &response)
+    }
+
+    fn fail(&mut self, id: &Json, code: i64, message: &str) -> io::Result<()> {
+        let response = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        });
+
+        write_message(&mut self.out, &response)
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        let mut notification = json!({ "jsonrpc": "2.0", "method": method });
+        notification["params"] = params;
+
+        write_message(&mut self.out, &notification)
+    }
+
+    fn request(&mut self, id: &Json, method: &str, params: &Json) -> io::Result<()> {
+        let result = match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    // Full document sync
+                    "textDocumentSync": 1,
+                    "documentSymbolProvider": true,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": "rlox", "version": env!("CARGO_PKG_VERSION") },
+            })),
+
+            "shutdown" => Some(Json::Null),
+
+            "textDocument/documentSymbol" => {
+                self.document(params).map(|doc| document_symbols(doc, None))
+            }
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => self.document(params).map(completions),
+
+            _ => return self.fail(id, METHOD_NOT_FOUND, &format!("Unknown method '{method}'")),
+        };
+
+        match result {
+            Some(result) => self.respond(id, result),
+            None => self.fail(id, INVALID_PARAMS, "Unknown document"),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.update(uri, text.to_string())
+            }
+
+            "textDocument/didChange" => {
+                // With full sync, the last change holds the whole document
+                let changes = params["contentChanges"].as_array();
+                match changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                {
+                    Some(text) => self.update(uri, text.to_string()),
+                    None => Ok(()),
+                }
+            }
+
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.notify(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                )
+            }
+
+            _ => Ok(()),
+        }
+    }
+
+    fn update(&mut self, uri: &str, text: String) -> io::Result<()> {
+        let analysis = Analysis::new(&text);
+        let document = Document { text, analysis };
+
+        let diagnostics: Vec<_> = document
+            .analysis
+            .diagnostics
+            .iter()
+            .map(|d| {
+                let range = match d.end {
+                    Some(end) => json!({
+                        "start": to_lsp(&document.text, d.start),
+                        "end": to_lsp(&document.text, end),
+                    }),
+                    None => line_range(&document.text, d.start.line),
+                };
+
+                json!({
+                    "range": range,
+                    // Error
+                    "severity": 1,
+                    "source": "rlox",
+                    "message": d.message,
+                })
+            })
+            .collect();
+
+        self.documents.insert(uri.to_string(), document);
+        self.notify(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        )
+    }
+
+    fn document(&self, params: &Json) -> Option<&Document> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        self.documents.get(uri)
+    }
+
+    /// The document and the declaration named at the request's cursor position.
+    fn target(&self, params: &Json) -> Option<(&Document, Option<usize>)> {
+        let document = self.document(params)?;
+        let (line, column) = from_lsp(&document.text, &params["position"])?;
+
+        Some((document, document.analysis.declaration_at(line, column)))
+    }
+
+    fn definition(&self, params: &Json) -> Option<Json> {
+        let uri = &params["textDocument"]["uri"];
+        let (document, decl) = self.target(params)?;
+
+        let result = decl.map_or(Json::Null, |decl| {
+            let span = document.analysis.declarations[decl].span;
+            json!({ "uri": uri, "range": range(&document.text, span) })
+        });
+
+        Some(result)
+    }
+
+    fn references(&self, params: &Json) -> Option<Json> {
+        let uri = &params["textDocument"]["uri"];
+        let include_declaration = params["context"]["includeDeclaration"]
+            .as_bool()
+            .unwrap_or(true);
+        let (document, decl) = self.target(params)?;
+
+        let Some(decl) = decl else {
+            return Some(json!([]));
+        };
+
+        let skip = usize::from(!include_declaration);
+        let locations: Vec<_> = document
+            .analysis
+            .occurrences(decl)
+            .skip(skip)
+            .map(|span| json!({ "uri": uri, "range": range(&document.text, span) }))
+            .collect();
+
+        Some(json!(locations))
+    }
+
+    fn hover(&self, params: &Json) -> Option<Json> {
+        let (document, decl) = self.target(params)?;
+
+        let result = decl.map_or(Json::Null, |decl| {
+            let decl = &document.analysis.declarations[decl];
+            let mut value = format!("({}) {}", decl.kind.describe(), decl.name);
+            if let Some(container) = decl.container {
+                let container = &document.analysis.declarations[container];
+                value = format!(
+                    "{value} in {} {}",
+                    container.kind.describe(),
+                    container.name
+                );
+            }
+
+            json!({
+                "contents": { "kind": "markdown", "value": format!("```lox\n{value}\n```") },
+                "range": range(&document.text, decl.span),
+            })
+        });
+
+        Some(result)
+    }
+}
+
+/// Hierarchical symbols for the functions, classes and methods declared in `container`.
+fn document_symbols(document: &Document, container: Option<usize>) -> Json {
+    let symbols: Vec<_> = document
+        .analysis
+        .declarations
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| d.container == container)
+        .filter_map(|(i, d)| {
+            // LSP SymbolKind values
+            let kind = match d.kind {
+                SymbolKind::Function => 12,
+                SymbolKind::Class => 5,
+                SymbolKind::Method => 6,
+                SymbolKind::Variable | SymbolKind::Parameter => return None,
+            };
+
+            let range = range(&document.text, d.span);
+            Some(json!({
+                "name": d.name,
+                "kind": kind,
+                "range": range,
+                "selectionRange": range,
+                "children": document_symbols(document, Some(i)),
+            }))
+        })
+        .collect();
+
+    json!(symbols)
+}
+
+fn completions(document: &Document) -> Json {
+    // LSP CompletionItemKind values
+    const KEYWORD: i64 = 14;
+
+    let mut items: Vec<_> = keywords()
+        .map(|k| json!({ "label": k, "kind": KEYWORD }))
+        .collect();
+
+    let mut seen = std::collections::HashSet::new();
+    for decl in &document.analysis.declarations {
+        if !seen.insert(&decl.name) {
+            continue;
+        }
+
+        let kind = match decl.kind {
+            SymbolKind::Variable | SymbolKind::Parameter => 6,
+            SymbolKind::Function => 3,
+            SymbolKind::Class => 7,
+            SymbolKind::Method => 2,
+        };
+
+        items.push(json!({
+            "label": decl.name,
+            "kind": kind,
+            "detail": decl.kind.describe(),
+        }));
+    }
+
+    json!(items)
+}
+
+/// Convert a 0-based LSP position with a UTF-16 character offset to a 1-based line and
+/// character column.
+fn from_lsp(text: &str, position: &Json) -> Option<(LineNum, ColumnNum)> {
+    let line = position["line"].as_u64()?;
+    let character = position["character"].as_u64()?;
+
+    let line_text = text
+        .lines()
+        .nth(usize::try_from(line).ok()?)
+        .unwrap_or_default();
+
+    let mut units = 0;
+    let mut column = 1;
+    for ch in line_text.chars() {
+        if units >= character {
+            break;
+        }
+
+        units += ch.len_utf16() as u64;
+        column += 1;
+    }
+
+    Some((LineNum::try_from(line + 1).ok()?, column))
+}
+
+/// Convert a 1-based line and character column to a 0-based LSP position with a UTF-16
+/// character offset.
+fn to_lsp(text: &str, position: Position) -> Json {
+    let skip = usize::try_from(position.column.saturating_sub(1)).unwrap_or(usize::MAX);
+    let character: usize = line_text(text, position.line)
+        .chars()
+        .take(skip)
+        .map(char::len_utf16)
+        .sum();
+
+    json!({ "line": position.line.saturating_sub(1), "character": character })
+}
+
+fn range(text: &str, span: Span) -> Json {
+    let start = Position::new(span.line, span.column);
+    let end = ColumnNum::try_from(span.len).map_or(start, |len| {
+        Position::new(span.line, span.column.saturating_add(len))
+    });
+
+    json!({ "start": to_lsp(text, start), "end": to_lsp(text, end) })
+}
+
+fn line_range(text: &str, line: LineNum) -> Json {
+    let len: usize = line_text(text, line).chars().map(char::len_utf16).sum();
+    let line = line.saturating_sub(1);
+
+    json!({
+        "start": { "line": line, "character": 0 },
+        "end": { "line": line, "character": len },
+    })
+}
+
+fn line_text(text: &str, line: LineNum) -> &str {
+    usize::try_from(line.saturating_sub(1))
+        .ok()
+        .and_then(|i| text.lines().nth(i))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const URI: &str = "file:///test.lox";
+
+    const SOURCE: &str = "\
+class Point {
+  init(x) {
+    this.x = x;
+  }
+}
+fun area(size) {
+  var π = 3;
+  return π * size * size;
+}
+var s = \"😀\"; print s + area(2);
+";
+
+    /// Open `text` and send each request in turn, returning every message the server wrote.
+    fn session(text: &str, requests: &[(&str, Json)]) -> Vec<Json> {
+        let mut input = Vec::new();
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "text": text } },
+        });
+        write_message(&mut input, &open).unwrap();
+
+        for (id, (method, params)) in requests.iter().enumerate() {
+            let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+            write_message(&mut input, &request).unwrap();
+        }
+        write_message(&mut input, &json!({ "jsonrpc": "2.0", "method": "exit" })).unwrap();
+
+        let mut out = Vec::new();
+        serve(&mut Cursor::new(input), &mut out).unwrap();
+
+        let mut out = Cursor::new(out);
+        std::iter::from_fn(|| read_message(&mut out).unwrap()).collect()
+    }
+
+    /// The result of a single request about `SOURCE`.
+    fn request(method: &str, params: Json) -> Json {
+        let mut params = params;
+        params["textDocument"] = json!({ "uri": URI });
+
+        let messages = session(SOURCE, &[(method, params)]);
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        messages[1]["result"].clone()
+    }
+
+    fn at(line: u64, character: u64) -> Json {
+        json!({ "position": { "line": line, "character": character } })
+    }
+
+    fn location(line: u64, start: u64, end: u64) -> Json {
+        json!({
+            "uri": URI,
+            "range": {
+                "start": { "line": line, "character": start },
+                "end": { "line": line, "character": end },
+            },
+        })
+    }
+
+    #[test]
+    fn publishes_diagnostics_in_utf16_ranges() {
+        let messages = session("var \"😀\nab\" = 1;\nprint 1;", &[]);
+        assert_eq!(messages[0]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(
+            messages[0]["params"]["diagnostics"],
+            json!([{
+                "range": {
+                    "start": { "line": 0, "character": 4 },
+                    "end": { "line": 1, "character": 3 },
+                },
+                "severity": 1,
+                "source": "rlox",
+                "message": "Expect variable name.",
+            }])
+        );
+
+        let messages = session(SOURCE, &[]);
+        assert_eq!(messages[0]["params"]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn finds_definitions() {
+        assert_eq!(
+            request("textDocument/definition", at(7, 9)),
+            location(6, 6, 7)
+        );
+
+        // The emoji before the reference takes two UTF-16 code units
+        assert_eq!(
+            request("textDocument/definition", at(9, 20)),
+            location(9, 4, 5)
+        );
+
+        assert_eq!(request("textDocument/definition", at(9, 8)), Json::Null);
+    }
+
+    #[test]
+    fn finds_references() {
+        let mut params = at(5, 10);
+        assert_eq!(
+            request("textDocument/references", params.clone()),
+            json!([location(5, 9, 13), location(7, 13, 17), location(7, 20, 24)])
+        );
+
+        params["context"] = json!({ "includeDeclaration": false });
+        assert_eq!(
+            request("textDocument/references", params),
+            json!([location(7, 13, 17), location(7, 20, 24)])
+        );
+
+        assert_eq!(request("textDocument/references", at(4, 0)), json!([]));
+    }
+
+    #[test]
+    fn describes_declarations_on_hover() {
+        let hover = request("textDocument/hover", at(2, 13));
+        assert_eq!(
+            hover["contents"]["value"],
+            "```lox\n(parameter) x in method init\n```"
+        );
+        assert_eq!(hover["range"], location(1, 7, 8)["range"]);
+
+        let hover = request("textDocument/hover", at(9, 26));
+        assert_eq!(hover["contents"]["value"], "```lox\n(function) area\n```");
+
+        // Properties are not resolved lexically
+        assert_eq!(request("textDocument/hover", at(2, 9)), Json::Null);
+    }
+
+    #[test]
+    fn completes_keywords_and_declared_names() {
+        let items = request("textDocument/completion", json!({}));
+        let items = items.as_array().unwrap();
+        let kind = |label: &str| {
+            let matching: Vec<_> = items.iter().filter(|i| i["label"] == label).collect();
+            assert_eq!(matching.len(), 1, "{label}");
+            matching[0]["kind"].clone()
+        };
+
+        assert_eq!(kind("while"), 14);
+        assert_eq!(kind("Point"), 7);
+        assert_eq!(kind("init"), 2);
+        assert_eq!(kind("area"), 3);
+        assert_eq!(kind("size"), 6);
+        assert_eq!(kind("π"), 6);
+    }
+
+    #[test]
+    fn lists_document_symbols() {
+        let range = |line: u64, start: u64, end: u64| location(line, start, end)["range"].clone();
+        assert_eq!(
+            request("textDocument/documentSymbol", json!({})),
+            json!([
+                {
+                    "name": "Point",
+                    "kind": 5,
+                    "range": range(0, 6, 11),
+                    "selectionRange": range(0, 6, 11),
+                    "children": [{
+                        "name": "init",
+                        "kind": 6,
+                        "range": range(1, 2, 6),
+                        "selectionRange": range(1, 2, 6),
+                        "children": [],
+                    }],
+                },
+                {
+                    "name": "area",
+                    "kind": 12,
+                    "range": range(5, 4, 8),
+                    "selectionRange": range(5, 4, 8),
+                    "children": [],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_methods_and_documents() {
+        let params = json!({ "textDocument": { "uri": "file:///other.lox" } });
+        let messages = session(
+            SOURCE,
+            &[
+                ("textDocument/rename", json!({})),
+                ("textDocument/hover", params),
+            ],
+        );
+
+        assert_eq!(messages[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(messages[2]["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[test]
+    fn converts_utf16_positions_to_columns() {
+        let text = "a😀b\nπx";
+        let column = |line: u64, character: u64| {
+            from_lsp(text, &json!({ "line": line, "character": character }))
+        };
+
+        assert_eq!(column(0, 0), Some((1, 1)));
+        assert_eq!(column(0, 1), Some((1, 2)));
+        assert_eq!(column(0, 3), Some((1, 3)));
+        assert_eq!(column(1, 1), Some((2, 2)));
+        // Past the end of the line
+        assert_eq!(column(0, 10), Some((1, 4)));
+        assert_eq!(column(0, u64::MAX), Some((1, 4)));
+        assert_eq!(from_lsp(text, &json!({ "line": 0 })), None);
+
+        let span = Span {
+            line: 1,
+            column: 3,
+            len: 1,
+        };
+        assert_eq!(range(text, span), location(0, 3, 4)["range"]);
+        assert_eq!(
+            to_lsp(text, Position::new(2, 3)),
+            json!({ "line": 1, "character": 2 })
+        );
+    }
+}
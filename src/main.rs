@@ -1,42 +1,331 @@
 #![warn(clippy::all, clippy::pedantic)]
 
-mod chunk;
-mod compiler; // TODO: Move?
-mod vm;
+mod bench;
 
-use std::fs::read_to_string;
-use std::io::{self, BufRead, Write, stdin, stdout};
-use std::path::PathBuf;
+use std::fs::{File, read, read_to_string};
+use std::io::{self, BufRead, BufWriter, Write, stdin, stdout};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use thiserror::Error;
 
-use vm::VM;
-
-const USIZE_SIZE: usize = std::mem::size_of::<usize>();
+use rlox::{
+    AsmError, BytecodeError, Chunk, CompileError, DEFAULT_FORMAT_WIDTH, Debugger, InterpretError,
+    InterpretResult, LineRange, Lint, LintConfig, Profiler, RegisterVM, Severity, TraceFormat,
+    Tracer, VM,
+};
 
 #[derive(Debug, Parser)]
 #[command(name = "rlox", author = "UserOfNames", version, about)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
-    #[arg(help = "Path to the file to interpret")]
+    #[arg(help = "Path to the file to interpret (source or compiled .loxc)")]
     path: Option<PathBuf>,
+
+    #[arg(long, help = "Print the bytecode for PATH instead of running it")]
+    disassemble: bool,
+
+    #[arg(
+        long,
+        requires = "disassemble",
+        help = "Disassemble as assembly source accepted by `rlox asm`"
+    )]
+    asm: bool,
+
+    #[arg(
+        short = 'O',
+        long = "optimize",
+        help = "Run the peephole optimizer over the compiled bytecode"
+    )]
+    optimize: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Backend::Stack,
+        conflicts_with_all = ["optimize", "asm", "trace", "trace_output", "trace_lines", "trace_fn", "profile", "profile_folded"],
+        help = "Virtual machine to compile for and run on"
+    )]
+    backend: Backend,
+
+    #[command(flatten)]
+    trace: TraceArgs,
+
+    #[command(flatten)]
+    profile: ProfileArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Backend {
+    /// The stack-based VM, which runs `.loxc` files and supports tracing, profiling and
+    /// optimization
+    Stack,
+    /// The register-based VM, which only runs source files
+    Register,
+}
+
+#[derive(Debug, clap::Args)]
+struct TraceArgs {
+    #[arg(long, help = "Trace each executed instruction and the stack")]
+    trace: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the trace to FILE instead of stderr"
+    )]
+    trace_output: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = TraceFormat::Text)]
+    trace_format: TraceFormat,
+
+    #[arg(
+        long,
+        value_name = "START-END",
+        help = "Only trace instructions on these source lines (repeatable)"
+    )]
+    trace_lines: Vec<LineRange>,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Only trace instructions in function NAME, or `script` for top-level code (repeatable)"
+    )]
+    trace_fn: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct ProfileArgs {
+    #[arg(
+        long,
+        help = "Print per-opcode, per-function and per-line statistics to stderr on exit"
+    )]
+    profile: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write time per call stack and line in folded-stack format for flamegraph tools"
+    )]
+    profile_folded: Option<PathBuf>,
+}
+
+impl ProfileArgs {
+    fn enabled(&self) -> bool {
+        self.profile || self.profile_folded.is_some()
+    }
+
+    fn finish(&self, profiler: &Profiler) -> io::Result<()> {
+        if self.profile {
+            eprint!("{}", profiler.report());
+        }
+
+        if let Some(p) = &self.profile_folded {
+            let mut out = BufWriter::new(File::create(p)?);
+            profiler.write_folded(&mut out)?;
+            out.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TraceArgs {
+    fn tracer(&self) -> io::Result<Option<Tracer>> {
+        let enabled = self.trace
+            || self.trace_output.is_some()
+            || !self.trace_lines.is_empty()
+            || !self.trace_fn.is_empty();
+        if !enabled {
+            return Ok(None);
+        }
+
+        let out: Box<dyn Write> = match &self.trace_output {
+            Some(p) => Box::new(BufWriter::new(File::create(p)?)),
+            None => Box::new(io::stderr()),
+        };
+
+        let tracer = self
+            .trace_lines
+            .iter()
+            .fold(Tracer::new(out, self.trace_format), |t, &r| t.with_lines(r));
+        let tracer = self
+            .trace_fn
+            .iter()
+            .fold(tracer, |t, name| t.with_function(name.clone()));
+
+        Ok(Some(tracer))
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compile a source file to a .loxc bytecode file
+    Compile {
+        #[arg(help = "Path to the source file")]
+        input: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Output path (defaults to the input with a .loxc extension)"
+        )]
+        output: Option<PathBuf>,
+        #[arg(
+            short = 'O',
+            long = "optimize",
+            help = "Run the peephole optimizer over the compiled bytecode"
+        )]
+        optimize: bool,
+    },
+
+    /// Assemble and run a bytecode assembly file
+    Asm {
+        #[arg(help = "Path to the assembly file")]
+        input: PathBuf,
+        #[arg(short, long, help = "Write a .loxc file instead of running the chunk")]
+        output: Option<PathBuf>,
+    },
+
+    /// Run a program under the interactive debugger
+    Debug {
+        #[arg(help = "Path to the file to debug (source or compiled .loxc)")]
+        input: PathBuf,
+    },
+
+    /// Serve the Debug Adapter Protocol over stdin and stdout
+    Dap,
+
+    /// Serve the Language Server Protocol over stdin and stdout
+    Lsp,
+
+    /// Check source files for likely mistakes
+    Lint {
+        #[arg(required = true, help = "Paths to the source files")]
+        inputs: Vec<PathBuf>,
+        #[arg(
+            long,
+            value_name = "LINT",
+            help = "Report LINT as an error (repeatable)"
+        )]
+        deny: Vec<Lint>,
+        #[arg(long, value_name = "LINT", help = "Do not check LINT (repeatable)")]
+        allow: Vec<Lint>,
+        #[arg(long, help = "Report every warning as an error")]
+        deny_warnings: bool,
+    },
+
+    /// Run scripts through both the tree-walking interpreter and the VM and compare the results
+    Difftest {
+        #[arg(
+            required = true,
+            help = "Paths to the scripts, or directories to search for .lox files"
+        )]
+        inputs: Vec<PathBuf>,
+    },
+
+    /// Run benchmark scripts repeatedly and report their timings and instruction counts
+    Bench {
+        #[arg(
+            default_value = "bench",
+            help = "Paths to the scripts, or directories to search for .lox files"
+        )]
+        inputs: Vec<PathBuf>,
+        #[arg(
+            short = 'n',
+            long,
+            default_value_t = 5,
+            value_parser = clap::value_parser!(u32).range(1..),
+            help = "Number of times to run each script"
+        )]
+        runs: u32,
+        #[arg(
+            short = 'O',
+            long = "optimize",
+            help = "Run the peephole optimizer over the compiled bytecode"
+        )]
+        optimize: bool,
+        #[arg(
+            long,
+            value_enum,
+            default_value_t = Backend::Stack,
+            conflicts_with = "optimize",
+            help = "Virtual machine to run the scripts on"
+        )]
+        backend: Backend,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Compare the results with a saved baseline"
+        )]
+        baseline: Option<PathBuf>,
+        #[arg(long, value_name = "FILE", help = "Save the results as a baseline")]
+        save: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "PERCENT",
+            default_value_t = 10.0,
+            help = "How much slower than the baseline a script may get before it counts as a regression"
+        )]
+        threshold: f64,
+    },
+
+    /// Rewrite source files in the canonical layout
+    Fmt {
+        #[arg(required = true, help = "Paths to the source files")]
+        inputs: Vec<PathBuf>,
+        #[arg(
+            long,
+            help = "Report files that are not formatted instead of rewriting them"
+        )]
+        check: bool,
+        #[arg(long, default_value_t = DEFAULT_FORMAT_WIDTH, help = "Maximum line width")]
+        width: usize,
+    },
 }
 
+/// Errors of the `rlox` commands, on top of those of running programs.
 #[derive(Debug, Error)]
-pub enum InterpretError {
-    #[error("Compiler error")]
-    Compiler,
-    #[error("Runtime error")]
-    Runtime,
-    #[error("IO error: {0}")]
-    Io(#[from] io::Error),
+enum CliError {
+    #[error(transparent)]
+    Interpret(#[from] InterpretError),
+    #[error("{}", errors.iter().map(|e| format!("{path}: {e}")).collect::<Vec<_>>().join("\n"))]
+    Format {
+        path: String,
+        errors: Vec<CompileError>,
+    },
+    #[error("{count} lint error(s)")]
+    LintErrors { count: usize },
+    #[error("{count} script(s) behaved differently in the tree-walker and the VM")]
+    Diverged { count: usize },
+    #[error("{count} benchmark(s) regressed against the baseline")]
+    Regressed { count: usize },
+    #[error("Compiled bytecode can only be run by the stack backend")]
+    BytecodeBackend,
+    #[error("{count} file(s) would be reformatted")]
+    Unformatted { count: usize },
 }
 
-type InterpretResult<T> = Result<T, InterpretError>;
+macro_rules! impl_from {
+    ($($type:ty),* $(,)?) => {
+        $(
+            impl From<$type> for CliError {
+                fn from(error: $type) -> Self {
+                    CliError::Interpret(error.into())
+                }
+            }
+        )*
+    };
+}
 
-fn repl() {
-    let mut vm = VM::new();
+impl_from!(io::Error, AsmError, BytecodeError);
 
+type CliResult<T> = Result<T, CliError>;
+
+fn repl(mut interpret: impl FnMut(&str) -> InterpretResult<()>) {
     let mut input = String::new();
     let mut stdin = stdin().lock();
 
@@ -45,37 +334,300 @@ fn repl() {
         stdout().flush().expect("Could not flush stdout");
         input.clear();
 
-        let read_result = stdin.read_line(&mut input);
-        if let Err(e) = read_result {
-            eprintln!("Error reading input: {e}");
-            continue;
+        match stdin.read_line(&mut input) {
+            // End of input
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Error reading input: {e}");
+                continue;
+            }
         }
 
-        let interpret_result = vm.interpret(&std::mem::take(&mut input));
+        let interpret_result = interpret(&std::mem::take(&mut input));
         if let Err(e) = interpret_result {
             eprintln!("Error interpreting input: {e}");
         }
     }
 }
 
-fn run_file(p: PathBuf) -> InterpretResult<()> {
-    let mut vm = VM::new();
+fn run_file(vm: &mut VM, p: &Path, optimize: bool) -> CliResult<()> {
+    let chunk = rlox::load_chunk(p)?;
+
+    vm.interpret_chunk(optimized(chunk, optimize))?;
+
+    Ok(())
+}
+
+fn run_file_registers(p: &Path) -> CliResult<()> {
+    let source = read_register_source(p)?;
 
+    Ok(RegisterVM::new().interpret(&source)?)
+}
+
+/// Read the source file `p` for the register backend, which cannot run compiled bytecode.
+fn read_register_source(p: &Path) -> CliResult<String> {
+    let bytes = read(p)?;
+    if rlox::is_bytecode(&bytes) {
+        return Err(CliError::BytecodeBackend);
+    }
+
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+fn optimized(chunk: Chunk, optimize: bool) -> Chunk {
+    if optimize {
+        rlox::optimize(&chunk)
+    } else {
+        chunk
+    }
+}
+
+fn debug_file(p: &Path) -> CliResult<()> {
+    let chunk = rlox::load_chunk(p)?;
+    let source = read_to_string(p)
+        .ok()
+        .filter(|s| !rlox::is_bytecode(s.as_bytes()));
+
+    let mut debugger = Debugger::new(chunk, p.display().to_string(), source)?;
+    debugger.run(&mut stdin().lock())?;
+
+    Ok(())
+}
+
+fn disassemble_file(p: &Path, as_asm: bool, optimize: bool) -> CliResult<()> {
+    let chunk = optimized(rlox::load_chunk(p)?, optimize);
+    if as_asm {
+        print!("{}", rlox::to_assembly(&chunk));
+    } else {
+        print!("{}", chunk.disassemble(&p.display().to_string()));
+    }
+
+    Ok(())
+}
+
+fn disassemble_file_registers(p: &Path) -> CliResult<()> {
+    let chunk = rlox::compile_registers(&read_register_source(p)?)?;
+    print!(
+        "{}",
+        rlox::disassemble_registers(&chunk, &p.display().to_string())
+    );
+
+    Ok(())
+}
+
+fn assemble_file(vm: &mut VM, p: &Path, output: Option<PathBuf>) -> CliResult<()> {
     let source = read_to_string(p)?;
+    let chunk = rlox::assemble(&source)?;
+
+    if let Some(output) = output {
+        return write_chunk_file(&chunk, &output);
+    }
+
+    vm.interpret_chunk(chunk)?;
+
+    Ok(())
+}
+
+fn compile_file(input: &Path, output: Option<PathBuf>, optimize: bool) -> CliResult<()> {
+    let source = read_to_string(input)?;
+    let chunk = optimized(rlox::compile(&source)?, optimize);
+
+    let output = output.unwrap_or_else(|| input.with_extension("loxc"));
+    write_chunk_file(&chunk, &output)
+}
+
+fn lint_files(inputs: &[PathBuf], config: &LintConfig) -> CliResult<()> {
+    let mut errors = 0;
+    for p in inputs {
+        let source = read_to_string(p)?;
+        for diagnostic in rlox::lint(&source, config) {
+            println!("{}:{diagnostic}", p.display());
+            if diagnostic.severity == Severity::Error {
+                errors += 1;
+            }
+        }
+    }
 
-    vm.interpret(&source)
+    if errors > 0 {
+        return Err(CliError::LintErrors { count: errors });
+    }
+
+    Ok(())
 }
 
-fn main() {
+fn format_files(inputs: &[PathBuf], check: bool, width: usize) -> CliResult<()> {
+    let mut unformatted = 0;
+    for p in inputs {
+        let source = read_to_string(p)?;
+        let formatted = rlox::format_source(&source, width).map_err(|errors| CliError::Format {
+            path: p.display().to_string(),
+            errors,
+        })?;
+
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            println!("Would reformat {}", p.display());
+            unformatted += 1;
+        } else {
+            std::fs::write(p, formatted)?;
+        }
+    }
+
+    if unformatted > 0 {
+        return Err(CliError::Unformatted { count: unformatted });
+    }
+
+    Ok(())
+}
+
+/// Compare every script in `inputs` across the interpreters, printing a line per script and the
+/// details of each divergence.
+fn difftest_files(inputs: &[PathBuf]) -> CliResult<()> {
+    let mut diverged = 0;
+    let scripts = rlox::scripts(inputs)?;
+
+    for path in &scripts {
+        let source = read_to_string(path)?;
+        match rlox::compare(&source) {
+            None => println!("ok       {}", path.display()),
+            Some(details) => {
+                println!("DIVERGED {}", path.display());
+                print!("{details}");
+                diverged += 1;
+            }
+        }
+    }
+
+    println!("\n{} script(s), {diverged} diverged", scripts.len());
+
+    if diverged > 0 {
+        return Err(CliError::Diverged { count: diverged });
+    }
+
+    Ok(())
+}
+
+fn write_chunk_file(chunk: &Chunk, output: &Path) -> CliResult<()> {
+    let mut out = BufWriter::new(File::create(output)?);
+    rlox::write_chunk(chunk, &mut out)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_lines)]
+fn main() -> ExitCode {
     let args = Args::parse();
 
-    match args.path {
-        Some(p) => {
-            if let Err(e) = run_file(p) {
-                eprintln!("{e}");
+    let mut vm = VM::new();
+    match args.trace.tracer() {
+        Ok(tracer) => vm.set_tracer(tracer),
+        Err(e) => {
+            eprintln!("Could not open trace output: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if args.profile.enabled() {
+        vm.set_profiler(Some(Profiler::new()));
+    }
+
+    let result = match (args.command, args.path) {
+        (
+            Some(Command::Compile {
+                input,
+                output,
+                optimize,
+            }),
+            _,
+        ) => compile_file(&input, output, optimize),
+        (Some(Command::Asm { input, output }), _) => assemble_file(&mut vm, &input, output),
+        (Some(Command::Debug { input }), _) => debug_file(&input),
+        (
+            Some(Command::Lint {
+                inputs,
+                deny,
+                allow,
+                deny_warnings,
+            }),
+            _,
+        ) => {
+            let config = LintConfig {
+                allow: allow.into_iter().collect(),
+                deny: deny.into_iter().collect(),
+                deny_warnings,
+            };
+            lint_files(&inputs, &config)
+        }
+        (
+            Some(Command::Fmt {
+                inputs,
+                check,
+                width,
+            }),
+            _,
+        ) => format_files(&inputs, check, width),
+        (Some(Command::Difftest { inputs }), _) => difftest_files(&inputs),
+        (
+            Some(Command::Bench {
+                inputs,
+                runs,
+                optimize,
+                backend,
+                baseline,
+                save,
+                threshold,
+            }),
+            _,
+        ) => {
+            let options = bench::Options {
+                runs,
+                backend,
+                optimize,
+                baseline,
+                save,
+                threshold,
+            };
+            bench::run(&inputs, &options)
+        }
+        (Some(Command::Lsp), _) => {
+            rlox::serve_lsp(&mut stdin().lock(), stdout().lock()).map_err(CliError::from)
+        }
+        (Some(Command::Dap), _) => {
+            rlox::serve_dap(&mut stdin().lock(), stdout().lock()).map_err(CliError::from)
+        }
+        (None, Some(p)) => match (args.backend, args.disassemble) {
+            (Backend::Stack, true) => disassemble_file(&p, args.asm, args.optimize),
+            (Backend::Stack, false) => run_file(&mut vm, &p, args.optimize),
+            (Backend::Register, true) => disassemble_file_registers(&p),
+            (Backend::Register, false) => run_file_registers(&p),
+        },
+        (None, None) => {
+            match args.backend {
+                Backend::Stack => repl(|source| vm.interpret(source).map(drop)),
+                Backend::Register => {
+                    let mut vm = RegisterVM::new();
+                    repl(|source| vm.interpret(source));
+                }
             }
+            Ok(())
         }
+    };
 
-        None => repl(),
+    if let Some(profiler) = vm.profiler()
+        && let Err(e) = args.profile.finish(profiler)
+    {
+        eprintln!("Could not write profile: {e}");
     }
+
+    if let Err(e) = result {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
 }
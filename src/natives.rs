@@ -0,0 +1,71 @@
+//! Functions implemented in Rust and predefined as globals.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+
+use crate::value::{Native, NativeFn, Value};
+use crate::{InterpretError, InterpretResult};
+
+/// Every native as (name, arity, function).
+pub const NATIVES: &[(&str, u8, NativeFn)] = &[("clock", 0, clock)];
+
+/// The virtual machine running a native function, which the native may call back into.
+pub trait Context {
+    /// Call `callee` with `arguments` and run it to completion, returning its result. Calls may
+    /// nest: the function called can itself call natives that call back in turn.
+    fn call(&mut self, callee: &Value, arguments: &[Value]) -> InterpretResult<Value>;
+}
+
+/// Why a native function failed.
+#[derive(Debug, Error)]
+pub enum NativeError {
+    /// Reported as a runtime error of the native
+    #[error("{0}")]
+    Message(String),
+    /// An error in Lox code called by the native, reported as it is
+    #[error(transparent)]
+    Callback(#[from] InterpretError),
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        Self::Message(message)
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        Self::Message(message.to_string())
+    }
+}
+
+/// The globals a program starts with: every native, under its own name.
+pub fn globals() -> HashMap<String, Value> {
+    NATIVES
+        .iter()
+        .map(|&(name, arity, function)| {
+            let native = Native {
+                name: name.to_string(),
+                arity,
+                function,
+            };
+            (name.to_string(), Value::from(Rc::new(native)))
+        })
+        .collect()
+}
+
+/// Seconds since the Unix epoch, for timing code.
+pub fn clock_seconds() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64())
+}
+
+// Natives share one signature, even those that cannot fail
+#[allow(clippy::unnecessary_wraps)]
+fn clock(_context: &mut dyn Context, _args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::from(clock_seconds()))
+}
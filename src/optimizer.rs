@@ -0,0 +1,837 @@
+//! A peephole optimizer that rewrites finished chunks.
+//!
+//! A chunk is decoded into a list of instructions whose jumps refer to other instructions rather
+//! than byte distances. Passes then mark instructions as removed or retarget jumps, repeating
+//! until none of them finds anything to change, and the surviving instructions are encoded into
+//! a new chunk with every jump distance recomputed. Each instruction keeps the position it was
+//! compiled from, so errors and traces still point at the right source.
+//!
+//! The passes are:
+//! - removing code that cannot be reached
+//! - threading jumps whose target is an unconditional jump, and removing jumps to the next
+//!   instruction
+//! - removing a constant that is immediately popped
+//! - replacing `Not` followed by a conditional jump with the opposite jump, when the condition
+//!   is popped on both paths and so never observed
+//!
+//! Finally, common sequences are fused into superinstructions, which do the same work in a
+//! single dispatch:
+//! - `GetLocal 0` to `GetLocal 3` become `GetLocal0` to `GetLocal3`
+//! - `Constant k; Add` becomes `AddConst k`
+//! - `GetLocal a; GetLocal b; Less` becomes `LessLocals a b`, as does `GetLocal b; GetLocal a;
+//!   Greater`
+//! - `GetLocal a; Constant k; Add; SetLocal a; Pop`, as in `i = i + 1;`, becomes
+//!   `IncrLocal a k`
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, JUMP_OPERAND_SIZE, OPCODE_SIZE, OpCode, Operand, Position};
+use crate::value::{Function, Value};
+
+#[derive(Debug, Clone)]
+struct Instruction {
+    opcode: OpCode,
+    /// Operand bytes of anything but a jump
+    operands: Vec<u8>,
+    /// Index of the instruction a jump transfers control to. It may be one past the last
+    /// instruction.
+    target: Option<usize>,
+    /// Offset in the original chunk
+    offset: usize,
+    position: Position,
+    removed: bool,
+}
+
+impl Instruction {
+    fn is_unconditional_jump(&self) -> bool {
+        matches!(self.opcode, OpCode::Jump | OpCode::Loop)
+    }
+
+    /// Whether execution never continues with the next instruction.
+    fn ends_block(&self) -> bool {
+        self.is_unconditional_jump() || self.opcode == OpCode::Return
+    }
+
+    fn pushes_constant(&self) -> bool {
+        matches!(
+            self.opcode,
+            OpCode::Constant | OpCode::ConstantLong | OpCode::Nil | OpCode::True | OpCode::False
+        )
+    }
+}
+
+/// Optimize `chunk` and the chunks of the functions nested in it.
+///
+/// A chunk that cannot be decoded, such as a corrupt one loaded from a file, is returned
+/// unchanged so that the verifier can report the problem.
+pub fn optimize(chunk: &Chunk) -> Chunk {
+    let constants = chunk.constants.iter().map(optimize_constant).collect();
+
+    let Some(mut instructions) = decode(chunk) else {
+        let mut res = chunk.clone();
+        res.constants = constants;
+        return res;
+    };
+
+    while remove_unreachable(&mut instructions)
+        | thread_jumps(&mut instructions)
+        | remove_constant_pops(&mut instructions)
+        | invert_negated_jumps(&mut instructions)
+    {}
+
+    fuse(&mut instructions);
+    encode(&instructions, constants, chunk)
+}
+
+fn optimize_constant(constant: &Value) -> Value {
+    match constant.as_function() {
+        Some(function) => Value::from(Rc::new(Function {
+            chunk: optimize(&function.chunk),
+            // The new code has not been verified
+            verified: Cell::default(),
+            ..function.clone()
+        })),
+        None => constant.clone(),
+    }
+}
+
+fn decode(chunk: &Chunk) -> Option<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let opcode = OpCode::try_from(chunk.code[offset]).ok()?;
+        let next = chunk.next_instruction(offset);
+        let operands = chunk.code.get(offset + OPCODE_SIZE..next)?;
+        let is_jump = matches!(opcode.operand(), Operand::Jump | Operand::Loop);
+
+        instructions.push(Instruction {
+            opcode,
+            operands: if is_jump {
+                Vec::new()
+            } else {
+                operands.to_vec()
+            },
+            // Resolved to an instruction index below
+            target: is_jump.then(|| chunk.jump_target(offset)).flatten(),
+            offset,
+            position: chunk.get_position(offset)?,
+            removed: false,
+        });
+
+        offset = next;
+    }
+
+    let offsets: Vec<_> = instructions.iter().map(|i| i.offset).collect();
+    for instruction in &mut instructions {
+        if let Some(target) = &mut instruction.target {
+            *target = match offsets.binary_search(target) {
+                Ok(index) => index,
+                Err(end) if end == offsets.len() && *target == chunk.code.len() => end,
+                Err(_) => return None,
+            };
+        }
+    }
+
+    Some(instructions)
+}
+
+/// Build the optimized chunk for `original`, whose local variable scopes are carried over.
+fn encode(instructions: &[Instruction], constants: Vec<Value>, original: &Chunk) -> Chunk {
+    // New offset of each instruction. A removed one takes the offset of the next one kept, which
+    // is where jumps to it now land.
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        if !instruction.removed {
+            offset += OPCODE_SIZE + instruction.opcode.operand_size();
+        }
+    }
+    offsets.push(offset);
+
+    let mut chunk = Chunk::new();
+    chunk.constants = constants;
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        if instruction.removed {
+            continue;
+        }
+
+        chunk.push_opcode(instruction.opcode, instruction.position);
+
+        let operands = match instruction.target {
+            Some(target) => {
+                let next = offsets[i] + OPCODE_SIZE + JUMP_OPERAND_SIZE;
+                let distance = next.abs_diff(offsets[target]);
+                u16::try_from(distance)
+                    .expect("Removing code only shortens jumps")
+                    .to_le_bytes()
+                    .to_vec()
+            }
+            None => instruction.operands.clone(),
+        };
+
+        for byte in operands {
+            chunk.push_byte(byte, instruction.position);
+        }
+    }
+
+    // Scopes begin and end at instruction boundaries, or at the end of the code
+    let moved = |offset: usize| {
+        let i = instructions.partition_point(|instruction| instruction.offset < offset);
+        offsets[i]
+    };
+    for local in original.locals() {
+        chunk.begin_local(&local.name, local.slot, moved(local.start));
+        if let Some(end) = local.end {
+            chunk.end_local(local.slot, moved(end));
+        }
+    }
+
+    chunk
+}
+
+/// Index of the first instruction at or after `i` that has not been removed.
+fn next_live(instructions: &[Instruction], i: usize) -> usize {
+    (i..instructions.len())
+        .find(|&j| !instructions[j].removed)
+        .unwrap_or(instructions.len())
+}
+
+/// Which instructions are the target of a jump.
+fn jump_targets(instructions: &[Instruction]) -> Vec<bool> {
+    let mut res = vec![false; instructions.len() + 1];
+    for instruction in instructions.iter().filter(|i| !i.removed) {
+        if let Some(target) = instruction.target {
+            res[target] = true;
+        }
+    }
+
+    res
+}
+
+/// Point jumps at removed instructions to the instructions that replaced them.
+fn retarget(instructions: &mut [Instruction]) {
+    for i in 0..instructions.len() {
+        if let Some(target) = instructions[i].target {
+            instructions[i].target = Some(next_live(instructions, target));
+        }
+    }
+}
+
+fn remove_unreachable(instructions: &mut [Instruction]) -> bool {
+    let mut reachable = vec![false; instructions.len()];
+    let mut worklist = vec![next_live(instructions, 0)];
+
+    while let Some(i) = worklist.pop() {
+        if i >= instructions.len() || reachable[i] {
+            continue;
+        }
+
+        reachable[i] = true;
+        let instruction = &instructions[i];
+        worklist.extend(instruction.target);
+        if !instruction.ends_block() {
+            worklist.push(next_live(instructions, i + 1));
+        }
+    }
+
+    let mut changed = false;
+    for (instruction, reachable) in instructions.iter_mut().zip(reachable) {
+        if !instruction.removed && !reachable {
+            instruction.removed = true;
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+fn thread_jumps(instructions: &mut [Instruction]) -> bool {
+    let mut changed = false;
+
+    for i in 0..instructions.len() {
+        let Some(original) = instructions[i].target else {
+            continue;
+        };
+        if instructions[i].removed {
+            continue;
+        }
+
+        // Follow the chain of unconditional jumps, giving up on a cycle
+        let mut target = original;
+        for _ in 0..instructions.len() {
+            match instructions.get(target) {
+                Some(next) if next.is_unconditional_jump() && next.target != Some(target) => {
+                    target = next.target.expect("Jumps have targets");
+                }
+                _ => break,
+            }
+        }
+
+        let opcode = match instructions[i].opcode {
+            OpCode::Jump | OpCode::Loop if target > i => OpCode::Jump,
+            OpCode::Jump | OpCode::Loop => OpCode::Loop,
+            conditional => conditional,
+        };
+
+        // Conditional jumps only go forwards, and distances must fit their operand as measured in
+        // the original code, which removing instructions only shortens
+        let offset = |j: usize| instructions.get(j).map_or(usize::MAX, |i| i.offset);
+        let next = offset(i) + OPCODE_SIZE + JUMP_OPERAND_SIZE;
+        let fits = u16::try_from(offset(target).abs_diff(next)).is_ok();
+        if target != original && (opcode.operand() == Operand::Jump) == (target > i) && fits {
+            instructions[i].opcode = opcode;
+            instructions[i].target = Some(target);
+            changed = true;
+        }
+
+        // A jump to the instruction after it does nothing
+        let instruction = &instructions[i];
+        if instruction.is_unconditional_jump()
+            && instruction.target == Some(next_live(instructions, i + 1))
+        {
+            instructions[i].removed = true;
+            changed = true;
+        }
+    }
+
+    if changed {
+        retarget(instructions);
+    }
+
+    changed
+}
+
+fn remove_constant_pops(instructions: &mut [Instruction]) -> bool {
+    let targets = jump_targets(instructions);
+    let mut changed = false;
+
+    for i in 0..instructions.len() {
+        if instructions[i].removed || !instructions[i].pushes_constant() {
+            continue;
+        }
+
+        let pop = next_live(instructions, i + 1);
+        if instructions
+            .get(pop)
+            .is_some_and(|p| p.opcode == OpCode::Pop)
+            && !targets[pop]
+        {
+            instructions[i].removed = true;
+            instructions[pop].removed = true;
+            changed = true;
+        }
+    }
+
+    if changed {
+        retarget(instructions);
+    }
+
+    changed
+}
+
+fn invert_negated_jumps(instructions: &mut [Instruction]) -> bool {
+    let targets = jump_targets(instructions);
+    let mut changed = false;
+
+    for i in 0..instructions.len() {
+        if instructions[i].removed || instructions[i].opcode != OpCode::Not {
+            continue;
+        }
+
+        let jump = next_live(instructions, i + 1);
+        let Some(inverted) = instructions.get(jump).and_then(|j| match j.opcode {
+            OpCode::JumpIfFalse => Some(OpCode::JumpIfTrue),
+            OpCode::JumpIfTrue => Some(OpCode::JumpIfFalse),
+            _ => None,
+        }) else {
+            continue;
+        };
+
+        // The negated value stays on the stack after the jump, so it must be discarded unseen
+        // whichever way the jump goes
+        let is_pop = |j: usize| instructions.get(j).is_some_and(|i| i.opcode == OpCode::Pop);
+        let fallthrough = next_live(instructions, jump + 1);
+        let target = instructions[jump].target.expect("Jumps have targets");
+        if targets[jump] || !is_pop(fallthrough) || !is_pop(target) {
+            continue;
+        }
+
+        instructions[i].removed = true;
+        instructions[jump].opcode = inverted;
+        changed = true;
+    }
+
+    if changed {
+        retarget(instructions);
+    }
+
+    changed
+}
+
+fn fuse(instructions: &mut [Instruction]) {
+    let targets = jump_targets(instructions);
+
+    for i in 0..instructions.len() {
+        if instructions[i].removed {
+            continue;
+        }
+
+        // The live instructions starting at `i`, up to the next one control can jump to
+        let mut window = vec![i];
+        let mut next = next_live(instructions, i + 1);
+        while window.len() < 5 && next < instructions.len() && !targets[next] {
+            window.push(next);
+            next = next_live(instructions, next + 1);
+        }
+
+        let Some((opcode, operands, length, fallible)) = superinstruction(instructions, &window)
+        else {
+            continue;
+        };
+
+        // Errors are reported at the position of the instruction that can raise them
+        instructions[i].position = instructions[window[fallible]].position;
+        instructions[i].opcode = opcode;
+        instructions[i].operands = operands;
+        for &j in &window[1..length] {
+            instructions[j].removed = true;
+        }
+    }
+}
+
+/// The superinstruction that can replace a prefix of the instructions at `window`, as its
+/// opcode, operands, the length of the prefix and the index in the window of the instruction
+/// that can fail.
+fn superinstruction(
+    instructions: &[Instruction],
+    window: &[usize],
+) -> Option<(OpCode, Vec<u8>, usize, usize)> {
+    let ops: Vec<_> = window
+        .iter()
+        .map(|&i| (instructions[i].opcode, instructions[i].operands.as_slice()))
+        .collect();
+
+    let res = match ops.as_slice() {
+        [
+            (OpCode::GetLocal, [a]),
+            (OpCode::Constant, [k]),
+            (OpCode::Add, []),
+            (OpCode::SetLocal, [b]),
+            (OpCode::Pop, []),
+            ..,
+        ] if a == b => (OpCode::IncrLocal, vec![*a, *k], 5, 2),
+        [
+            (OpCode::GetLocal, [a]),
+            (OpCode::GetLocal, [b]),
+            (OpCode::Less, []),
+            ..,
+        ] => (OpCode::LessLocals, vec![*a, *b], 3, 2),
+        [
+            (OpCode::GetLocal, [a]),
+            (OpCode::GetLocal, [b]),
+            (OpCode::Greater, []),
+            ..,
+        ] => (OpCode::LessLocals, vec![*b, *a], 3, 2),
+        [(OpCode::Constant, [k]), (OpCode::Add, []), ..] => (OpCode::AddConst, vec![*k], 2, 1),
+        [(OpCode::GetLocal, [0]), ..] => (OpCode::GetLocal0, Vec::new(), 1, 0),
+        [(OpCode::GetLocal, [1]), ..] => (OpCode::GetLocal1, Vec::new(), 1, 0),
+        [(OpCode::GetLocal, [2]), ..] => (OpCode::GetLocal2, Vec::new(), 1, 0),
+        [(OpCode::GetLocal, [3]), ..] => (OpCode::GetLocal3, Vec::new(), 1, 0),
+        _ => return None,
+    };
+
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, to_assembly};
+    use crate::compiler::compile;
+    use crate::difftest::run_vm;
+
+    /// The instructions and labels of `chunk`, without its constants and positions.
+    fn listing(chunk: &Chunk) -> Vec<String> {
+        to_assembly(chunk)
+            .lines()
+            .filter(|line| !line.starts_with('.'))
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+
+    /// Assemble `code` and run `pass` over it until it changes nothing more.
+    fn apply(pass: fn(&mut [Instruction]) -> bool, code: &str) -> Vec<String> {
+        let chunk = assemble(&format!(".line 1\n{code}")).unwrap();
+        let mut instructions = decode(&chunk).unwrap();
+        while pass(&mut instructions) {}
+
+        listing(&encode(&instructions, chunk.constants.clone(), &chunk))
+    }
+
+    #[test]
+    fn removes_unreachable_code() {
+        let code = "
+    Jump over
+    Constant 1
+    Print
+over:
+    Nil
+    Return
+    Constant 2
+    Print
+";
+        assert_eq!(
+            apply(remove_unreachable, code),
+            ["Jump L0003", "L0003:", "Nil", "Return"]
+        );
+    }
+
+    #[test]
+    fn keeps_code_reached_only_by_jumps() {
+        let code = "
+    Jump forward
+back:
+    Nil
+    Return
+forward:
+    Loop back
+";
+        assert_eq!(
+            apply(remove_unreachable, code),
+            [
+                "Jump L0005",
+                "L0003:",
+                "Nil",
+                "Return",
+                "L0005:",
+                "Loop L0003"
+            ]
+        );
+    }
+
+    #[test]
+    fn threads_jumps_to_jumps() {
+        let code = "
+    True
+    JumpIfFalse first
+    Pop
+    Jump first
+    Nil
+first:
+    Jump second
+    Nil
+second:
+    Nil
+    Return
+";
+        assert_eq!(
+            apply(thread_jumps, code),
+            [
+                "True",
+                "JumpIfFalse L0013",
+                "Pop",
+                "Jump L0013",
+                "Nil",
+                "Jump L0013",
+                "Nil",
+                "L0013:",
+                "Nil",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_jumps_to_the_next_instruction() {
+        let code = "
+    Jump next
+next:
+    Nil
+    Return
+";
+        assert_eq!(apply(thread_jumps, code), ["Nil", "Return"]);
+    }
+
+    #[test]
+    fn threads_loops_through_forward_jumps() {
+        // The loop becomes a jump to the next instruction, which is then removed
+        let code = "
+    Jump body
+exit:
+    Jump end
+body:
+    True
+    JumpIfFalse end
+    Pop
+    Loop exit
+end:
+    Nil
+    Return
+";
+        assert_eq!(
+            apply(thread_jumps, code),
+            [
+                "Jump L0006",
+                "Jump L0011",
+                "L0006:",
+                "True",
+                "JumpIfFalse L0011",
+                "Pop",
+                "L0011:",
+                "Nil",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_constants_that_are_popped() {
+        let code = "
+    Constant 1
+    Pop
+    Nil
+    Pop
+    GetGlobal \"x\"
+    Pop
+    Nil
+    Return
+";
+        assert_eq!(
+            apply(remove_constant_pops, code),
+            ["GetGlobal #1", "Pop", "Nil", "Return"]
+        );
+    }
+
+    #[test]
+    fn keeps_pops_that_are_jump_targets() {
+        let code = "
+    True
+    JumpIfFalse pop
+    Nil
+pop:
+    Pop
+    Nil
+    Return
+";
+        assert_eq!(
+            apply(remove_constant_pops, code),
+            [
+                "True",
+                "JumpIfFalse L0005",
+                "Nil",
+                "L0005:",
+                "Pop",
+                "Nil",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn inverts_negated_jumps() {
+        let code = "
+    GetGlobal \"x\"
+    Not
+    JumpIfFalse else
+    Pop
+    Jump end
+else:
+    Pop
+end:
+    Nil
+    Return
+";
+        assert_eq!(
+            apply(invert_negated_jumps, code),
+            [
+                "GetGlobal #0",
+                "JumpIfTrue L0009",
+                "Pop",
+                "Jump L0010",
+                "L0009:",
+                "Pop",
+                "L0010:",
+                "Nil",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_negations_whose_value_is_used() {
+        // `!x and y` leaves the negated value on the stack when it is false
+        let code = "
+    GetGlobal \"x\"
+    Not
+    JumpIfFalse end
+    Pop
+    GetGlobal \"y\"
+end:
+    Print
+    Nil
+    Return
+";
+        assert_eq!(
+            apply(invert_negated_jumps, code),
+            [
+                "GetGlobal #0",
+                "Not",
+                "JumpIfFalse L0009",
+                "Pop",
+                "GetGlobal #1",
+                "L0009:",
+                "Print",
+                "Nil",
+                "Return"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_local_variable_scopes() {
+        let chunk = compile("{ var a = 1; 2; if (!a) print a; var b = a; print b; }").unwrap();
+        let optimized = optimize(&chunk);
+        assert!(optimized.code.len() < chunk.code.len());
+
+        let names = |chunk: &Chunk| -> Vec<_> {
+            chunk
+                .locals()
+                .iter()
+                .map(|local| (local.name.clone(), local.slot))
+                .collect()
+        };
+        assert_eq!(names(&optimized), names(&chunk));
+
+        // Both variables are live at the last `Print`, and neither after the block
+        let mut offset = 0;
+        let mut last_print = None;
+        while offset < optimized.code.len() {
+            if optimized.code[offset] == OpCode::Print as u8 {
+                last_print = Some(offset);
+            }
+            offset = optimized.next_instruction(offset);
+        }
+
+        let live = |offset| -> Vec<_> {
+            optimized
+                .locals_at(offset)
+                .map(|local| local.name.as_str())
+                .collect()
+        };
+        assert_eq!(live(last_print.unwrap()), ["a", "b"]);
+        assert!(live(optimized.code.len() - 1).is_empty());
+    }
+
+    /// Sequences that fuse into a superinstruction, and the instruction they become.
+    const FUSIBLE: &[(&[&str], &str)] = &[
+        (
+            &["GetLocal 1", "Constant 1", "Add", "SetLocal 1", "Pop"],
+            "IncrLocal 1 #0",
+        ),
+        (&["GetLocal 4", "GetLocal 5", "Less"], "LessLocals 4 5"),
+        (&["GetLocal 4", "GetLocal 5", "Greater"], "LessLocals 5 4"),
+        (&["Constant 1", "Add"], "AddConst #0"),
+        (&["GetLocal 0"], "GetLocal0"),
+        (&["GetLocal 1"], "GetLocal1"),
+        (&["GetLocal 2"], "GetLocal2"),
+        (&["GetLocal 3"], "GetLocal3"),
+    ];
+
+    /// Fuse `sequence`, with a label before the instruction at `label` that a later `Loop`
+    /// jumps to.
+    fn fused(sequence: &[&str], label: Option<usize>) -> Vec<String> {
+        let mut code = String::new();
+        for (i, instruction) in sequence.iter().enumerate() {
+            if label == Some(i) {
+                code.push_str("target:\n");
+            }
+            code.push_str(instruction);
+            code.push('\n');
+        }
+        if label.is_some() {
+            code.push_str("Loop target\n");
+        }
+
+        apply(
+            |instructions| {
+                fuse(instructions);
+                false
+            },
+            &code,
+        )
+    }
+
+    #[test]
+    fn fuses_sequences_without_jump_targets_inside() {
+        for &(sequence, superinstruction) in FUSIBLE {
+            assert_eq!(fused(sequence, None), [superinstruction], "{sequence:?}");
+
+            // Jumping to the start of the sequence still runs all of it
+            let listing = fused(sequence, Some(0));
+            assert_eq!(listing[1], superinstruction, "{sequence:?}");
+        }
+    }
+
+    #[test]
+    fn does_not_fuse_sequences_with_jump_targets_inside() {
+        for &(sequence, superinstruction) in FUSIBLE {
+            for label in 1..sequence.len() {
+                let listing = fused(sequence, Some(label));
+                assert!(
+                    !listing.iter().any(|i| i == superinstruction),
+                    "{sequence:?} with a jump to {label}: {listing:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn does_not_fuse_near_misses() {
+        assert_eq!(
+            fused(
+                &["GetLocal 4", "Constant 1", "Add", "SetLocal 5", "Pop"],
+                None
+            ),
+            ["GetLocal 4", "AddConst #0", "SetLocal 5", "Pop"]
+        );
+        assert_eq!(
+            fused(&["GetLocal 4", "GetLocal 5", "Equal"], None),
+            ["GetLocal 4", "GetLocal 5", "Equal"]
+        );
+        assert_eq!(fused(&["GetLocal 4"], None), ["GetLocal 4"]);
+        assert_eq!(fused(&["Constant 1", "Sub"], None), ["Constant #0", "Sub"]);
+    }
+
+    #[test]
+    fn reports_errors_in_superinstructions_at_their_operator() {
+        // Each operand and operator on a line of its own, with the error raised on line 4
+        for (source, superinstruction) in [
+            ("{ var i = \"a\";\ni =\ni\n+\n1; }", "IncrLocal"),
+            (
+                "{ var a = 1; var b = \"b\";\nprint\na\n<\nb; }",
+                "LessLocals",
+            ),
+            (
+                "{ var a = 1; var b = \"b\";\nprint\nb\n>\na; }",
+                "LessLocals",
+            ),
+            ("{ var s = nil;\nprint\ns\n+\n1; }", "AddConst"),
+        ] {
+            let optimized = optimize(&compile(source).unwrap());
+            assert!(
+                listing(&optimized)
+                    .iter()
+                    .any(|i| i.starts_with(superinstruction)),
+                "{source} was not fused"
+            );
+
+            let plain = run_vm(source, false).error.unwrap();
+            let fused = run_vm(source, true).error.unwrap();
+            assert!(plain.starts_with("[line 4]"), "{plain}");
+            assert_eq!(fused, plain);
+        }
+    }
+}
@@ -0,0 +1,429 @@
+//! An instruction-level profiler for the stack VM, enabled with `--profile`.
+//!
+//! Every instruction is counted, by opcode and by the source line and call stack it ran under.
+//! Time is sampled rather than measured per instruction: the clock is read once every
+//! [`SAMPLE_INTERVAL`] instructions, and the time since the last reading is credited to the line
+//! and stack of the instruction that had just run.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::chunk::{LineNum, OpCode};
+use crate::value::Function;
+
+/// Name under which top-level code is reported.
+const SCRIPT_NAME: &str = "<script>";
+
+/// Instructions executed between readings of the clock. Odd, so that samples do not keep landing
+/// on the same instruction of a loop whose body has an even length.
+pub const SAMPLE_INTERVAL: u64 = 63;
+
+/// Counts executed instructions and attributes wall time to the source lines they came from and
+/// the call stacks they ran under.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    op_counts: HashMap<OpCode, u64>,
+    // Every distinct call stack seen, as a tree of function names
+    nodes: Vec<StackNode>,
+    samples: HashMap<(NodeId, Option<LineNum>), LineStats>,
+    // The VM's frames as of the last recorded instruction: each frame's node and function. The
+    // functions are kept alive so that a new one cannot take the address of a freed one.
+    frames: Vec<(NodeId, Rc<Function>)>,
+    // Stack and line of the last recorded instruction
+    current: Option<(NodeId, Option<LineNum>)>,
+    // When the clock was last read, and the instructions recorded since
+    last_sample: Option<Instant>,
+    unsampled: u64,
+}
+
+type NodeId = usize;
+
+#[derive(Debug)]
+struct StackNode {
+    name: String,
+    parent: Option<NodeId>,
+    children: HashMap<String, NodeId>,
+    calls: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct LineStats {
+    count: u64,
+    time: Duration,
+}
+
+/// Time spent in one function, over all the stacks it appears in.
+#[derive(Debug, Default, Clone, Copy)]
+struct FunctionStats {
+    calls: u64,
+    count: u64,
+    self_time: Duration,
+    total_time: Duration,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that the instruction at `offset` in the innermost of `frames` is about to execute.
+    /// `frames` holds the function of every active call frame, outermost first.
+    pub(crate) fn record<'a, I>(&mut self, frames: I, offset: usize, opcode: OpCode)
+    where
+        I: DoubleEndedIterator<Item = &'a Rc<Function>> + ExactSizeIterator + Clone,
+    {
+        // The clock is read as the previous instruction finishes, and that is the one credited
+        if self.last_sample.is_none() || self.unsampled >= SAMPLE_INTERVAL {
+            self.sample(Instant::now());
+        }
+        self.unsampled += 1;
+
+        *self.op_counts.entry(opcode).or_default() += 1;
+
+        let Some(function) = frames.clone().next_back() else {
+            return;
+        };
+        let node = self.enter(frames);
+
+        let line = function.chunk.get_line(offset);
+        self.samples.entry((node, line)).or_default().count += 1;
+        self.current = Some((node, line));
+    }
+
+    /// Bring the shadow call stack in line with `frames`, counting a call for each new frame,
+    /// and return the node of the innermost one.
+    fn enter<'a>(
+        &mut self,
+        frames: impl DoubleEndedIterator<Item = &'a Rc<Function>> + ExactSizeIterator + Clone,
+    ) -> NodeId {
+        // Almost every instruction runs in the same frame as the one before it
+        if let (Some((node, top)), Some(function)) =
+            (self.frames.last(), frames.clone().next_back())
+            && self.frames.len() == frames.len()
+            && Rc::ptr_eq(top, function)
+        {
+            return *node;
+        }
+
+        let common = self
+            .frames
+            .iter()
+            .zip(frames.clone())
+            .take_while(|((_, known), function)| Rc::ptr_eq(known, function))
+            .count();
+        self.frames.truncate(common);
+
+        for function in frames.skip(common) {
+            let parent = self.frames.last().map(|(node, _)| *node);
+            let node = self.child(parent, function);
+            self.nodes[node].calls += 1;
+            self.frames.push((node, Rc::clone(function)));
+        }
+
+        self.frames.last().expect("`frames` is not empty").0
+    }
+
+    /// The node for calling `function` from `parent`, created if it is new.
+    fn child(&mut self, parent: Option<NodeId>, function: &Function) -> NodeId {
+        let name = function.name.as_deref().unwrap_or(SCRIPT_NAME);
+
+        let existing = match parent {
+            Some(parent) => self.nodes[parent].children.get(name).copied(),
+            None => self
+                .nodes
+                .iter()
+                .position(|n| n.parent.is_none() && n.name == name),
+        };
+        if let Some(node) = existing {
+            return node;
+        }
+
+        let node = self.nodes.len();
+        self.nodes.push(StackNode {
+            name: name.to_string(),
+            parent,
+            children: HashMap::new(),
+            calls: 0,
+        });
+        if let Some(parent) = parent {
+            self.nodes[parent].children.insert(name.to_string(), node);
+        }
+
+        node
+    }
+
+    /// Credit the time since the clock was last read to the instruction recorded last, and stop
+    /// timing. Called when execution stops.
+    pub fn stop(&mut self) {
+        self.sample(Instant::now());
+        self.last_sample = None;
+        self.current = None;
+    }
+
+    fn sample(&mut self, now: Instant) {
+        if let (Some(last), Some(current)) = (self.last_sample, self.current) {
+            self.samples.entry(current).or_default().time += now - last;
+        }
+
+        self.last_sample = Some(now);
+        self.unsampled = 0;
+    }
+
+    pub fn total_instructions(&self) -> u64 {
+        self.op_counts.values().sum()
+    }
+
+    /// Statistics per source line, over all call stacks.
+    fn lines(&self) -> HashMap<Option<LineNum>, LineStats> {
+        let mut lines: HashMap<_, LineStats> = HashMap::new();
+        for (&(_, line), stats) in &self.samples {
+            let entry = lines.entry(line).or_default();
+            entry.count += stats.count;
+            entry.time += stats.time;
+        }
+
+        lines
+    }
+
+    /// Statistics per function name. A function's total time includes the functions it calls,
+    /// counted once however deeply it recurses.
+    fn functions(&self) -> HashMap<&str, FunctionStats> {
+        let mut self_stats: HashMap<NodeId, LineStats> = HashMap::new();
+        for (&(node, _), stats) in &self.samples {
+            let entry = self_stats.entry(node).or_default();
+            entry.count += stats.count;
+            entry.time += stats.time;
+        }
+
+        let mut functions: HashMap<&str, FunctionStats> = HashMap::new();
+        for node in &self.nodes {
+            functions.entry(&node.name).or_default().calls += node.calls;
+        }
+
+        for (&id, stats) in &self_stats {
+            let name = self.nodes[id].name.as_str();
+            let entry = functions.entry(name).or_default();
+            entry.count += stats.count;
+            entry.self_time += stats.time;
+
+            // Credit each distinct function on the stack once
+            let mut seen = Vec::new();
+            let mut ancestor = Some(id);
+            while let Some(a) = ancestor {
+                let name = self.nodes[a].name.as_str();
+                if !seen.contains(&name) {
+                    seen.push(name);
+                    functions.entry(name).or_default().total_time += stats.time;
+                }
+                ancestor = self.nodes[a].parent;
+            }
+        }
+
+        functions
+    }
+
+    /// The names on the stack ending at `node`, outermost first.
+    fn stack(&self, node: NodeId) -> Vec<&str> {
+        let mut names = Vec::new();
+        let mut ancestor = Some(node);
+        while let Some(a) = ancestor {
+            names.push(self.nodes[a].name.as_str());
+            ancestor = self.nodes[a].parent;
+        }
+
+        names.reverse();
+        names
+    }
+
+    /// A human-readable summary, with the most frequent opcodes and the most expensive
+    /// functions and lines first.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn report(&self) -> String {
+        let mut res = String::new();
+        let total = self.total_instructions();
+        let lines = self.lines();
+        let total_time: Duration = lines.values().map(|s| s.time).sum();
+
+        // `write!`ing into a String is infallible
+        writeln!(res, "== Profile ==").unwrap();
+        writeln!(res, "{total} instructions executed in {total_time:?}").unwrap();
+
+        let mut ops: Vec<_> = self.op_counts.iter().collect();
+        ops.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+        writeln!(res, "\n{:<16} {:>12} {:>7}", "Opcode", "Count", "%").unwrap();
+        for (op, &count) in ops {
+            let op = format!("{op:?}");
+            let share = percent(count as f64, total as f64);
+            writeln!(res, "{op:<16} {count:>12} {share:>6.1}%").unwrap();
+        }
+
+        let mut functions: Vec<_> = self.functions().into_iter().collect();
+        functions.sort_by(|a, b| {
+            (b.1.total_time, b.1.self_time)
+                .cmp(&(a.1.total_time, a.1.self_time))
+                .then_with(|| a.0.cmp(b.0))
+        });
+
+        writeln!(
+            res,
+            "\n{:<16} {:>10} {:>12} {:>14} {:>14} {:>7}",
+            "Function", "Calls", "Count", "Self time", "Total time", "%"
+        )
+        .unwrap();
+        for (name, stats) in functions {
+            let self_time = format!("{:?}", stats.self_time);
+            let total = format!("{:?}", stats.total_time);
+            let share = percent(stats.total_time.as_secs_f64(), total_time.as_secs_f64());
+            writeln!(
+                res,
+                "{name:<16} {:>10} {:>12} {self_time:>14} {total:>14} {share:>6.1}%",
+                stats.calls, stats.count
+            )
+            .unwrap();
+        }
+
+        let mut lines: Vec<_> = lines.into_iter().collect();
+        lines.sort_by(|a, b| b.1.time.cmp(&a.1.time).then_with(|| a.0.cmp(&b.0)));
+
+        writeln!(
+            res,
+            "\n{:<16} {:>12} {:>14} {:>7}",
+            "Line", "Count", "Time", "%"
+        )
+        .unwrap();
+        for (line, stats) in lines {
+            let line = line.map_or("?".to_string(), |l| l.to_string());
+            let time = format!("{:?}", stats.time);
+            let share = percent(stats.time.as_secs_f64(), total_time.as_secs_f64());
+            writeln!(
+                res,
+                "{line:<16} {:>12} {time:>14} {share:>6.1}%",
+                stats.count
+            )
+            .unwrap();
+        }
+
+        res
+    }
+
+    /// Write time per call stack and line in the folded-stack format consumed by flamegraph
+    /// tools, with one `outer;inner;line N weight` entry each and the weight in microseconds.
+    pub fn write_folded(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut entries: Vec<_> = self
+            .samples
+            .iter()
+            .map(|(&(node, line), stats)| {
+                let line = line.map_or("?".to_string(), |l| l.to_string());
+                (
+                    format!("{};line {line}", self.stack(node).join(";")),
+                    stats.time,
+                )
+            })
+            .collect();
+        entries.sort();
+
+        for (stack, time) in entries {
+            writeln!(out, "{stack} {}", time.as_micros())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn percent(part: f64, total: f64) -> f64 {
+    if total == 0.0 {
+        0.0
+    } else {
+        part / total * 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    const PROGRAM: &str = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+fun run() {
+  return fib(5);
+}
+print run();
+print run();
+";
+
+    /// Profile `PROGRAM` and inspect the results with `check`.
+    fn profile(check: impl FnOnce(&Profiler)) {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        vm.set_profiler(Some(Profiler::new()));
+        vm.interpret(PROGRAM).unwrap();
+
+        let profiler = vm.profiler().unwrap();
+        assert_eq!(profiler.total_instructions(), vm.instructions_executed());
+        check(profiler);
+    }
+
+    #[test]
+    fn counts_calls_per_function() {
+        profile(|profiler| {
+            let functions = profiler.functions();
+            assert_eq!(functions[SCRIPT_NAME].calls, 1);
+            assert_eq!(functions["run"].calls, 2);
+            // fib(5) makes 15 calls in all
+            assert_eq!(functions["fib"].calls, 30);
+        });
+    }
+
+    #[test]
+    fn tells_apart_functions_that_reuse_an_address() {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        vm.set_profiler(Some(Profiler::new()));
+        vm.interpret("var a = 1;").unwrap();
+
+        // Each evaluation compiles a function that is freed once it returns, so the next one is
+        // likely to be allocated where it was
+        for _ in 0..3 {
+            vm.evaluate("a + 1", None).unwrap();
+        }
+
+        let functions = vm.profiler().unwrap().functions();
+        assert_eq!(functions[SCRIPT_NAME].calls, 4);
+    }
+
+    #[test]
+    fn counts_recursive_time_once() {
+        profile(|profiler| {
+            let functions = profiler.functions();
+            let fib = functions["fib"];
+            assert_eq!(fib.self_time, fib.total_time);
+            assert!(functions["run"].total_time >= fib.total_time);
+            assert!(functions[SCRIPT_NAME].total_time >= functions["run"].total_time);
+        });
+    }
+
+    #[test]
+    fn folds_whole_call_stacks() {
+        let mut folded = Vec::new();
+        profile(|profiler| profiler.write_folded(&mut folded).unwrap());
+        let folded = String::from_utf8(folded).unwrap();
+
+        let stacks: Vec<_> = folded
+            .lines()
+            .map(|l| l.rsplit_once(' ').unwrap().0)
+            .collect();
+        assert!(stacks.contains(&"<script>;line 9"));
+        assert!(stacks.contains(&"<script>;run;line 7"));
+        assert!(stacks.contains(&"<script>;run;fib;line 4"));
+        assert!(stacks.contains(&"<script>;run;fib;fib;fib;fib;fib;line 3"));
+        assert!(!stacks.iter().any(|s| s.contains("fib;fib;fib;fib;fib;fib")));
+    }
+}
@@ -0,0 +1,956 @@
+//! Register code generation from the syntax tree.
+//!
+//! Locals live in registers of their function's window for as long as they are in scope, and
+//! temporaries are allocated above them in stack order. An expression is compiled to leave its
+//! value in a destination register, and operands that are already in a local's register are
+//! read from there rather than copied.
+
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, MAX_CONSTANTS, Position};
+use crate::compiler::ast::Function as FunctionDecl;
+use crate::compiler::ast::{
+    BinaryOp, Expr, ExprKind, FunctionKind, Literal, LogicalOp, Name, Stmt, UnaryOp,
+};
+use crate::compiler::{CompileError, analyze};
+use crate::value::{Function, UpvalueRef, Value};
+use crate::{InterpretError, InterpretResult};
+
+use super::{INSTRUCTION_SIZE, MAX_REGISTERS, Op};
+
+/// Upvalues are addressed by one-byte operands.
+const MAX_UPVALUES: usize = 256;
+
+/// Compile `source` to register code for its top-level function.
+pub fn compile(source: &str) -> InterpretResult<Chunk> {
+    let program = analyze(source)?;
+    generate(&program).map_err(InterpretError::Compiler)
+}
+
+/// Generate the top-level chunk for a program that has already passed scope resolution.
+pub fn generate(program: &[Stmt]) -> Result<Chunk, Vec<CompileError>> {
+    let mut generator = Generator {
+        functions: vec![FunctionState::new(None, None, 0)],
+        errors: Vec::new(),
+    };
+
+    generator.statements(program);
+    let end = program
+        .last()
+        .map_or_else(Position::default, Stmt::position);
+    generator.emit_return(end);
+
+    if !generator.errors.is_empty() {
+        return Err(generator.errors);
+    }
+
+    let script = generator
+        .functions
+        .pop()
+        .expect("The script is always being compiled");
+    Ok(script.function.chunk)
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    register: u8,
+    depth: usize,
+    captured: bool,
+}
+
+/// Compilation state of one function, innermost last.
+struct FunctionState {
+    function: Function,
+    kind: Option<FunctionKind>,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    /// The lowest register holding neither a local nor a live temporary
+    free: usize,
+}
+
+impl FunctionState {
+    fn new(kind: Option<FunctionKind>, name: Option<String>, arity: u8) -> Self {
+        // Register 0 holds the function being called, or the receiver in methods
+        let register_zero = match kind {
+            Some(FunctionKind::Method | FunctionKind::Initializer) => "this",
+            _ => "",
+        };
+
+        Self {
+            function: Function {
+                name,
+                arity,
+                ..Function::default()
+            },
+            kind,
+            locals: vec![Local {
+                name: register_zero.to_string(),
+                register: 0,
+                depth: 0,
+                captured: false,
+            }],
+            scope_depth: 0,
+            free: 1,
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<usize> {
+        self.locals.iter().rposition(|l| l.name == name)
+    }
+
+    /// Whether `register` holds a temporary rather than a local.
+    fn is_temporary(&self, register: u8) -> bool {
+        self.locals.last().is_none_or(|l| register > l.register)
+    }
+}
+
+enum Variable {
+    Local(u8),
+    Upvalue(u8),
+    Global(usize),
+}
+
+struct Generator {
+    functions: Vec<FunctionState>,
+    errors: Vec<CompileError>,
+}
+
+impl Generator {
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("The script is always being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().function.chunk
+    }
+
+    /// Report an error at `position`, naming the token `name` found there if there is one.
+    fn error(&mut self, position: Position, name: Option<&str>, message: &str) {
+        self.errors.push(CompileError {
+            position,
+            location: name.map(|name| format!(" at '{name}'")).unwrap_or_default(),
+            message: message.to_string(),
+            end: name.map(|name| position.after(name)),
+        });
+    }
+
+    fn emit(&mut self, op: Op, operands: [u8; 3], position: Position) {
+        let chunk = self.chunk();
+        chunk.code.push(op as u8);
+        chunk.code.extend(operands);
+        chunk.push_position(position, INSTRUCTION_SIZE);
+    }
+
+    /// Emit an instruction whose operand `slot` (1 for B, 2 for C) names the constant `constant`.
+    /// An index too wide for the operand byte goes in an `ExtraArg` after the instruction.
+    fn emit_named(
+        &mut self,
+        op: Op,
+        mut operands: [u8; 3],
+        slot: usize,
+        constant: usize,
+        position: Position,
+    ) {
+        if let Ok(constant) = u8::try_from(constant) {
+            operands[slot] = constant;
+            self.emit(op, operands, position);
+        } else {
+            self.emit(op, operands, position);
+            let [a, b, c, ..] = constant.to_le_bytes();
+            self.emit(Op::ExtraArg, [a, b, c], position);
+        }
+    }
+
+    /// Emit an instruction whose B and C operands are read together as `bx`.
+    fn emit_wide(&mut self, op: Op, a: u8, bx: u16, position: Position) {
+        let [b, c] = bx.to_le_bytes();
+        self.emit(op, [a, b, c], position);
+    }
+
+    fn emit_return(&mut self, position: Position) {
+        if self.current().kind == Some(FunctionKind::Initializer) {
+            self.emit(Op::Return, [0, 0, 0], position);
+            return;
+        }
+
+        let register = self.allocate(position);
+        self.emit(Op::LoadNil, [register, 0, 0], position);
+        self.emit(Op::Return, [register, 0, 0], position);
+        self.current().free -= 1;
+    }
+
+    /// Add `value` to the constant pool for an instruction with a `Bx` constant operand.
+    fn make_constant(&mut self, value: Value, position: Position) -> u16 {
+        let index = self.chunk().push_constant(value);
+        u16::try_from(index).unwrap_or_else(|_| {
+            self.error(position, None, "Too many constants in one chunk.");
+            0
+        })
+    }
+
+    /// The constant holding the string `name`, reusing an existing one where possible.
+    fn name_constant(&mut self, name: &str, position: Position) -> usize {
+        let existing = self
+            .chunk()
+            .constants
+            .iter()
+            .position(|c| c.as_str() == Some(name));
+        if let Some(index) = existing {
+            return index;
+        }
+
+        if self.chunk().constants.len() >= MAX_CONSTANTS {
+            self.error(position, None, "Too many constants in one chunk.");
+            return 0;
+        }
+
+        self.chunk().push_constant(Value::string(name))
+    }
+
+    /// Emit a jump with a placeholder distance, returning its offset.
+    fn emit_jump(&mut self, op: Op, register: u8, position: Position) -> usize {
+        let offset = self.chunk().code.len();
+        self.emit(op, [register, 0xff, 0xff], position);
+        offset
+    }
+
+    /// Point the jump at `offset` to the next instruction.
+    fn patch_jump(&mut self, offset: usize, position: Position) {
+        let distance = self.chunk().code.len() - offset - INSTRUCTION_SIZE;
+        let Ok(distance) = i16::try_from(distance) else {
+            self.error(position, None, "Too much code to jump over.");
+            return;
+        };
+
+        self.chunk().code[offset + 2..offset + INSTRUCTION_SIZE]
+            .copy_from_slice(&distance.to_le_bytes());
+    }
+
+    fn emit_loop(&mut self, start: usize, position: Position) {
+        let end = self.chunk().code.len() + INSTRUCTION_SIZE;
+        let distance = i16::try_from(end - start).map_or_else(
+            |_| {
+                self.error(position, None, "Loop body too large.");
+                0
+            },
+            |distance| -distance,
+        );
+
+        let [b, c] = distance.to_le_bytes();
+        self.emit(Op::Jump, [0, b, c], position);
+    }
+
+    /// Take the lowest free register for a temporary or a new local.
+    fn allocate(&mut self, position: Position) -> u8 {
+        let state = self.current();
+        let register = state.free;
+        state.free += 1;
+
+        u8::try_from(register)
+            .ok()
+            .filter(|_| register < MAX_REGISTERS)
+            .unwrap_or_else(|| {
+                // Only the allocation that crosses the limit reports it, not every one above
+                if register == MAX_REGISTERS {
+                    self.error(position, None, "Too many registers in function.");
+                }
+                0
+            })
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self, position: Position) {
+        let state = self.current();
+        state.scope_depth -= 1;
+
+        let depth = state.scope_depth;
+        let mut lowest = None;
+        let mut captured = None;
+        while let Some(local) = self.current().locals.pop_if(|l| l.depth > depth) {
+            lowest = Some(local.register);
+            if local.captured {
+                captured = Some(local.register);
+            }
+        }
+
+        if let Some(register) = captured {
+            self.emit(Op::Close, [register, 0, 0], position);
+        }
+
+        if let Some(register) = lowest {
+            self.current().free = usize::from(register);
+        }
+    }
+
+    /// Make `register` the home of the local `name` in the current scope.
+    fn add_local(&mut self, name: &str, register: u8, position: Position) {
+        if self.current().locals.len() >= MAX_REGISTERS {
+            self.error(
+                position,
+                Some(name),
+                "Too many local variables in function.",
+            );
+            return;
+        }
+
+        let state = self.current();
+        state.locals.push(Local {
+            name: name.to_string(),
+            register,
+            depth: state.scope_depth,
+            captured: false,
+        });
+    }
+
+    fn resolve(&mut self, name: &str, position: Position) -> Variable {
+        let level = self.functions.len() - 1;
+        if let Some(i) = self.functions[level].resolve_local(name) {
+            return Variable::Local(self.functions[level].locals[i].register);
+        }
+
+        if let Some(index) = self.resolve_upvalue(level, name, position) {
+            return Variable::Upvalue(index);
+        }
+
+        Variable::Global(self.name_constant(name, position))
+    }
+
+    /// Find `name` in the functions enclosing the one at `level`, capturing it through every
+    /// function in between.
+    fn resolve_upvalue(&mut self, level: usize, name: &str, position: Position) -> Option<u8> {
+        let enclosing = level.checked_sub(1)?;
+
+        if let Some(i) = self.functions[enclosing].resolve_local(name) {
+            let local = &mut self.functions[enclosing].locals[i];
+            local.captured = true;
+            let register = local.register;
+            return Some(self.add_upvalue(level, register, true, position));
+        }
+
+        let index = self.resolve_upvalue(enclosing, name, position)?;
+        Some(self.add_upvalue(level, index, false, position))
+    }
+
+    fn add_upvalue(&mut self, level: usize, index: u8, is_local: bool, position: Position) -> u8 {
+        let upvalue = UpvalueRef { is_local, index };
+        let upvalues = &mut self.functions[level].function.upvalues;
+
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return u8::try_from(existing).expect("Upvalue count is checked when adding");
+        }
+
+        if upvalues.len() >= MAX_UPVALUES {
+            self.error(position, None, "Too many closure variables in function.");
+            return 0;
+        }
+
+        upvalues.push(upvalue);
+        u8::try_from(upvalues.len() - 1).expect("Upvalue count was checked above")
+    }
+
+    fn get_variable(&mut self, name: &str, dst: u8, position: Position) {
+        match self.resolve(name, position) {
+            Variable::Local(register) => self.emit_move(dst, register, position),
+            Variable::Upvalue(index) => self.emit(Op::GetUpvalue, [dst, index, 0], position),
+            Variable::Global(constant) => {
+                self.emit_named(Op::GetGlobal, [dst, 0, 0], 1, constant, position);
+            }
+        }
+    }
+
+    fn emit_move(&mut self, dst: u8, src: u8, position: Position) {
+        if dst != src {
+            self.emit(Op::Move, [dst, src, 0], position);
+        }
+    }
+
+    /// Assign `value` to the variable `name`, also leaving it in `dst` if there is one.
+    fn assign(&mut self, name: &str, value: &Expr, dst: Option<u8>, position: Position) {
+        let free = self.current().free;
+
+        let variable = match self.resolve(name, position) {
+            Variable::Local(register) => {
+                // An `and`, `or` or property assignment writes its destination before it has
+                // read all of its operands, which may include the variable itself
+                if writes_early(value) {
+                    let temporary = self.allocate(position);
+                    self.expression(value, temporary);
+                    self.emit_move(register, temporary, position);
+                } else {
+                    self.expression(value, register);
+                }
+
+                if let Some(dst) = dst {
+                    self.emit_move(dst, register, position);
+                }
+                self.current().free = free;
+                return;
+            }
+            variable => variable,
+        };
+
+        let register = dst.unwrap_or_else(|| self.allocate(position));
+        self.expression(value, register);
+        match variable {
+            Variable::Upvalue(index) => self.emit(Op::SetUpvalue, [register, index, 0], position),
+            Variable::Global(constant) => {
+                self.emit_named(Op::SetGlobal, [register, 0, 0], 1, constant, position);
+            }
+            Variable::Local(_) => unreachable!("Locals are assigned above"),
+        }
+        self.current().free = free;
+    }
+
+    fn statements(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        let free = self.current().free;
+
+        match stmt {
+            Stmt::Var { name, initializer } => {
+                let register = self.allocate(name.position);
+                match initializer {
+                    Some(initializer) => self.expression(initializer, register),
+                    None => self.emit(Op::LoadNil, [register, 0, 0], name.position),
+                }
+                self.define(name, register, free);
+            }
+
+            Stmt::Function(function) => {
+                let register = self.allocate(function.name.position);
+                // A function may refer to itself, so it is usable before its body is compiled
+                if self.current().scope_depth > 0 {
+                    self.define(&function.name, register, free);
+                    self.function(function, register);
+                } else {
+                    self.function(function, register);
+                    self.define(&function.name, register, free);
+                }
+            }
+
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+
+            Stmt::Expression(expr) => {
+                // An assignment to a local can leave its value in the variable alone
+                if let ExprKind::Assign { name, value } = &expr.kind {
+                    self.assign(&name.text, value, None, expr.position);
+                } else {
+                    let register = self.allocate(expr.position);
+                    self.expression(expr, register);
+                    self.current().free = free;
+                }
+            }
+
+            Stmt::Print(expr) => {
+                let register = self.operand(expr);
+                self.emit(Op::Print, [register, 0, 0], expr.position);
+                self.current().free = free;
+            }
+
+            Stmt::Return { position, value } => match value {
+                Some(value) => {
+                    let register = self.operand(value);
+                    self.emit(Op::Return, [register, 0, 0], *position);
+                    self.current().free = free;
+                }
+                None => self.emit_return(*position),
+            },
+
+            Stmt::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let position = condition.position;
+                let register = self.operand(condition);
+                let then_jump = self.emit_jump(Op::JumpIfFalse, register, position);
+                self.current().free = free;
+                self.statement(then);
+
+                if let Some(otherwise) = otherwise {
+                    let else_jump = self.emit_jump(Op::Jump, 0, position);
+                    self.patch_jump(then_jump, position);
+                    self.statement(otherwise);
+                    self.patch_jump(else_jump, position);
+                } else {
+                    self.patch_jump(then_jump, position);
+                }
+            }
+
+            Stmt::While { condition, body } => {
+                let position = condition.position;
+                let start = self.chunk().code.len();
+                let register = self.operand(condition);
+                let exit_jump = self.emit_jump(Op::JumpIfFalse, register, position);
+                self.current().free = free;
+
+                self.statement(body);
+                self.emit_loop(start, position);
+                self.patch_jump(exit_jump, position);
+            }
+
+            Stmt::Block(stmts) => {
+                self.begin_scope();
+                self.statements(stmts);
+                let end = stmts.last().map_or_else(Position::default, Stmt::position);
+                self.end_scope(end);
+            }
+        }
+    }
+
+    /// Make the variable `name` available with the value in `register`. A local keeps the
+    /// register; a global is defined from it, and the registers from `free` up are released.
+    fn define(&mut self, name: &Name, register: u8, free: usize) {
+        if self.current().scope_depth > 0 {
+            self.add_local(&name.text, register, name.position);
+            return;
+        }
+
+        let constant = self.name_constant(&name.text, name.position);
+        self.emit_named(
+            Op::DefineGlobal,
+            [register, 0, 0],
+            1,
+            constant,
+            name.position,
+        );
+        self.current().free = free;
+    }
+
+    /// Compile `decl` into a function constant and emit the closure that captures its upvalues
+    /// into `dst`.
+    fn function(&mut self, decl: &FunctionDecl, dst: u8) {
+        let position = decl.name.position;
+        let arity = u8::try_from(decl.params.len()).expect("The parser limits parameter counts");
+        self.functions.push(FunctionState::new(
+            Some(decl.kind),
+            Some(decl.name.text.clone()),
+            arity,
+        ));
+
+        self.begin_scope();
+        for param in &decl.params {
+            let register = self.allocate(param.position);
+            self.add_local(&param.text, register, param.position);
+        }
+
+        self.statements(&decl.body);
+        let end = decl.body.last().map_or(position, Stmt::position);
+        self.emit_return(end);
+
+        // The function's registers are discarded with its frame, so the scope is not closed
+        let state = self.functions.pop().expect("Pushed above");
+        let constant = self.make_constant(Value::from(Rc::new(state.function)), position);
+        self.emit_wide(Op::Closure, dst, constant, position);
+    }
+
+    fn class(&mut self, name: &Name, superclass: Option<&Expr>, methods: &[Rc<FunctionDecl>]) {
+        let position = name.position;
+        let free = self.current().free;
+        let name_constant = self.name_constant(&name.text, position);
+
+        let class = self.allocate(position);
+        self.emit_named(Op::Class, [class, 0, 0], 1, name_constant, position);
+        let global = self.current().scope_depth == 0;
+        if global {
+            self.emit_named(Op::DefineGlobal, [class, 0, 0], 1, name_constant, position);
+        } else {
+            self.add_local(&name.text, class, position);
+        }
+
+        if let Some(superclass) = superclass {
+            self.begin_scope();
+            let register = self.allocate(superclass.position);
+            self.expression(superclass, register);
+            self.add_local("super", register, superclass.position);
+            self.emit(Op::Inherit, [class, register, 0], superclass.position);
+        }
+
+        for method in methods {
+            let method_constant = self.name_constant(&method.name.text, method.name.position);
+            let register = self.allocate(method.name.position);
+            self.function(method, register);
+            self.emit_named(
+                Op::Method,
+                [class, 0, register],
+                1,
+                method_constant,
+                method.name.position,
+            );
+            self.current().free -= 1;
+        }
+
+        if superclass.is_some() {
+            self.end_scope(position);
+        }
+
+        if global {
+            self.current().free = free;
+        }
+    }
+
+    fn literal(&mut self, literal: &Literal, dst: u8, position: Position) {
+        match literal {
+            Literal::Nil => self.emit(Op::LoadNil, [dst, 0, 0], position),
+            Literal::Bool(true) => self.emit(Op::LoadTrue, [dst, 0, 0], position),
+            Literal::Bool(false) => self.emit(Op::LoadFalse, [dst, 0, 0], position),
+            Literal::Number(n) => {
+                let constant = self.make_constant(Value::from(*n), position);
+                self.emit_wide(Op::LoadConstant, dst, constant, position);
+            }
+            Literal::String(s) => {
+                let constant = self.make_constant(Value::string(s.as_str()), position);
+                self.emit_wide(Op::LoadConstant, dst, constant, position);
+            }
+        }
+    }
+
+    /// The register holding the value of `expr`: a local's own register when `expr` just reads
+    /// one, and otherwise a new temporary the value is computed into.
+    fn operand(&mut self, expr: &Expr) -> u8 {
+        if let Some(register) = self.local_register(expr) {
+            return register;
+        }
+
+        let register = self.allocate(expr.position);
+        self.expression(expr, register);
+        register
+    }
+
+    /// The registers holding the values of `left` and `right`, evaluated in that order. A local
+    /// read by `left` is copied if evaluating `right` could assign to it first.
+    fn operands(&mut self, left: &Expr, right: &Expr) -> (u8, u8) {
+        let left = if has_effects(right) {
+            let register = self.allocate(left.position);
+            self.expression(left, register);
+            register
+        } else {
+            self.operand(left)
+        };
+
+        (left, self.operand(right))
+    }
+
+    /// The register of the local `expr` reads, if it only reads a local of the current function.
+    fn local_register(&mut self, expr: &Expr) -> Option<u8> {
+        let name = match &expr.kind {
+            ExprKind::Variable(name) => name.text.as_str(),
+            ExprKind::This => "this",
+            ExprKind::Grouping(inner) => return self.local_register(inner),
+            _ => return None,
+        };
+
+        let state = self.current();
+        let i = state.resolve_local(name)?;
+        Some(state.locals[i].register)
+    }
+
+    /// Compile `expr`, leaving its value in `dst`.
+    fn expression(&mut self, expr: &Expr, dst: u8) {
+        let position = expr.position;
+        let free = self.current().free;
+
+        match &expr.kind {
+            ExprKind::Literal(literal) => self.literal(literal, dst, position),
+
+            ExprKind::Variable(name) => self.get_variable(&name.text, dst, name.position),
+
+            ExprKind::Assign { name, value } => self.assign(&name.text, value, Some(dst), position),
+
+            ExprKind::Unary { op, operand } => {
+                let operand = self.operand(operand);
+                let op = match op {
+                    UnaryOp::Negate => Op::Negate,
+                    UnaryOp::Not => Op::Not,
+                };
+                self.emit(op, [dst, operand, 0], position);
+            }
+
+            ExprKind::Binary { op, left, right } => {
+                let (left, right) = self.operands(left, right);
+                let op = match op {
+                    BinaryOp::Add => Op::Add,
+                    BinaryOp::Sub => Op::Sub,
+                    BinaryOp::Mul => Op::Mul,
+                    BinaryOp::Div => Op::Div,
+                    BinaryOp::Equal => Op::Equal,
+                    BinaryOp::NotEqual => Op::NotEqual,
+                    BinaryOp::Less => Op::Less,
+                    BinaryOp::LessEqual => Op::LessEqual,
+                    BinaryOp::Greater => Op::Greater,
+                    BinaryOp::GreaterEqual => Op::GreaterEqual,
+                };
+                self.emit(op, [dst, left, right], position);
+            }
+
+            ExprKind::Logical { op, left, right } => {
+                self.expression(left, dst);
+                let op = match op {
+                    LogicalOp::And => Op::JumpIfFalse,
+                    LogicalOp::Or => Op::JumpIfTrue,
+                };
+                let end_jump = self.emit_jump(op, dst, position);
+                self.expression(right, dst);
+                self.patch_jump(end_jump, position);
+            }
+
+            ExprKind::Grouping(inner) => self.expression(inner, dst),
+
+            ExprKind::Call { callee, args } => self.call(callee, args, dst, position),
+
+            ExprKind::Get { object, name } => {
+                let object = self.operand(object);
+                let constant = self.name_constant(&name.text, name.position);
+                self.emit_named(Op::GetProperty, [dst, object, 0], 2, constant, position);
+            }
+
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                let object = if has_effects(value) {
+                    let register = self.allocate(object.position);
+                    self.expression(object, register);
+                    register
+                } else {
+                    self.operand(object)
+                };
+                self.expression(value, dst);
+                let constant = self.name_constant(&name.text, name.position);
+                self.emit_named(Op::SetProperty, [object, 0, dst], 1, constant, position);
+            }
+
+            ExprKind::This => self.get_variable("this", dst, position),
+
+            ExprKind::Super { method } => {
+                let constant = self.name_constant(&method.text, method.position);
+                self.get_variable("this", dst, position);
+                let superclass = self.variable_operand("super", position);
+                self.emit_named(Op::GetSuper, [dst, superclass, 0], 2, constant, position);
+            }
+        }
+
+        self.current().free = free;
+    }
+
+    /// The register holding the variable `name`, read into a new temporary unless it is a local.
+    fn variable_operand(&mut self, name: &str, position: Position) -> u8 {
+        if let Variable::Local(register) = self.resolve(name, position) {
+            return register;
+        }
+
+        let register = self.allocate(position);
+        self.get_variable(name, register, position);
+        register
+    }
+
+    /// Compile a call into `dst`, fusing method lookups with the call into a single `Invoke`.
+    /// The callee and arguments go in consecutive registers starting at `dst` when it is the
+    /// topmost temporary, and are copied there otherwise.
+    fn call(&mut self, callee: &Expr, args: &[Expr], dst: u8, position: Position) {
+        let arg_count = u8::try_from(args.len()).expect("The parser limits argument counts");
+
+        let state = self.current();
+        let base = if state.is_temporary(dst) && usize::from(dst) + 1 == state.free {
+            dst
+        } else {
+            self.allocate(position)
+        };
+
+        match &callee.kind {
+            ExprKind::Get { object, name } => {
+                self.expression(object, base);
+                let constant = self.name_constant(&name.text, name.position);
+                self.arguments(args);
+                self.emit_named(Op::Invoke, [base, 0, arg_count], 1, constant, position);
+            }
+
+            ExprKind::Super { method } => {
+                let constant = self.name_constant(&method.text, method.position);
+                self.get_variable("this", base, callee.position);
+                self.arguments(args);
+                let superclass = self.allocate(callee.position);
+                self.get_variable("super", superclass, callee.position);
+                self.emit_named(Op::SuperInvoke, [base, 0, arg_count], 1, constant, position);
+            }
+
+            _ => {
+                self.expression(callee, base);
+                self.arguments(args);
+                self.emit(Op::Call, [base, arg_count, 0], position);
+            }
+        }
+
+        self.emit_move(dst, base, position);
+    }
+
+    /// Compile each argument into the next free register.
+    fn arguments(&mut self, args: &[Expr]) {
+        for arg in args {
+            let register = self.allocate(arg.position);
+            self.expression(arg, register);
+        }
+    }
+}
+
+/// Whether evaluating `expr` could assign to a variable, directly or by calling a function.
+fn has_effects(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Literal(_) | ExprKind::Variable(_) | ExprKind::This | ExprKind::Super { .. } => {
+            false
+        }
+        ExprKind::Assign { .. } | ExprKind::Call { .. } => true,
+        ExprKind::Unary { operand, .. } => has_effects(operand),
+        ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+            has_effects(left) || has_effects(right)
+        }
+        ExprKind::Grouping(inner) | ExprKind::Get { object: inner, .. } => has_effects(inner),
+        ExprKind::Set { object, value, .. } => has_effects(object) || has_effects(value),
+    }
+}
+
+/// Whether compiling `expr` into a register writes it before the last of `expr`'s operands is
+/// read.
+fn writes_early(expr: &Expr) -> bool {
+    match &expr.kind {
+        // Constant logical expressions were folded away by `analyze`
+        ExprKind::Logical { .. } | ExprKind::Set { .. } => true,
+        ExprKind::Grouping(inner) => writes_early(inner),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write as _;
+
+    use super::*;
+    use crate::register::disassemble;
+
+    /// The disassembly of `source`, without offsets and positions.
+    fn listing(source: &str) -> Vec<String> {
+        let chunk = compile(source).unwrap();
+        disassemble(&chunk, "script")
+            .lines()
+            .map(|line| {
+                if line.starts_with("==") {
+                    line.to_string()
+                } else {
+                    line.split_whitespace()
+                        .skip(2)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+            })
+            .collect()
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        let Err(InterpretError::Compiler(errors)) = compile(source) else {
+            panic!("expected compile errors");
+        };
+        errors.iter().map(|e| e.message.clone()).collect()
+    }
+
+    #[test]
+    fn reads_operands_from_locals_registers() {
+        let listing = listing("fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}");
+        let add = listing.iter().position(|l| l == "== <fn add> ==").unwrap();
+        assert_eq!(
+            listing[add + 1..],
+            ["Add r3 r1 r2", "Return r3", "LoadNil r4", "Return r4"]
+        );
+    }
+
+    #[test]
+    fn reuses_temporaries_after_each_statement() {
+        assert_eq!(
+            listing("var a = 1;\nvar b = 2;\nprint a + b * a;\nprint a;"),
+            [
+                "== script ==",
+                "LoadConstant r1 0 (number 1)",
+                "DefineGlobal r1 1 (string a)",
+                "LoadConstant r1 2 (number 2)",
+                "DefineGlobal r1 3 (string b)",
+                "GetGlobal r2 1 (string a)",
+                "GetGlobal r4 3 (string b)",
+                "GetGlobal r5 1 (string a)",
+                "Mul r3 r4 r5",
+                "Add r1 r2 r3",
+                "Print r1",
+                "GetGlobal r1 1 (string a)",
+                "Print r1",
+                "LoadNil r1",
+                "Return r1",
+            ]
+        );
+    }
+
+    #[test]
+    fn names_constants_beyond_a_byte_with_an_extra_arg() {
+        let mut source = String::new();
+        for i in 0..300 {
+            writeln!(source, "var v{i};").unwrap();
+        }
+        source.push_str("print v0;");
+
+        let listing = listing(&source);
+        assert_eq!(
+            listing[listing.len() - 7..],
+            [
+                "LoadNil r1",
+                "DefineGlobal r1 299 (string v299)",
+                "ExtraArg 299",
+                "GetGlobal r1 0 (string v0)",
+                "Print r1",
+                "LoadNil r1",
+                "Return r1",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_running_out_of_registers_once() {
+        let depth = MAX_REGISTERS;
+        let source = format!(
+            "var a = 1;\nprint {}a{};",
+            "a + (".repeat(depth),
+            ")".repeat(depth)
+        );
+        assert_eq!(errors(&source), ["Too many registers in function."]);
+    }
+
+    #[test]
+    fn limits_locals_like_the_stack_compiler() {
+        let block = |locals: usize| {
+            let mut block = "{\n".to_string();
+            for i in 0..locals {
+                writeln!(block, "var v{i};").unwrap();
+            }
+            block + "}"
+        };
+
+        assert!(compile(&block(MAX_REGISTERS - 1)).is_ok());
+        assert!(crate::compiler::compile(&block(MAX_REGISTERS - 1)).is_ok());
+
+        // The local's register is allocated before it is declared, so that fails too
+        let errors = errors(&block(MAX_REGISTERS));
+        assert!(errors.contains(&"Too many local variables in function.".to_string()));
+        assert!(crate::compiler::compile(&block(MAX_REGISTERS)).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! A register-based backend, an alternative to the stack VM in the style of Lua 5.
+//!
+//! It shares the front end and the value model with the stack VM: programs are parsed and
+//! resolved by [`crate::compiler`], functions are `Function` values whose [`Chunk`] holds their
+//! code, constants and positions, and the same natives are predefined.
+//!
+//! Each instruction is four bytes: an [`Op`] followed by the operands A, B and C. A is the
+//! register an instruction writes, or the first register it reads when it writes none. Registers
+//! are numbered from the start of the calling frame's window, where register 0 holds the callee
+//! (or the receiver in methods) and the parameters follow. Some instructions read B and C together
+//! as `Bx`, a little-endian constant index, or as `sBx`, a signed jump distance in bytes measured
+//! from the end of the instruction. A name constant whose index does not fit in its one-byte
+//! operand is named instead by an `ExtraArg` following the instruction.
+
+pub mod codegen;
+pub mod vm;
+
+use std::fmt::Write as _;
+
+use crate::chunk::Chunk;
+use crate::value::Value;
+
+pub use codegen::compile;
+pub use vm::RegisterVM;
+
+pub const INSTRUCTION_SIZE: usize = 4;
+
+/// Registers are addressed by one-byte operands, so a frame's window holds at most this many.
+pub const MAX_REGISTERS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Op {
+    /// R(A) = R(B)
+    Move,
+    /// R(A) = K(Bx)
+    LoadConstant,
+    LoadNil,
+    LoadTrue,
+    LoadFalse,
+    /// R(A) = the global named K(B)
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    /// R(A) = upvalue B
+    GetUpvalue,
+    SetUpvalue,
+    /// R(A) = R(B).K(C)
+    GetProperty,
+    /// R(A).K(B) = R(C)
+    SetProperty,
+    /// R(A) = the method K(C) of the class R(B), bound to the receiver in R(A)
+    GetSuper,
+    /// R(A) = R(B) op R(C)
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// R(A) = op R(B)
+    Not,
+    Negate,
+    Print,
+    /// Jump by sBx
+    Jump,
+    /// Jump by sBx if R(A) is falsey
+    JumpIfFalse,
+    JumpIfTrue,
+    /// Call R(A) with the B arguments above it, leaving the result in R(A)
+    Call,
+    /// Call the method K(B) of R(A) with the C arguments above it
+    Invoke,
+    /// Call the method K(B) of the class after the C arguments above the receiver R(A)
+    SuperInvoke,
+    /// R(A) = a closure of the function K(Bx)
+    Closure,
+    /// Close the upvalues of registers A and above
+    Close,
+    Return,
+    /// R(A) = a new class named K(B)
+    Class,
+    /// Copy the methods of the class R(B) into the class R(A)
+    Inherit,
+    /// Add the closure R(C) to the class R(A) as the method K(B)
+    Method,
+    /// Not executed: A, B and C read together as `Ax` are the index of the name constant of the
+    /// instruction before, which does not fit in its operand byte
+    ExtraArg,
+}
+
+impl Op {
+    pub const ALL: [Op; 39] = [
+        Op::Move,
+        Op::LoadConstant,
+        Op::LoadNil,
+        Op::LoadTrue,
+        Op::LoadFalse,
+        Op::GetGlobal,
+        Op::DefineGlobal,
+        Op::SetGlobal,
+        Op::GetUpvalue,
+        Op::SetUpvalue,
+        Op::GetProperty,
+        Op::SetProperty,
+        Op::GetSuper,
+        Op::Equal,
+        Op::NotEqual,
+        Op::Less,
+        Op::LessEqual,
+        Op::Greater,
+        Op::GreaterEqual,
+        Op::Add,
+        Op::Sub,
+        Op::Mul,
+        Op::Div,
+        Op::Not,
+        Op::Negate,
+        Op::Print,
+        Op::Jump,
+        Op::JumpIfFalse,
+        Op::JumpIfTrue,
+        Op::Call,
+        Op::Invoke,
+        Op::SuperInvoke,
+        Op::Closure,
+        Op::Close,
+        Op::Return,
+        Op::Class,
+        Op::Inherit,
+        Op::Method,
+        Op::ExtraArg,
+    ];
+}
+
+impl TryFrom<u8> for Op {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Op::ALL
+            .get(usize::from(value))
+            .copied()
+            .ok_or("Invalid opcode")
+    }
+}
+
+/// The instruction at `i`, as its opcode byte and operands A, B and C.
+pub fn instruction(chunk: &Chunk, i: usize) -> Option<[u8; INSTRUCTION_SIZE]> {
+    chunk.code.get(i..i + INSTRUCTION_SIZE)?.try_into().ok()
+}
+
+/// The B and C operands read together as one 16-bit operand.
+pub fn wide(b: u8, c: u8) -> u16 {
+    u16::from_le_bytes([b, c])
+}
+
+/// The operands A, B and C read together as the 24-bit operand of `ExtraArg`.
+pub fn extra_arg(a: u8, b: u8, c: u8) -> usize {
+    usize::from(a) | usize::from(b) << 8 | usize::from(c) << 16
+}
+
+/// The index of the name constant given by the operand `byte` of the instruction at `i`, or by
+/// the `ExtraArg` following it if there is one.
+pub fn name_index(chunk: &Chunk, i: usize, byte: u8) -> usize {
+    match instruction(chunk, i + INSTRUCTION_SIZE) {
+        Some([op, a, b, c]) if op == Op::ExtraArg as u8 => extra_arg(a, b, c),
+        _ => usize::from(byte),
+    }
+}
+
+/// Offset of the instruction a jump at `i` with distance `distance` transfers control to.
+pub fn jump_target(i: usize, distance: i16) -> Option<usize> {
+    (i + INSTRUCTION_SIZE).checked_add_signed(isize::from(distance))
+}
+
+/// Disassemble register code under a `== name ==` header, followed by that of each nested
+/// function under headers of their own.
+pub fn disassemble(chunk: &Chunk, name: &str) -> String {
+    let mut res = format!("== {name} ==\n");
+
+    for i in (0..chunk.code.len()).step_by(INSTRUCTION_SIZE) {
+        let position = chunk
+            .get_position(i)
+            .map_or("?".to_string(), |p| p.to_string());
+        let description = describe_instruction(chunk, i).unwrap_or_else(|| "<invalid>".to_string());
+
+        // `write!`ing into a String is infallible
+        writeln!(res, "{i:04} {position:>8} {description}").unwrap();
+    }
+
+    for function in chunk.functions() {
+        res.push_str(&disassemble(&function.chunk, &function.to_string()));
+    }
+
+    res
+}
+
+/// The mnemonic and decoded operands of the instruction at `i`.
+fn describe_instruction(chunk: &Chunk, i: usize) -> Option<String> {
+    let [op, a, b, c] = instruction(chunk, i)?;
+    let op = Op::try_from(op).ok()?;
+    let constant = |index: usize| chunk.constants.get(index).map(describe_constant);
+    let name = |byte: u8| {
+        let index = name_index(chunk, i, byte);
+        Some(format!("{index} {}", constant(index)?))
+    };
+
+    let res = match op {
+        Op::LoadNil | Op::LoadTrue | Op::LoadFalse | Op::Print | Op::Close | Op::Return => {
+            format!("{op:?} r{a}")
+        }
+
+        Op::Move | Op::Not | Op::Negate | Op::Inherit => format!("{op:?} r{a} r{b}"),
+
+        Op::Equal
+        | Op::NotEqual
+        | Op::Less
+        | Op::LessEqual
+        | Op::Greater
+        | Op::GreaterEqual
+        | Op::Add
+        | Op::Sub
+        | Op::Mul
+        | Op::Div => format!("{op:?} r{a} r{b} r{c}"),
+
+        Op::LoadConstant => {
+            let index = usize::from(wide(b, c));
+            format!("{op:?} r{a} {index} {}", constant(index)?)
+        }
+
+        Op::GetGlobal | Op::DefineGlobal | Op::SetGlobal | Op::Class => {
+            format!("{op:?} r{a} {}", name(b)?)
+        }
+
+        Op::GetUpvalue | Op::SetUpvalue => format!("{op:?} r{a} u{b}"),
+
+        Op::GetProperty | Op::GetSuper => format!("{op:?} r{a} r{b} {}", name(c)?),
+
+        Op::SetProperty | Op::Method => format!("{op:?} r{a} {} r{c}", name(b)?),
+
+        Op::Call => format!("{op:?} r{a} {b}"),
+
+        Op::Invoke | Op::SuperInvoke => format!("{op:?} r{a} {} {c}", name(b)?),
+
+        Op::ExtraArg => format!("{op:?} {}", extra_arg(a, b, c)),
+
+        Op::Jump => {
+            let distance = i16::from_le_bytes([b, c]);
+            format!("{op:?} {distance} -> {:04}", jump_target(i, distance)?)
+        }
+
+        Op::JumpIfFalse | Op::JumpIfTrue => {
+            let distance = i16::from_le_bytes([b, c]);
+            format!("{op:?} r{a} {distance} -> {:04}", jump_target(i, distance)?)
+        }
+
+        Op::Closure => {
+            let index = usize::from(wide(b, c));
+            let function = chunk.constants.get(index)?;
+            let mut res = format!("{op:?} r{a} {index} {}", describe_constant(function));
+            for upvalue in function.as_function().map_or(&[][..], |f| &f.upvalues) {
+                let captured = if upvalue.is_local { "r" } else { "u" };
+                write!(res, " [{captured}{}]", upvalue.index).unwrap();
+            }
+            res
+        }
+    };
+
+    Some(res)
+}
+
+fn describe_constant(constant: &Value) -> String {
+    format!("({} {constant})", constant.type_name())
+}
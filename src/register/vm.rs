@@ -0,0 +1,840 @@
+//! The interpreter for register code. Each call frame owns a window of [`MAX_REGISTERS`]
+//! registers starting at the register that held the callee, so the arguments a caller leaves
+//! above the callee become the callee's parameters without being copied. The windows of nested
+//! calls overlap, and every one fits in the register file allocated up front.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::natives::{self, Context, NativeError};
+use crate::value::{Class, Closure, Function, Instance, Unpacked, Upvalue, Value};
+use crate::vm::{FRAMES_MAX, RuntimeError, add, bind_method};
+use crate::{InterpretError, InterpretResult};
+
+use super::{
+    INSTRUCTION_SIZE, MAX_REGISTERS, Op, compile, extra_arg, instruction, jump_target, wide,
+};
+
+/// An active function call.
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// Index of the frame's register 0 in the register file
+    base: usize,
+}
+
+pub struct RegisterVM {
+    frames: Vec<CallFrame>,
+    registers: Vec<Value>,
+    globals: HashMap<String, Value>,
+    /// Upvalues still referring to registers, closed when those registers go out of scope
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    output: Box<dyn Write>,
+    /// Offset of the instruction being executed in the current frame, for error reporting
+    offset: usize,
+    /// Instructions executed since the VM was created
+    executed: u64,
+    /// Number of frames below the call being run to completion. Returning to this depth hands
+    /// the result back to Rust.
+    entry_depth: usize,
+}
+
+impl Default for RegisterVM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Context for RegisterVM {
+    fn call(&mut self, callee: &Value, arguments: &[Value]) -> InterpretResult<Value> {
+        RegisterVM::call(self, callee, arguments)
+    }
+}
+
+impl RegisterVM {
+    pub fn new() -> Self {
+        // A frame's window starts within its caller's, so the deepest one ends at most one window
+        // past the start of the last frame allowed
+        let registers = (FRAMES_MAX + 1) * MAX_REGISTERS;
+
+        Self {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            registers: vec![Value::nil(); registers],
+            globals: natives::globals(),
+            open_upvalues: Vec::new(),
+            output: Box::new(io::stdout()),
+            offset: 0,
+            executed: 0,
+            entry_depth: 0,
+        }
+    }
+
+    /// Send the output of `print` statements to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Compile `source` to register code and run it. Globals defined by previous calls are kept.
+    pub fn interpret(&mut self, source: &str) -> InterpretResult<()> {
+        self.interpret_chunk(compile(source)?)
+    }
+
+    /// Run `chunk`, which holds register code compiled by [`compile`].
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> InterpretResult<()> {
+        self.load(chunk);
+
+        self.run().map_err(|e| {
+            // As in the stack VM, unwind the frames once the error has been located in them
+            let error = self.locate(e);
+            self.reset();
+            error
+        })
+    }
+
+    /// Make `chunk`, which holds register code, the top-level code to be run.
+    fn load(&mut self, chunk: Chunk) {
+        let closure = Rc::new(Closure {
+            function: Rc::new(Function {
+                chunk,
+                ..Function::default()
+            }),
+            upvalues: Vec::new(),
+        });
+
+        self.reset();
+
+        self.registers[0] = Value::from(Rc::clone(&closure));
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base: 0,
+        });
+    }
+
+    /// Call `callee` with `arguments` and run it to completion, returning its result. The frames
+    /// are left as they were, even on error.
+    pub fn call(&mut self, callee: &Value, arguments: &[Value]) -> InterpretResult<Value> {
+        let depth = self.frames.len();
+        let offset = self.offset;
+        let entry_depth = std::mem::replace(&mut self.entry_depth, depth);
+
+        // The callee goes past every register of the innermost frame, which at most FRAMES_MAX
+        // frames keep within the register file
+        let callee_register = self
+            .frames
+            .last()
+            .map_or(0, |frame| frame.base + MAX_REGISTERS);
+
+        let result = self
+            .call_to_completion(callee, callee_register, arguments)
+            .map_err(|error| {
+                // Only Rust code is running if the call failed before entering a frame
+                if self.frames.is_empty() {
+                    InterpretError::Runtime {
+                        error,
+                        position: None,
+                    }
+                } else {
+                    self.locate(error)
+                }
+            });
+
+        self.close_upvalues(callee_register);
+        self.frames.truncate(depth);
+        self.offset = offset;
+        self.entry_depth = entry_depth;
+
+        if depth == 0 {
+            self.output.flush().map_err(|e| InterpretError::Runtime {
+                error: RuntimeError::Output(e),
+                position: None,
+            })?;
+        }
+
+        result
+    }
+
+    fn call_to_completion(
+        &mut self,
+        callee: &Value,
+        callee_register: usize,
+        arguments: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        let argc = u8::try_from(arguments.len()).map_err(|_| RuntimeError::TooManyArguments)?;
+        if self.frames.len() >= FRAMES_MAX {
+            return Err(RuntimeError::CallDepth { max: FRAMES_MAX });
+        }
+
+        self.registers[callee_register] = callee.clone();
+        self.registers[callee_register + 1..][..arguments.len()].clone_from_slice(arguments);
+
+        let depth = self.frames.len();
+        self.call_value(callee, callee_register, argc)?;
+
+        // Natives and classes without initializers return without entering a frame
+        if self.frames.len() > depth {
+            self.execute()?;
+        }
+
+        Ok(self.registers[callee_register].clone())
+    }
+
+    fn locate(&self, error: RuntimeError) -> InterpretError {
+        // Errors in code called back into by natives were located where they happened
+        if let RuntimeError::Callback(error) = error {
+            return *error;
+        }
+
+        InterpretError::Runtime {
+            error,
+            position: self
+                .frames
+                .last()
+                .and_then(|frame| frame.closure.function.chunk.get_position(self.offset)),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        let result = self.execute();
+        self.output.flush().map_err(RuntimeError::Output)?;
+        result
+    }
+
+    /// Run the loaded code until the top-level function returns. The current frame's closure,
+    /// `ip` and base are kept in locals, and written back to the frame when a call is made.
+    #[allow(clippy::too_many_lines)]
+    fn execute(&mut self) -> Result<(), RuntimeError> {
+        let frame = self.frames.last().ok_or(RuntimeError::UnexpectedEnd)?;
+        let mut closure = Rc::clone(&frame.closure);
+        let mut ip = frame.ip;
+        let mut base = frame.base;
+
+        loop {
+            let chunk = &closure.function.chunk;
+            let [op, a, b, c] = instruction(chunk, ip).ok_or(RuntimeError::UnexpectedEnd)?;
+            let op = Op::try_from(op).map_err(|_| RuntimeError::InvalidOpcode {
+                offset: ip,
+                byte: op,
+            })?;
+
+            self.offset = ip;
+            self.executed += 1;
+            ip += INSTRUCTION_SIZE;
+
+            let ra = base + usize::from(a);
+            let rb = base + usize::from(b);
+            let rc = base + usize::from(c);
+
+            match op {
+                Op::Move => self.registers[ra] = self.registers[rb].clone(),
+
+                Op::LoadConstant => {
+                    self.registers[ra] = chunk.constants[usize::from(wide(b, c))].clone();
+                }
+
+                Op::LoadNil => self.registers[ra] = Value::nil(),
+                Op::LoadTrue => self.registers[ra] = Value::from(true),
+                Op::LoadFalse => self.registers[ra] = Value::from(false),
+
+                Op::GetGlobal => {
+                    let name = name(chunk, &mut ip, b);
+                    let value = self.globals.get(name).cloned().ok_or_else(|| {
+                        RuntimeError::UndefinedVariable {
+                            name: name.to_string(),
+                        }
+                    })?;
+                    self.registers[ra] = value;
+                }
+
+                Op::DefineGlobal => {
+                    let name = name(chunk, &mut ip, b);
+                    self.globals
+                        .insert(name.to_string(), self.registers[ra].clone());
+                }
+
+                Op::SetGlobal => {
+                    let name = name(chunk, &mut ip, b);
+                    let Some(global) = self.globals.get_mut(name) else {
+                        return Err(RuntimeError::UndefinedVariable {
+                            name: name.to_string(),
+                        });
+                    };
+                    *global = self.registers[ra].clone();
+                }
+
+                Op::GetUpvalue => {
+                    let value = match &*closure.upvalues[usize::from(b)].borrow() {
+                        Upvalue::Open(register) => self.registers[*register].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.registers[ra] = value;
+                }
+
+                Op::SetUpvalue => {
+                    let value = self.registers[ra].clone();
+                    match &mut *closure.upvalues[usize::from(b)].borrow_mut() {
+                        Upvalue::Open(register) => self.registers[*register] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+
+                Op::GetProperty => {
+                    let name = name(chunk, &mut ip, c);
+                    let Unpacked::Instance(instance) = self.registers[rb].unpack() else {
+                        return Err(RuntimeError::NotAnInstance);
+                    };
+
+                    let field = instance.fields.borrow().get(name).cloned();
+                    self.registers[ra] = match field {
+                        Some(value) => value,
+                        None => bind_method(&instance.class, name, Value::from(instance.clone()))?,
+                    };
+                }
+
+                Op::SetProperty => {
+                    let name = name(chunk, &mut ip, b);
+                    let Unpacked::Instance(instance) = self.registers[ra].unpack() else {
+                        return Err(RuntimeError::FieldOnNonInstance);
+                    };
+
+                    let value = self.registers[rc].clone();
+                    instance.fields.borrow_mut().insert(name.to_string(), value);
+                }
+
+                Op::GetSuper => {
+                    let name = name(chunk, &mut ip, c);
+                    let Unpacked::Class(superclass) = self.registers[rb].unpack() else {
+                        return Err(RuntimeError::SuperclassNotClass);
+                    };
+
+                    let receiver = self.registers[ra].clone();
+                    self.registers[ra] = bind_method(&superclass, name, receiver)?;
+                }
+
+                Op::Equal => {
+                    self.registers[ra] = Value::from(self.registers[rb] == self.registers[rc]);
+                }
+                Op::NotEqual => {
+                    self.registers[ra] = Value::from(self.registers[rb] != self.registers[rc]);
+                }
+
+                Op::Less => self.registers[ra] = Value::from(self.numbers(rb, rc, |a, b| a < b)?),
+                Op::Greater => {
+                    self.registers[ra] = Value::from(self.numbers(rb, rc, |a, b| a > b)?);
+                }
+                // Like the stack VM, `a <= b` is `!(a > b)` and `a >= b` is `!(a < b)`
+                Op::LessEqual => {
+                    self.registers[ra] = Value::from(!self.numbers(rb, rc, |a, b| a > b)?);
+                }
+                Op::GreaterEqual => {
+                    self.registers[ra] = Value::from(!self.numbers(rb, rc, |a, b| a < b)?);
+                }
+
+                Op::Add => self.registers[ra] = add(&self.registers[rb], &self.registers[rc])?,
+                Op::Sub => self.registers[ra] = Value::from(self.numbers(rb, rc, |a, b| a - b)?),
+                Op::Mul => self.registers[ra] = Value::from(self.numbers(rb, rc, |a, b| a * b)?),
+                Op::Div => self.registers[ra] = Value::from(self.numbers(rb, rc, |a, b| a / b)?),
+
+                Op::Not => self.registers[ra] = Value::from(self.registers[rb].is_falsey()),
+
+                Op::Negate => {
+                    let Some(operand) = self.registers[rb].as_number() else {
+                        return Err(RuntimeError::OperandMustBeNumber);
+                    };
+                    self.registers[ra] = Value::from(-operand);
+                }
+
+                Op::Print => {
+                    writeln!(self.output, "{}", self.registers[ra])
+                        .map_err(RuntimeError::Output)?;
+                }
+
+                Op::Jump => ip = jump(self.offset, b, c)?,
+
+                Op::JumpIfFalse => {
+                    if self.registers[ra].is_falsey() {
+                        ip = jump(self.offset, b, c)?;
+                    }
+                }
+
+                Op::JumpIfTrue => {
+                    if !self.registers[ra].is_falsey() {
+                        ip = jump(self.offset, b, c)?;
+                    }
+                }
+
+                Op::Call | Op::Invoke | Op::SuperInvoke => {
+                    // The method name is read first, as it may move `ip` past an `ExtraArg`
+                    let method = (op != Op::Call).then(|| name(chunk, &mut ip, b));
+                    self.frame_mut().ip = ip;
+
+                    match (op, method) {
+                        (Op::Invoke, Some(method)) => self.invoke(method, ra, c)?,
+                        (Op::SuperInvoke, Some(method)) => {
+                            let superclass = ra + usize::from(c) + 1;
+                            let Unpacked::Class(superclass) = self.registers[superclass].unpack()
+                            else {
+                                return Err(RuntimeError::SuperclassNotClass);
+                            };
+                            self.invoke_from_class(&superclass, method, ra, c)?;
+                        }
+                        _ => {
+                            let callee = self.registers[ra].clone();
+                            self.call_value(&callee, ra, b)?;
+                        }
+                    }
+
+                    let frame = self.frames.last().expect("Calls leave the caller's frame");
+                    closure = Rc::clone(&frame.closure);
+                    ip = frame.ip;
+                    base = frame.base;
+                }
+
+                Op::Closure => {
+                    let Unpacked::Function(function) =
+                        chunk.constants[usize::from(wide(b, c))].unpack()
+                    else {
+                        return Err(RuntimeError::InvalidConstant {
+                            offset: self.offset,
+                        });
+                    };
+
+                    let upvalues = function
+                        .upvalues
+                        .iter()
+                        .map(|upvalue| {
+                            let index = usize::from(upvalue.index);
+                            if upvalue.is_local {
+                                self.capture_upvalue(base + index)
+                            } else {
+                                Rc::clone(&closure.upvalues[index])
+                            }
+                        })
+                        .collect();
+
+                    self.registers[ra] = Value::from(Rc::new(Closure { function, upvalues }));
+                }
+
+                Op::Close => self.close_upvalues(ra),
+
+                Op::Return => {
+                    let result = self.registers[ra].clone();
+                    let frame = self.frames.pop().expect("Instructions only run in a frame");
+                    self.close_upvalues(frame.base);
+
+                    // The callee's register 0 is the register the caller called it from
+                    self.registers[frame.base] = result;
+                    if self.frames.len() == self.entry_depth {
+                        return Ok(());
+                    }
+
+                    let caller = self
+                        .frames
+                        .last()
+                        .expect("Frames remain above the entry depth");
+                    closure = Rc::clone(&caller.closure);
+                    ip = caller.ip;
+                    base = caller.base;
+                }
+
+                Op::Class => {
+                    let class = Class {
+                        name: name(chunk, &mut ip, b).to_string(),
+                        methods: RefCell::default(),
+                    };
+                    self.registers[ra] = Value::from(Rc::new(class));
+                }
+
+                Op::Inherit => {
+                    let Unpacked::Class(superclass) = self.registers[rb].unpack() else {
+                        return Err(RuntimeError::SuperclassNotClass);
+                    };
+                    let Unpacked::Class(subclass) = self.registers[ra].unpack() else {
+                        return Err(RuntimeError::InvalidConstant {
+                            offset: self.offset,
+                        });
+                    };
+
+                    let methods = superclass.methods.borrow().clone();
+                    subclass.methods.borrow_mut().extend(methods);
+                }
+
+                Op::Method => {
+                    let (Unpacked::Class(class), Unpacked::Closure(method)) =
+                        (self.registers[ra].unpack(), self.registers[rc].unpack())
+                    else {
+                        return Err(RuntimeError::InvalidConstant {
+                            offset: self.offset,
+                        });
+                    };
+
+                    let name = name(chunk, &mut ip, b);
+                    class.methods.borrow_mut().insert(name.to_string(), method);
+                }
+
+                // Always skipped by the instruction it belongs to
+                Op::ExtraArg => {
+                    return Err(RuntimeError::InvalidOpcode {
+                        offset: self.offset,
+                        byte: Op::ExtraArg as u8,
+                    });
+                }
+            }
+        }
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("Instructions only run in a frame")
+    }
+
+    /// Apply `operator` to the numbers in registers `a` and `b`.
+    fn numbers<T>(
+        &self,
+        a: usize,
+        b: usize,
+        operator: impl Fn(f64, f64) -> T,
+    ) -> Result<T, RuntimeError> {
+        let (Some(a), Some(b)) = (self.registers[a].as_number(), self.registers[b].as_number())
+        else {
+            return Err(RuntimeError::OperandsMustBeNumbers);
+        };
+
+        Ok(operator(a, b))
+    }
+
+    /// Call `callee`, which is in register `callee_register` below its `argc` arguments. Its
+    /// result is left in that register.
+    fn call_value(
+        &mut self,
+        callee: &Value,
+        callee_register: usize,
+        argc: u8,
+    ) -> Result<(), RuntimeError> {
+        match callee.unpack() {
+            Unpacked::Closure(closure) => self.call_closure(closure, callee_register, argc),
+
+            Unpacked::BoundMethod(bound) => {
+                self.registers[callee_register] = bound.receiver.clone();
+                self.call_closure(Rc::clone(&bound.method), callee_register, argc)
+            }
+
+            Unpacked::Class(class) => {
+                let instance = Instance {
+                    class: Rc::clone(&class),
+                    fields: RefCell::default(),
+                };
+                self.registers[callee_register] = Value::from(Rc::new(instance));
+
+                let initializer = class.methods.borrow().get("init").cloned();
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, callee_register, argc),
+                    None if argc != 0 => Err(RuntimeError::Arity {
+                        expected: 0,
+                        got: argc,
+                    }),
+                    None => Ok(()),
+                }
+            }
+
+            Unpacked::Native(native) => {
+                if argc != native.arity {
+                    return Err(RuntimeError::Arity {
+                        expected: native.arity,
+                        got: argc,
+                    });
+                }
+
+                // The native may call back into the VM, which writes registers past this frame's
+                let arguments = callee_register + 1..callee_register + 1 + usize::from(argc);
+                let arguments = self.registers[arguments].to_vec();
+                let result = (native.function)(self, &arguments).map_err(|error| match error {
+                    NativeError::Message(message) => RuntimeError::Native {
+                        name: native.name.clone(),
+                        message,
+                    },
+                    NativeError::Callback(error) => RuntimeError::Callback(Box::new(error)),
+                })?;
+
+                self.registers[callee_register] = result;
+                Ok(())
+            }
+
+            _ => Err(RuntimeError::NotCallable),
+        }
+    }
+
+    fn call_closure(
+        &mut self,
+        closure: Rc<Closure>,
+        callee_register: usize,
+        argc: u8,
+    ) -> Result<(), RuntimeError> {
+        if argc != closure.function.arity {
+            return Err(RuntimeError::Arity {
+                expected: closure.function.arity,
+                got: argc,
+            });
+        }
+
+        if self.frames.len() >= FRAMES_MAX {
+            return Err(RuntimeError::CallDepth { max: FRAMES_MAX });
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base: callee_register,
+        });
+
+        Ok(())
+    }
+
+    /// Call the method `name` on the receiver in `receiver`, below its `argc` arguments.
+    fn invoke(&mut self, name: &str, receiver: usize, argc: u8) -> Result<(), RuntimeError> {
+        let Unpacked::Instance(instance) = self.registers[receiver].unpack() else {
+            return Err(RuntimeError::NotAnInstance);
+        };
+
+        // A field holding a function shadows a method of the same name
+        let field = instance.fields.borrow().get(name).cloned();
+        if let Some(field) = field {
+            self.registers[receiver] = field.clone();
+            return self.call_value(&field, receiver, argc);
+        }
+
+        self.invoke_from_class(&instance.class, name, receiver, argc)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: &Class,
+        name: &str,
+        receiver: usize,
+        argc: u8,
+    ) -> Result<(), RuntimeError> {
+        let method = class.methods.borrow().get(name).cloned();
+        match method {
+            Some(method) => self.call_closure(method, receiver, argc),
+            None => Err(RuntimeError::UndefinedProperty {
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    /// The upvalue for the variable in `register`, shared with any closure that already
+    /// captured it.
+    fn capture_upvalue(&mut self, register: usize) -> Rc<RefCell<Upvalue>> {
+        let existing = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(r) if r == register));
+
+        if let Some(existing) = existing {
+            return Rc::clone(existing);
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(register)));
+        self.open_upvalues.push(Rc::clone(&upvalue));
+        upvalue
+    }
+
+    /// Drop every frame, closing the upvalues of closures that outlive them.
+    fn reset(&mut self) {
+        self.close_upvalues(0);
+        self.frames.clear();
+        self.offset = 0;
+    }
+
+    /// Move the values of variables in registers `from` and above into the upvalues capturing
+    /// them.
+    fn close_upvalues(&mut self, from: usize) {
+        let registers = &self.registers;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(register) if register >= from => {
+                    *upvalue = Upvalue::Closed(registers[register].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+}
+
+/// The name of a variable, property or method held by the constant that the operand `byte` of
+/// the instruction before `ip` refers to. If an `ExtraArg` holding the index follows instead,
+/// `ip` is moved past it.
+fn name<'c>(chunk: &'c Chunk, ip: &mut usize, byte: u8) -> &'c str {
+    let index = match instruction(chunk, *ip) {
+        Some([op, a, b, c]) if op == Op::ExtraArg as u8 => {
+            *ip += INSTRUCTION_SIZE;
+            extra_arg(a, b, c)
+        }
+        _ => usize::from(byte),
+    };
+
+    chunk.constants[index]
+        .as_str()
+        .expect("Register code is only compiled with string name constants")
+}
+
+/// Offset of the instruction a jump at `offset` with the `sBx` operand `b`, `c` leads to.
+fn jump(offset: usize, b: u8, c: u8) -> Result<usize, RuntimeError> {
+    jump_target(offset, i16::from_le_bytes([b, c])).ok_or(RuntimeError::UnexpectedEnd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Capture;
+
+    fn run(source: &str) -> (String, InterpretResult<()>) {
+        let capture = Capture::default();
+        let mut vm = RegisterVM::new();
+        vm.set_output(Box::new(capture.clone()));
+
+        let result = vm.interpret(source);
+        (capture.contents(), result)
+    }
+
+    /// A closure of the first function declared in `source`, which must capture nothing.
+    fn function(source: &str) -> Value {
+        let chunk = compile(source).unwrap();
+        let function = chunk
+            .constants
+            .iter()
+            .find_map(|c| match c.unpack() {
+                Unpacked::Function(function) => Some(function),
+                _ => None,
+            })
+            .unwrap();
+
+        Value::from(Rc::new(Closure {
+            function,
+            upvalues: Vec::new(),
+        }))
+    }
+
+    #[test]
+    fn runs_closures_and_classes() {
+        let (output, result) = run("\
+fun counter() {
+  var n = 0;
+  fun next() { n = n + 1; return n; }
+  return next;
+}
+var c = counter();
+c();
+print c();
+
+class A { init(x) { this.x = x; } get() { return this.x; } }
+class B < A { get() { return super.get() * 10; } }
+print B(4).get();
+");
+        result.unwrap();
+        assert_eq!(output, "2\n40\n");
+    }
+
+    #[test]
+    fn keeps_globals_between_runs() {
+        let capture = Capture::default();
+        let mut vm = RegisterVM::new();
+        vm.set_output(Box::new(capture.clone()));
+
+        vm.interpret("var a = 1;").unwrap();
+        vm.interpret("a = a + 1; print a;").unwrap();
+        assert_eq!(capture.take(), "2\n");
+    }
+
+    #[test]
+    fn locates_runtime_errors() {
+        let (output, result) = run("print 1;\nfun f(a) {\n  return a + nil;\n}\nf(1);");
+        assert_eq!(output, "1\n");
+
+        let Err(InterpretError::Runtime { error, position }) = result else {
+            panic!("expected a runtime error, got {result:?}");
+        };
+        assert!(matches!(
+            error,
+            RuntimeError::OperandsMustBeNumbersOrStrings
+        ));
+        assert_eq!(position.map(|p| p.line), Some(3));
+    }
+
+    #[test]
+    fn unwinds_frames_after_an_error() {
+        let mut vm = RegisterVM::new();
+        let source = "\
+var get;
+fun fail(n) {
+  var captured = n;
+  fun inner() { return captured; }
+  get = inner;
+  return n + nil;
+}
+fail(42);";
+        assert!(vm.interpret(source).is_err());
+        assert!(vm.frames.is_empty());
+
+        // A closure that outlives the failed frame keeps the value it captured
+        let get = vm.globals["get"].clone();
+        let value = vm.call(&get, &[]).unwrap();
+        assert_eq!(value.as_number(), Some(42.0));
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn fits_every_frame_in_the_register_file() {
+        // Every frame may use its whole window, and the script takes one frame of its own
+        let source = |frames: usize| {
+            format!(
+                "fun f(n) {{ if (n > 0) return f(n - 1) + 1; return 0; }}\nprint f({});",
+                frames - 2
+            )
+        };
+
+        let (output, result) = run(&source(FRAMES_MAX));
+        result.unwrap();
+        assert_eq!(output, format!("{}\n", FRAMES_MAX - 2));
+
+        let (_, result) = run(&source(FRAMES_MAX + 1));
+        assert!(matches!(
+            result,
+            Err(InterpretError::Runtime {
+                error: RuntimeError::CallDepth { .. },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn call_leaves_frames_as_they_were() {
+        let mut vm = RegisterVM::new();
+        let double = function("fun double(x) {\n  return x * 2;\n}");
+
+        let result = vm.call(&double, &[Value::from(21.0)]).unwrap();
+        assert_eq!(result.as_number(), Some(42.0));
+        assert!(vm.frames.is_empty());
+
+        let Err(InterpretError::Runtime { position, .. }) = vm.call(&double, &[Value::nil()])
+        else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(position.map(|p| p.line), Some(2));
+        assert!(vm.frames.is_empty());
+        assert!(vm.open_upvalues.is_empty());
+
+        let result = vm.call(&double, &[Value::from(1.5)]).unwrap();
+        assert_eq!(result.as_number(), Some(3.0));
+    }
+}
@@ -0,0 +1,248 @@
+//! Execution tracing for the stack VM, enabled with `--trace`.
+//!
+//! A [`Tracer`] writes one record per executed instruction, as text or JSON, optionally limited
+//! to some source lines or functions.
+
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::chunk::{LineNum, OpCode};
+use crate::value::{Function, Value};
+
+/// The name `--trace-fn` uses for top-level code, which is not in a named function.
+pub const SCRIPT_NAME: &str = "script";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TraceFormat {
+    /// The stack followed by the disassembled instruction, as plain text
+    Text,
+    /// One JSON object per executed instruction
+    Json,
+}
+
+/// An inclusive range of source lines, written `N` or `START-END`.
+#[derive(Debug, Clone, Copy)]
+pub struct LineRange {
+    pub start: LineNum,
+    pub end: LineNum,
+}
+
+impl LineRange {
+    pub fn contains(self, line: LineNum) -> bool {
+        (self.start..=self.end).contains(&line)
+    }
+}
+
+impl FromStr for LineRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<LineNum>()
+                .map_err(|_| format!("Invalid line number '{n}'"))
+        };
+
+        let range = Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+
+        if range.start > range.end {
+            return Err(format!("Line range {s} is empty"));
+        }
+
+        Ok(range)
+    }
+}
+
+/// Writes a record of every executed instruction to an output stream.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    lines: Vec<LineRange>,
+    functions: Vec<String>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat) -> Self {
+        Self {
+            out,
+            format,
+            lines: Vec::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    /// Only trace instructions on the given lines. May be called repeatedly to add ranges.
+    #[must_use]
+    pub fn with_lines(mut self, range: LineRange) -> Self {
+        self.lines.push(range);
+        self
+    }
+
+    /// Only trace instructions executing in functions called `name`, or in top-level code for
+    /// `"script"`. May be called repeatedly to add functions.
+    #[must_use]
+    pub fn with_function(mut self, name: String) -> Self {
+        self.functions.push(name);
+        self
+    }
+
+    /// Record the instruction at `offset` in `function`, about to execute with `stack`.
+    pub(crate) fn trace(
+        &mut self,
+        function: &Function,
+        offset: usize,
+        stack: &[Value],
+    ) -> io::Result<()> {
+        let chunk = &function.chunk;
+        let position = chunk.get_position(offset);
+        let name = function.name.as_deref().unwrap_or(SCRIPT_NAME);
+
+        if !self.lines.is_empty()
+            && !position.is_some_and(|p| self.lines.iter().any(|r| r.contains(p.line)))
+        {
+            return Ok(());
+        }
+
+        if !self.functions.is_empty() && !self.functions.iter().any(|f| f == name) {
+            return Ok(());
+        }
+
+        match self.format {
+            TraceFormat::Text => {
+                let values: Vec<_> = stack.iter().map(ToString::to_string).collect();
+                writeln!(self.out, "          [{}]", values.join(", "))?;
+                match chunk.disassemble_instruction(offset) {
+                    Some(instruction) => write!(self.out, "{instruction}")?,
+                    None => writeln!(self.out, "{offset:04} <invalid>")?,
+                }
+            }
+
+            TraceFormat::Json => {
+                let opcode = chunk
+                    .code
+                    .get(offset)
+                    .and_then(|&b| OpCode::try_from(b).ok())
+                    .map(|op| format!("{op:?}"));
+
+                let record = serde_json::json!({
+                    "function": name,
+                    "offset": offset,
+                    "line": position.map(|p| p.line),
+                    "column": position.map(|p| p.column),
+                    "op": opcode,
+                    "instruction": chunk.describe_instruction(offset),
+                    "stack": stack.iter().map(stack_entry).collect::<Vec<_>>(),
+                });
+
+                writeln!(self.out, "{record}")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Numbers appear in JSON traces as numbers, every other value as its printed form.
+fn stack_entry(value: &Value) -> serde_json::Value {
+    match value.as_number() {
+        Some(n) => serde_json::json!(n),
+        None => serde_json::json!(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Capture, VM};
+
+    const PROGRAM: &str = "
+fun double(n) {
+  return n * 2;
+}
+fun quadruple(n) {
+  return double(double(n));
+}
+print quadruple(1);
+";
+
+    /// Run `PROGRAM` with a JSON tracer, returning one record per traced instruction.
+    fn trace(configure: impl FnOnce(Tracer) -> Tracer) -> Vec<serde_json::Value> {
+        let trace = Capture::default();
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        vm.set_tracer(Some(configure(Tracer::new(
+            Box::new(trace.clone()),
+            TraceFormat::Json,
+        ))));
+        vm.interpret(PROGRAM).unwrap();
+
+        trace
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn functions(records: &[serde_json::Value]) -> Vec<&str> {
+        let mut names: Vec<_> = records
+            .iter()
+            .map(|r| r["function"].as_str().unwrap())
+            .collect();
+        names.dedup();
+        names
+    }
+
+    #[test]
+    fn parses_line_ranges() {
+        let single: LineRange = "3".parse().unwrap();
+        assert!(single.contains(3) && !single.contains(4));
+
+        let range: LineRange = "2-4".parse().unwrap();
+        assert!(range.contains(2) && range.contains(4) && !range.contains(5));
+
+        assert!("4-2".parse::<LineRange>().is_err());
+        assert!("x".parse::<LineRange>().is_err());
+    }
+
+    #[test]
+    fn traces_every_function_by_default() {
+        let records = trace(|t| t);
+        assert_eq!(
+            functions(&records),
+            [
+                "script",
+                "quadruple",
+                "double",
+                "quadruple",
+                "double",
+                "quadruple",
+                "script"
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_by_function() {
+        let records = trace(|t| t.with_function("double".to_string()));
+        assert!(!records.is_empty());
+        assert_eq!(functions(&records), ["double"]);
+
+        let records = trace(|t| t.with_function(SCRIPT_NAME.to_string()));
+        assert_eq!(functions(&records), ["script"]);
+    }
+
+    #[test]
+    fn filters_by_line() {
+        let records = trace(|t| t.with_lines("3".parse().unwrap()));
+        assert!(!records.is_empty());
+        assert!(records.iter().all(|r| r["line"] == 3));
+    }
+}
@@ -0,0 +1,668 @@
+//! A tree-walking interpreter that executes the syntax tree directly. It is much slower than the
+//! bytecode VM but follows the language semantics without any of the VM's machinery, so it
+//! serves as the reference the VM is tested against.
+//!
+//! Runtime errors are reported with the VM's [`RuntimeError`] so that both interpreters describe
+//! a failure the same way.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::chunk::Position;
+use crate::compiler::ast::{
+    BinaryOp, Expr, ExprId, ExprKind, Function, FunctionKind, Literal, LogicalOp, Name, Stmt,
+    UnaryOp,
+};
+use crate::compiler::{parse, resolver};
+use crate::natives::clock_seconds;
+use crate::value::format_number;
+use crate::vm::{FRAMES_MAX, RuntimeError};
+use crate::{InterpretError, InterpretResult};
+
+#[derive(Debug, Clone)]
+enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Rc<String>),
+    Function(Rc<LoxFunction>),
+    Native(Rc<Native>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Number(n) => write!(f, "{}", format_number(*n)),
+            Value::String(s) => write!(f, "{s}"),
+            Value::Function(function) => write!(f, "<fn {}>", function.declaration.name.text),
+            Value::Native(_) => write!(f, "<native fn>"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LoxFunction {
+    declaration: Rc<Function>,
+    closure: Rc<RefCell<Environment>>,
+}
+
+impl LoxFunction {
+    /// This method with `this` bound to `instance`.
+    fn bind(&self, instance: Value) -> LoxFunction {
+        let mut environment = Environment::new(Some(Rc::clone(&self.closure)));
+        environment.define("this", instance);
+
+        LoxFunction {
+            declaration: Rc::clone(&self.declaration),
+            closure: Rc::new(RefCell::new(environment)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Native {
+    arity: u8,
+    function: fn() -> Value,
+}
+
+#[derive(Debug)]
+struct Class {
+    name: String,
+    superclass: Option<Rc<Class>>,
+    methods: HashMap<String, Rc<LoxFunction>>,
+}
+
+impl Class {
+    fn find_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
+        self.methods.get(name).cloned().or_else(|| {
+            self.superclass
+                .as_ref()
+                .and_then(|superclass| superclass.find_method(name))
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Instance {
+    class: Rc<Class>,
+    fields: RefCell<HashMap<String, Value>>,
+}
+
+#[derive(Debug, Default)]
+struct Environment {
+    values: HashMap<String, Value>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
+}
+
+impl Environment {
+    fn new(enclosing: Option<Rc<RefCell<Environment>>>) -> Self {
+        Self {
+            values: HashMap::new(),
+            enclosing,
+        }
+    }
+
+    fn define(&mut self, name: &str, value: Value) {
+        self.values.insert(name.to_string(), value);
+    }
+}
+
+/// The environment `distance` steps out from `environment`.
+fn ancestor(environment: &Rc<RefCell<Environment>>, distance: usize) -> Rc<RefCell<Environment>> {
+    let mut environment = Rc::clone(environment);
+    for _ in 0..distance {
+        let enclosing = environment
+            .borrow()
+            .enclosing
+            .clone()
+            .expect("The resolver only records distances to enclosing scopes");
+        environment = enclosing;
+    }
+
+    environment
+}
+
+/// Why evaluation stopped before reaching the end of a statement.
+enum Unwind {
+    Return(Value),
+    Error(RuntimeError, Position),
+}
+
+type Exec<T> = Result<T, Unwind>;
+
+fn fail<T>(error: RuntimeError, position: Position) -> Exec<T> {
+    Err(Unwind::Error(error, position))
+}
+
+pub struct Interpreter {
+    globals: Rc<RefCell<Environment>>,
+    environment: Rc<RefCell<Environment>>,
+    /// Scope distances of local variable references, from the resolver
+    locals: HashMap<ExprId, usize>,
+    output: Box<dyn Write>,
+    /// Number of active calls, including the top-level code
+    depth: usize,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        let mut globals = Environment::default();
+        let clock = Native {
+            arity: 0,
+            function: || Value::Number(clock_seconds()),
+        };
+        globals.define("clock", Value::Native(Rc::new(clock)));
+
+        let globals = Rc::new(RefCell::new(globals));
+        Self {
+            environment: Rc::clone(&globals),
+            globals,
+            locals: HashMap::new(),
+            output: Box::new(io::stdout()),
+            depth: 1,
+        }
+    }
+
+    /// Send the output of `print` statements to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub fn interpret(&mut self, source: &str) -> InterpretResult<()> {
+        let program = parse(source).map_err(InterpretError::Compiler)?;
+        let resolution = resolver::resolve(&program);
+        if !resolution.errors.is_empty() {
+            return Err(InterpretError::Compiler(resolution.errors));
+        }
+
+        self.locals = resolution.locals;
+        self.environment = Rc::clone(&self.globals);
+        self.depth = 1;
+
+        let result = self.statements(&program);
+        self.output.flush()?;
+
+        match result {
+            Ok(()) | Err(Unwind::Return(_)) => Ok(()),
+            Err(Unwind::Error(error, position)) => Err(InterpretError::Runtime {
+                error,
+                position: Some(position),
+            }),
+        }
+    }
+
+    fn statements(&mut self, stmts: &[Stmt]) -> Exec<()> {
+        for stmt in stmts {
+            self.statement(stmt)?;
+        }
+
+        Ok(())
+    }
+
+    /// Execute `stmts` in `environment`, restoring the current environment afterwards.
+    fn block(&mut self, stmts: &[Stmt], environment: Environment) -> Exec<()> {
+        let previous = std::mem::replace(&mut self.environment, Rc::new(RefCell::new(environment)));
+        let result = self.statements(stmts);
+        self.environment = previous;
+
+        result
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Exec<()> {
+        match stmt {
+            Stmt::Var { name, initializer } => {
+                let value = match initializer {
+                    Some(initializer) => self.evaluate(initializer)?,
+                    None => Value::Nil,
+                };
+                self.environment.borrow_mut().define(&name.text, value);
+            }
+
+            Stmt::Function(declaration) => {
+                let function = LoxFunction {
+                    declaration: Rc::clone(declaration),
+                    closure: Rc::clone(&self.environment),
+                };
+                self.environment
+                    .borrow_mut()
+                    .define(&declaration.name.text, Value::Function(Rc::new(function)));
+            }
+
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods)?,
+
+            Stmt::Expression(expr) => {
+                self.evaluate(expr)?;
+            }
+
+            Stmt::Print(expr) => {
+                let value = self.evaluate(expr)?;
+                if let Err(e) = writeln!(self.output, "{value}") {
+                    return fail(RuntimeError::Output(e), expr.position);
+                }
+            }
+
+            Stmt::Return { value, .. } => {
+                let value = match value {
+                    Some(value) => self.evaluate(value)?,
+                    None => Value::Nil,
+                };
+                return Err(Unwind::Return(value));
+            }
+
+            Stmt::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                if self.evaluate(condition)?.is_truthy() {
+                    self.statement(then)?;
+                } else if let Some(otherwise) = otherwise {
+                    self.statement(otherwise)?;
+                }
+            }
+
+            Stmt::While { condition, body } => {
+                while self.evaluate(condition)?.is_truthy() {
+                    self.statement(body)?;
+                }
+            }
+
+            Stmt::Block(stmts) => {
+                let environment = Environment::new(Some(Rc::clone(&self.environment)));
+                self.block(stmts, environment)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn class(
+        &mut self,
+        name: &Name,
+        superclass: Option<&Expr>,
+        methods: &[Rc<Function>],
+    ) -> Exec<()> {
+        let superclass = match superclass {
+            Some(expr) => match self.evaluate(expr)? {
+                Value::Class(class) => Some(class),
+                _ => return fail(RuntimeError::SuperclassNotClass, expr.position),
+            },
+            None => None,
+        };
+
+        self.environment.borrow_mut().define(&name.text, Value::Nil);
+
+        let enclosing = Rc::clone(&self.environment);
+        if let Some(superclass) = &superclass {
+            let mut environment = Environment::new(Some(Rc::clone(&enclosing)));
+            environment.define("super", Value::Class(Rc::clone(superclass)));
+            self.environment = Rc::new(RefCell::new(environment));
+        }
+
+        let methods = methods
+            .iter()
+            .map(|declaration| {
+                let method = LoxFunction {
+                    declaration: Rc::clone(declaration),
+                    closure: Rc::clone(&self.environment),
+                };
+                (declaration.name.text.clone(), Rc::new(method))
+            })
+            .collect();
+
+        self.environment = enclosing;
+
+        let class = Class {
+            name: name.text.clone(),
+            superclass,
+            methods,
+        };
+        self.environment
+            .borrow_mut()
+            .define(&name.text, Value::Class(Rc::new(class)));
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn evaluate(&mut self, expr: &Expr) -> Exec<Value> {
+        let position = expr.position;
+
+        let value = match &expr.kind {
+            ExprKind::Literal(literal) => match literal {
+                Literal::Nil => Value::Nil,
+                Literal::Bool(b) => Value::Bool(*b),
+                Literal::Number(n) => Value::Number(*n),
+                Literal::String(s) => Value::String(Rc::new(s.clone())),
+            },
+
+            ExprKind::Variable(name) => self.look_up(expr.id, name)?,
+
+            ExprKind::Assign { name, value } => {
+                let value = self.evaluate(value)?;
+                self.assign(expr.id, name, value.clone())?;
+                value
+            }
+
+            ExprKind::Unary { op, operand } => {
+                let operand = self.evaluate(operand)?;
+                match op {
+                    UnaryOp::Not => Value::Bool(!operand.is_truthy()),
+                    UnaryOp::Negate => match operand {
+                        Value::Number(n) => Value::Number(-n),
+                        _ => return fail(RuntimeError::OperandMustBeNumber, position),
+                    },
+                }
+            }
+
+            ExprKind::Binary { op, left, right } => {
+                let left = self.evaluate(left)?;
+                let right = self.evaluate(right)?;
+                binary(*op, &left, &right).or_else(|error| fail(error, position))?
+            }
+
+            ExprKind::Logical { op, left, right } => {
+                let left = self.evaluate(left)?;
+                let short_circuits = match op {
+                    LogicalOp::And => !left.is_truthy(),
+                    LogicalOp::Or => left.is_truthy(),
+                };
+
+                if short_circuits {
+                    left
+                } else {
+                    self.evaluate(right)?
+                }
+            }
+
+            ExprKind::Grouping(inner) => self.evaluate(inner)?,
+
+            ExprKind::Call { callee, args } => {
+                let callee = self.evaluate(callee)?;
+                let args = args
+                    .iter()
+                    .map(|arg| self.evaluate(arg))
+                    .collect::<Exec<Vec<_>>>()?;
+
+                self.call(callee, args, position)?
+            }
+
+            ExprKind::Get { object, name } => {
+                let object = self.evaluate(object)?;
+                let Value::Instance(instance) = &object else {
+                    return fail(RuntimeError::NotAnInstance, position);
+                };
+
+                let field = instance.fields.borrow().get(&name.text).cloned();
+                match field {
+                    Some(value) => value,
+                    None => match instance.class.find_method(&name.text) {
+                        Some(method) => Value::Function(Rc::new(method.bind(object.clone()))),
+                        None => {
+                            return fail(
+                                RuntimeError::UndefinedProperty {
+                                    name: name.text.clone(),
+                                },
+                                position,
+                            );
+                        }
+                    },
+                }
+            }
+
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                let object = self.evaluate(object)?;
+                let value = self.evaluate(value)?;
+                let Value::Instance(instance) = object else {
+                    return fail(RuntimeError::FieldOnNonInstance, position);
+                };
+
+                instance
+                    .fields
+                    .borrow_mut()
+                    .insert(name.text.clone(), value.clone());
+                value
+            }
+
+            ExprKind::This => self.look_up(
+                expr.id,
+                &Name {
+                    text: "this".to_string(),
+                    position,
+                },
+            )?,
+
+            ExprKind::Super { method } => {
+                let distance = self.locals[&expr.id];
+                let environment = ancestor(&self.environment, distance);
+                let Some(Value::Class(superclass)) =
+                    environment.borrow().values.get("super").cloned()
+                else {
+                    unreachable!("'super' is always bound to a class");
+                };
+
+                let this_environment = ancestor(&self.environment, distance - 1);
+                let this = this_environment.borrow().values["this"].clone();
+
+                match superclass.find_method(&method.text) {
+                    Some(found) => Value::Function(Rc::new(found.bind(this))),
+                    None => {
+                        return fail(
+                            RuntimeError::UndefinedProperty {
+                                name: method.text.clone(),
+                            },
+                            position,
+                        );
+                    }
+                }
+            }
+        };
+
+        Ok(value)
+    }
+
+    fn look_up(&self, id: ExprId, name: &Name) -> Exec<Value> {
+        let environment = match self.locals.get(&id) {
+            Some(&distance) => ancestor(&self.environment, distance),
+            None => Rc::clone(&self.globals),
+        };
+
+        let value = environment.borrow().values.get(&name.text).cloned();
+        value.map_or_else(
+            || {
+                fail(
+                    RuntimeError::UndefinedVariable {
+                        name: name.text.clone(),
+                    },
+                    name.position,
+                )
+            },
+            Ok,
+        )
+    }
+
+    fn assign(&self, id: ExprId, name: &Name, value: Value) -> Exec<()> {
+        let environment = match self.locals.get(&id) {
+            Some(&distance) => ancestor(&self.environment, distance),
+            None => Rc::clone(&self.globals),
+        };
+
+        let mut environment = environment.borrow_mut();
+        match environment.values.get_mut(&name.text) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => fail(
+                RuntimeError::UndefinedVariable {
+                    name: name.text.clone(),
+                },
+                name.position,
+            ),
+        }
+    }
+
+    fn call(&mut self, callee: Value, args: Vec<Value>, position: Position) -> Exec<Value> {
+        let got = u8::try_from(args.len()).expect("The parser limits argument counts");
+
+        match callee {
+            Value::Function(function) => self.call_function(&function, args, position),
+
+            Value::Native(native) => {
+                if got != native.arity {
+                    return fail(
+                        RuntimeError::Arity {
+                            expected: native.arity,
+                            got,
+                        },
+                        position,
+                    );
+                }
+
+                Ok((native.function)())
+            }
+
+            Value::Class(class) => {
+                let instance = Value::Instance(Rc::new(Instance {
+                    class: Rc::clone(&class),
+                    fields: RefCell::default(),
+                }));
+
+                match class.find_method("init") {
+                    Some(initializer) => {
+                        let initializer = initializer.bind(instance.clone());
+                        self.call_function(&initializer, args, position)?;
+                    }
+                    None if got != 0 => {
+                        return fail(RuntimeError::Arity { expected: 0, got }, position);
+                    }
+                    None => {}
+                }
+
+                Ok(instance)
+            }
+
+            _ => fail(RuntimeError::NotCallable, position),
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        function: &LoxFunction,
+        args: Vec<Value>,
+        position: Position,
+    ) -> Exec<Value> {
+        let declaration = &function.declaration;
+        let expected =
+            u8::try_from(declaration.params.len()).expect("The parser limits parameters");
+        let got = u8::try_from(args.len()).expect("The parser limits argument counts");
+        if got != expected {
+            return fail(RuntimeError::Arity { expected, got }, position);
+        }
+
+        if self.depth >= FRAMES_MAX {
+            return fail(RuntimeError::CallDepth { max: FRAMES_MAX }, position);
+        }
+
+        let mut environment = Environment::new(Some(Rc::clone(&function.closure)));
+        for (param, arg) in declaration.params.iter().zip(args) {
+            environment.define(&param.text, arg);
+        }
+
+        self.depth += 1;
+        let result = self.block(&declaration.body, environment);
+        self.depth -= 1;
+
+        let returned = match result {
+            Ok(()) => Value::Nil,
+            Err(Unwind::Return(value)) => value,
+            Err(error) => return Err(error),
+        };
+
+        if declaration.kind == FunctionKind::Initializer {
+            return Ok(function.closure.borrow().values["this"].clone());
+        }
+
+        Ok(returned)
+    }
+}
+
+fn binary(op: BinaryOp, left: &Value, right: &Value) -> Result<Value, RuntimeError> {
+    if let BinaryOp::Equal | BinaryOp::NotEqual = op {
+        let equal = left == right;
+        return Ok(Value::Bool(equal == (op == BinaryOp::Equal)));
+    }
+
+    if op == BinaryOp::Add
+        && let (Value::String(a), Value::String(b)) = (left, right)
+    {
+        return Ok(Value::String(Rc::new(format!("{a}{b}"))));
+    }
+
+    let (&Value::Number(a), &Value::Number(b)) = (left, right) else {
+        return Err(match op {
+            BinaryOp::Add => RuntimeError::OperandsMustBeNumbersOrStrings,
+            _ => RuntimeError::OperandsMustBeNumbers,
+        });
+    };
+
+    Ok(match op {
+        BinaryOp::Add => Value::Number(a + b),
+        BinaryOp::Sub => Value::Number(a - b),
+        BinaryOp::Mul => Value::Number(a * b),
+        BinaryOp::Div => Value::Number(a / b),
+        BinaryOp::Less => Value::Bool(a < b),
+        // The VM compiles these as negated `>` and `<`, which makes them true when either
+        // operand is NaN
+        BinaryOp::LessEqual => Value::Bool(a.partial_cmp(&b) != Some(Ordering::Greater)),
+        BinaryOp::Greater => Value::Bool(a > b),
+        BinaryOp::GreaterEqual => Value::Bool(a.partial_cmp(&b) != Some(Ordering::Less)),
+        BinaryOp::Equal | BinaryOp::NotEqual => unreachable!("Handled above"),
+    })
+}
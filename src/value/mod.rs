@@ -0,0 +1,225 @@
+//! Runtime values of the bytecode VM. Heap objects are reference counted, so they are freed when
+//! the last reference goes away; reference cycles are not collected.
+//!
+//! A [`Value`] is an enum of its possible types by default, or a single NaN-boxed 64-bit word
+//! with the `nan_boxing` feature. Both representations have the same API: values are built with
+//! `From` and inspected with [`Value::unpack`] or the `as_*` accessors, never by matching on the
+//! representation itself.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::natives::{Context, NativeError};
+
+#[cfg(feature = "nan_boxing")]
+mod nan_boxed;
+#[cfg(not(feature = "nan_boxing"))]
+mod tagged;
+
+#[cfg(feature = "nan_boxing")]
+pub use nan_boxed::Value;
+#[cfg(not(feature = "nan_boxing"))]
+pub use tagged::Value;
+
+/// The contents of a [`Value`], for matching on its type.
+#[derive(Debug, Clone)]
+pub(crate) enum Unpacked {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Rc<String>),
+    Function(Rc<Function>),
+    Native(Rc<Native>),
+    Closure(Rc<Closure>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+}
+
+macro_rules! impl_from {
+    ($($variant:ident($type:ty)),* $(,)?) => {
+        $(
+            impl From<$type> for Value {
+                fn from(value: $type) -> Self {
+                    Unpacked::$variant(value).into()
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    Bool(bool),
+    Number(f64),
+    String(Rc<String>),
+    Function(Rc<Function>),
+    Native(Rc<Native>),
+    Closure(Rc<Closure>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+);
+
+impl Value {
+    /// Name of the kind of a value, as shown in disassembly and error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self.unpack() {
+            Unpacked::Nil => "nil",
+            Unpacked::Bool(_) => "bool",
+            Unpacked::Number(_) => "number",
+            Unpacked::String(_) => "string",
+            Unpacked::Function(_) => "function",
+            Unpacked::Native(_) => "native",
+            Unpacked::Closure(_) => "closure",
+            Unpacked::Class(_) => "class",
+            Unpacked::Instance(_) => "instance",
+            Unpacked::BoundMethod(_) => "bound method",
+        }
+    }
+
+    /// `nil` and `false` are falsey, everything else is truthy.
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || self.as_bool() == Some(false)
+    }
+
+    pub fn string(s: impl Into<String>) -> Self {
+        Rc::new(s.into()).into()
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::nil()
+    }
+}
+
+/// Numbers and strings compare by value, other objects by identity.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        if let (Some(a), Some(b)) = (self.as_number(), other.as_number()) {
+            return a == b;
+        }
+
+        match (self.unpack(), other.unpack()) {
+            (Unpacked::Nil, Unpacked::Nil) => true,
+            (Unpacked::Bool(a), Unpacked::Bool(b)) => a == b,
+            (Unpacked::String(a), Unpacked::String(b)) => a == b,
+            (Unpacked::Function(a), Unpacked::Function(b)) => Rc::ptr_eq(&a, &b),
+            (Unpacked::Native(a), Unpacked::Native(b)) => Rc::ptr_eq(&a, &b),
+            (Unpacked::Closure(a), Unpacked::Closure(b)) => Rc::ptr_eq(&a, &b),
+            (Unpacked::Class(a), Unpacked::Class(b)) => Rc::ptr_eq(&a, &b),
+            (Unpacked::Instance(a), Unpacked::Instance(b)) => Rc::ptr_eq(&a, &b),
+            (Unpacked::BoundMethod(a), Unpacked::BoundMethod(b)) => Rc::ptr_eq(&a, &b),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.unpack() {
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::Bool(b) => write!(f, "{b}"),
+            Unpacked::Number(n) => write!(f, "{}", format_number(n)),
+            Unpacked::String(s) => write!(f, "{s}"),
+            Unpacked::Function(function) => write!(f, "{function}"),
+            Unpacked::Native(_) => write!(f, "<native fn>"),
+            Unpacked::Closure(closure) => write!(f, "{}", closure.function),
+            Unpacked::Class(class) => write!(f, "{}", class.name),
+            Unpacked::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Unpacked::BoundMethod(bound) => write!(f, "{}", bound.method.function),
+        }
+    }
+}
+
+impl Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.unpack().fmt(f)
+    }
+}
+
+/// Format a number the way `print` shows it: integral values without a fractional part.
+pub fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+/// How a closure captures one variable: a local slot of the enclosing function, or one of the
+/// enclosing function's own upvalues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueRef {
+    pub is_local: bool,
+    pub index: u8,
+}
+
+/// A compiled function. Top-level code is a function without a name.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Function {
+    pub(crate) name: Option<String>,
+    pub(crate) arity: u8,
+    pub(crate) upvalues: Vec<UpvalueRef>,
+    pub(crate) chunk: Chunk,
+    /// Set by the verifier once the chunk has passed, so the VM can run it without checking each
+    /// instruction. Functions built any other way are verified before they are first called.
+    pub(crate) verified: Cell<bool>,
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// A native function, given the VM calling it and its arguments.
+pub type NativeFn = fn(&mut dyn Context, &[Value]) -> Result<Value, NativeError>;
+
+/// A function implemented in Rust.
+#[derive(Debug)]
+pub(crate) struct Native {
+    pub name: String,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+/// A captured variable. It refers to a stack slot while the variable is in scope and owns the
+/// value once it is closed over.
+#[derive(Debug)]
+pub(crate) enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub(crate) struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<String, Rc<Closure>>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Instance {
+    pub class: Rc<Class>,
+    pub fields: RefCell<HashMap<String, Value>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
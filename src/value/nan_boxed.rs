@@ -0,0 +1,195 @@
+//! The `nan_boxing` representation of [`Value`]: a single 64-bit word.
+//!
+//! A number is stored as its own bits. Every other value hides in the payload of a quiet NaN
+//! that arithmetic never produces: nil and the booleans as small constants, and objects as a
+//! pointer from [`Rc::into_raw`] with the sign bit set. Pointers to reference counted objects are
+//! 8-byte aligned and fit in 48 bits, so the three bits below the pointer hold the object's type.
+//! A value owns one strong reference to the object it points to.
+
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ptr::with_exposed_provenance;
+use std::rc::Rc;
+
+use super::{Function, Unpacked};
+
+/// Bits set in every value that is not a number. The NaNs produced by arithmetic lack the lowest
+/// of them, and any other NaN is replaced by one of those when stored.
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const OBJECT: u64 = QNAN | SIGN_BIT;
+
+const NIL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+
+/// The bits of an object value holding its pointer, and those holding its type.
+const POINTER: u64 = 0x0000_ffff_ffff_fff8;
+const TYPE: u64 = 0b111;
+
+const STRING: u64 = 0;
+const FUNCTION: u64 = 1;
+const NATIVE: u64 = 2;
+const CLOSURE: u64 = 3;
+const CLASS: u64 = 4;
+const INSTANCE: u64 = 5;
+const BOUND_METHOD: u64 = 6;
+
+const _: () = assert!(
+    size_of::<usize>() == size_of::<u64>(),
+    "NaN boxing needs 64-bit pointers"
+);
+const _: () = assert!(size_of::<Value>() == size_of::<u64>());
+
+/// A Lox value packed into a word.
+///
+/// Object values own an [`Rc`], so like the enum representation a value must stay on the thread
+/// that created it. The marker makes it neither `Send` nor `Sync`, which the bare `u64` would be:
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<rlox::Value>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<rlox::Value>();
+/// ```
+pub struct Value(u64, PhantomData<Rc<()>>);
+
+impl Value {
+    pub fn nil() -> Self {
+        Value(NIL, PhantomData)
+    }
+
+    pub(crate) fn unpack(&self) -> Unpacked {
+        if !self.is_object() {
+            return match self.0 {
+                NIL => Unpacked::Nil,
+                FALSE => Unpacked::Bool(false),
+                TRUE => Unpacked::Bool(true),
+                bits => Unpacked::Number(f64::from_bits(bits)),
+            };
+        }
+
+        // SAFETY: The reference this value owns is only borrowed, and never dropped here
+        let owned = ManuallyDrop::new(unsafe { self.reclaim() });
+        (*owned).clone()
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0 {
+            FALSE => Some(false),
+            TRUE => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        (self.0 & QNAN != QNAN).then(|| f64::from_bits(self.0))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        // SAFETY: The tag says the object is a string, which lives as long as this value
+        self.has_type(STRING)
+            .then(|| unsafe { &*with_exposed_provenance::<String>(self.address()) }.as_str())
+    }
+
+    pub(crate) fn as_function(&self) -> Option<&Function> {
+        // SAFETY: The tag says the object is a function, which lives as long as this value
+        self.has_type(FUNCTION)
+            .then(|| unsafe { &*with_exposed_provenance::<Function>(self.address()) })
+    }
+
+    fn is_object(&self) -> bool {
+        self.0 & OBJECT == OBJECT
+    }
+
+    fn has_type(&self, tag: u64) -> bool {
+        self.is_object() && self.0 & TYPE == tag
+    }
+
+    fn address(&self) -> usize {
+        usize::try_from(self.0 & POINTER).expect("Pointers are 64 bits wide")
+    }
+
+    /// Take back the reference this object value owns, leaving the count unchanged.
+    ///
+    /// # Safety
+    ///
+    /// The value must be an object, and each reference it owns may only be reclaimed once.
+    unsafe fn reclaim(&self) -> Unpacked {
+        let address = self.address();
+
+        // SAFETY: The pointer came from `Rc::into_raw` on the type its tag names
+        unsafe {
+            match self.0 & TYPE {
+                STRING => Unpacked::String(Rc::from_raw(with_exposed_provenance(address))),
+                FUNCTION => Unpacked::Function(Rc::from_raw(with_exposed_provenance(address))),
+                NATIVE => Unpacked::Native(Rc::from_raw(with_exposed_provenance(address))),
+                CLOSURE => Unpacked::Closure(Rc::from_raw(with_exposed_provenance(address))),
+                CLASS => Unpacked::Class(Rc::from_raw(with_exposed_provenance(address))),
+                INSTANCE => Unpacked::Instance(Rc::from_raw(with_exposed_provenance(address))),
+                BOUND_METHOD => {
+                    Unpacked::BoundMethod(Rc::from_raw(with_exposed_provenance(address)))
+                }
+                tag => unreachable!("Invalid object tag {tag}"),
+            }
+        }
+    }
+}
+
+/// Box an object, transferring the reference `object` holds into the returned bits.
+fn box_object<T>(object: Rc<T>, tag: u64) -> u64 {
+    let address = Rc::into_raw(object).expose_provenance() as u64;
+    assert!(
+        address & !POINTER == 0,
+        "Object pointer {address:#x} does not fit in a NaN box"
+    );
+
+    OBJECT | address | tag
+}
+
+impl From<Unpacked> for Value {
+    fn from(value: Unpacked) -> Self {
+        let bits = match value {
+            Unpacked::Nil => NIL,
+            Unpacked::Bool(false) => FALSE,
+            Unpacked::Bool(true) => TRUE,
+            Unpacked::Number(n) if n.is_nan() => f64::NAN.to_bits(),
+            Unpacked::Number(n) => n.to_bits(),
+            Unpacked::String(s) => box_object(s, STRING),
+            Unpacked::Function(function) => box_object(function, FUNCTION),
+            Unpacked::Native(native) => box_object(native, NATIVE),
+            Unpacked::Closure(closure) => box_object(closure, CLOSURE),
+            Unpacked::Class(class) => box_object(class, CLASS),
+            Unpacked::Instance(instance) => box_object(instance, INSTANCE),
+            Unpacked::BoundMethod(bound) => box_object(bound, BOUND_METHOD),
+        };
+
+        Value(bits, PhantomData)
+    }
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        if self.is_object() {
+            self.unpack().into()
+        } else {
+            Value(self.0, PhantomData)
+        }
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        if self.is_object() {
+            // SAFETY: The value is going away, so its reference is reclaimed exactly once
+            drop(unsafe { self.reclaim() });
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! The default representation of [`Value`]: an enum, two words wide, tagging each type.
+
+use super::{Function, Unpacked};
+
+#[derive(Clone)]
+pub struct Value(Unpacked);
+
+impl Value {
+    pub fn nil() -> Self {
+        Value(Unpacked::Nil)
+    }
+
+    pub(crate) fn unpack(&self) -> Unpacked {
+        self.0.clone()
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self.0, Unpacked::Nil)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0 {
+            Unpacked::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self.0 {
+            Unpacked::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.0 {
+            Unpacked::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_function(&self) -> Option<&Function> {
+        match &self.0 {
+            Unpacked::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+}
+
+impl From<Unpacked> for Value {
+    fn from(value: Unpacked) -> Self {
+        Value(value)
+    }
+}
@@ -0,0 +1,768 @@
+use thiserror::Error;
+
+use crate::chunk::{Chunk, OPCODE_SIZE, OpCode, Operand};
+use crate::value::{Function, Value};
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("Chunk contains no instructions")]
+    Empty,
+    #[error("Invalid opcode {byte:#04x} at offset {offset}")]
+    InvalidOpcode { offset: usize, byte: u8 },
+    #[error("Instruction at offset {offset} is missing operand bytes")]
+    TruncatedOperand { offset: usize },
+    #[error("Constant index {index} at offset {offset} is out of range (pool has {len} entries)")]
+    InvalidConstant {
+        offset: usize,
+        index: usize,
+        len: usize,
+    },
+    #[error("Constant {index} used at offset {offset} is not a {expected}")]
+    ConstantType {
+        offset: usize,
+        index: usize,
+        expected: &'static str,
+    },
+    #[error("Local slot {slot} at offset {offset} is out of range (frame has {depth} slots)")]
+    InvalidLocal {
+        offset: usize,
+        slot: usize,
+        depth: usize,
+    },
+    #[error("Upvalue {index} at offset {offset} is out of range (function has {count})")]
+    InvalidUpvalue {
+        offset: usize,
+        index: usize,
+        count: usize,
+    },
+    #[error("Jump at offset {offset} leads outside the chunk")]
+    JumpOutOfBounds { offset: usize },
+    #[error("In {function}: {source}")]
+    InFunction {
+        function: String,
+        source: Box<VerifyError>,
+    },
+    #[error("No line information for offset {offset}")]
+    MissingLine { offset: usize },
+    #[error(
+        "Control flow from offset {from} reaches {target}, which is not an instruction boundary"
+    )]
+    MisalignedTarget { from: usize, target: usize },
+    #[error("Execution can run off the end of the chunk after offset {offset}")]
+    FallsOffEnd { offset: usize },
+    #[error("Instruction at offset {offset} needs {needed} values but the stack holds {depth}")]
+    StackUnderflow {
+        offset: usize,
+        needed: usize,
+        depth: usize,
+    },
+    #[error("Inconsistent stack depth at offset {offset}: reached with {first} and {second}")]
+    StackMismatch {
+        offset: usize,
+        first: usize,
+        second: usize,
+    },
+}
+
+pub type VerifyResult<T> = Result<T, VerifyError>;
+
+/// Facts established about a chunk that passed verification.
+#[derive(Debug, Clone, Copy)]
+pub struct Verified {
+    /// The deepest the value stack can grow while executing the chunk.
+    pub max_stack: usize,
+}
+
+/// A single decoded instruction.
+struct Instruction {
+    offset: usize,
+    opcode: OpCode,
+    /// The first operand byte, or 0 for instructions without operands
+    byte: usize,
+    /// The operand byte after the first, or after the constant index of `Invoke` and
+    /// `SuperInvoke`, where it is the argument count
+    second: usize,
+    /// The constant index operand, if the instruction has one
+    constant: Option<usize>,
+    /// Where a jump or loop transfers control
+    target: Option<usize>,
+}
+
+impl Instruction {
+    /// Values popped and pushed by executing this instruction.
+    fn stack_effect(&self) -> (usize, usize) {
+        match self.opcode {
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetLocal0
+            | OpCode::GetLocal1
+            | OpCode::GetLocal2
+            | OpCode::GetLocal3
+            | OpCode::LessLocals
+            | OpCode::GetGlobal
+            | OpCode::GetGlobalLong
+            | OpCode::GetUpvalue
+            | OpCode::Closure
+            | OpCode::ClosureLong
+            | OpCode::Class
+            | OpCode::ClassLong => (0, 1),
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::DefineGlobalLong
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return => (1, 0),
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetGlobalLong
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::GetPropertyLong
+            | OpCode::Negate
+            | OpCode::Not
+            | OpCode::AddConst
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfTrue => (1, 1),
+            OpCode::SetProperty
+            | OpCode::SetPropertyLong
+            | OpCode::GetSuper
+            | OpCode::GetSuperLong
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Div
+            | OpCode::Inherit
+            | OpCode::Method
+            | OpCode::MethodLong => (2, 1),
+            OpCode::Jump | OpCode::Loop | OpCode::IncrLocal => (0, 0),
+            OpCode::Call => (self.byte + 1, 1),
+            OpCode::Invoke | OpCode::InvokeLong => (self.second + 1, 1),
+            OpCode::SuperInvoke | OpCode::SuperInvokeLong => (self.second + 2, 1),
+        }
+    }
+
+    /// Stack slots of the current frame this instruction reads or writes.
+    fn locals(&self) -> Vec<usize> {
+        match self.opcode {
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::IncrLocal => vec![self.byte],
+            OpCode::GetLocal0 => vec![0],
+            OpCode::GetLocal1 => vec![1],
+            OpCode::GetLocal2 => vec![2],
+            OpCode::GetLocal3 => vec![3],
+            OpCode::LessLocals => vec![self.byte, self.second],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Offsets execution may continue at after this instruction.
+    fn successors(&self) -> Vec<usize> {
+        let next = self.offset + OPCODE_SIZE + self.opcode.operand_size();
+        match self.opcode {
+            OpCode::Return => Vec::new(),
+            OpCode::Jump | OpCode::Loop => self.target.into_iter().collect(),
+            OpCode::JumpIfFalse | OpCode::JumpIfTrue => {
+                std::iter::once(next).chain(self.target).collect()
+            }
+            _ => vec![next],
+        }
+    }
+}
+
+/// What a function's code may refer to beyond its own chunk.
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    arity: usize,
+    upvalues: usize,
+}
+
+/// Verify the top-level code of a program and every function nested in it.
+///
+/// Each chunk is decoded once, checking that opcodes are valid, operands are present and in
+/// range, every byte has line information, control flow only lands on instruction boundaries,
+/// and each instruction is always reached with the same stack depth. Local slots are checked
+/// against that depth, so no instruction reads a slot that does not exist yet.
+pub fn verify(chunk: &Chunk) -> VerifyResult<Verified> {
+    verify_function(
+        chunk,
+        Frame {
+            arity: 0,
+            upvalues: 0,
+        },
+    )
+}
+
+/// Verify a function that did not come from a verified chunk, such as one built in Rust and
+/// passed to `VM::call`, and mark it as verified.
+pub fn verify_closure(function: &Function) -> VerifyResult<Verified> {
+    let frame = Frame {
+        arity: usize::from(function.arity),
+        upvalues: function.upvalues.len(),
+    };
+
+    let verified =
+        verify_function(&function.chunk, frame).map_err(|e| VerifyError::InFunction {
+            function: function.to_string(),
+            source: Box::new(e),
+        })?;
+    function.verified.set(true);
+
+    Ok(verified)
+}
+
+fn verify_function(chunk: &Chunk, frame: Frame) -> VerifyResult<Verified> {
+    let instructions = decode(chunk)?;
+    if instructions.is_empty() {
+        return Err(VerifyError::Empty);
+    }
+
+    // Maps each code offset to the index of the instruction starting there, if any
+    let mut boundaries = vec![None; chunk.code.len()];
+    for (i, instruction) in instructions.iter().enumerate() {
+        boundaries[instruction.offset] = Some(i);
+    }
+
+    // Abstract interpretation over stack depths; each instruction is visited once. Slot 0 holds
+    // the function being called, followed by its arguments.
+    let start = frame.arity + 1;
+    let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+    let mut worklist = vec![0];
+    depths[0] = Some(start);
+    let mut max_stack = start;
+
+    while let Some(i) = worklist.pop() {
+        let instruction = &instructions[i];
+        let depth = depths[i].expect("Queued instructions always have a depth");
+
+        check_operands(chunk, instruction, depth, frame)?;
+
+        let (pops, pushes) = instruction.stack_effect();
+        if depth < pops {
+            return Err(VerifyError::StackUnderflow {
+                offset: instruction.offset,
+                needed: pops,
+                depth,
+            });
+        }
+
+        let depth = depth - pops + pushes;
+        max_stack = max_stack.max(depth);
+
+        for target in instruction.successors() {
+            let next = match boundaries.get(target) {
+                Some(Some(next)) => *next,
+                Some(None) => {
+                    return Err(VerifyError::MisalignedTarget {
+                        from: instruction.offset,
+                        target,
+                    });
+                }
+                None => {
+                    return Err(VerifyError::FallsOffEnd {
+                        offset: instruction.offset,
+                    });
+                }
+            };
+
+            match depths[next] {
+                None => {
+                    depths[next] = Some(depth);
+                    worklist.push(next);
+                }
+                Some(existing) if existing != depth => {
+                    return Err(VerifyError::StackMismatch {
+                        offset: instructions[next].offset,
+                        first: existing,
+                        second: depth,
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    Ok(Verified { max_stack })
+}
+
+/// Check the operands that depend on the state of the frame when `instruction` executes.
+fn check_operands(
+    chunk: &Chunk,
+    instruction: &Instruction,
+    depth: usize,
+    frame: Frame,
+) -> VerifyResult<()> {
+    let offset = instruction.offset;
+
+    if let Some(slot) = instruction.locals().into_iter().find(|&slot| slot >= depth) {
+        return Err(VerifyError::InvalidLocal {
+            offset,
+            slot,
+            depth,
+        });
+    }
+
+    match instruction.opcode {
+        OpCode::GetUpvalue | OpCode::SetUpvalue if instruction.byte >= frame.upvalues => {
+            Err(VerifyError::InvalidUpvalue {
+                offset,
+                index: instruction.byte,
+                count: frame.upvalues,
+            })
+        }
+
+        OpCode::Closure | OpCode::ClosureLong => {
+            let Some(function) = instruction
+                .constant
+                .and_then(|index| chunk.constants.get(index))
+                .and_then(Value::as_function)
+            else {
+                unreachable!("Closure operands are checked while decoding");
+            };
+
+            for upvalue in &function.upvalues {
+                let index = usize::from(upvalue.index);
+                if upvalue.is_local && index >= depth {
+                    return Err(VerifyError::InvalidLocal {
+                        offset,
+                        slot: index,
+                        depth,
+                    });
+                }
+
+                if !upvalue.is_local && index >= frame.upvalues {
+                    return Err(VerifyError::InvalidUpvalue {
+                        offset,
+                        index,
+                        count: frame.upvalues,
+                    });
+                }
+            }
+
+            let nested = Frame {
+                arity: usize::from(function.arity),
+                upvalues: function.upvalues.len(),
+            };
+
+            verify_function(&function.chunk, nested).map_err(|e| VerifyError::InFunction {
+                function: function.to_string(),
+                source: Box::new(e),
+            })?;
+            function.verified.set(true);
+
+            Ok(())
+        }
+
+        _ => Ok(()),
+    }
+}
+
+/// Linearly decode `chunk` into instructions, validating each one in isolation.
+fn decode(chunk: &Chunk) -> VerifyResult<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    while let Some(&byte) = chunk.code.get(offset) {
+        let opcode: OpCode = byte
+            .try_into()
+            .map_err(|_| VerifyError::InvalidOpcode { offset, byte })?;
+
+        let operands = offset + OPCODE_SIZE;
+        let size = opcode.operand_size();
+        if operands + size > chunk.code.len() {
+            return Err(VerifyError::TruncatedOperand { offset });
+        }
+
+        // Constant operands as (start, width), and the type the instruction needs the constant to
+        // have
+        let constant = match opcode.operand() {
+            Operand::Constant(width) => Some((operands, width, None)),
+            Operand::Name(width) | Operand::Invoke(width) => {
+                Some((operands, width, Some("string")))
+            }
+            Operand::Closure(width) => Some((operands, width, Some("function"))),
+            Operand::LocalConstant => Some((operands + 1, 1, None)),
+            _ => None,
+        };
+
+        let constant = if let Some((start, width, expected)) = constant {
+            let index = chunk
+                .constant_index(start, width)
+                .expect("Operand length was checked above");
+
+            let Some(constant) = chunk.constants.get(index) else {
+                return Err(VerifyError::InvalidConstant {
+                    offset,
+                    index,
+                    len: chunk.constants.len(),
+                });
+            };
+
+            if let Some(expected) = expected
+                && constant.type_name() != expected
+            {
+                return Err(VerifyError::ConstantType {
+                    offset,
+                    index,
+                    expected,
+                });
+            }
+
+            Some(index)
+        } else {
+            None
+        };
+
+        let target = match opcode.operand() {
+            Operand::Jump | Operand::Loop => Some(
+                chunk
+                    .jump_target(offset)
+                    .ok_or(VerifyError::JumpOutOfBounds { offset })?,
+            ),
+            _ => None,
+        };
+
+        if chunk.get_line(offset).is_none() {
+            return Err(VerifyError::MissingLine { offset });
+        }
+
+        let operand_bytes = &chunk.code[operands..operands + size];
+        let second = match opcode.operand() {
+            Operand::Invoke(width) => width,
+            _ => 1,
+        };
+        instructions.push(Instruction {
+            offset,
+            opcode,
+            byte: operand_bytes.first().map_or(0, |&b| usize::from(b)),
+            second: operand_bytes.get(second).map_or(0, |&b| usize::from(b)),
+            constant,
+            target,
+        });
+        offset = operands + size;
+    }
+
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write as _;
+
+    use super::*;
+    use crate::asm::assemble;
+    use crate::chunk::Position;
+
+    fn check(source: &str) -> VerifyResult<Verified> {
+        let source = format!(".line 1\n{source}");
+        verify(&assemble(&source).expect("Test assembly is valid"))
+    }
+
+    #[test]
+    fn accepts_valid_code_and_measures_the_stack() {
+        let verified = check("Constant 1\nConstant 2\nAdd\nPrint\nNil\nReturn").unwrap();
+
+        // The script's own slot plus the two operands of `Add`
+        assert_eq!(verified.max_stack, 3);
+    }
+
+    #[test]
+    fn accepts_branches_that_rejoin_at_the_same_depth() {
+        let source = "
+            True
+            JumpIfFalse else
+            Pop
+            Constant 1
+            Jump end
+        else:
+            Pop
+            Constant 2
+        end:
+            Print
+            Nil
+            Return";
+
+        check(source).unwrap();
+    }
+
+    #[test]
+    fn rejects_an_empty_chunk() {
+        assert!(matches!(verify(&Chunk::new()), Err(VerifyError::Empty)));
+    }
+
+    #[test]
+    fn rejects_invalid_opcodes() {
+        let result = check("Nil\n.byte 0xff");
+        assert!(matches!(
+            result,
+            Err(VerifyError::InvalidOpcode {
+                offset: 1,
+                byte: 0xff
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_operands() {
+        let result = check(&format!("Nil\n.byte {:#04x}", OpCode::GetLocal as u8));
+        assert!(matches!(
+            result,
+            Err(VerifyError::TruncatedOperand { offset: 1 })
+        ));
+    }
+
+    #[test]
+    fn rejects_constants_outside_the_pool() {
+        let mut chunk = Chunk::new();
+        chunk.push_opcode(OpCode::Constant, Position::default());
+        chunk.push_byte(3, Position::default());
+        chunk.push_opcode(OpCode::Return, Position::default());
+
+        assert!(matches!(
+            verify(&chunk),
+            Err(VerifyError::InvalidConstant {
+                offset: 0,
+                index: 3,
+                len: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_constants_of_the_wrong_type() {
+        let result = check(".const 1.5\nGetGlobal #0\nReturn");
+        assert!(matches!(
+            result,
+            Err(VerifyError::ConstantType {
+                index: 0,
+                expected: "string",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn checks_the_constants_of_long_forms() {
+        let mut chunk = Chunk::new();
+        chunk.push_indexed(OpCode::GetGlobal, 300, Position::default());
+        chunk.push_opcode(OpCode::Return, Position::default());
+        assert_eq!(chunk.code[0], OpCode::GetGlobalLong as u8);
+        assert!(matches!(
+            verify(&chunk),
+            Err(VerifyError::InvalidConstant {
+                offset: 0,
+                index: 300,
+                len: 0
+            })
+        ));
+
+        let mut source = String::new();
+        for i in 0..300 {
+            writeln!(source, ".const {i}").unwrap();
+        }
+        source.push_str("GetPropertyLong #299\nReturn");
+        assert!(matches!(
+            check(&source),
+            Err(VerifyError::ConstantType {
+                index: 299,
+                expected: "string",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_locals_beyond_the_stack() {
+        let result = check("GetLocal 1\nReturn");
+        assert!(matches!(
+            result,
+            Err(VerifyError::InvalidLocal {
+                slot: 1,
+                depth: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_upvalues_the_function_does_not_have() {
+        let result = check("GetUpvalue 0\nReturn");
+        assert!(matches!(
+            result,
+            Err(VerifyError::InvalidUpvalue {
+                index: 0,
+                count: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_loops_before_the_start() {
+        let result = check("Loop 100\nNil\nReturn");
+        assert!(matches!(
+            result,
+            Err(VerifyError::JumpOutOfBounds { offset: 0 })
+        ));
+    }
+
+    #[test]
+    fn rejects_jumps_into_the_middle_of_an_instruction() {
+        let result = check("Jump 1\nConstant 1\nReturn");
+        assert!(matches!(
+            result,
+            Err(VerifyError::MisalignedTarget { from: 0, target: 4 })
+        ));
+    }
+
+    #[test]
+    fn rejects_code_that_runs_off_the_end() {
+        let result = check("Nil\nPrint");
+        assert!(matches!(
+            result,
+            Err(VerifyError::FallsOffEnd { offset: 1 })
+        ));
+    }
+
+    #[test]
+    fn rejects_instructions_without_enough_operands_on_the_stack() {
+        // The script's slot is the only value on the stack
+        let result = check("Not\nAdd\nReturn");
+        assert!(matches!(
+            result,
+            Err(VerifyError::StackUnderflow {
+                offset: 1,
+                needed: 2,
+                depth: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_branches_that_rejoin_at_different_depths() {
+        let source = "
+            True
+            JumpIfFalse end
+            Nil
+        end:
+            Return";
+
+        assert!(matches!(
+            check(source),
+            Err(VerifyError::StackMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_code_without_line_information() {
+        let mut chunk = Chunk::new();
+        chunk.code.push(OpCode::Nil as u8);
+        chunk.code.push(OpCode::Return as u8);
+
+        assert!(matches!(
+            verify(&chunk),
+            Err(VerifyError::MissingLine { offset: 0 })
+        ));
+    }
+
+    #[test]
+    fn measures_the_stack_through_superinstructions() {
+        let source = "
+            .const 1
+            Nil
+            IncrLocal 1 #0
+            LessLocals 0 1
+            AddConst #0
+            GetLocal1
+            Print
+            Print
+            Pop
+            Nil
+            Return";
+
+        // The script's slot, the local, and the result of `LessLocals` and `GetLocal1`
+        assert_eq!(check(source).unwrap().max_stack, 4);
+    }
+
+    #[test]
+    fn rejects_superinstruction_locals_beyond_the_stack() {
+        for (code, slot, depth) in [
+            ("GetLocal1", 1, 1),
+            ("GetLocal2", 2, 1),
+            ("Nil\nNil\nGetLocal3", 3, 3),
+            ("Nil\nLessLocals 0 2", 2, 2),
+            ("Nil\nLessLocals 2 0", 2, 2),
+            (".const 1\nIncrLocal 1 #0", 1, 1),
+        ] {
+            let result = check(&format!("{code}\nReturn"));
+            assert!(
+                matches!(
+                    result,
+                    Err(VerifyError::InvalidLocal { slot: s, depth: d, .. }) if s == slot && d == depth
+                ),
+                "{code}: {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_superinstruction_constants_outside_the_pool() {
+        for (opcode, operands) in [(OpCode::AddConst, &[2][..]), (OpCode::IncrLocal, &[0, 2])] {
+            let mut chunk = Chunk::new();
+            chunk.push_opcode(OpCode::Nil, Position::default());
+            chunk.push_opcode(opcode, Position::default());
+            for &byte in operands {
+                chunk.push_byte(byte, Position::default());
+            }
+            chunk.push_opcode(OpCode::Return, Position::default());
+
+            assert!(
+                matches!(
+                    verify(&chunk),
+                    Err(VerifyError::InvalidConstant {
+                        offset: 1,
+                        index: 2,
+                        len: 0
+                    })
+                ),
+                "{opcode:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_superinstructions_without_enough_operands_on_the_stack() {
+        let result = check(".const 1\nPop\nAddConst #0\nReturn");
+        assert!(matches!(
+            result,
+            Err(VerifyError::StackUnderflow {
+                offset: 1,
+                needed: 1,
+                depth: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn reports_errors_in_nested_functions() {
+        let source = "
+        .function f 0
+        .line 2
+            Add
+            Return
+        .end
+            Closure #0
+            Return";
+
+        let Err(VerifyError::InFunction { function, source }) = check(source) else {
+            panic!("Expected an error in the nested function");
+        };
+        assert_eq!(function, "<fn f>");
+        assert!(matches!(*source, VerifyError::StackUnderflow { .. }));
+    }
+}
@@ -1,95 +1,1541 @@
-use crate::chunk::{Chunk, OpCode, Value};
-use crate::compiler::compile;
-use crate::{InterpretError, InterpretResult, USIZE_SIZE};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
 
-pub struct VM {
-    chunk: Chunk,
+use thiserror::Error;
+
+use crate::chunk::{Chunk, JUMP_OPERAND_SIZE, LocalVariable, OPCODE_SIZE, OpCode, Position};
+use crate::compiler::{compile, compile_expression};
+use crate::natives::{self, Context, NativeError};
+use crate::profile::Profiler;
+use crate::trace::Tracer;
+use crate::value::{
+    BoundMethod, Class, Closure, Function, Instance, Native, NativeFn, Unpacked, Upvalue, Value,
+};
+use crate::verifier::{VerifyError, verify, verify_closure};
+use crate::{InterpretError, InterpretResult};
+
+/// Maximum depth of nested calls.
+pub const FRAMES_MAX: usize = 64;
+
+pub const DEFAULT_STACK_MAX: usize = FRAMES_MAX * 256;
+
+#[derive(Debug, Error)]
+pub enum RuntimeError {
+    #[error("Stack underflow at offset {offset}")]
+    StackUnderflow { offset: usize },
+    #[error("Stack overflow at offset {offset} (maximum depth is {max})")]
+    StackOverflow { offset: usize, max: usize },
+    #[error("Chunk needs a stack depth of {needed}, exceeding the maximum of {max}")]
+    StackLimit { needed: usize, max: usize },
+    #[error("Stack overflow: more than {max} nested calls.")]
+    CallDepth { max: usize },
+    #[error("Invalid opcode {byte:#04x} at offset {offset}")]
+    InvalidOpcode { offset: usize, byte: u8 },
+    #[error("Invalid constant operand at offset {offset}")]
+    InvalidConstant { offset: usize },
+    #[error("Instruction pointer ran past the end of the chunk")]
+    UnexpectedEnd,
+    #[error("Invalid bytecode: {0}")]
+    Verify(VerifyError),
+    #[error("Operand must be a number.")]
+    OperandMustBeNumber,
+    #[error("Operands must be numbers.")]
+    OperandsMustBeNumbers,
+    #[error("Operands must be two numbers or two strings.")]
+    OperandsMustBeNumbersOrStrings,
+    #[error("Undefined variable '{name}'.")]
+    UndefinedVariable { name: String },
+    #[error("Undefined property '{name}'.")]
+    UndefinedProperty { name: String },
+    #[error("Only instances have properties.")]
+    NotAnInstance,
+    #[error("Only instances have fields.")]
+    FieldOnNonInstance,
+    #[error("Can only call functions and classes.")]
+    NotCallable,
+    #[error("Expected {expected} arguments but got {got}.")]
+    Arity { expected: u8, got: u8 },
+    #[error("Can't have more than 255 arguments.")]
+    TooManyArguments,
+    #[error("Superclass must be a class.")]
+    SuperclassNotClass,
+    #[error("{name}: {message}")]
+    Native { name: String, message: String },
+    /// An error in Lox code called back into by a native, already located where it happened
+    #[error(transparent)]
+    Callback(Box<InterpretError>),
+    #[error("Could not write output: {0}")]
+    Output(std::io::Error),
+    #[error("Could not write trace: {0}")]
+    Trace(#[from] std::io::Error),
+}
+
+/// What tools such as the debugger can see of an active call.
+#[derive(Debug)]
+pub struct FrameInfo<'a> {
+    pub function: &'a Function,
+    /// An offset within the instruction the frame is executing
+    pub offset: usize,
+    /// The frame's visible initialized locals and their values, outer scopes first
+    pub locals: Vec<(&'a str, Value)>,
+}
+
+impl FrameInfo<'_> {
+    pub fn position(&self) -> Option<Position> {
+        self.function.chunk.get_position(self.offset)
+    }
+}
+
+/// Outcome of executing a single instruction.
+#[derive(Debug, Clone)]
+pub enum Step {
+    Continue,
+    Returned(Option<Value>),
+}
+
+/// A `Write` sink for `print` output whose contents can be read back after running.
+#[derive(Debug, Clone, Default)]
+pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Capture {
+    /// Everything written so far.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    /// Everything written since the last call, emptying the buffer.
+    pub fn take(&self) -> String {
+        let bytes = std::mem::take(&mut *self.0.borrow_mut());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// An active function call.
+struct CallFrame {
+    closure: Rc<Closure>,
     ip: usize,
+    /// Index of the stack slot holding the callee, where the frame's locals begin
+    slots: usize,
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
+    /// Top-level code of the loaded program
+    script: Rc<Function>,
     stack: Vec<Value>,
+    stack_max: usize,
+    globals: HashMap<String, Value>,
+    /// Upvalues still referring to stack slots, closed when those slots are discarded
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    output: Box<dyn Write>,
+    /// Offset of the instruction being executed in the current frame, for error reporting
+    offset: usize,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    /// Instructions executed by `run` since the VM was created
+    executed: u64,
+    /// Number of frames below the call being run to completion. Returning to this depth hands
+    /// the result back to Rust.
+    entry_depth: usize,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Context for VM {
+    fn call(&mut self, callee: &Value, arguments: &[Value]) -> InterpretResult<Value> {
+        VM::call(self, callee, arguments)
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
+        Self::with_stack_max(DEFAULT_STACK_MAX)
+    }
+
+    /// Create a VM whose value stack may hold at most `stack_max` values. Pushing beyond that
+    /// produces a [`RuntimeError::StackOverflow`] rather than growing without bound.
+    pub fn with_stack_max(stack_max: usize) -> Self {
         Self {
-            chunk: Chunk::new(),
-            ip: 0,
-            stack: Vec::with_capacity(256),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            script: Rc::new(Function::default()),
+            stack: Vec::with_capacity(stack_max),
+            stack_max,
+            globals: natives::globals(),
+            open_upvalues: Vec::new(),
+            output: Box::new(io::stdout()),
+            offset: 0,
+            tracer: None,
+            profiler: None,
+            executed: 0,
+            entry_depth: 0,
         }
     }
 
-    pub fn interpret(&mut self, source: &str) -> InterpretResult<()> {
-        self.chunk = compile(source)?;
+    /// Trace every instruction executed from now on, or stop tracing if `tracer` is `None`.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Send the output of `print` statements to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Compile and run `source`, returning the value of its final statement if that is an
+    /// expression statement, and nil otherwise. Globals defined by earlier calls are kept.
+    pub fn interpret(&mut self, source: &str) -> InterpretResult<Value> {
+        let chunk = compile(source)?;
+        self.interpret_chunk(chunk)
+    }
+
+    /// Execute an already compiled chunk, such as one loaded from a `.loxc` file, returning the
+    /// value its top-level code returns.
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> InterpretResult<Value> {
+        self.load(chunk)?;
 
-        self.run()
+        self.run().map_err(|e| {
+            // Locate the error while the failing frame is still there, then unwind everything so
+            // that `call` starts from an empty stack, as it does after a successful run
+            let error = self.locate(e);
+            self.reset();
+            error
+        })
     }
 
-    fn run(&mut self) -> InterpretResult<()> {
+    /// Verify `chunk` and make it the top-level code to be executed by the next call to `run` or
+    /// `step`. Globals defined by previously loaded chunks are kept.
+    pub fn load(&mut self, chunk: Chunk) -> InterpretResult<()> {
+        let verified = verify(&chunk)?;
+        if verified.max_stack > self.stack_max {
+            return Err(InterpretError::Runtime {
+                error: RuntimeError::StackLimit {
+                    needed: verified.max_stack,
+                    max: self.stack_max,
+                },
+                position: None,
+            });
+        }
+
+        self.script = Rc::new(Function {
+            chunk,
+            verified: Cell::new(true),
+            ..Function::default()
+        });
+
+        let closure = Rc::new(Closure {
+            function: Rc::clone(&self.script),
+            upvalues: Vec::new(),
+        });
+
+        self.reset();
+
+        self.stack.push(Value::from(Rc::clone(&closure)));
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: 0,
+        });
+
+        Ok(())
+    }
+
+    /// The value of the global variable `name`, if it is defined.
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).cloned()
+    }
+
+    /// Define the global variable `name` as a top-level `var` declaration would, replacing any
+    /// value it already has.
+    pub fn define_global(&mut self, name: &str, value: impl Into<Value>) {
+        self.globals.insert(name.to_string(), value.into());
+    }
+
+    /// Assign to the global variable `name`, which must already be defined.
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) -> Result<(), RuntimeError> {
+        let global = self
+            .globals
+            .get_mut(name)
+            .ok_or_else(|| RuntimeError::UndefinedVariable {
+                name: name.to_string(),
+            })?;
+        *global = value.into();
+
+        Ok(())
+    }
+
+    /// Define the global `name` as a native function taking `arity` arguments.
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let native = Native {
+            name: name.to_string(),
+            arity,
+            function,
+        };
+        self.define_global(name, Rc::new(native));
+    }
+
+    /// Collect execution statistics from now on, or stop profiling if `profiler` is `None`.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    /// Statistics gathered so far, if profiling is enabled.
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// The chunk of the function currently executing, or of the top-level code once it has
+    /// returned.
+    pub fn chunk(&self) -> &Chunk {
+        self.frames
+            .last()
+            .map_or(&self.script.chunk, |frame| &frame.closure.function.chunk)
+    }
+
+    /// Offset of the next instruction to execute in the current function.
+    pub fn ip(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.ip)
+    }
+
+    /// Number of active calls, including the top-level code.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Number of instructions executed by running programs to completion, not counting those
+    /// the debugger steps through one at a time.
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    fn run(&mut self) -> Result<Value, RuntimeError> {
+        let result = self.execute().map(Option::unwrap_or_default);
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.stop();
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush()?;
+        }
+
+        self.output.flush().map_err(RuntimeError::Output)?;
+        result
+    }
+
+    /// Run the loaded chunk to completion, returning the value it returns.
+    fn execute(&mut self) -> Result<Option<Value>, RuntimeError> {
+        if self.tracer.is_none() && self.profiler.is_none() {
+            return self.execute_verified();
+        }
+
         loop {
-            #[cfg(feature = "debug_trace_execution")]
-            {
-                println!("{:?}", self.stack);
-                println!("{}", self.chunk.disassemble_instruction(self.ip).unwrap());
+            self.executed += 1;
+            if let Step::Returned(value) = self.step()? {
+                return Ok(value);
             }
+        }
+    }
+
+    /// Run the loaded chunk to completion without the checks `step` makes on every instruction.
+    ///
+    /// Every frame is pushed by `call_closure`, which verifies any function that did not come
+    /// from a loaded chunk before entering it, so each instruction the frame's `ip` reaches is a
+    /// valid opcode followed by all of its operands, and each constant operand has the type its
+    /// instruction expects. The loop keeps the current frame's closure and `ip` in locals and
+    /// executes the common instructions itself. Anything else, including every instruction that
+    /// enters or leaves a frame, goes through `step` after `ip` is written back.
+    #[allow(clippy::too_many_lines)]
+    fn execute_verified(&mut self) -> Result<Option<Value>, RuntimeError> {
+        let frame = self.frames.last().ok_or(RuntimeError::UnexpectedEnd)?;
+        let mut closure = Rc::clone(&frame.closure);
+        let mut ip = frame.ip;
+        let mut slots = frame.slots;
 
-            let &instruction = self
-                .chunk
-                .code
-                .get(self.ip)
-                .ok_or(InterpretError::Runtime)?;
-            self.ip += 1;
+        loop {
+            let chunk = &closure.function.chunk;
+            let code = chunk.code.as_slice();
 
-            // TODO: This error handling is kinda questionable
-            let code: OpCode = instruction
-                .try_into()
-                .map_err(|_| InterpretError::Runtime)?;
+            // SAFETY: Verification guarantees that `ip` is the offset of an instruction in `code`
+            // and that all of its operand bytes follow it
+            let opcode = unsafe { OpCode::from_byte_unchecked(*code.get_unchecked(ip)) };
+            let operand = |i: usize| unsafe { *code.get_unchecked(ip + OPCODE_SIZE + i) };
+            let next = ip + OPCODE_SIZE + opcode.operand_size();
+            self.offset = ip;
+            self.executed += 1;
 
-            match code {
+            match opcode {
                 OpCode::Constant => {
-                    // TODO: Model this properly (if Value isn't copy, deref will move)
-                    let (_i, constant) = self
-                        .chunk
-                        .get_constant(self.ip)
-                        .ok_or(InterpretError::Runtime)?;
-                    // TODO: Organize this constant
-                    self.ip += USIZE_SIZE;
-                    self.stack.push(constant);
+                    let constant = chunk.constants[usize::from(operand(0))].clone();
+                    self.push(constant)?;
+                }
+
+                OpCode::Nil => self.push(Value::nil())?,
+                OpCode::True => self.push(Value::from(true))?,
+                OpCode::False => self.push(Value::from(false))?,
+
+                OpCode::Pop => {
+                    self.pop()?;
+                }
+
+                OpCode::GetLocal => {
+                    let value = self.stack[slots + usize::from(operand(0))].clone();
+                    self.push(value)?;
+                }
+
+                OpCode::GetLocal0 => self.push(self.stack[slots].clone())?,
+                OpCode::GetLocal1 => self.push(self.stack[slots + 1].clone())?,
+                OpCode::GetLocal2 => self.push(self.stack[slots + 2].clone())?,
+                OpCode::GetLocal3 => self.push(self.stack[slots + 3].clone())?,
+
+                OpCode::SetLocal => {
+                    self.stack[slots + usize::from(operand(0))] = self.peek(0)?.clone();
+                }
+
+                OpCode::GetGlobal => {
+                    let name = chunk.constants[usize::from(operand(0))]
+                        .as_str()
+                        .expect("Verified names are strings");
+                    let value = self.globals.get(name).cloned().ok_or_else(|| {
+                        RuntimeError::UndefinedVariable {
+                            name: name.to_string(),
+                        }
+                    })?;
+                    self.push(value)?;
+                }
+
+                OpCode::Equal => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(Value::from(a == b))?;
+                }
+
+                OpCode::Greater => self.number_operator(|a, b| Value::from(a > b))?,
+                OpCode::Less => self.number_operator(|a, b| Value::from(a < b))?,
+                OpCode::Sub => self.number_operator(|a, b| Value::from(a - b))?,
+                OpCode::Mul => self.number_operator(|a, b| Value::from(a * b))?,
+                OpCode::Div => self.number_operator(|a, b| Value::from(a / b))?,
+
+                OpCode::Add => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(add(&a, &b)?)?;
+                }
+
+                OpCode::AddConst => {
+                    let a = self.pop()?;
+                    self.push(add(&a, &chunk.constants[usize::from(operand(0))])?)?;
+                }
+
+                OpCode::LessLocals => {
+                    let a = &self.stack[slots + usize::from(operand(0))];
+                    let b = &self.stack[slots + usize::from(operand(1))];
+                    let (Some(a), Some(b)) = (a.as_number(), b.as_number()) else {
+                        return Err(RuntimeError::OperandsMustBeNumbers);
+                    };
+                    self.push(Value::from(a < b))?;
+                }
+
+                OpCode::IncrLocal => {
+                    let slot = slots + usize::from(operand(0));
+                    let increment = &chunk.constants[usize::from(operand(1))];
+                    self.stack[slot] = add(&self.stack[slot], increment)?;
+                }
+
+                OpCode::Not => {
+                    let operand = self.pop()?;
+                    self.push(Value::from(operand.is_falsey()))?;
                 }
 
                 OpCode::Negate => {
-                    // TODO: Is expect appropriate here?
-                    let operand = self
-                        .stack
-                        .pop()
-                        .expect("Attempted to pop value, but the stack was empty");
-                    self.stack.push(-operand);
+                    let Some(operand) = self.pop()?.as_number() else {
+                        return Err(RuntimeError::OperandMustBeNumber);
+                    };
+                    self.push(Value::from(-operand))?;
+                }
+
+                OpCode::Jump => {
+                    ip = next + jump_distance(operand(0), operand(1));
+                    continue;
+                }
+
+                OpCode::JumpIfFalse => {
+                    if self.peek(0)?.is_falsey() {
+                        ip = next + jump_distance(operand(0), operand(1));
+                        continue;
+                    }
+                }
+
+                OpCode::JumpIfTrue => {
+                    if !self.peek(0)?.is_falsey() {
+                        ip = next + jump_distance(operand(0), operand(1));
+                        continue;
+                    }
+                }
+
+                OpCode::Loop => {
+                    ip = next - jump_distance(operand(0), operand(1));
+                    continue;
+                }
+
+                _ => {
+                    self.frame_mut().ip = ip;
+                    if let Step::Returned(value) = self.step()? {
+                        return Ok(value);
+                    }
+
+                    let frame = self
+                        .frames
+                        .last()
+                        .expect("Only the last return leaves no frame");
+                    closure = Rc::clone(&frame.closure);
+                    ip = frame.ip;
+                    slots = frame.slots;
+                    continue;
+                }
+            }
+
+            ip = next;
+        }
+    }
+
+    /// Execute the single instruction at `ip`.
+    #[allow(clippy::too_many_lines)]
+    pub(crate) fn step(&mut self) -> Result<Step, RuntimeError> {
+        let frame = self.frames.last().ok_or(RuntimeError::UnexpectedEnd)?;
+        let chunk = &frame.closure.function.chunk;
+        let offset = frame.ip;
+        self.offset = offset;
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&frame.closure.function, offset, &self.stack)?;
+        }
+
+        let &instruction = chunk.code.get(offset).ok_or(RuntimeError::UnexpectedEnd)?;
+        let code: OpCode = instruction
+            .try_into()
+            .map_err(|_| RuntimeError::InvalidOpcode {
+                offset,
+                byte: instruction,
+            })?;
+
+        if let Some(profiler) = &mut self.profiler {
+            let frames = self.frames.iter().map(|f| &f.closure.function);
+            profiler.record(frames, offset, code);
+        }
+
+        self.frame_mut().ip += 1;
+
+        match code {
+            OpCode::Constant | OpCode::ConstantLong => {
+                let constant = self.read_constant(code.operand_size())?;
+                self.push(constant)?;
+            }
+
+            OpCode::Nil => self.push(Value::nil())?,
+            OpCode::True => self.push(Value::from(true))?,
+            OpCode::False => self.push(Value::from(false))?,
+
+            OpCode::Pop => {
+                self.pop()?;
+            }
+
+            OpCode::GetLocal => {
+                let slot = self.local_slot()?;
+                let value = self.stack[slot].clone();
+                self.push(value)?;
+            }
+
+            OpCode::GetLocal0 => self.get_local(0)?,
+            OpCode::GetLocal1 => self.get_local(1)?,
+            OpCode::GetLocal2 => self.get_local(2)?,
+            OpCode::GetLocal3 => self.get_local(3)?,
+
+            OpCode::SetLocal => {
+                let slot = self.local_slot()?;
+                self.stack[slot] = self.peek(0)?.clone();
+            }
+
+            OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                let name = self.read_name(code)?;
+                let value = self.globals.get(name.as_str()).cloned().ok_or_else(|| {
+                    RuntimeError::UndefinedVariable {
+                        name: name.to_string(),
+                    }
+                })?;
+                self.push(value)?;
+            }
+
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                let name = self.read_name(code)?;
+                let value = self.pop()?;
+                self.globals.insert(name.to_string(), value);
+            }
+
+            OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                let name = self.read_name(code)?;
+                let value = self.peek(0)?.clone();
+                let Some(global) = self.globals.get_mut(name.as_str()) else {
+                    return Err(RuntimeError::UndefinedVariable {
+                        name: name.to_string(),
+                    });
+                };
+                *global = value;
+            }
+
+            OpCode::GetUpvalue => {
+                let upvalue = self.read_upvalue()?;
+                let value = match &*upvalue.borrow() {
+                    Upvalue::Open(slot) => self.stack[*slot].clone(),
+                    Upvalue::Closed(value) => value.clone(),
+                };
+                self.push(value)?;
+            }
+
+            OpCode::SetUpvalue => {
+                let upvalue = self.read_upvalue()?;
+                let value = self.peek(0)?.clone();
+                match &mut *upvalue.borrow_mut() {
+                    Upvalue::Open(slot) => self.stack[*slot] = value,
+                    Upvalue::Closed(closed) => *closed = value,
+                }
+            }
+
+            OpCode::GetProperty | OpCode::GetPropertyLong => {
+                let name = self.read_name(code)?;
+                let Unpacked::Instance(instance) = self.peek(0)?.unpack() else {
+                    return Err(RuntimeError::NotAnInstance);
+                };
+
+                let field = instance.fields.borrow().get(name.as_str()).cloned();
+                let value = match field {
+                    Some(value) => value,
+                    None => bind_method(&instance.class, &name, Value::from(instance.clone()))?,
+                };
+
+                self.pop()?;
+                self.push(value)?;
+            }
+
+            OpCode::SetProperty | OpCode::SetPropertyLong => {
+                let name = self.read_name(code)?;
+                let Unpacked::Instance(instance) = self.peek(1)?.unpack() else {
+                    return Err(RuntimeError::FieldOnNonInstance);
+                };
+
+                let value = self.peek(0)?.clone();
+                instance.fields.borrow_mut().insert(name.to_string(), value);
+
+                let value = self.pop()?;
+                self.pop()?;
+                self.push(value)?;
+            }
+
+            OpCode::GetSuper | OpCode::GetSuperLong => {
+                let name = self.read_name(code)?;
+                let Unpacked::Class(superclass) = self.pop()?.unpack() else {
+                    return Err(RuntimeError::SuperclassNotClass);
+                };
+
+                let receiver = self.pop()?;
+                let method = bind_method(&superclass, &name, receiver)?;
+                self.push(method)?;
+            }
+
+            OpCode::Equal => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(Value::from(a == b))?;
+            }
+
+            OpCode::Greater => self.number_operator(|a, b| Value::from(a > b))?,
+            OpCode::Less => self.number_operator(|a, b| Value::from(a < b))?,
+
+            OpCode::Add => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(add(&a, &b)?)?;
+            }
+
+            OpCode::AddConst => {
+                let b = self.read_constant(1)?;
+                let a = self.pop()?;
+                self.push(add(&a, &b)?)?;
+            }
+
+            OpCode::LessLocals => {
+                let a = self.local_slot()?;
+                let b = self.local_slot()?;
+                let (Some(a), Some(b)) = (self.stack[a].as_number(), self.stack[b].as_number())
+                else {
+                    return Err(RuntimeError::OperandsMustBeNumbers);
+                };
+                self.push(Value::from(a < b))?;
+            }
+
+            OpCode::IncrLocal => {
+                let slot = self.local_slot()?;
+                let increment = self.read_constant(1)?;
+                self.stack[slot] = add(&self.stack[slot], &increment)?;
+            }
+
+            OpCode::Sub => self.number_operator(|a, b| Value::from(a - b))?,
+            OpCode::Mul => self.number_operator(|a, b| Value::from(a * b))?,
+            OpCode::Div => self.number_operator(|a, b| Value::from(a / b))?,
+
+            OpCode::Not => {
+                let operand = self.pop()?;
+                self.push(Value::from(operand.is_falsey()))?;
+            }
+
+            OpCode::Negate => {
+                let Unpacked::Number(operand) = self.pop()?.unpack() else {
+                    return Err(RuntimeError::OperandMustBeNumber);
+                };
+                self.push(Value::from(-operand))?;
+            }
+
+            OpCode::Print => {
+                let value = self.pop()?;
+                writeln!(self.output, "{value}").map_err(RuntimeError::Output)?;
+            }
+
+            OpCode::Jump => {
+                let distance = self.read_jump()?;
+                self.frame_mut().ip += distance;
+            }
+
+            OpCode::JumpIfFalse => {
+                let distance = self.read_jump()?;
+                if self.peek(0)?.is_falsey() {
+                    self.frame_mut().ip += distance;
+                }
+            }
+
+            OpCode::JumpIfTrue => {
+                let distance = self.read_jump()?;
+                if !self.peek(0)?.is_falsey() {
+                    self.frame_mut().ip += distance;
+                }
+            }
+
+            OpCode::Loop => {
+                let distance = self.read_jump()?;
+                self.frame_mut().ip -= distance;
+            }
+
+            OpCode::Call => {
+                let argc = self.read_byte()?;
+                let callee = self.peek(usize::from(argc))?.clone();
+                self.call_value(&callee, argc)?;
+            }
+
+            OpCode::Invoke | OpCode::InvokeLong => {
+                let name = self.read_name(code)?;
+                let argc = self.read_byte()?;
+                self.invoke(&name, argc)?;
+            }
+
+            OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                let name = self.read_name(code)?;
+                let argc = self.read_byte()?;
+                let Unpacked::Class(superclass) = self.pop()?.unpack() else {
+                    return Err(RuntimeError::SuperclassNotClass);
+                };
+                self.invoke_from_class(&superclass, &name, argc)?;
+            }
+
+            OpCode::Closure | OpCode::ClosureLong => {
+                let Unpacked::Function(function) =
+                    self.read_constant(code.operand_size())?.unpack()
+                else {
+                    return Err(RuntimeError::InvalidConstant {
+                        offset: self.offset,
+                    });
+                };
+
+                let frame = self
+                    .frames
+                    .last()
+                    .expect("Checked at the start of the step");
+                let slots = frame.slots;
+                let enclosing = Rc::clone(&frame.closure);
+
+                let upvalues = function
+                    .upvalues
+                    .iter()
+                    .map(|upvalue| {
+                        let index = usize::from(upvalue.index);
+                        if upvalue.is_local {
+                            self.capture_upvalue(slots + index)
+                        } else {
+                            Rc::clone(&enclosing.upvalues[index])
+                        }
+                    })
+                    .collect();
+
+                self.push(Value::from(Rc::new(Closure { function, upvalues })))?;
+            }
+
+            OpCode::CloseUpvalue => {
+                self.close_upvalues(self.stack.len().saturating_sub(1));
+                self.pop()?;
+            }
+
+            OpCode::Return => {
+                let result = self.pop()?;
+                let frame = self.frames.pop().expect("Checked at the start of the step");
+                self.close_upvalues(frame.slots);
+                self.stack.truncate(frame.slots);
+
+                if self.frames.len() == self.entry_depth {
+                    return Ok(Step::Returned(Some(result)));
+                }
+
+                self.push(result)?;
+            }
+
+            OpCode::Class | OpCode::ClassLong => {
+                let name = self.read_name(code)?;
+                let class = Class {
+                    name: name.to_string(),
+                    methods: RefCell::default(),
+                };
+                self.push(Value::from(Rc::new(class)))?;
+            }
+
+            OpCode::Inherit => {
+                let Unpacked::Class(superclass) = self.peek(1)?.unpack() else {
+                    return Err(RuntimeError::SuperclassNotClass);
+                };
+                let Unpacked::Class(subclass) = self.peek(0)?.unpack() else {
+                    return Err(RuntimeError::InvalidConstant {
+                        offset: self.offset,
+                    });
+                };
+
+                let methods = superclass.methods.borrow().clone();
+                subclass.methods.borrow_mut().extend(methods);
+                self.pop()?;
+            }
+
+            OpCode::Method | OpCode::MethodLong => {
+                let name = self.read_name(code)?;
+                let Unpacked::Closure(method) = self.pop()?.unpack() else {
+                    return Err(RuntimeError::InvalidConstant {
+                        offset: self.offset,
+                    });
+                };
+                let Unpacked::Class(class) = self.peek(0)?.unpack() else {
+                    return Err(RuntimeError::InvalidConstant {
+                        offset: self.offset,
+                    });
+                };
+
+                class.methods.borrow_mut().insert(name.to_string(), method);
+            }
+        }
+
+        Ok(Step::Continue)
+    }
+
+    /// Evaluate the expression `source` as if it appeared where the call frame `frame` (0 being
+    /// the outermost) is executing, so it can read and assign the locals in scope there as well
+    /// as globals. With no frame, only globals are visible. Used by tools such as the debugger;
+    /// the frames and stack are left as they were.
+    pub fn evaluate(&mut self, source: &str, frame: Option<usize>) -> InterpretResult<Value> {
+        let frame = frame.and_then(|i| self.frames.get(i).map(|f| (i, f.slots)));
+        let scope: Vec<_> = frame.map_or_else(Vec::new, |(i, slots)| {
+            self.frame_locals(i)
+                .filter(|l| slots + usize::from(l.slot) < self.stack.len())
+                .cloned()
+                .collect()
+        });
+
+        let function = compile_expression(source, &scope)?;
+        let slots = frame.map_or(0, |(_, slots)| slots);
+        let already_open = self.open_upvalues.len();
+        let upvalues = function
+            .upvalues
+            .iter()
+            .map(|upvalue| self.capture_upvalue(slots + usize::from(upvalue.index)))
+            .collect();
+
+        // Upvalues opened here only live as long as the evaluation. The frame's own code may pop
+        // their slots without closing them, so they must not be closed with the frame.
+        self.open_upvalues.truncate(already_open);
+
+        let closure = Closure {
+            function: Rc::new(function),
+            upvalues,
+        };
+        self.call(&Value::from(Rc::new(closure)), &[])
+    }
+
+    /// The active calls, outermost first.
+    pub(crate) fn frames(&self) -> Vec<FrameInfo<'_>> {
+        (0..self.frames.len())
+            .map(|i| {
+                let frame = &self.frames[i];
+                let mut locals: Vec<_> = self
+                    .frame_locals(i)
+                    .filter_map(|local| {
+                        let value = self.stack.get(frame.slots + usize::from(local.slot))?;
+                        Some((local.name.as_str(), value.clone()))
+                    })
+                    .collect();
+
+                // Only the innermost of several variables with the same name is visible
+                let mut seen = Vec::new();
+                locals.reverse();
+                locals.retain(|&(name, _)| {
+                    let shadowed = seen.contains(&name);
+                    seen.push(name);
+                    !shadowed
+                });
+                locals.reverse();
+
+                FrameInfo {
+                    function: &frame.closure.function,
+                    offset: self.frame_offset(i),
+                    locals,
+                }
+            })
+            .collect()
+    }
+
+    /// Every global variable, including natives, sorted by name.
+    pub fn globals(&self) -> Vec<(&str, Value)> {
+        let mut globals: Vec<_> = self
+            .globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.clone()))
+            .collect();
+        globals.sort_by_key(|&(name, _)| name);
+        globals
+    }
+
+    /// An offset within the instruction frame `i` is executing: the next one for the innermost
+    /// frame, or the call the others are waiting on.
+    fn frame_offset(&self, i: usize) -> usize {
+        let ip = self.frames[i].ip;
+        if i + 1 == self.frames.len() {
+            ip
+        } else {
+            ip.saturating_sub(1)
+        }
+    }
+
+    /// The locals of frame `i` that are initialized where it is executing.
+    fn frame_locals(&self, i: usize) -> impl Iterator<Item = &LocalVariable> + '_ {
+        self.frames[i]
+            .closure
+            .function
+            .chunk
+            .locals_at(self.frame_offset(i))
+    }
+
+    /// Call `callee` with `arguments` and run it to completion, returning its result. This is
+    /// how Rust code calls Lox functions, such as closures read with [`VM::global`], and how
+    /// natives call back into Lox. The frames and stack are left as they were, even on error.
+    pub fn call(&mut self, callee: &Value, arguments: &[Value]) -> InterpretResult<Value> {
+        let depth = self.frames.len();
+        let height = self.stack.len();
+        let offset = self.offset;
+        let entry_depth = std::mem::replace(&mut self.entry_depth, depth);
+
+        let result = self.call_to_completion(callee, arguments).map_err(|error| {
+            // Only Rust code is running if the call failed before entering a frame
+            if self.frames.is_empty() {
+                InterpretError::Runtime {
+                    error,
+                    position: None,
+                }
+            } else {
+                self.locate(error)
+            }
+        });
+
+        self.close_upvalues(height);
+        self.frames.truncate(depth);
+        self.stack.truncate(height);
+        self.offset = offset;
+        self.entry_depth = entry_depth;
+
+        if depth == 0 {
+            self.output.flush().map_err(|e| InterpretError::Runtime {
+                error: RuntimeError::Output(e),
+                position: None,
+            })?;
+        }
+
+        result
+    }
+
+    fn call_to_completion(
+        &mut self,
+        callee: &Value,
+        arguments: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        let argc = u8::try_from(arguments.len()).map_err(|_| RuntimeError::TooManyArguments)?;
+
+        self.push(callee.clone())?;
+        for argument in arguments {
+            self.push(argument.clone())?;
+        }
+
+        let depth = self.frames.len();
+        self.call_value(callee, argc)?;
+
+        // Natives and classes without initializers return without entering a frame
+        if self.frames.len() == depth {
+            return self.pop();
+        }
+
+        self.execute().map(Option::unwrap_or_default)
+    }
+
+    /// Attach the source position of the failing instruction to a runtime error.
+    pub fn locate(&self, error: RuntimeError) -> InterpretError {
+        // Errors in code called back into by natives were located where they happened
+        if let RuntimeError::Callback(error) = error {
+            return *error;
+        }
+
+        InterpretError::Runtime {
+            error,
+            position: self.chunk().get_position(self.offset),
+        }
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("Instructions only execute within a frame")
+    }
+
+    fn read_byte(&mut self) -> Result<u8, RuntimeError> {
+        let frame = self.frame_mut();
+        let &byte = frame
+            .closure
+            .function
+            .chunk
+            .code
+            .get(frame.ip)
+            .ok_or(RuntimeError::UnexpectedEnd)?;
+        frame.ip += 1;
+
+        Ok(byte)
+    }
+
+    fn read_jump(&mut self) -> Result<usize, RuntimeError> {
+        let frame = self.frame_mut();
+        let distance = frame
+            .closure
+            .function
+            .chunk
+            .jump_distance(frame.ip)
+            .ok_or(RuntimeError::UnexpectedEnd)?;
+        frame.ip += JUMP_OPERAND_SIZE;
+
+        Ok(distance)
+    }
+
+    fn read_constant(&mut self, width: usize) -> Result<Value, RuntimeError> {
+        let offset = self.offset;
+        let frame = self.frame_mut();
+        let (_i, constant) = frame
+            .closure
+            .function
+            .chunk
+            .get_constant(frame.ip, width)
+            .ok_or(RuntimeError::InvalidConstant { offset })?;
+        frame.ip += width;
+
+        Ok(constant)
+    }
+
+    /// Read the constant index operand of `code`, which names a string constant.
+    fn read_name(&mut self, code: OpCode) -> Result<Rc<String>, RuntimeError> {
+        let width = code
+            .operand()
+            .index_width()
+            .expect("Only instructions with a name operand read one");
+        match self.read_constant(width)?.unpack() {
+            Unpacked::String(name) => Ok(name),
+            _ => Err(RuntimeError::InvalidConstant {
+                offset: self.offset,
+            }),
+        }
+    }
+
+    /// Read a one-byte local slot operand, returning the absolute stack index it refers to.
+    fn local_slot(&mut self) -> Result<usize, RuntimeError> {
+        let slot = usize::from(self.read_byte()?) + self.frame_mut().slots;
+        if slot >= self.stack.len() {
+            return Err(RuntimeError::StackUnderflow {
+                offset: self.offset,
+            });
+        }
+
+        Ok(slot)
+    }
+
+    /// Push local `slot` of the current frame, for the short forms of `GetLocal`.
+    fn get_local(&mut self, slot: usize) -> Result<(), RuntimeError> {
+        let slot = slot + self.frame_mut().slots;
+        let value = self
+            .stack
+            .get(slot)
+            .cloned()
+            .ok_or(RuntimeError::StackUnderflow {
+                offset: self.offset,
+            })?;
+
+        self.push(value)
+    }
+
+    fn read_upvalue(&mut self) -> Result<Rc<RefCell<Upvalue>>, RuntimeError> {
+        let index = usize::from(self.read_byte()?);
+        self.frame_mut()
+            .closure
+            .upvalues
+            .get(index)
+            .cloned()
+            .ok_or(RuntimeError::InvalidConstant {
+                offset: self.offset,
+            })
+    }
+
+    /// Call `callee`, which sits below its `argc` arguments on the stack.
+    fn call_value(&mut self, callee: &Value, argc: u8) -> Result<(), RuntimeError> {
+        let callee_slot = self.stack.len() - usize::from(argc) - 1;
+
+        match callee.unpack() {
+            Unpacked::Closure(closure) => self.call_closure(closure, argc),
+
+            Unpacked::BoundMethod(bound) => {
+                self.stack[callee_slot] = bound.receiver.clone();
+                self.call_closure(Rc::clone(&bound.method), argc)
+            }
+
+            Unpacked::Class(class) => {
+                let instance = Instance {
+                    class: Rc::clone(&class),
+                    fields: RefCell::default(),
+                };
+                self.stack[callee_slot] = Value::from(Rc::new(instance));
+
+                let initializer = class.methods.borrow().get("init").cloned();
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, argc),
+                    None if argc != 0 => Err(RuntimeError::Arity {
+                        expected: 0,
+                        got: argc,
+                    }),
+                    None => Ok(()),
+                }
+            }
+
+            Unpacked::Native(native) => {
+                if argc != native.arity {
+                    return Err(RuntimeError::Arity {
+                        expected: native.arity,
+                        got: argc,
+                    });
                 }
 
-                OpCode::Add => self.binary_operator(std::ops::Add::add),
-                OpCode::Sub => self.binary_operator(std::ops::Sub::sub),
-                OpCode::Mul => self.binary_operator(std::ops::Mul::mul),
-                OpCode::Div => self.binary_operator(std::ops::Div::div),
+                // The native may call back into the VM, which pushes onto the stack
+                let arguments = self.stack[callee_slot + 1..].to_vec();
+                let result = (native.function)(self, &arguments).map_err(|error| match error {
+                    NativeError::Message(message) => RuntimeError::Native {
+                        name: native.name.clone(),
+                        message,
+                    },
+                    NativeError::Callback(error) => RuntimeError::Callback(Box::new(error)),
+                })?;
+
+                self.stack.truncate(callee_slot);
+                self.push(result)
+            }
+
+            _ => Err(RuntimeError::NotCallable),
+        }
+    }
+
+    fn call_closure(&mut self, closure: Rc<Closure>, argc: u8) -> Result<(), RuntimeError> {
+        if argc != closure.function.arity {
+            return Err(RuntimeError::Arity {
+                expected: closure.function.arity,
+                got: argc,
+            });
+        }
+
+        if self.frames.len() >= FRAMES_MAX {
+            return Err(RuntimeError::CallDepth { max: FRAMES_MAX });
+        }
 
-                OpCode::Return => {
-                    println!("Returned: {:?}", self.stack.pop());
-                    return Ok(());
+        // Functions from a loaded chunk were verified with it, but Rust code can hand `call` any
+        // closure, and the fast path trusts its bytecode
+        if !closure.function.verified.get() {
+            verify_closure(&closure.function).map_err(RuntimeError::Verify)?;
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - usize::from(argc) - 1,
+        });
+
+        Ok(())
+    }
+
+    /// Call the method `name` on the receiver below the `argc` arguments on the stack.
+    fn invoke(&mut self, name: &str, argc: u8) -> Result<(), RuntimeError> {
+        let Unpacked::Instance(instance) = self.peek(usize::from(argc))?.unpack() else {
+            return Err(RuntimeError::NotAnInstance);
+        };
+
+        // A field holding a function shadows a method of the same name
+        let field = instance.fields.borrow().get(name).cloned();
+        if let Some(field) = field {
+            let callee_slot = self.stack.len() - usize::from(argc) - 1;
+            self.stack[callee_slot] = field.clone();
+            return self.call_value(&field, argc);
+        }
+
+        self.invoke_from_class(&instance.class, name, argc)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: &Class,
+        name: &str,
+        argc: u8,
+    ) -> Result<(), RuntimeError> {
+        let method = class.methods.borrow().get(name).cloned();
+        match method {
+            Some(method) => self.call_closure(method, argc),
+            None => Err(RuntimeError::UndefinedProperty {
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    /// The upvalue for the variable in stack slot `slot`, shared with any closure that already
+    /// captured it.
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let existing = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s == slot));
+
+        if let Some(existing) = existing {
+            return Rc::clone(existing);
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(Rc::clone(&upvalue));
+        upvalue
+    }
+
+    /// Drop every frame and stack slot, closing the upvalues of closures that outlive them.
+    fn reset(&mut self) {
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        self.offset = 0;
+    }
+
+    /// Move the values of variables in slots `from` and above into the upvalues capturing them.
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(slot) if slot >= from => {
+                    *upvalue = Upvalue::Closed(stack[slot].clone());
+                    false
                 }
+                _ => true,
             }
+        });
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.stack.len() >= self.stack_max {
+            return Err(RuntimeError::StackOverflow {
+                offset: self.offset,
+                max: self.stack_max,
+            });
         }
+
+        self.stack.push(value);
+        Ok(())
     }
 
-    // TODO: This helper will probably not work when Value becomes more complex
-    fn binary_operator<F>(&mut self, operator: F)
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
+        self.stack.pop().ok_or(RuntimeError::StackUnderflow {
+            offset: self.offset,
+        })
+    }
+
+    /// The value `distance` slots below the top of the stack.
+    fn peek(&self, distance: usize) -> Result<&Value, RuntimeError> {
+        self.stack
+            .len()
+            .checked_sub(distance + 1)
+            .and_then(|i| self.stack.get(i))
+            .ok_or(RuntimeError::StackUnderflow {
+                offset: self.offset,
+            })
+    }
+
+    fn number_operator<F>(&mut self, operator: F) -> Result<(), RuntimeError>
     where
-        F: Fn(Value, Value) -> Value,
+        F: Fn(f64, f64) -> Value,
     {
-        let r = self
-            .stack
-            .pop()
-            .expect("Attempted to pop value, but the stack was empty");
-        let l = self
-            .stack
-            .pop()
-            .expect("Attempted to pop value, but the stack was empty");
-        self.stack.push(operator(l, r));
+        let r = self.pop()?;
+        let l = self.pop()?;
+        let (Some(l), Some(r)) = (l.as_number(), r.as_number()) else {
+            return Err(RuntimeError::OperandsMustBeNumbers);
+        };
+
+        self.push(operator(l, r))
+    }
+}
+
+/// `class`'s method `name` bound to `receiver`.
+pub fn bind_method(class: &Class, name: &str, receiver: Value) -> Result<Value, RuntimeError> {
+    let method = class.methods.borrow().get(name).cloned().ok_or_else(|| {
+        RuntimeError::UndefinedProperty {
+            name: name.to_string(),
+        }
+    })?;
+
+    Ok(Value::from(Rc::new(BoundMethod { receiver, method })))
+}
+
+/// The distance of a jump whose little-endian operand bytes are `low` and `high`.
+fn jump_distance(low: u8, high: u8) -> usize {
+    usize::from(u16::from_le_bytes([low, high]))
+}
+
+/// The `+` operator, which adds numbers and concatenates strings.
+pub fn add(a: &Value, b: &Value) -> Result<Value, RuntimeError> {
+    if let (Some(a), Some(b)) = (a.as_number(), b.as_number()) {
+        return Ok(Value::from(a + b));
+    }
+
+    if let (Some(a), Some(b)) = (a.as_str(), b.as_str()) {
+        return Ok(Value::string(format!("{a}{b}")));
+    }
+
+    Err(RuntimeError::OperandsMustBeNumbersOrStrings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    /// A closure whose code was never verified, as Rust code could build one.
+    fn unverified(code: &str) -> Value {
+        let function = Function {
+            chunk: assemble(code).unwrap(),
+            ..Function::default()
+        };
+
+        Value::from(Rc::new(Closure {
+            function: Rc::new(function),
+            upvalues: Vec::new(),
+        }))
+    }
+
+    /// Step through `code` without verifying it first, as only a bug in the verifier would, until
+    /// it fails. The checks `step` makes are all that stands between such code and a panic.
+    fn step_unverified(code: &str) -> RuntimeError {
+        let mut vm = VM::new();
+        let closure = Rc::new(Closure {
+            function: Rc::new(Function {
+                chunk: assemble(code).unwrap(),
+                ..Function::default()
+            }),
+            upvalues: Vec::new(),
+        });
+        vm.stack.push(Value::from(Rc::clone(&closure)));
+        vm.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: 0,
+        });
+
+        loop {
+            match vm.step() {
+                Ok(Step::Continue) => {}
+                Ok(Step::Returned(_)) => panic!("{code:?} ran to completion"),
+                Err(error) => return error,
+            }
+        }
+    }
+
+    #[test]
+    fn reports_stack_underflow() {
+        // Only the script's closure is on the stack to begin with
+        assert!(matches!(
+            step_unverified(".line 1\nPop\nPop"),
+            RuntimeError::StackUnderflow { offset: 1 }
+        ));
+        assert!(matches!(
+            step_unverified(".line 1\nAdd"),
+            RuntimeError::StackUnderflow { offset: 0 }
+        ));
+        assert!(matches!(
+            step_unverified(".line 1\nGetLocal 3"),
+            RuntimeError::StackUnderflow { offset: 0 }
+        ));
+        assert!(matches!(
+            step_unverified(".line 1\nNil\nSetLocal 2"),
+            RuntimeError::StackUnderflow { offset: 1 }
+        ));
+    }
+
+    #[test]
+    fn rejects_chunks_deeper_than_the_stack() {
+        let code = ".line 1\nNil\nNil\nNil\nNil\nAdd\nAdd\nAdd\nPop\nNil\nReturn";
+
+        // The script's closure and four values
+        let mut vm = VM::with_stack_max(4);
+        let error = vm.interpret_chunk(assemble(code).unwrap()).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                InterpretError::Runtime {
+                    error: RuntimeError::StackLimit { needed: 5, max: 4 },
+                    position: None,
+                }
+            ),
+            "{error:?}"
+        );
+
+        let mut vm = VM::with_stack_max(5);
+        assert!(vm.interpret_chunk(assemble(code).unwrap()).is_err_and(|e| {
+            // Adding nils fails, but only once it runs
+            matches!(
+                e,
+                InterpretError::Runtime {
+                    error: RuntimeError::OperandsMustBeNumbersOrStrings,
+                    ..
+                }
+            )
+        }));
+    }
+
+    #[test]
+    fn reports_stack_overflow_in_deep_recursion() {
+        let mut vm = VM::with_stack_max(32);
+        let error = vm
+            .interpret("fun f(n) {\n  return f(n + 1);\n}\nf(0);")
+            .unwrap_err();
+
+        // The stack runs out long before the frames do
+        assert!(
+            matches!(
+                error,
+                InterpretError::Runtime {
+                    error: RuntimeError::StackOverflow { max: 32, .. },
+                    position: Some(Position { line: 2, .. }),
+                }
+            ),
+            "{error:?}"
+        );
+
+        // The same program fails the same way again rather than panicking
+        let error = vm.interpret("f(0);").unwrap_err();
+        assert!(matches!(
+            error,
+            InterpretError::Runtime {
+                error: RuntimeError::StackOverflow { max: 32, .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn verifies_closures_called_from_rust() {
+        let mut vm = VM::new();
+        let invalid = unverified(".line 1\n.byte 0xff");
+
+        let result = vm.call(&invalid, &[]);
+        assert!(
+            matches!(
+                result,
+                Err(InterpretError::Runtime {
+                    error: RuntimeError::Verify(_),
+                    ..
+                })
+            ),
+            "{result:?}"
+        );
+
+        // Nothing was left behind, and the VM is still usable
+        assert!(vm.stack().is_empty());
+        assert_eq!(vm.depth(), 0);
+        let three = vm.interpret("1 + 2;").unwrap();
+        assert_eq!(three.as_number(), Some(3.0));
+    }
+
+    #[test]
+    fn verifies_closures_called_from_lox() {
+        let mut vm = VM::new();
+        vm.define_global("invalid", unverified(".line 1\n.byte 0xff"));
+
+        let error = vm.interpret("invalid();").unwrap_err();
+        assert!(error.to_string().contains("Invalid bytecode"), "{error}");
+    }
+
+    #[test]
+    fn runs_valid_closures_once_verified() {
+        let mut vm = VM::new();
+        let two = unverified(".line 1\nConstant 2\nReturn");
+
+        for _ in 0..2 {
+            let result = vm.call(&two, &[]).unwrap();
+            assert_eq!(result.as_number(), Some(2.0));
+        }
+
+        let Unpacked::Closure(closure) = two.unpack() else {
+            panic!("not a closure");
+        };
+        assert!(closure.function.verified.get());
+    }
+
+    #[test]
+    fn verifies_evaluated_expressions() {
+        let mut vm = VM::new();
+        vm.interpret("var a = 20;").unwrap();
+
+        let result = vm.evaluate("a * 2 + 2", None).unwrap();
+        assert_eq!(result.as_number(), Some(42.0));
     }
 }
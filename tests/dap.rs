@@ -0,0 +1,200 @@
+//! Drives `rlox dap` as an editor would, over Content-Length framed stdin and stdout.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use serde_json::{Value as Json, json};
+
+const PROGRAM: &str = "\
+var total = 0;
+fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+{
+  var x = 1;
+  total = add(x, 2);
+}
+print total;
+";
+
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: i64,
+    /// Events received while waiting for responses
+    events: Vec<Json>,
+}
+
+impl Client {
+    fn spawn() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .arg("dap")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        Self {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            seq: 0,
+            events: Vec::new(),
+        }
+    }
+
+    fn receive(&mut self) -> Json {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            assert_ne!(
+                self.stdout.read_line(&mut header).unwrap(),
+                0,
+                "adapter hung up"
+            );
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length: ") {
+                length = Some(value.parse().unwrap());
+            }
+        }
+
+        let mut body = vec![0; length.expect("missing Content-Length")];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Send a request and return the body of its response, which must succeed.
+    fn request(&mut self, command: &str, arguments: Json) -> Json {
+        self.seq += 1;
+        let request = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        });
+        let body = request.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+        self.stdin.flush().unwrap();
+
+        loop {
+            let message = self.receive();
+            if message["type"] == "event" {
+                self.events.push(message);
+            } else if message["request_seq"] == self.seq {
+                assert_eq!(message["success"], true, "{command} failed: {message}");
+                return message["body"].clone();
+            }
+        }
+    }
+
+    fn wait_for(&mut self, event: &str) -> Json {
+        if let Some(i) = self.events.iter().position(|e| e["event"] == event) {
+            return self.events.remove(i);
+        }
+        loop {
+            let message = self.receive();
+            if message["event"] == event {
+                return message;
+            }
+            self.events.push(message);
+        }
+    }
+}
+
+fn names(variables: &Json) -> Vec<String> {
+    variables["variables"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| {
+            format!(
+                "{} = {}",
+                v["name"].as_str().unwrap(),
+                v["value"].as_str().unwrap()
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn inspects_a_paused_program() {
+    let path = std::env::temp_dir().join(format!("rlox-dap-{}.lox", std::process::id()));
+    fs::write(&path, PROGRAM).unwrap();
+
+    let mut client = Client::spawn();
+    let capabilities = client.request("initialize", json!({ "adapterID": "rlox" }));
+    assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+    client.wait_for("initialized");
+
+    let breakpoints = client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": path }, "breakpoints": [{ "line": 3 }] }),
+    );
+    assert_eq!(breakpoints["breakpoints"][0]["line"], 3);
+
+    client.request("launch", json!({ "program": path }));
+    let changed = client.wait_for("breakpoint");
+    assert_eq!(changed["body"]["breakpoint"]["verified"], true);
+
+    client.request("configurationDone", json!({}));
+    let stopped = client.wait_for("stopped");
+    assert_eq!(stopped["body"]["reason"], "breakpoint");
+
+    let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+    let frames = trace["stackFrames"].as_array().unwrap();
+    let frames: Vec<_> = frames
+        .iter()
+        .map(|f| {
+            (
+                f["id"].as_i64().unwrap(),
+                f["name"].as_str().unwrap(),
+                f["line"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(frames, [(0, "<fn add>", 3), (1, "<script>", 8)]);
+
+    let scopes = client.request("scopes", json!({ "frameId": 0 }));
+    let scopes = scopes["scopes"].as_array().unwrap();
+    assert_eq!(scopes[0]["name"], "Locals");
+    assert_eq!(scopes[1]["name"], "Globals");
+
+    let locals = client.request(
+        "variables",
+        json!({ "variablesReference": scopes[0]["variablesReference"] }),
+    );
+    assert_eq!(names(&locals), ["a = 1", "b = 2"]);
+
+    let globals = client.request(
+        "variables",
+        json!({ "variablesReference": scopes[1]["variablesReference"] }),
+    );
+    assert!(names(&globals).contains(&"total = 0".to_string()));
+
+    let caller = client.request("scopes", json!({ "frameId": 1 }));
+    let caller = client.request(
+        "variables",
+        json!({ "variablesReference": caller["scopes"][0]["variablesReference"] }),
+    );
+    assert_eq!(names(&caller), ["x = 1"]);
+
+    let sum = client.request("evaluate", json!({ "expression": "a + b", "frameId": 0 }));
+    assert_eq!(sum["result"], "3");
+    let x = client.request("evaluate", json!({ "expression": "x * 10", "frameId": 1 }));
+    assert_eq!(x["result"], "10");
+
+    client.request("continue", json!({ "threadId": 1 }));
+    let output = client.wait_for("output");
+    assert_eq!(output["body"]["output"], "3\n");
+    client.wait_for("terminated");
+
+    client.request("disconnect", json!({}));
+    assert!(client.child.wait().unwrap().success());
+    fs::remove_file(path).unwrap();
+}
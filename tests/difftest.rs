@@ -0,0 +1,24 @@
+//! Runs every test program through the tree-walker and each VM, which must agree on output and
+//! errors.
+
+use std::fs;
+use std::path::Path;
+
+use rlox::{compare, scripts};
+
+#[test]
+fn interpreters_agree_on_the_test_programs() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let scripts = scripts(&[dir]).unwrap();
+    assert!(!scripts.is_empty());
+
+    let diverged: Vec<_> = scripts
+        .iter()
+        .filter_map(|path| {
+            let source = fs::read_to_string(path).unwrap();
+            compare(&source).map(|diff| format!("{}\n{diff}", path.display()))
+        })
+        .collect();
+
+    assert!(diverged.is_empty(), "{}", diverged.join("\n"));
+}
@@ -0,0 +1,276 @@
+//! Embeds the VM as a Rust program would, through the crate's root exports alone.
+
+use rlox::{Context, InterpretError, NativeError, RuntimeError, VM, Value};
+
+fn number(value: Option<Value>) -> Option<f64> {
+    value.and_then(|v| v.as_number())
+}
+
+#[test]
+fn returns_the_value_of_the_final_expression() {
+    let mut vm = VM::new();
+
+    let value = vm.interpret("var a = 2;\na * 21;").unwrap();
+    assert_eq!(value.as_number(), Some(42.0));
+
+    let value = vm.interpret("\"a\" + \"b\";").unwrap();
+    assert_eq!(value.as_str(), Some("ab"));
+
+    // Only a final expression statement has a value
+    let value = vm.interpret("a * 21;\nvar b = 1;").unwrap();
+    assert!(value.is_nil());
+}
+
+#[test]
+fn shares_globals_with_scripts() {
+    let mut vm = VM::new();
+    vm.define_global("limit", 10.0);
+    vm.define_global("name", Value::string("lox"));
+
+    vm.interpret("var count = limit + 1;\nvar greeting = \"hi \" + name;")
+        .unwrap();
+    assert_eq!(number(vm.global("count")), Some(11.0));
+    assert_eq!(
+        vm.global("greeting").as_ref().and_then(Value::as_str),
+        Some("hi lox")
+    );
+    assert!(vm.global("missing").is_none());
+
+    vm.set_global("count", 1.0).unwrap();
+    let value = vm.interpret("count + 1;").unwrap();
+    assert_eq!(value.as_number(), Some(2.0));
+
+    // Assigning never defines a global
+    assert!(matches!(
+        vm.set_global("missing", 1.0),
+        Err(RuntimeError::UndefinedVariable { name }) if name == "missing"
+    ));
+    assert!(vm.global("missing").is_none());
+}
+
+fn hypot(_context: &mut dyn Context, args: &[Value]) -> Result<Value, NativeError> {
+    match (args[0].as_number(), args[1].as_number()) {
+        (Some(a), Some(b)) => Ok(Value::from(a.hypot(b))),
+        _ => Err(NativeError::Message(
+            "Arguments must be numbers.".to_string(),
+        )),
+    }
+}
+
+/// Call the Lox function given as the first argument with the second.
+fn apply(context: &mut dyn Context, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(context.call(&args[0], &args[1..])?)
+}
+
+#[test]
+fn calls_natives_defined_in_rust() {
+    let mut vm = VM::new();
+    vm.define_native("hypot", 2, hypot);
+    vm.define_native("apply", 2, apply);
+
+    let value = vm.interpret("hypot(3, 4);").unwrap();
+    assert_eq!(value.as_number(), Some(5.0));
+
+    let value = vm
+        .interpret("fun twice(x) { return x * 2; }\napply(twice, hypot(6, 8));")
+        .unwrap();
+    assert_eq!(value.as_number(), Some(20.0));
+
+    let Err(InterpretError::Runtime { error, position }) = vm.interpret("\nhypot(3, nil);") else {
+        panic!("expected a runtime error");
+    };
+    assert_eq!(error.to_string(), "hypot: Arguments must be numbers.");
+    assert_eq!(position.map(|p| p.line), Some(2));
+
+    let Err(InterpretError::Runtime { error, .. }) = vm.interpret("hypot(3);") else {
+        panic!("expected a runtime error");
+    };
+    assert!(matches!(
+        error,
+        RuntimeError::Arity {
+            expected: 2,
+            got: 1
+        }
+    ));
+}
+
+#[test]
+fn calls_lox_from_rust() {
+    let mut vm = VM::new();
+    vm.interpret("var total = 0;\nfun add(n) { total = total + n; return total; }")
+        .unwrap();
+
+    let add = vm.global("add").unwrap();
+    for n in [1.0, 2.0, 3.0] {
+        vm.call(&add, &[Value::from(n)]).unwrap();
+    }
+    assert_eq!(number(vm.global("total")), Some(6.0));
+}
+
+#[test]
+fn runtime_errors_unwind_the_vm() {
+    let mut vm = VM::new();
+    let result = vm.interpret(
+        "\
+var get;
+fun fail(n) {
+  var captured = n;
+  fun inner() { return captured; }
+  get = inner;
+  return n + nil;
+}
+fail(42);",
+    );
+    assert!(matches!(result, Err(InterpretError::Runtime { .. })));
+    assert_eq!(vm.depth(), 0);
+    assert!(vm.stack().is_empty());
+
+    // A closure that outlives the failed frame keeps the value it captured
+    let get = vm.global("get").unwrap();
+    assert_eq!(number(vm.call(&get, &[]).ok()), Some(42.0));
+    assert_eq!(vm.depth(), 0);
+    assert!(vm.stack().is_empty());
+}
+
+/// Sort a list of numbers separated by spaces with a Lox comparator returning whether its first
+/// argument goes before its second, calling back into Lox for every comparison.
+fn sort(context: &mut dyn Context, args: &[Value]) -> Result<Value, NativeError> {
+    let list = args[0]
+        .as_str()
+        .ok_or_else(|| NativeError::Message("List must be a string.".to_string()))?;
+    let mut items = list
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|e| NativeError::Message(e.to_string()))?;
+
+    // An insertion sort, since the comparator can fail
+    for i in 1..items.len() {
+        let mut j = i;
+        while j > 0 {
+            let before = context.call(&args[1], &[items[j].into(), items[j - 1].into()])?;
+            if before.is_falsey() {
+                break;
+            }
+            items.swap(j, j - 1);
+            j -= 1;
+        }
+    }
+
+    let sorted: Vec<_> = items
+        .into_iter()
+        .map(|n| Value::from(n).to_string())
+        .collect();
+    Ok(Value::string(sorted.join(" ")))
+}
+
+/// Call the Lox function given as the only argument, returning the message of any error it
+/// fails with instead of failing.
+fn attempt(context: &mut dyn Context, args: &[Value]) -> Result<Value, NativeError> {
+    match context.call(&args[0], &[]) {
+        Ok(value) => Ok(value),
+        Err(error) => Ok(Value::string(error.to_string())),
+    }
+}
+
+fn sorting_vm() -> VM {
+    let mut vm = VM::new();
+    vm.define_native("sort", 2, sort);
+    vm.define_native("attempt", 1, attempt);
+    vm.interpret(
+        "\
+fun less(a, b) { return a < b; }
+fun greater(a, b) { return less(b, a); }
+
+// Sorts again inside each comparison, so the VM is entered from a native called by Lox code
+// that was itself called by a native
+fun nested(a, b) {
+  var inner = sort(\"3 1 2\", less);
+  if (inner != \"1 2 3\") return nil;
+  return greater(a, b);
+}
+
+fun broken(a, b) {
+  return a < nil;
+}
+fun sortBroken() {
+  return sort(\"2 1\", broken);
+}
+",
+    )
+    .unwrap();
+    vm
+}
+
+#[test]
+fn natives_call_back_into_lox_at_any_depth() {
+    let mut vm = sorting_vm();
+
+    let value = vm.interpret("sort(\"5 3 8 1\", less);").unwrap();
+    assert_eq!(value.as_str(), Some("1 3 5 8"));
+
+    // The caller's locals and the operands waiting on the call survive it
+    let value = vm
+        .interpret(
+            "\
+var result;
+{
+  var before = \"<\";
+  var sorted = before + sort(\"5 3 8 1\", nested) + \">\";
+  var after = \"!\";
+  result = sorted + after;
+}
+result;",
+        )
+        .unwrap();
+    assert_eq!(value.as_str(), Some("<8 5 3 1>!"));
+
+    let sort = vm.global("sort").unwrap();
+    let greater = vm.global("greater").unwrap();
+    let value = vm.call(&sort, &[Value::string("2 10 1"), greater]).unwrap();
+    assert_eq!(value.as_str(), Some("10 2 1"));
+    assert_eq!(vm.depth(), 0);
+    assert!(vm.stack().is_empty());
+}
+
+#[test]
+fn errors_in_callbacks_leave_the_caller_intact() {
+    let mut vm = sorting_vm();
+
+    // The comparator fails two levels of natives down, and the script carries on
+    let value = vm
+        .interpret(
+            "\
+var result;
+{
+  var before = \"<\";
+  var message = attempt(sortBroken);
+  var after = \">\";
+  result = before + message + after + sort(\"2 1\", less);
+}
+result;",
+        )
+        .unwrap();
+    assert_eq!(
+        value.as_str(),
+        Some("<[13:12] Runtime error: Operands must be numbers.>1 2")
+    );
+
+    let sort = vm.global("sort").unwrap();
+    let broken = vm.global("broken").unwrap();
+    let (depth, height) = (vm.depth(), vm.stack().len());
+    assert!(vm.call(&sort, &[Value::string("2 1"), broken]).is_err());
+    assert_eq!((vm.depth(), vm.stack().len()), (depth, height));
+
+    // Uncaught, the error is reported where it happened rather than at the native
+    let Err(InterpretError::Runtime { error, position }) = vm.interpret("\n\nsortBroken();") else {
+        panic!("expected a runtime error");
+    };
+    assert!(matches!(error, RuntimeError::OperandsMustBeNumbers));
+    assert_eq!(position.map(|p| p.line), Some(13));
+    assert_eq!(vm.depth(), 0);
+    assert!(vm.stack().is_empty());
+
+    let value = vm.interpret("sort(\"2 1\", less);").unwrap();
+    assert_eq!(value.as_str(), Some("1 2"));
+}